@@ -5,9 +5,8 @@
 //! inference, and deployment, centered on its decoder-only transformer architecture
 //! with SwiGLU activations, RoPE embeddings, and grouped-query attention (GQA).
 
-use crate::messages::{AiRequest, AiResponse, ResponseMetadata, MessageContext, AiParameters};
-use std::time::{Duration, Instant};
-use rand::Rng;
+use crate::messages::{ResponseMetadata, MessageContext};
+use std::time::Instant;
 
 /// Configuration for AI processing
 #[derive(Clone, Debug)]
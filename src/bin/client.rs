@@ -6,14 +6,24 @@
 //! - Process AI queries locally
 //!
 //! Usage:
-//!   cargo run --bin client download [torrent_file] [output_file] [tracker_server] [tracker_port]
+//!   cargo run --bin client download [torrent_file] [output_file] [tracker_server] [tracker_port] [--port=N] [--sequential] [--seed] [--seed-ratio=R] [--seed-time=SECS] [--dht] [--dht-port=N] [--dht-bootstrap=HOST:PORT,...] [--dht-quic] [--no-lsd] [--lsd-group=IP:PORT] [--lsd-loopback]
+//!   cargo run --bin client session [torrent_file]... [--out=DIR] [--tracker=HOST:PORT] [--max-active=N] [--max-peers=N] [--download-rate=BYTES] [--upload-rate=BYTES] [download flags]
+//!   cargo run --bin client dht-node [port] [--dht-bootstrap=HOST:PORT,...] [--dht-quic]
+//!   cargo run --bin client info [server] [port]
+//!   cargo run --bin client list [server] [port] [pattern]
+//!   cargo run --bin client watch [server] [port] [--swarm=HASH]... [--seed-files] [--work-nodes]
+//!   cargo run --bin client publish [local_file] [remote_name] [server] [port] [--token=T] [--torrent]
+//!   cargo run --bin client fetch [file] [output_file] [server] [port] [--offset=N] [--length=N] [--tail=N] [--resume]
 //!   cargo run --bin client ai-query [server] [port] [query]
 //!   cargo run --bin client ai-local [query]
 
+use quic_torrent_client_server::budget;
 use quic_torrent_client_server::client;
+use quic_torrent_client_server::dht;
+use quic_torrent_client_server::lsd;
 use quic_torrent_client_server::logger;
+use quic_torrent_client_server::session;
 use std::env;
-use std::io;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -37,6 +47,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "download" => {
             handle_download(&args[2..]).await?;
         }
+        "fetch" => {
+            handle_fetch(&args[2..]).await?;
+        }
+        "publish" => {
+            handle_publish(&args[2..]).await?;
+        }
+        "list" | "ls" => {
+            handle_list(&args[2..]).await?;
+        }
+        "info" => {
+            handle_info(&args[2..]).await?;
+        }
+        "watch" => {
+            handle_watch(&args[2..]).await?;
+        }
+        "dht-node" => {
+            handle_dht_node(&args[2..]).await?;
+        }
+        "session" => {
+            handle_session(&args[2..]).await?;
+        }
         _ => {
             println!("Unknown command: {}", command);
             print_usage();
@@ -71,6 +102,12 @@ fn print_complete_instructions() {
     println!("   # Download with defaults (uses 127.0.0.1:7001):");
     println!("   cargo run --bin client download hello_world.txt output.txt");
     println!();
+    println!("   # Fetch the last 4096 bytes of log.txt (no torrent needed):");
+    println!("   cargo run --bin client fetch log.txt log_tail.txt 162.221.207.169 7001 --tail=4096");
+    println!();
+    println!("   # Resume a partial copy of medium.bin:");
+    println!("   cargo run --bin client fetch medium.bin medium.bin 162.221.207.169 7001 --resume");
+    println!();
     println!("2. SEND AI QUERY:");
     println!("   Use the random_json_test binary for AI queries:");
     println!("   cargo run --release --bin random_json_test -- 162.221.207.169 7001 10");
@@ -104,11 +141,7 @@ fn print_complete_instructions() {
     println!("  - Features:     File serving, Tracker announce, AI processing");
     println!();
     println!("Available Files on Server:");
-    println!("  - hello_world.txt");
-    println!("  - small.txt");
-    println!("  - medium.bin");
-    println!("  - data.json");
-    println!("  - log.txt");
+    println!("  List them with: cargo run --bin client list 162.221.207.169 7001");
     println!("  (Files are in ~/seed/ directory on server)");
     println!();
     println!("========================================");
@@ -141,12 +174,72 @@ fn print_usage() {
     println!("  (no command) | console | interactive");
     println!("    Start interactive console with input/output areas (default)");
     println!();
-    println!("  download [torrent_file] [output_file] [tracker_server] [tracker_port]");
-    println!("    Download a file using a torrent (QUIC protocol)");
+    println!("  download [torrent_file] [output_file] [tracker_server] [tracker_port] [--port=N] [--sequential] [--seed] [--seed-ratio=R] [--seed-time=SECS] [--dht] [--dht-port=N] [--dht-bootstrap=HOST:PORT,...] [--dht-quic] [--no-lsd] [--lsd-group=IP:PORT] [--lsd-loopback]");
+    println!("    Download a file using a torrent (QUIC protocol), fetching pieces from peers and the server");
     println!("    tracker_server: Server IP or hostname (default: 127.0.0.1)");
     println!("    tracker_port: Server port (default: 7001)");
+    println!("    --port: UDP port to accept peer connections on (default: 6881, or the next free one)");
+    println!("    --sequential: download pieces in order (for streaming) instead of rarest-first");
+    println!("    --seed: keep serving pieces after the download completes, until Ctrl+C");
+    println!("    --seed-ratio: stop seeding once uploaded bytes reach R times the file size (implies --seed)");
+    println!("    --seed-time: stop seeding after SECS seconds (implies --seed)");
+    println!("    --dht: also find peers through the DHT; the download then works without the tracker");
+    println!("    --dht-port: UDP port for the DHT node (default: 6890; implies --dht)");
+    println!("    --dht-bootstrap: comma-separated DHT nodes to join through (implies --dht)");
+    println!("    --dht-quic: talk to DHT nodes over QUIC instead of plain UDP (implies --dht)");
+    println!("    --no-lsd: don't look for peers on the local network");
+    println!("    --lsd-group: multicast group for local discovery (default: 239.192.152.143:6771)");
+    println!("    --lsd-loopback: also discover clients on this host");
     println!("    Example: download seed\\file.torrent downloaded\\file.txt 192.168.1.100 7001");
     println!();
+    println!("  session [torrent_file]... [--out=DIR] [--tracker=HOST:PORT] [--max-active=N] [--max-peers=N] [--download-rate=BYTES] [--upload-rate=BYTES] [download flags]");
+    println!("    Download (and seed) several torrents at once, printing their status until all are done");
+    println!("    --out: directory for the downloaded files (default: downloaded)");
+    println!("    --tracker: tracker for torrents whose announce URL isn't quic://HOST:PORT");
+    println!("    --max-active: torrents downloading at once; the rest wait in a queue (default: 4)");
+    println!("    --max-peers: outgoing peer connections across all torrents (default: unlimited)");
+    println!("    --download-rate/--upload-rate: bandwidth limits in bytes per second across all torrents");
+    println!("    Accepts the download flags above (--port, --seed, --dht, --no-lsd, ...)");
+    println!("    Example: session seed\\a.torrent seed\\b.torrent --out=downloaded --max-active=1");
+    println!();
+    println!("  dht-node [port] [--dht-bootstrap=HOST:PORT,...] [--dht-quic]");
+    println!("    Run a standalone DHT node (e.g. as a bootstrap node for downloads) until Ctrl+C");
+    println!("    port: UDP port to listen on (default: 6890)");
+    println!("    Example: dht-node 6890 --dht-bootstrap=192.168.1.100:6890");
+    println!();
+    println!("  info [server] [port]");
+    println!("    Show the server's protocol version, message types, codecs and features");
+    println!("    Example: info 192.168.1.100 7001");
+    println!();
+    println!("  list [server] [port] [pattern]");
+    println!("    List files in the server's seed directory (size, SHA-256, torrent info hash)");
+    println!("    pattern: optional glob such as *.txt");
+    println!("    Example: list 192.168.1.100 7001 *.bin");
+    println!();
+    println!("  watch [server] [port] [--swarm=HASH]... [--seed-files] [--work-nodes]");
+    println!("    Print events the server pushes until Ctrl+C (everything if no flag is given)");
+    println!("    --swarm: peers joining and leaving the swarm for HASH (repeatable; * for every swarm)");
+    println!("    --seed-files: files published to or appearing in the seed directory");
+    println!("    --work-nodes: nodes registered for work distribution");
+    println!("    Example: watch 192.168.1.100 7001 --swarm=* --seed-files");
+    println!();
+    println!("  publish [local_file] [remote_name] [server] [port] [--token=T] [--torrent]");
+    println!("    Upload a file into the server's seed directory (requires the server's publish token)");
+    println!("    --token: publish token (default: QUIC_PUBLISH_TOKEN environment variable)");
+    println!("    --torrent: generate a .torrent for the file and register it with the tracker");
+    println!("    Example: publish build/app.tar.gz releases/app.tar.gz 192.168.1.100 7001 --torrent");
+    println!();
+    println!("  fetch [file] [output_file] [server] [port] [--offset=N] [--length=N] [--tail=N] [--resume]");
+    println!("    Fetch a file or a byte range of it directly by name (no torrent needed)");
+    println!("    --offset/--length: byte range to fetch; --tail: last N bytes");
+    println!("    --resume: append the missing bytes to an existing partial output file");
+    println!("    Example: fetch log.txt log_tail.txt 192.168.1.100 7001 --tail=4096");
+    println!();
+    println!("Environment:");
+    println!("  QUIC_CODEC=json|msgpack  Codec to ask servers for (default: json)");
+    println!("  QUIC_TIMEOUT_MS=MS       Give up on requests not answered within MS milliseconds (default: no limit)");
+    println!("  QUIC_AUTH_TOKEN=TOKEN    Token to send with requests to servers started with --auth-token");
+    println!();
     println!("========================================");
 }
 
@@ -157,7 +250,7 @@ async fn handle_download(args: &[String]) -> Result<(), Box<dyn std::error::Erro
         .collect();
     
     let default_torrent = "test.torrent".to_string();
-    let torrent_path = filtered_args.get(0)
+    let torrent_path = filtered_args.first()
         .map(|s| s.to_string())
         .unwrap_or(default_torrent);
     
@@ -183,6 +276,8 @@ async fn handle_download(args: &[String]) -> Result<(), Box<dyn std::error::Erro
         .and_then(|p| p.parse().ok())
         .unwrap_or(default_port);
     
+    let options = parse_download_options(args)?;
+    
     println!("========================================");
     println!("BitTorrent Client - Download (QUIC)");
     println!("========================================");
@@ -190,17 +285,448 @@ async fn handle_download(args: &[String]) -> Result<(), Box<dyn std::error::Erro
     println!("Torrent file: {}", torrent_path);
     println!("Output file: {}", output_path);
     println!("Tracker: {}:{}", tracker_server, tracker_port);
+    print_download_options(&options);
     println!("Logging to: client.log");
     println!("========================================");
     
-    client::download_file_quic_torrent(
+    client::download_file_quic_torrent_with_options(
         &torrent_path,
         &output_path,
         tracker_server,
         tracker_port,
+        &options,
     ).await?;
     
     Ok(())
 }
 
+/// Builds `DownloadOptions` from the flags shared by `download` and `session`.
+fn parse_download_options(args: &[String]) -> Result<client::DownloadOptions, Box<dyn std::error::Error>> {
+    // Port other peers connect to for pieces
+    let peer_port = match parse_flag_value::<u64>(args, "port")? {
+        Some(port) => u16::try_from(port).map_err(|_| format!("Invalid value for --port: {}", port))?,
+        None => quic_torrent_client_server::peer_wire::DEFAULT_PEER_PORT,
+    };
+    let seed_ratio: Option<f64> = parse_flag_value(args, "seed-ratio")?;
+    let seed_time: Option<u64> = parse_flag_value(args, "seed-time")?;
+    Ok(client::DownloadOptions {
+        peer_port,
+        sequential: args.iter().any(|arg| arg == "--sequential"),
+        seed: args.iter().any(|arg| arg == "--seed") || seed_ratio.is_some() || seed_time.is_some(),
+        seed_ratio,
+        seed_time: seed_time.map(std::time::Duration::from_secs),
+        dht: parse_dht_config(args, None)?,
+        lsd: parse_lsd_config(args)?,
+    })
+}
+
+fn print_download_options(options: &client::DownloadOptions) {
+    println!("Peer port: {}", options.peer_port);
+    if options.seed {
+        println!("Seeding after download: ratio={}, time={}", 
+            options.seed_ratio.map_or("unlimited".to_string(), |r| r.to_string()),
+            options.seed_time.map_or("unlimited".to_string(), |t| format!("{}s", t.as_secs())));
+    }
+    if let Some(config) = &options.dht {
+        println!("DHT: port {}, {:?}, bootstrap {:?}", config.bind.port(), config.transport, config.bootstrap);
+    }
+    match &options.lsd {
+        Some(config) => println!("Local discovery: {}{}", config.group, if config.loopback { " (loopback)" } else { "" }),
+        None => println!("Local discovery: off"),
+    }
+}
+
+/// Runs a `Session` with several torrents, printing their status until all
+/// are done (or Ctrl+C).
+async fn handle_session(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let torrent_paths: Vec<&String> = args.iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    if torrent_paths.is_empty() {
+        println!("Usage: session [torrent_file]... [--out=DIR] [--tracker=HOST:PORT] [--max-active=N] [--max-peers=N] [--download-rate=BYTES] [--upload-rate=BYTES] [download flags]");
+        return Ok(());
+    }
+    let out_dir: String = parse_flag_value(args, "out")?.unwrap_or_else(|| "downloaded".to_string());
+    let default_tracker = match parse_flag_value::<String>(args, "tracker")? {
+        Some(tracker) => {
+            let (host, port) = tracker.rsplit_once(':').ok_or_else(|| format!("Invalid value for --tracker: {}", tracker))?;
+            Some((host.to_string(), port.parse::<u16>().map_err(|_| format!("Invalid value for --tracker: {}", tracker))?))
+        }
+        None => None,
+    };
+    let config = session::SessionConfig {
+        options: parse_download_options(args)?,
+        default_tracker,
+        max_active_downloads: parse_flag_value(args, "max-active")?.unwrap_or(session::DEFAULT_MAX_ACTIVE_DOWNLOADS),
+        budget: budget::BudgetConfig {
+            max_connections: parse_flag_value(args, "max-peers")?,
+            download_rate: parse_flag_value(args, "download-rate")?,
+            upload_rate: parse_flag_value(args, "upload-rate")?,
+        },
+    };
+    
+    println!("========================================");
+    println!("BitTorrent Client - Session (QUIC)");
+    println!("========================================");
+    println!("Torrents: {}", torrent_paths.len());
+    println!("Output directory: {}", out_dir);
+    println!("Max active downloads: {}", config.max_active_downloads);
+    println!("Budget: peers={}, download={}, upload={}",
+        config.budget.max_connections.map_or("unlimited".to_string(), |n| n.to_string()),
+        config.budget.download_rate.map_or("unlimited".to_string(), |r| format!("{} B/s", r)),
+        config.budget.upload_rate.map_or("unlimited".to_string(), |r| format!("{} B/s", r)));
+    print_download_options(&config.options);
+    println!("Logging to: client.log");
+    println!("========================================");
+    
+    let session = session::Session::start(config).await?;
+    for path in torrent_paths {
+        let torrent = client::TorrentFile::from_file(path)?;
+        let output = std::path::Path::new(&out_dir).join(&torrent.name);
+        match session.add_torrent(torrent, &output, None) {
+            Ok(info_hash) => println!("Added {} ({})", path, info_hash),
+            Err(e) => println!("Could not add {}: {}", path, e),
+        }
+    }
+    
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(2));
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = tokio::signal::ctrl_c() => {
+                println!("Interrupted");
+                break;
+            }
+        }
+        let statuses = session.torrents();
+        println!("----------------------------------------");
+        for status in &statuses {
+            println!("  {:<30} {:>4}/{:<4} pieces  down {:>10}  up {:>10}  {}",
+                status.name, status.pieces, status.piece_count, status.downloaded, status.uploaded, status.state);
+        }
+        let done = statuses.iter().all(|status| matches!(status.state,
+            session::TorrentState::Complete | session::TorrentState::Error(_) | session::TorrentState::Paused));
+        if done {
+            break;
+        }
+    }
+    session.shutdown().await;
+    
+    Ok(())
+}
+
+/// Builds the local discovery settings from the `--lsd*` flags.
+///
+/// # Returns
+/// `None` if `--no-lsd` is given
+fn parse_lsd_config(args: &[String]) -> Result<Option<lsd::LsdConfig>, Box<dyn std::error::Error>> {
+    if args.iter().any(|arg| arg == "--no-lsd") {
+        return Ok(None);
+    }
+    let mut config = lsd::LsdConfig::default();
+    if let Some(group) = parse_flag_value(args, "lsd-group")? {
+        config.group = group;
+    }
+    if args.iter().any(|arg| arg == "--lsd-loopback") {
+        config.loopback = true;
+    }
+    Ok(Some(config))
+}
+
+/// Builds the DHT settings from the `--dht*` flags.
+///
+/// # Returns
+/// `None` if no DHT flag is given and `port` is `None`
+fn parse_dht_config(args: &[String], port: Option<u16>) -> Result<Option<dht::DhtConfig>, Box<dyn std::error::Error>> {
+    let flag_port: Option<u16> = parse_flag_value(args, "dht-port")?;
+    let bootstrap: Option<String> = parse_flag_value(args, "dht-bootstrap")?;
+    let quic = args.iter().any(|arg| arg == "--dht-quic");
+    let enabled = port.is_some() || flag_port.is_some() || bootstrap.is_some() || quic || args.iter().any(|arg| arg == "--dht");
+    if !enabled {
+        return Ok(None);
+    }
+    Ok(Some(dht::DhtConfig {
+        bind: std::net::SocketAddr::from(([0, 0, 0, 0], port.or(flag_port).unwrap_or(dht::DEFAULT_DHT_PORT))),
+        transport: if quic { dht::DhtTransport::Quic } else { dht::DhtTransport::Udp },
+        bootstrap: bootstrap.iter()
+            .flat_map(|list| list.split(','))
+            .map(|node| node.trim().to_string())
+            .filter(|node| !node.is_empty())
+            .collect(),
+        node_id: None,
+    }))
+}
+
+async fn handle_dht_node(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let port = args.iter()
+        .find(|arg| !arg.starts_with("--"))
+        .map(|p| p.parse::<u16>().map_err(|_| format!("Invalid port: {}", p)))
+        .transpose()?
+        .unwrap_or(dht::DEFAULT_DHT_PORT);
+    let config = parse_dht_config(args, Some(port))?.ok_or("DHT settings missing")?;
+    
+    println!("========================================");
+    println!("BitTorrent Client - DHT Node");
+    println!("========================================");
+    println!("Port: {} ({:?})", port, config.transport);
+    println!("Bootstrap nodes: {:?}", config.bootstrap);
+    println!("Logging to: client.log");
+    println!("========================================");
+    
+    let node = dht::Dht::start(config).await.map_err(|e| e.to_string())?;
+    let contacts = node.bootstrap().await.map_err(|e| e.to_string())?;
+    println!("Node {} listening on {} ({} contacts)", node.id(), node.local_addr(), contacts);
+    println!("Press Ctrl+C to stop");
+    
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {
+                // Refresh the neighbourhood so the routing table stays current
+                node.find_node(node.id()).await;
+                println!("Routing table: {} contacts", node.node_count());
+            }
+        }
+    }
+    println!("DHT node stopped");
+    Ok(())
+}
+
+/// Returns the parsed value of a `--name=VALUE` flag, if present.
+fn parse_flag_value<T: std::str::FromStr>(args: &[String], name: &str) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    let prefix = format!("--{}=", name);
+    match args.iter().find_map(|arg| arg.strip_prefix(&prefix)) {
+        Some(value) => Ok(Some(value.parse().map_err(|_| format!("Invalid value for --{}: {}", name, value))?)),
+        None => Ok(None),
+    }
+}
+
+async fn handle_fetch(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let filtered_args: Vec<&String> = args.iter()
+        .filter(|arg| !arg.starts_with("--") && !arg.starts_with("-"))
+        .collect();
+    
+    let file = filtered_args.first()
+        .map(|s| s.to_string())
+        .ok_or("Usage: fetch [file] [output_file] [server] [port] [--offset=N] [--length=N] [--tail=N] [--resume]")?;
+    let output_path = filtered_args.get(1)
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| format!("downloaded/{}", file));
+    let server = filtered_args.get(2)
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1");
+    let port = filtered_args.get(3)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(7001u16);
+    
+    let offset = parse_flag_value(args, "offset")?;
+    let length = parse_flag_value(args, "length")?;
+    let tail = parse_flag_value(args, "tail")?;
+    let resume = args.iter().any(|arg| arg == "--resume");
+    
+    println!("========================================");
+    println!("BitTorrent Client - Fetch (QUIC)");
+    println!("========================================");
+    println!("File: {}", file);
+    println!("Output file: {}", output_path);
+    println!("Server: {}:{}", server, port);
+    println!("Logging to: client.log");
+    println!("========================================");
+    
+    if resume {
+        // 4MB ranges stay below the server's 5MB per-response limit
+        let total = client::resume_download_file_quic(server, port, &file, &output_path, 4 * 1024 * 1024).await?;
+        println!("Resumed download complete: {} bytes", total);
+        return Ok(());
+    }
+    
+    let offset = match tail {
+        Some(tail) => {
+            // Ask for an empty range first to learn the file size
+            let probe = client::fetch_file_range_quic(server, port, &file, 0, Some(0)).await?;
+            let total = probe.total_size.ok_or("Server does not support range requests")?;
+            total.saturating_sub(tail)
+        }
+        None => offset.unwrap_or(0),
+    };
+    
+    let response = client::fetch_file_range_quic(server, port, &file, offset, length.or(tail)).await?;
+    if let Some(parent) = std::path::Path::new(&output_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&output_path, &response.data)?;
+    println!("Fetched {} bytes at offset {} (file size: {} bytes)", 
+        response.size, 
+        response.offset.unwrap_or(offset), 
+        response.total_size.map_or("unknown".to_string(), |t| t.to_string()));
+    println!("File saved to: {}", output_path);
+    
+    Ok(())
+}
+
+async fn handle_list(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let server = args.first()
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1");
+    let port = args.get(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(7001u16);
+    let pattern = args.get(2).map(|s| s.as_str());
+    
+    let files = client::list_all_files_quic(server, port, pattern).await?;
+    
+    println!("========================================");
+    println!("Files on {}:{}{}", server, port, 
+        pattern.map(|p| format!(" matching {}", p)).unwrap_or_default());
+    println!("========================================");
+    for file in &files {
+        println!("{:<40} {:>12} bytes  sha256={}", file.name, file.size, file.sha256);
+        if let Some(info_hash) = &file.info_hash {
+            println!("{:<40} torrent={} info_hash={}", "", 
+                file.torrent.as_deref().unwrap_or("-"), info_hash);
+        }
+    }
+    println!("========================================");
+    println!("{} file(s)", files.len());
+    
+    Ok(())
+}
+
+async fn handle_info(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let server = args.first()
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1");
+    let port = args.get(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(7001u16);
+    
+    let client = quic_torrent_client_server::quic_client::QuicClient::new()?;
+    let hello = client.hello(server, port).await?;
+    
+    println!("========================================");
+    println!("Server {}:{}", server, port);
+    println!("========================================");
+    match hello {
+        Some(hello) => {
+            println!("Software:          {}", hello.agent.as_deref().unwrap_or("unknown"));
+            println!("Protocol version:  {} (server speaks {}-{})", hello.version, hello.min_version, hello.max_version);
+            println!("Message types:     {}", hello.message_types.join(", "));
+            println!("Codecs:            {} (this connection: {})", hello.codecs.join(", "), 
+                quic_torrent_client_server::protocol::Codec::from_hello(&hello));
+            println!("Features:          {}", if hello.features.is_empty() { "none".to_string() } else { hello.features.join(", ") });
+        }
+        None => println!("Server predates the protocol handshake (bare JSON messages only)"),
+    }
+    println!("========================================");
+    
+    Ok(())
+}
+
+async fn handle_watch(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    use quic_torrent_client_server::messages::{ServerEvent, SubscribeRequest};
+    
+    let filtered_args: Vec<&String> = args.iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let server = filtered_args.first()
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1");
+    let port = filtered_args.get(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(7001u16);
+    let mut request = SubscribeRequest {
+        swarms: args.iter().filter_map(|arg| arg.strip_prefix("--swarm=")).map(|h| h.to_string()).collect(),
+        seed_files: args.iter().any(|arg| arg == "--seed-files"),
+        work_nodes: args.iter().any(|arg| arg == "--work-nodes"),
+    };
+    if request.swarms.is_empty() && !request.seed_files && !request.work_nodes {
+        request = SubscribeRequest { swarms: vec!["*".to_string()], seed_files: true, work_nodes: true };
+    }
+    
+    let client = quic_torrent_client_server::quic_client::QuicClient::new()?;
+    let mut subscription = client.subscribe(server, port, request).await?;
+    let accepted = &subscription.accepted;
+    println!("========================================");
+    println!("Watching {}:{} (Ctrl+C to stop)", server, port);
+    println!("Swarms: {}", if accepted.swarms.is_empty() { "none".to_string() } else { accepted.swarms.join(", ") });
+    println!("Seed files: {}, work nodes: {}", accepted.seed_files, accepted.work_nodes);
+    println!("========================================");
+    
+    loop {
+        let event = tokio::select! {
+            event = subscription.next() => event?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match event {
+            ServerEvent::Swarm { info_hash, change, peer, complete, incomplete } => {
+                let peer = peer.map(|p| format!(" {}:{} ({})", p.ip, p.port, p.peer_id.unwrap_or_default())).unwrap_or_default();
+                println!("[swarm] {} {:?}{} - {} complete, {} incomplete", info_hash, change, peer, complete, incomplete);
+            }
+            ServerEvent::SeedFile { name, change, size, info_hash } => {
+                println!("[seed] {} {:?}{}{}", name, change, 
+                    size.map(|s| format!(" ({} bytes)", s)).unwrap_or_default(),
+                    info_hash.map(|h| format!(" info_hash={}", h)).unwrap_or_default());
+            }
+            ServerEvent::WorkNode { node_id, ip, port, capabilities, weight } => {
+                println!("[node] {} at {}:{} - capabilities: {}, weight: {}", node_id, ip, port, capabilities.join(", "), weight);
+            }
+            ServerEvent::Dropped { missed: Some(missed) } => println!("[dropped] {} event(s) missed (reading too slowly)", missed),
+            ServerEvent::Dropped { missed: None } => println!("[dropped] Connection lost and resubscribed; events in between were missed"),
+        }
+    }
+    subscription.close().await?;
+    
+    Ok(())
+}
 
+async fn handle_publish(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let filtered_args: Vec<&String> = args.iter()
+        .filter(|arg| !arg.starts_with("--") && !arg.starts_with("-"))
+        .collect();
+    
+    let usage = "Usage: publish [local_file] [remote_name] [server] [port] [--token=T] [--torrent]";
+    let local_path = filtered_args.first().map(|s| s.to_string()).ok_or(usage)?;
+    let remote_name = filtered_args.get(1)
+        .map(|s| s.to_string())
+        .or_else(|| std::path::Path::new(&local_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.to_string()))
+        .ok_or(usage)?;
+    let server = filtered_args.get(2)
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1");
+    let port = filtered_args.get(3)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(7001u16);
+    let token = args.iter()
+        .find_map(|arg| arg.strip_prefix("--token="))
+        .map(|t| t.to_string())
+        .or_else(|| std::env::var("QUIC_PUBLISH_TOKEN").ok())
+        .ok_or("Publish token required: pass --token=T or set QUIC_PUBLISH_TOKEN")?;
+    let create_torrent = args.iter().any(|arg| arg == "--torrent");
+    
+    println!("========================================");
+    println!("BitTorrent Client - Publish (QUIC)");
+    println!("========================================");
+    println!("Local file: {}", local_path);
+    println!("Remote name: {}", remote_name);
+    println!("Server: {}:{}", server, port);
+    println!("Generate torrent: {}", create_torrent);
+    println!("Logging to: client.log");
+    println!("========================================");
+    
+    // 1MB chunks keep each JSON request well below the server's response limits
+    let response = client::publish_file_quic(
+        server, port, &local_path, &remote_name, &token, create_torrent, 1024 * 1024,
+    ).await?;
+    
+    println!("Published: {} ({} bytes)", response.path, response.received);
+    if let Some(info_hash) = &response.info_hash {
+        println!("Torrent info hash: {}", info_hash);
+    }
+    
+    Ok(())
+}
@@ -3,9 +3,14 @@
 //! Sends random combinations of:
 //! - TrackerAnnounceRequest (JSON)
 //! - FileRequest (JSON)
-//! - Custom JSON messages (to test error handling)
+//! - Custom JSON messages of unknown type, enveloped or bare (to test error handling)
+//! - AiRequest, half of them streamed over a framed stream
+//!
+//! Usage: `random_json_test [server] [port] [iterations] [json|msgpack]`;
+//! the codec (JSON by default) is asked for in the handshake.
 
 use quic_torrent_client_server::messages::*;
+use quic_torrent_client_server::protocol::{decode_response, Codec, MessageKind, Reply, PROTOCOL_VERSION};
 use quic_torrent_client_server::quic_client::QuicClient;
 use std::time::Instant;
 use rand::Rng;
@@ -16,26 +21,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server = args.get(1).map(|s| s.as_str()).unwrap_or("162.221.207.169");
     let port: u16 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(7001);
     let iterations: usize = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(20);
+    let requested_codec = match args.get(4) {
+        Some(name) => Codec::from_name(name).ok_or_else(|| format!("Unknown codec: {} (expected json or msgpack)", name))?,
+        None => Codec::Json,
+    };
 
     println!("========================================");
     println!("Random JSON & File Request Test");
     println!("========================================");
     println!("Server: {}:{}", server, port);
     println!("Iterations: {}", iterations);
+
+    // Handshake first: an incompatible server fails the whole run here
+    let client = QuicClient::new()?.with_codec(requested_codec);
+    let codec = match client.hello(server, port).await? {
+        Some(hello) => {
+            println!("Protocol: version {} ({}), codecs: {}, features: {}",
+                hello.version,
+                hello.agent.as_deref().unwrap_or("unknown server"),
+                hello.codecs.join(", "),
+                hello.features.join(", "));
+            Codec::from_hello(&hello)
+        }
+        None => {
+            println!("Protocol: legacy server (no handshake, bare JSON)");
+            Codec::Json
+        }
+    };
+    println!("Codec: {} (requested {})", codec, requested_codec);
     println!();
 
-    // Available files (excluding large.bin)
-    let available_files = vec![
-        "hello_world.txt",
-        "small.txt",
-        "medium.bin",
-        "data.json",
-        "log.txt",
-    ];
+    // Available files: ask the server, falling back to the standard test set
+    let list_request = ListFilesRequest { list_files: true, pattern: None, limit: None, offset: None };
+    let listed: Vec<String> = match client.send_message::<_, ListFilesResponse>(server, port, &list_request).await.map(|r| r.files) {
+        Ok(files) => files.into_iter()
+            .filter(|f| f.size <= 5 * 1024 * 1024) // Skip files above the per-response limit
+            .map(|f| f.name)
+            .collect(),
+        Err(e) => {
+            println!("File listing unavailable: {}", e);
+            Vec::new()
+        }
+    };
+    let available_files: Vec<String> = if listed.is_empty() {
+        println!("Using default file set");
+        ["hello_world.txt", "small.txt", "medium.bin", "data.json", "log.txt"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    } else {
+        println!("Server lists {} files", listed.len());
+        listed
+    };
+    println!();
 
     // Generate random info hashes and peer IDs
     let mut rng = rand::thread_rng();
-    let client = QuicClient::new()?;
 
     let mut stats = TestStats::new();
 
@@ -89,19 +130,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             1 => {
                 // FileRequest
-                let file = available_files[rng.gen_range(0..available_files.len())];
+                let file = available_files[rng.gen_range(0..available_files.len())].as_str();
                 println!("[{}] Testing: FileRequest - {}", i, file);
                 let start = Instant::now();
 
-                let request = FileRequest {
-                    file: file.to_string(),
+                // Half of the file requests ask for a random byte range
+                let request = if rng.gen_bool(0.5) {
+                    FileRequest::whole(file)
+                } else {
+                    FileRequest::range(file, rng.gen_range(0..16), Some(rng.gen_range(1..64)))
                 };
 
                 match client.send_message::<_, FileResponse>(server, port, &request).await {
                     Ok(response) => {
                         let duration = start.elapsed();
                         println!("  [OK] File download successful!");
-                        println!("    File: {}, Size: {} bytes", response.filename, response.size);
+                        println!("    File: {}, Size: {} bytes, Offset: {:?}, Total: {:?}", 
+                            response.filename, response.size, response.offset, response.total_size);
                         println!("    Duration: {:.2}s", duration.as_secs_f64());
                         stats.file_success += 1;
                     }
@@ -114,34 +159,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
             2 => {
-                // Custom JSON (to test error handling)
-                // We'll send a malformed request by using a struct that doesn't match server expectations
-                println!("[{}] Testing: Custom JSON (Unknown Request)", i);
+                // Custom JSON (to test error handling): an unknown message type,
+                // either in an envelope or bare like a pre-envelope client
+                let enveloped = rng.gen_bool(0.5);
+                println!("[{}] Testing: Custom JSON (Unknown Request, {})", i, if enveloped { "envelope" } else { "legacy" });
                 let start = Instant::now();
 
-                // Create a struct that serializes to JSON but isn't a valid request type
-                #[derive(serde::Serialize)]
-                struct UnknownRequest {
-                    #[serde(rename = "type")]
-                    request_type: String,
-                    data: String,
-                }
-
-                let unknown_request = UnknownRequest {
-                    request_type: match rng.gen_range(0..3) {
-                        0 => "ping".to_string(),
-                        1 => "status".to_string(),
-                        _ => "unknown".to_string(),
-                    },
-                    data: generate_random_string(&mut rng, 20),
+                let request_type = match rng.gen_range(0..3) {
+                    0 => "ping",
+                    1 => "status",
+                    _ => "unknown",
+                };
+                let data = generate_random_string(&mut rng, 20);
+                let id = format!("custom-{}", i);
+                let unknown_request = if enveloped {
+                    serde_json::json!({ "v": PROTOCOL_VERSION, "type": request_type, "id": id, "body": { "data": data } })
+                } else {
+                    serde_json::json!({ "type": request_type, "data": data })
                 };
 
                 let json_str = serde_json::to_string(&unknown_request)?;
                 println!("  Sending: {}", json_str);
 
-                // Try to send and expect an error response
-                match client.send_message::<_, ErrorResponse>(server, port, &unknown_request).await {
-                    Ok(response) => {
+                // Expect an error response in the same style as the request
+                // (sent raw without a handshake, so always JSON)
+                let result = client.send_raw(server, port, json_str.as_bytes()).await
+                    .and_then(|bytes| Ok(decode_response::<ErrorResponse>(&bytes, enveloped.then_some(id.as_str()), Codec::Json)?));
+                match result {
+                    Ok((_, Reply::Ok(response) | Reply::Error(response))) => {
                         let duration = start.elapsed();
                         println!("  [OK] Server responded with error (expected): {}", response.error);
                         println!("    Code: {:?}", response.code);
@@ -150,15 +195,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                     Err(e) => {
                         let duration = start.elapsed();
-                        println!("  [INFO] Request rejected (expected): {}", e);
+                        println!("  [FAIL] No error response: {}", e);
                         println!("    Duration: {:.2}s", duration.as_secs_f64());
-                        stats.custom_success += 1; // Still counts as success if server rejects properly
+                        stats.custom_fail += 1;
                     }
                 }
             }
             3 => {
                 // AiRequest
-                let queries = vec![
+                let queries = [
                     "What is the capital of France?",
                     "Explain quantum computing in simple terms",
                     "Hello, how are you?",
@@ -166,18 +211,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     "Tell me about artificial intelligence",
                 ];
                 let query = queries[rng.gen_range(0..queries.len())];
-                println!("[{}] Testing: AiRequest - {}", i, query);
+                // Half of the AI queries stream the answer over a framed stream
+                let streaming = rng.gen_bool(0.5);
+                println!("[{}] Testing: AiRequest{} - {}", i, if streaming { " (streamed)" } else { "" }, query);
                 let start = Instant::now();
 
-                match quic_torrent_client_server::client::send_ai_query(
-                    server,
-                    port,
-                    query,
-                    None,
-                    Some(0.7),
-                    Some(100),
-                    Some(0.9),
-                ).await {
+                let request = AiRequest {
+                    query: query.to_string(),
+                    context: None,
+                    parameters: Some(AiParameters { temperature: Some(0.7), max_tokens: Some(100), top_p: Some(0.9) }),
+                    stream: streaming.then_some(true),
+                };
+                let result = if streaming {
+                    let mut tokens = 0usize;
+                    let result = match client.open_stream(server, port).await {
+                        Ok(mut stream) => {
+                            let result = stream.request::<_, AiResponse, _>(&request, |frame| {
+                                if frame.kind == MessageKind::AiToken {
+                                    tokens += 1;
+                                }
+                                Ok(())
+                            }).await;
+                            let _ = stream.finish().await;
+                            result
+                        }
+                        Err(e) => Err(e),
+                    };
+                    println!("    Streamed tokens: {}", tokens);
+                    result
+                } else {
+                    client.send_message::<_, AiResponse>(server, port, &request).await
+                };
+                match result {
                     Ok(response) => {
                         let duration = start.elapsed();
                         println!("  [OK] AI query successful!");
@@ -216,7 +281,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("========================================");
     println!("Test Results Summary");
     println!("========================================");
-    println!("Total tests: {}", iterations);
+    println!("Total tests: {} (codec: {})", iterations, codec);
     println!("  Announce: {} success, {} failed", stats.announce_success, stats.announce_fail);
     println!("  File: {} success, {} failed", stats.file_success, stats.file_fail);
     println!("  Custom JSON: {} success, {} failed", stats.custom_success, stats.custom_fail);
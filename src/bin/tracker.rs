@@ -1,9 +1,20 @@
 //! # Tracker Server Binary
 //!
 //! Deployable BitTorrent tracker server.
-//! Usage: cargo run --bin tracker [--quic] [port]
+//! Usage: cargo run --bin tracker [--quic] [port] [--seed-dir=PATH]
 //!   --quic: Use QUIC protocol (default: HTTP)
 //!   port: Server port (default: 7000 for HTTP, 7001 for QUIC)
+//!   --seed-dir: Directory of files to serve (default: seed/ under the project directory)
+//!   --publish-token=TOKEN: Enable uploads authenticated by TOKEN
+//!                          (or set QUIC_TRACKER_PUBLISH_TOKEN)
+//!   --advertise-ip=IP: Address the server registers under when seeding (default: 127.0.0.1)
+//!   --seed-scan-interval=SECS: Rescan the seed directory every SECS seconds (default: 30, 0 = startup only)
+//!   --no-auto-torrents: Don't generate torrents for seed files or seed them from the server
+//!   --read-timeout=SECS: Seconds a client may take to send a request (default: 30)
+//!   --request-timeout=MS: Longest a request may be worked on, in milliseconds (default: 60000)
+//!   --auth-token=TOKEN: Require TOKEN on every request but Hello
+//!                       (or set QUIC_TRACKER_AUTH_TOKEN)
+//!   --rate-limit=N: Requests per second each client IP may send (default: unlimited)
 
 use quic_torrent_client_server::quic_tracker;
 use quic_torrent_client_server::logger;
@@ -32,7 +43,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     
     // Change to the project directory so relative paths work
-    if let Err(e) = env::set_current_dir(&project_dir) {
+    if let Err(e) = env::set_current_dir(project_dir) {
         eprintln!("Warning: Failed to change to project directory {}: {}", project_dir.display(), e);
         eprintln!("Continuing with current directory...");
     }
@@ -41,14 +52,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let log_path = project_dir.join("tracker.log");
     if let Err(e) = logger::init_logger(log_path.to_str().unwrap_or("tracker.log")) {
         eprintln!("Error initializing logger: {}", e);
-        return Err(e.into());
+        return Err(e);
     }
     
     // Stop any running tracker processes on startup (but not this one)
-    let current_pid = std::process::id();
     #[cfg(target_os = "windows")]
     {
         use std::process::Command;
+        let current_pid = std::process::id();
         // Get all tracker.exe processes and kill only those that aren't us
         if let Ok(output) = Command::new("tasklist")
             .args(["/FI", "IMAGENAME eq tracker.exe", "/FO", "CSV", "/NH"])
@@ -95,6 +106,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .and_then(|s| s.parse().ok())
         .unwrap_or(default_port);
     
+    // Seed directory (relative paths are resolved against the project directory)
+    let seed_dir = args.iter()
+        .find_map(|arg| arg.strip_prefix("--seed-dir="))
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| std::path::PathBuf::from("seed"));
+    
+    // Publishing is only enabled when a token is configured
+    let publish_token = args.iter()
+        .find_map(|arg| arg.strip_prefix("--publish-token="))
+        .map(|t| t.to_string())
+        .or_else(|| env::var("QUIC_TRACKER_PUBLISH_TOKEN").ok())
+        .filter(|t| !t.is_empty());
+    let advertise_ip = args.iter()
+        .find_map(|arg| arg.strip_prefix("--advertise-ip="))
+        .map(|ip| ip.to_string());
+    
+    // Torrent generation for the seed directory
+    let auto_torrents = !args.iter().any(|arg| arg == "--no-auto-torrents");
+    let seed_scan_interval_secs = args.iter()
+        .find_map(|arg| arg.strip_prefix("--seed-scan-interval="))
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(quic_tracker::TrackerConfig::default().seed_scan_interval_secs);
+    
+    // Limits on slow clients and long-running requests
+    let read_timeout_secs = args.iter()
+        .find_map(|arg| arg.strip_prefix("--read-timeout="))
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(quic_tracker::TrackerConfig::default().read_timeout_secs);
+    let request_timeout_ms = args.iter()
+        .find_map(|arg| arg.strip_prefix("--request-timeout="))
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(quic_tracker::TrackerConfig::default().request_timeout_ms);
+    
+    // Request authentication and per-client rate limiting
+    let auth_token = args.iter()
+        .find_map(|arg| arg.strip_prefix("--auth-token="))
+        .map(|t| t.to_string())
+        .or_else(|| env::var("QUIC_TRACKER_AUTH_TOKEN").ok())
+        .filter(|t| !t.is_empty());
+    let rate_limit = args.iter()
+        .find_map(|arg| arg.strip_prefix("--rate-limit="))
+        .and_then(|s| s.parse().ok())
+        .filter(|&n: &u32| n > 0);
+    
     println!("========================================");
     println!("BitTorrent Tracker Server");
     println!("========================================");
@@ -102,6 +157,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Executable directory: {}", exe_dir.display());
     println!("Working directory: {}", project_dir.display());
     println!("Stopped any existing tracker processes");
+    println!("Seed directory: {}", seed_dir.display());
     println!("Starting tracker on port {}...", port);
     println!("Logging to: {}", log_path.display());
     println!("========================================");
@@ -110,7 +166,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     quic_torrent_client_server::log_server!("Starting QUIC tracker server on port {}", port);
     
     // Run QUIC tracker
-    let result = quic_tracker::run_quic_tracker(port).await;
+    let result = quic_tracker::run_quic_tracker_with_config(quic_tracker::TrackerConfig {
+        port,
+        seed_dir,
+        publish_token,
+        advertise_ip: advertise_ip.unwrap_or_else(|| "127.0.0.1".to_string()),
+        auto_torrents,
+        seed_scan_interval_secs,
+        read_timeout_secs,
+        request_timeout_ms,
+        auth_token,
+        rate_limit,
+        ..quic_tracker::TrackerConfig::default()
+    }).await;
     
     match result {
         Ok(()) => {
@@ -121,7 +189,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let error_msg = format!("Tracker error: {}", e);
             eprintln!("{}", error_msg);
             quic_torrent_client_server::log_server!("{}", error_msg);
-            Err(e)
+            Err(e.into())
         }
     }
 }
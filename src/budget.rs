@@ -0,0 +1,209 @@
+//! # Transfer Budgets
+//!
+//! Limits shared by every torrent of a `PeerListener` (and so of a
+//! `Session`):
+//!
+//! - a connection budget: at most `max_connections` outgoing peer
+//!   connections at once, across all downloads;
+//! - download and upload rates in bytes per second, enforced with a
+//!   `TokenBucket` each.
+//!
+//! A transfer is counted after (download) or before (upload) it happens and
+//! the caller then waits until the bucket has paid it back, so a burst can
+//! run ahead by up to one second's worth of data. Data from web seeds and the
+//! tracker's seed directory counts towards the download rate too; incoming
+//! peer connections don't use the connection budget.
+//!
+//! `TokenBucket` reads no clock; callers pass `Instant`s.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::futures::Notified;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// A token bucket refilled at a fixed rate; taking more than it holds puts
+/// it in debt instead of failing.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    /// Most tokens saved up (one second's worth)
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    ///
+    /// # Arguments
+    /// * `rate` - Bytes per second (at least 1)
+    /// * `now` - Current time
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        Self { rate, capacity: rate, tokens: rate, last: now }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    /// Takes `bytes` tokens.
+    ///
+    /// # Returns
+    /// How long to wait before the bucket is out of debt again (zero if it
+    /// never went into debt)
+    pub fn take(&mut self, now: Instant, bytes: u64) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Budget settings; `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct BudgetConfig {
+    /// Outgoing peer connections open at once
+    pub max_connections: Option<usize>,
+    /// Bytes per second received from all sources
+    pub download_rate: Option<u64>,
+    /// Bytes per second sent to all peers
+    pub upload_rate: Option<u64>,
+}
+
+/// Connection and bandwidth limits shared by many torrents.
+pub struct Budget {
+    config: BudgetConfig,
+    connections: Option<Arc<Semaphore>>,
+    /// Signalled when a connection permit is returned
+    released: Arc<Notify>,
+    download: Option<Mutex<TokenBucket>>,
+    upload: Option<Mutex<TokenBucket>>,
+}
+
+/// Holds one connection of the budget; dropping it frees the slot.
+pub struct ConnectionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    released: Arc<Notify>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.released.notify_waiters();
+    }
+}
+
+impl Budget {
+    pub fn new(config: BudgetConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            connections: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            released: Arc::new(Notify::new()),
+            download: config.download_rate.map(|rate| Mutex::new(TokenBucket::new(rate, now))),
+            upload: config.upload_rate.map(|rate| Mutex::new(TokenBucket::new(rate, now))),
+            config,
+        }
+    }
+
+    /// A budget without limits.
+    pub fn unlimited() -> Self {
+        Self::new(BudgetConfig::default())
+    }
+
+    pub fn config(&self) -> &BudgetConfig {
+        &self.config
+    }
+
+    /// Connection slots free right now (`None` if unlimited).
+    pub fn available_connections(&self) -> Option<usize> {
+        self.connections.as_ref().map(|slots| slots.available_permits())
+    }
+
+    /// Takes up to `wanted` connection slots without waiting.
+    ///
+    /// # Returns
+    /// The permits obtained, possibly fewer than `wanted` (or none)
+    pub fn try_connections(&self, wanted: usize) -> Vec<ConnectionPermit> {
+        (0..wanted)
+            .map_while(|_| {
+                let permit = match &self.connections {
+                    Some(slots) => Some(Arc::clone(slots).try_acquire_owned().ok()?),
+                    None => None,
+                };
+                Some(ConnectionPermit { _permit: permit, released: Arc::clone(&self.released) })
+            })
+            .collect()
+    }
+
+    /// A future completing when some connection permit is dropped.
+    ///
+    /// Releases only wake futures that exist, so pin it and call `enable`
+    /// before `try_connections`: a slot freed in between isn't missed then.
+    pub fn connection_released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+
+    /// Counts received bytes, waiting if the download rate is exceeded.
+    pub async fn downloaded(&self, bytes: u64) {
+        if let Some(bucket) = &self.download {
+            let wait = bucket.lock().unwrap().take(Instant::now(), bytes);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    /// Counts bytes about to be sent, waiting if the upload rate is exceeded.
+    pub async fn uploading(&self, bytes: u64) {
+        if let Some(bucket) = &self.upload {
+            let wait = bucket.lock().unwrap().take(Instant::now(), bytes);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn release_between_enable_and_await_is_not_lost() {
+        let budget = Budget::new(BudgetConfig { max_connections: Some(1), ..Default::default() });
+        let held = budget.try_connections(2);
+        assert_eq!(held.len(), 1);
+
+        let released = budget.connection_released();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        assert!(budget.try_connections(1).is_empty());
+        // Another torrent frees its slot before this one starts waiting
+        drop(held);
+
+        tokio::time::timeout(Duration::from_secs(1), released).await.expect("wakeup lost");
+        assert_eq!(budget.try_connections(1).len(), 1);
+    }
+
+    #[test]
+    fn unlimited_budget_always_grants_connections() {
+        let budget = Budget::unlimited();
+        assert_eq!(budget.try_connections(500).len(), 500);
+        assert_eq!(budget.available_connections(), None);
+    }
+
+    #[test]
+    fn token_bucket_goes_into_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.take(start, 1000), Duration::ZERO);
+        assert_eq!(bucket.take(start, 500), Duration::from_millis(500));
+        assert_eq!(bucket.take(start + Duration::from_secs(1), 0), Duration::ZERO);
+    }
+}
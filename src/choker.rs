@@ -0,0 +1,308 @@
+//! # Choker
+//!
+//! Decides which peers we upload to (tit-for-tat).
+//!
+//! Every `rechoke_interval` (10 s) the interested peers are ranked by rate and
+//! the best `upload_slots` of them are unchoked:
+//!
+//! - while downloading, by how fast they upload to us (reciprocation);
+//! - once seeding, by how fast we upload to them (spreading data fastest).
+//!
+//! One more peer, the optimistic unchoke, is chosen at random from the rest
+//! and rotated every `optimistic_interval` (30 s), so newcomers get a chance
+//! to prove themselves. A newly interested peer is unchoked at once while a
+//! slot is free.
+//!
+//! The choker does no I/O and reads no clock: callers pass the current
+//! `Instant` and per-peer `PeerRates`, so it can be driven deterministically.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// How often the regular unchoke slots are recalculated.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves to another peer.
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Regular (rate-based) upload slots per torrent.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 3;
+
+/// Choker settings.
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    /// Peers unchoked by rate, in addition to the optimistic unchoke
+    pub upload_slots: usize,
+    pub rechoke_interval: Duration,
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            rechoke_interval: RECHOKE_INTERVAL,
+            optimistic_interval: OPTIMISTIC_UNCHOKE_INTERVAL,
+        }
+    }
+}
+
+/// What the choker knows about a peer at a rechoke.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerRates {
+    /// Caller-chosen peer key
+    pub peer: usize,
+    /// The peer wants pieces from us
+    pub interested: bool,
+    /// Bytes per second the peer sent us recently
+    pub download_rate: f64,
+    /// Bytes per second we sent the peer recently
+    pub upload_rate: f64,
+}
+
+/// Tit-for-tat choker for one torrent.
+pub struct Choker {
+    config: ChokerConfig,
+    regular: BTreeSet<usize>,
+    optimistic: Option<usize>,
+    optimistic_since: Option<Instant>,
+    last_rechoke: Option<Instant>,
+    rng: StdRng,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self::with_rng(config, StdRng::from_entropy())
+    }
+
+    /// Like `new`, with a fixed seed for reproducible optimistic unchokes.
+    pub fn with_seed(config: ChokerConfig, seed: u64) -> Self {
+        Self::with_rng(config, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(config: ChokerConfig, rng: StdRng) -> Self {
+        Self {
+            config,
+            regular: BTreeSet::new(),
+            optimistic: None,
+            optimistic_since: None,
+            last_rechoke: None,
+            rng,
+        }
+    }
+
+    pub fn config(&self) -> &ChokerConfig {
+        &self.config
+    }
+
+    /// Currently unchoked peers (regular and optimistic).
+    pub fn unchoked(&self) -> BTreeSet<usize> {
+        let mut unchoked = self.regular.clone();
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+
+    pub fn is_unchoked(&self, peer: usize) -> bool {
+        self.regular.contains(&peer) || self.optimistic == Some(peer)
+    }
+
+    pub fn optimistic(&self) -> Option<usize> {
+        self.optimistic
+    }
+
+    /// True if `rechoke_interval` has passed since the last rechoke.
+    pub fn rechoke_due(&self, now: Instant) -> bool {
+        self.last_rechoke.is_none_or(|last| now.duration_since(last) >= self.config.rechoke_interval)
+    }
+
+    /// Unchokes a newly interested peer right away if a slot is free.
+    ///
+    /// # Returns
+    /// `true` if the peer is now unchoked
+    pub fn peer_interested(&mut self, peer: usize, now: Instant) -> bool {
+        if self.is_unchoked(peer) {
+            return true;
+        }
+        if self.regular.len() < self.config.upload_slots {
+            self.regular.insert(peer);
+            return true;
+        }
+        if self.optimistic.is_none() {
+            self.optimistic = Some(peer);
+            self.optimistic_since = Some(now);
+            return true;
+        }
+        false
+    }
+
+    /// Drops a disconnected peer, freeing its slot until the next rechoke.
+    pub fn remove_peer(&mut self, peer: usize) {
+        self.regular.remove(&peer);
+        if self.optimistic == Some(peer) {
+            self.optimistic = None;
+            self.optimistic_since = None;
+        }
+    }
+
+    /// Recalculates the unchoked set.
+    ///
+    /// # Arguments
+    /// * `now` - Current time
+    /// * `peers` - Every connected peer with its recent rates
+    /// * `seeding` - Rank by our upload rate to peers instead of their upload rate to us
+    ///
+    /// # Returns
+    /// The peers to unchoke; every other peer should be choked
+    pub fn rechoke(&mut self, now: Instant, peers: &[PeerRates], seeding: bool) -> BTreeSet<usize> {
+        self.last_rechoke = Some(now);
+
+        let mut interested: Vec<&PeerRates> = peers.iter().filter(|p| p.interested).collect();
+        let rate = |p: &PeerRates| if seeding { p.upload_rate } else { p.download_rate };
+        // Fastest first; equal rates keep a stable order by key
+        interested.sort_by(|a, b| rate(b).total_cmp(&rate(a)).then(a.peer.cmp(&b.peer)));
+
+        self.regular = interested.iter()
+            .take(self.config.upload_slots)
+            .map(|p| p.peer)
+            .collect();
+
+        // Rotate the optimistic unchoke when it's due, or when its peer left,
+        // lost interest, or earned a regular slot
+        let current_valid = self.optimistic
+            .is_some_and(|o| !self.regular.contains(&o) && interested.iter().any(|p| p.peer == o));
+        let rotation_due = self.optimistic_since
+            .is_none_or(|since| now.duration_since(since) >= self.config.optimistic_interval);
+        if !current_valid || rotation_due {
+            let candidates: Vec<usize> = interested.iter()
+                .map(|p| p.peer)
+                .filter(|p| !self.regular.contains(p))
+                .collect();
+            // Prefer someone other than the current holder when rotating
+            let fresh: Vec<usize> = candidates.iter().copied().filter(|&p| Some(p) != self.optimistic).collect();
+            let pool = if fresh.is_empty() { &candidates } else { &fresh };
+            self.optimistic = if pool.is_empty() {
+                None
+            } else {
+                Some(pool[self.rng.gen_range(0..pool.len())])
+            };
+            self.optimistic_since = self.optimistic.map(|_| now);
+        }
+
+        self.unchoked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(peer: usize, download_rate: f64, upload_rate: f64) -> PeerRates {
+        PeerRates { peer, interested: true, download_rate, upload_rate }
+    }
+
+    /// Six interested peers; peer `n` uploads to us at `n` KB/s and gets
+    /// `6 - n` KB/s from us.
+    fn swarm() -> Vec<PeerRates> {
+        (0..6).map(|n| peer(n, n as f64 * 1000.0, (6 - n) as f64 * 1000.0)).collect()
+    }
+
+    fn regular(choker: &Choker) -> BTreeSet<usize> {
+        choker.unchoked().into_iter().filter(|&p| Some(p) != choker.optimistic()).collect()
+    }
+
+    #[test]
+    fn downloading_unchokes_the_fastest_uploaders() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 1);
+        let unchoked = choker.rechoke(Instant::now(), &swarm(), false);
+
+        assert_eq!(regular(&choker), BTreeSet::from([3, 4, 5]));
+        let optimistic = choker.optimistic().expect("a peer left over for the optimistic unchoke");
+        assert!(optimistic < 3);
+        assert_eq!(unchoked.len(), 4);
+    }
+
+    #[test]
+    fn seeding_unchokes_the_peers_we_upload_to_fastest() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 1);
+        choker.rechoke(Instant::now(), &swarm(), true);
+
+        assert_eq!(regular(&choker), BTreeSet::from([0, 1, 2]));
+        assert!(choker.optimistic().is_some_and(|o| o >= 3));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 1);
+        let mut peers = swarm();
+        for p in &mut peers[3..] {
+            p.interested = false;
+        }
+        let unchoked = choker.rechoke(Instant::now(), &peers, false);
+
+        assert_eq!(unchoked, BTreeSet::from([0, 1, 2]));
+        assert_eq!(choker.optimistic(), None);
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_30_seconds() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 7);
+        let start = Instant::now();
+        let peers = swarm();
+
+        choker.rechoke(start, &peers, false);
+        let first = choker.optimistic().unwrap();
+
+        // Regular rechokes inside the interval keep it
+        for secs in [10, 20] {
+            choker.rechoke(start + Duration::from_secs(secs), &peers, false);
+            assert_eq!(choker.optimistic(), Some(first));
+        }
+
+        // At 30 s it moves to another of the choked peers
+        choker.rechoke(start + OPTIMISTIC_UNCHOKE_INTERVAL, &peers, false);
+        let second = choker.optimistic().unwrap();
+        assert_ne!(second, first);
+        assert!(second < 3);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_decisions() {
+        let start = Instant::now();
+        let run = |seed| {
+            let mut choker = Choker::with_seed(ChokerConfig::default(), seed);
+            (0..5u64)
+                .map(|i| {
+                    choker.rechoke(start + OPTIMISTIC_UNCHOKE_INTERVAL * i as u32, &swarm(), false);
+                    choker.optimistic()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn interested_peers_fill_free_slots_at_once() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 1);
+        let now = Instant::now();
+        assert!((0..3).all(|p| choker.peer_interested(p, now)));
+        assert!(choker.peer_interested(3, now));
+        assert_eq!(choker.optimistic(), Some(3));
+        assert!(!choker.peer_interested(4, now));
+
+        choker.remove_peer(1);
+        assert!(choker.peer_interested(4, now));
+        assert!(choker.is_unchoked(4));
+    }
+
+    #[test]
+    fn rechoke_is_due_after_the_interval() {
+        let mut choker = Choker::with_seed(ChokerConfig::default(), 1);
+        let start = Instant::now();
+        assert!(choker.rechoke_due(start));
+        choker.rechoke(start, &[], false);
+        assert!(!choker.rechoke_due(start + Duration::from_secs(9)));
+        assert!(choker.rechoke_due(start + RECHOKE_INTERVAL));
+    }
+}
@@ -6,6 +6,7 @@ use std::fs;
 use rand;
 use sha1::{Sha1, Digest};
 use crate::{decode_bencode, BencodeValue};
+use crate::error::{Error, Result};
 
 #[derive(Clone)]
 pub struct TorrentFile {
@@ -15,32 +16,37 @@ pub struct TorrentFile {
     pub pieces: Vec<Vec<u8>>,
     pub length: usize,
     pub name: String,
+    /// BEP 19 web seed URLs (`url-list`); pieces can also be fetched from these over HTTP
+    pub url_list: Vec<String>,
 }
 
 impl TorrentFile {
-    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = |s: &[u8]| String::from_utf8(s.to_vec())
+            .map_err(|_| Error::Torrent("Torrent strings must be UTF-8".into()));
         let torrent_data = fs::read(path)?;
-        let (torrent_value, _) = decode_bencode(&torrent_data)?;
+        let (torrent_value, _) = decode_bencode(&torrent_data)
+            .map_err(|e| Error::Torrent(format!("Invalid bencode in {}: {}", path, e)))?;
         
         let torrent_dict = match torrent_value {
             BencodeValue::Dict(d) => d,
-            _ => return Err("Torrent file must be a dictionary".into()),
+            _ => return Err(Error::Torrent("Torrent file must be a dictionary".into())),
         };
         
         // Get announce URL
         let announce_bytes = torrent_dict.get(b"announce".as_slice())
-            .ok_or("Missing 'announce' field")?;
+            .ok_or_else(|| Error::Torrent("Missing 'announce' field".into()))?;
         let announce = match announce_bytes {
-            BencodeValue::String(s) => String::from_utf8(s.clone())?,
-            _ => return Err("'announce' must be a string".into()),
+            BencodeValue::String(s) => text(s)?,
+            _ => return Err(Error::Torrent("'announce' must be a string".into())),
         };
         
         // Get info dictionary
         let info_value = torrent_dict.get(b"info".as_slice())
-            .ok_or("Missing 'info' field")?;
+            .ok_or_else(|| Error::Torrent("Missing 'info' field".into()))?;
         let info_dict = match info_value {
             BencodeValue::Dict(d) => d,
-            _ => return Err("'info' must be a dictionary".into()),
+            _ => return Err(Error::Torrent("'info' must be a dictionary".into())),
         };
         
         // Calculate info hash (SHA-1 of bencoded info dictionary)
@@ -51,36 +57,50 @@ impl TorrentFile {
         
         // Get name
         let name_bytes = info_dict.get(b"name".as_slice())
-            .ok_or("Missing 'name' field in info")?;
+            .ok_or_else(|| Error::Torrent("Missing 'name' field in info".into()))?;
         let name = match name_bytes {
-            BencodeValue::String(s) => String::from_utf8(s.clone())?,
-            _ => return Err("'name' must be a string".into()),
+            BencodeValue::String(s) => text(s)?,
+            _ => return Err(Error::Torrent("'name' must be a string".into())),
         };
         
         // Get length
         let length_value = info_dict.get(b"length".as_slice())
-            .ok_or("Missing 'length' field in info")?;
+            .ok_or_else(|| Error::Torrent("Missing 'length' field in info".into()))?;
         let length = match length_value {
             BencodeValue::Int(i) => *i as usize,
-            _ => return Err("'length' must be an integer".into()),
+            _ => return Err(Error::Torrent("'length' must be an integer".into())),
         };
         
         // Get piece length
         let piece_length_value = info_dict.get(b"piece length".as_slice())
-            .ok_or("Missing 'piece length' field in info")?;
+            .ok_or_else(|| Error::Torrent("Missing 'piece length' field in info".into()))?;
         let piece_length = match piece_length_value {
             BencodeValue::Int(i) => *i as usize,
-            _ => return Err("'piece length' must be an integer".into()),
+            _ => return Err(Error::Torrent("'piece length' must be an integer".into())),
         };
         
         // Get pieces (concatenated SHA-1 hashes, each 20 bytes)
         let pieces_bytes = info_dict.get(b"pieces".as_slice())
-            .ok_or("Missing 'pieces' field in info")?;
+            .ok_or_else(|| Error::Torrent("Missing 'pieces' field in info".into()))?;
         let pieces_data = match pieces_bytes {
             BencodeValue::String(s) => s.clone(),
-            _ => return Err("'pieces' must be a string".into()),
+            _ => return Err(Error::Torrent("'pieces' must be a string".into())),
         };
         
+        // Get web seeds (optional; a single URL or a list of them)
+        let url_list = match torrent_dict.get(b"url-list".as_slice()) {
+            None => Vec::new(),
+            Some(BencodeValue::String(s)) => vec![text(s)?],
+            Some(BencodeValue::List(urls)) => urls.iter()
+                .map(|url| match url {
+                    BencodeValue::String(s) => text(s),
+                    _ => Err(Error::Torrent("'url-list' entries must be strings".into())),
+                })
+                .collect::<Result<Vec<String>>>()?,
+            Some(_) => return Err(Error::Torrent("'url-list' must be a string or a list".into())),
+        };
+        let url_list = url_list.into_iter().filter(|url| !url.is_empty()).collect();
+        
         let mut pieces = Vec::new();
         for chunk in pieces_data.chunks(20) {
             if chunk.len() == 20 {
@@ -95,8 +115,17 @@ impl TorrentFile {
             pieces,
             length,
             name,
+            url_list,
         })
     }
+    
+    /// The tracker in `announce`, if it is a `quic://host:port[/path]` URL.
+    pub fn tracker_address(&self) -> Option<(String, u16)> {
+        let rest = self.announce.strip_prefix("quic://")?;
+        let authority = rest.split('/').next()?;
+        let (host, port) = authority.rsplit_once(':')?;
+        Some((host.to_string(), port.parse().ok()?))
+    }
 }
 
 
@@ -104,17 +133,18 @@ impl TorrentFile {
 pub struct PeerInfo {
     pub ip: String,
     pub port: u16,
+    pub peer_id: Option<String>,
 }
 
 pub async fn download_file(
     torrent_path: &str,
     output_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<()> {
     download_file_quic_torrent(torrent_path, output_path, "127.0.0.1", 7001).await
 }
 
 
-/// Announces to a QUIC tracker server.
+/// Announces to a QUIC tracker server with the `started` event.
 ///
 /// # Arguments
 /// * `server` - Tracker server hostname or IP address
@@ -128,6 +158,7 @@ pub async fn download_file(
 ///
 /// # Returns
 /// List of peer information
+#[allow(clippy::too_many_arguments)]
 pub async fn announce_to_quic_tracker(
     server: &str,
     port: u16,
@@ -137,9 +168,67 @@ pub async fn announce_to_quic_tracker(
     uploaded: u64,
     downloaded: u64,
     left: u64,
-) -> Result<Vec<PeerInfo>, Box<dyn std::error::Error>> {
-    crate::log_client!("[announce_to_quic_tracker] ENTRY - server={}, port={}, info_hash={}, peer_id={}, peer_port={}, uploaded={}, downloaded={}, left={}", 
-        server, port, info_hash, peer_id, peer_port, uploaded, downloaded, left);
+) -> Result<Vec<PeerInfo>> {
+    announce_event_to_quic_tracker(server, port, info_hash, peer_id, peer_port, uploaded, downloaded, left, Some("started")).await
+}
+
+/// Announces to a QUIC tracker server with an explicit event.
+///
+/// # Arguments
+/// * `event` - `"started"`, `"completed"`, `"stopped"`, or `None` for a regular update
+///
+/// The other arguments are as for `announce_to_quic_tracker`.
+///
+/// # Returns
+/// List of peer information
+#[allow(clippy::too_many_arguments)]
+pub async fn announce_event_to_quic_tracker(
+    server: &str,
+    port: u16,
+    info_hash: &str,
+    peer_id: &str,
+    peer_port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    event: Option<&str>,
+) -> Result<Vec<PeerInfo>> {
+    let response = announce_with_response(server, port, info_hash, peer_id, peer_port, uploaded, downloaded, left, event).await?;
+    
+    // Convert to PeerInfo format
+    let peers: Vec<PeerInfo> = response.peers
+        .into_iter()
+        .map(|p| PeerInfo {
+            ip: p.ip.clone(),
+            port: p.port,
+            peer_id: p.peer_id.clone(),
+        })
+        .collect();
+    
+    crate::log_client!("[announce_to_quic_tracker] EXIT - success=true, peers_count={}", peers.len());
+    crate::log_client!("[announce_to_quic_tracker] Return: Vec<PeerInfo> with {} peers", peers.len());
+    
+    Ok(peers)
+}
+
+/// Announces to a QUIC tracker server and returns the full response,
+/// including the re-announce `interval` and the swarm counts.
+///
+/// Arguments are as for `announce_event_to_quic_tracker`.
+#[allow(clippy::too_many_arguments)]
+pub async fn announce_with_response(
+    server: &str,
+    port: u16,
+    info_hash: &str,
+    peer_id: &str,
+    peer_port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    event: Option<&str>,
+) -> Result<crate::messages::TrackerAnnounceResponse> {
+    crate::log_client!("[announce_to_quic_tracker] ENTRY - server={}, port={}, info_hash={}, peer_id={}, peer_port={}, uploaded={}, downloaded={}, left={}, event={:?}", 
+        server, port, info_hash, peer_id, peer_port, uploaded, downloaded, left, event);
     crate::log_client_sent!("Sending QUIC announce request to {}:{} - info_hash={}, peer_id={}", 
         server, port, info_hash, peer_id);
     
@@ -150,7 +239,7 @@ pub async fn announce_to_quic_tracker(
         uploaded: Some(uploaded),
         downloaded: Some(downloaded),
         left: Some(left),
-        event: Some("started".to_string()),
+        event: event.map(|e| e.to_string()),
         ip: None,
     };
     
@@ -158,9 +247,9 @@ pub async fn announce_to_quic_tracker(
     crate::log_client!("[CLIENT] Function: client::announce_to_quic_tracker()");
     crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_announce_request()");
     crate::log_client!("[CLIENT] Processing module: Tracker Module");
-    crate::log_client!("[announce_to_quic_tracker] Created TrackerAnnounceRequest, creating QUIC client");
-    let client = crate::quic_client::QuicClient::new()?;
-    crate::log_client!("[announce_to_quic_tracker] QUIC client created, sending message to {}:{}", server, port);
+    crate::log_client!("[announce_to_quic_tracker] Created TrackerAnnounceRequest, using the shared QUIC client");
+    let client = crate::quic_client::QuicClient::shared()?;
+    crate::log_client!("[announce_to_quic_tracker] QUIC client ready, sending message to {}:{}", server, port);
     
     let response: crate::messages::TrackerAnnounceResponse = 
         client.send_message(server, port, &request).await?;
@@ -169,19 +258,7 @@ pub async fn announce_to_quic_tracker(
         response.peers.len(), response.complete, response.incomplete, response.interval);
     crate::log_client!("Received QUIC announce response: {} peers", response.peers.len());
     
-    // Convert to PeerInfo format
-    let peers: Vec<PeerInfo> = response.peers
-        .into_iter()
-        .map(|p| PeerInfo {
-            ip: p.ip.clone(),
-            port: p.port,
-        })
-        .collect();
-    
-    crate::log_client!("[announce_to_quic_tracker] EXIT - success=true, peers_count={}", peers.len());
-    crate::log_client!("[announce_to_quic_tracker] Return: Vec<PeerInfo> with {} peers", peers.len());
-    
-    Ok(peers)
+    Ok(response)
 }
 
 /// Downloads a file from a QUIC tracker server.
@@ -199,23 +276,21 @@ pub async fn download_file_quic(
     port: u16,
     filename: &str,
     output_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<()> {
     crate::log_client!("[download_file_quic] ENTRY - server={}, port={}, filename={}, output_path={}", 
         server, port, filename, output_path);
     crate::log_client_sent!("Requesting file download via QUIC from {}:{} - file: {}", 
         server, port, filename);
     
-    let request = crate::messages::FileRequest {
-        file: filename.to_string(),
-    };
+    let request = crate::messages::FileRequest::whole(filename);
     
     crate::log_client!("[CLIENT] REQUEST TYPE: FileRequest");
     crate::log_client!("[CLIENT] Function: client::download_file_quic()");
     crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_file_request()");
     crate::log_client!("[CLIENT] Processing module: File Serving Module");
-    crate::log_client!("[download_file_quic] Created FileRequest, creating QUIC client");
-    let client = crate::quic_client::QuicClient::new()?;
-    crate::log_client!("[download_file_quic] QUIC client created, sending file request to {}:{}", server, port);
+    crate::log_client!("[download_file_quic] Created FileRequest, using the shared QUIC client");
+    let client = crate::quic_client::QuicClient::shared()?;
+    crate::log_client!("[download_file_quic] QUIC client ready, sending file request to {}:{}", server, port);
     
     let response: crate::messages::FileResponse = 
         client.send_message(server, port, &request).await?;
@@ -240,17 +315,336 @@ pub async fn download_file_quic(
     Ok(())
 }
 
-/// Downloads a file using a torrent file via QUIC.
+/// Fetches a byte range of a file from a QUIC tracker server.
+///
+/// # Arguments
+/// * `server` - Server hostname or IP address
+/// * `port` - Server port (default 7001 for QUIC tracker)
+/// * `filename` - Name of the file on the server
+/// * `offset` - Byte offset to start reading from
+/// * `length` - Maximum number of bytes to fetch (`None` reads to the end of the file)
+///
+/// # Returns
+/// The file response; `offset` and `total_size` describe the served range
+pub async fn fetch_file_range_quic(
+    server: &str,
+    port: u16,
+    filename: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<crate::messages::FileResponse> {
+    crate::log_client!("[fetch_file_range_quic] ENTRY - server={}, port={}, filename={}, offset={}, length={:?}", 
+        server, port, filename, offset, length);
+    crate::log_client_sent!("Requesting file range via QUIC from {}:{} - file: {}, offset: {}, length: {:?}", 
+        server, port, filename, offset, length);
+    
+    let request = crate::messages::FileRequest::range(filename, offset, length);
+    
+    crate::log_client!("[CLIENT] REQUEST TYPE: FileRequest (range)");
+    crate::log_client!("[CLIENT] Function: client::fetch_file_range_quic()");
+    crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_file_request()");
+    let client = crate::quic_client::QuicClient::shared()?;
+    
+    let response: crate::messages::FileResponse = 
+        client.send_message(server, port, &request).await?;
+    
+    crate::log_client!("[fetch_file_range_quic] EXIT - size={}, offset={:?}, total_size={:?}", 
+        response.size, response.offset, response.total_size);
+    
+    Ok(response)
+}
+
+/// Downloads a file from a QUIC tracker server, resuming a partial copy.
+///
+/// If `output_path` already exists, only the bytes past its current length are
+/// requested and appended. The file is fetched in ranges of at most `chunk_size`
+/// bytes, so files larger than the server's per-response limit can be copied too.
+///
+/// # Returns
+/// The total size of the file on the server
+pub async fn resume_download_file_quic(
+    server: &str,
+    port: u16,
+    filename: &str,
+    output_path: &str,
+    chunk_size: u64,
+) -> Result<u64> {
+    use std::io::Write;
+    
+    crate::log_client!("[resume_download_file_quic] ENTRY - server={}, port={}, filename={}, output_path={}, chunk_size={}", 
+        server, port, filename, output_path, chunk_size);
+    
+    if chunk_size == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "chunk_size must be greater than zero").into());
+    }
+    
+    if let Some(parent) = std::path::Path::new(output_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    
+    let mut offset = fs::metadata(output_path).map(|m| m.len()).unwrap_or(0);
+    crate::log_client!("[resume_download_file_quic] Existing local size: {} bytes", offset);
+    
+    let mut output = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output_path)?;
+    
+    loop {
+        let response = fetch_file_range_quic(server, port, filename, offset, Some(chunk_size)).await?;
+        let total_size = response.total_size
+            .ok_or_else(|| Error::Protocol("Server does not support range requests (no total_size in response)".into()))?;
+        
+        if offset > total_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Local file {} ({} bytes) is larger than remote file ({} bytes)", 
+                output_path, offset, total_size)).into());
+        }
+        
+        output.write_all(&response.data)?;
+        offset += response.data.len() as u64;
+        crate::log_client!("[resume_download_file_quic] Progress: {}/{} bytes", offset, total_size);
+        
+        if offset >= total_size {
+            crate::log_client!("[resume_download_file_quic] EXIT - success=true, total_size={}", total_size);
+            return Ok(total_size);
+        }
+        if response.data.is_empty() {
+            return Err(Error::Protocol(format!("Server returned no data at offset {} of {}", offset, total_size)));
+        }
+    }
+}
+
+/// Lists the files available in a QUIC tracker server's seed directory.
+///
+/// # Arguments
+/// * `server` - Server hostname or IP address
+/// * `port` - Server port (default 7001 for QUIC tracker)
+/// * `pattern` - Optional glob pattern applied to file names (e.g. `*.txt`)
+/// * `offset` - Index of the first entry to return
+/// * `limit` - Maximum number of entries to return (`None` uses the server default)
+///
+/// # Returns
+/// One page of the catalog; follow `next_offset` to fetch the rest
+pub async fn list_files_quic(
+    server: &str,
+    port: u16,
+    pattern: Option<&str>,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<crate::messages::ListFilesResponse> {
+    crate::log_client!("[list_files_quic] ENTRY - server={}, port={}, pattern={:?}, offset={}, limit={:?}", 
+        server, port, pattern, offset, limit);
+    crate::log_client_sent!("Requesting seed directory listing from {}:{}", server, port);
+    
+    let request = crate::messages::ListFilesRequest {
+        list_files: true,
+        pattern: pattern.map(|p| p.to_string()),
+        offset: Some(offset),
+        limit,
+    };
+    
+    crate::log_client!("[CLIENT] REQUEST TYPE: ListFilesRequest");
+    crate::log_client!("[CLIENT] Function: client::list_files_quic()");
+    crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_list_files_request()");
+    let client = crate::quic_client::QuicClient::shared()?;
+    
+    let response: crate::messages::ListFilesResponse = 
+        client.send_message(server, port, &request).await?;
+    
+    crate::log_client!("[list_files_quic] EXIT - files={}, total={}, next_offset={:?}", 
+        response.files.len(), response.total, response.next_offset);
+    
+    Ok(response)
+}
+
+/// Lists every file in a QUIC tracker server's seed directory, following pagination.
+pub async fn list_all_files_quic(
+    server: &str,
+    port: u16,
+    pattern: Option<&str>,
+) -> Result<Vec<crate::messages::SeedFileInfo>> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = list_files_quic(server, port, pattern, offset, None).await?;
+        files.extend(page.files);
+        match page.next_offset {
+            Some(next) if next > offset => offset = next,
+            _ => return Ok(files),
+        }
+    }
+}
+
+/// Publishes (uploads) a local file into a QUIC tracker server's seed directory.
+///
+/// The file is hashed with SHA-256 and sent in chunks of `chunk_size` bytes;
+/// the server verifies the hash before moving the file into place.
+///
+/// # Arguments
+/// * `server` - Server hostname or IP address
+/// * `port` - Server port (default 7001 for QUIC tracker)
+/// * `local_path` - File to upload
+/// * `remote_name` - Destination path relative to the server's seed directory
+/// * `token` - Publish token configured on the server
+/// * `create_torrent` - Ask the server to generate and register a `.torrent`
+/// * `chunk_size` - Bytes per publish request
 ///
-/// This is the QUIC version of download_file, using QUIC for all communication.
+/// # Returns
+/// The final publish response (with the torrent info hash if one was generated)
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_file_quic(
+    server: &str,
+    port: u16,
+    local_path: &str,
+    remote_name: &str,
+    token: &str,
+    create_torrent: bool,
+    chunk_size: usize,
+) -> Result<crate::messages::PublishResponse> {
+    use std::io::Read;
+    
+    crate::log_client!("[publish_file_quic] ENTRY - server={}, port={}, local_path={}, remote_name={}, create_torrent={}", 
+        server, port, local_path, remote_name, create_torrent);
+    
+    if chunk_size == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "chunk_size must be greater than zero").into());
+    }
+    
+    let size = fs::metadata(local_path)?.len();
+    let sha256 = crate::seed::sha256_file(std::path::Path::new(local_path))?;
+    crate::log_client!("[publish_file_quic] Local file: size={}, sha256={}", size, sha256);
+    crate::log_client_sent!("Publishing {} ({} bytes) to {}:{} as {}", local_path, size, server, port, remote_name);
+    
+    crate::log_client!("[CLIENT] REQUEST TYPE: PublishRequest");
+    crate::log_client!("[CLIENT] Function: client::publish_file_quic()");
+    crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_publish_request()");
+    let client = crate::quic_client::QuicClient::shared()?;
+    
+    let mut file = fs::File::open(local_path)?;
+    let mut offset = 0u64;
+    loop {
+        let mut data = vec![0u8; chunk_size.min((size - offset) as usize)];
+        file.read_exact(&mut data)?;
+        
+        let request = crate::messages::PublishRequest {
+            publish: remote_name.to_string(),
+            token: token.to_string(),
+            sha256: sha256.clone(),
+            size,
+            offset,
+            data,
+            create_torrent: Some(create_torrent),
+        };
+        let response: crate::messages::PublishResponse = 
+            client.send_message(server, port, &request).await?;
+        crate::log_client!("[publish_file_quic] Progress: {}/{} bytes", response.received, size);
+        
+        if response.complete {
+            crate::log_client!("[publish_file_quic] EXIT - success=true, path={}, info_hash={:?}", 
+                response.path, response.info_hash);
+            return Ok(response);
+        }
+        if response.received <= offset {
+            return Err(Error::Protocol(format!("Server made no progress at offset {}", offset)));
+        }
+        offset = response.received;
+    }
+}
+
+/// Options for torrent downloads.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Preferred port for the peer listener (the next free one is used if taken)
+    pub peer_port: u16,
+    /// Download pieces in order (for streaming) instead of rarest-first
+    pub sequential: bool,
+    /// Keep serving pieces after the download completes
+    pub seed: bool,
+    /// Stop seeding once `uploaded / file size` reaches this ratio
+    pub seed_ratio: Option<f64>,
+    /// Stop seeding after this long
+    pub seed_time: Option<std::time::Duration>,
+    /// Also find peers through the DHT, joining it with these settings;
+    /// the download then works without the tracker
+    pub dht: Option<crate::dht::DhtConfig>,
+    /// Find peers on the local network by multicast announcements
+    /// (`None` disables local discovery)
+    pub lsd: Option<crate::lsd::LsdConfig>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            peer_port: crate::peer_wire::DEFAULT_PEER_PORT,
+            sequential: false,
+            seed: false,
+            seed_ratio: None,
+            seed_time: None,
+            dht: None,
+            lsd: Some(crate::lsd::LsdConfig::default()),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Checks the seeding limits.
+    ///
+    /// # Arguments
+    /// * `uploaded` - Bytes uploaded so far
+    /// * `size` - Size of the torrent's file
+    /// * `seeding_for` - Time spent seeding so far
+    ///
+    /// # Returns
+    /// Why seeding should stop, or `None` to keep going
+    pub fn seed_limit_reached(&self, uploaded: u64, size: u64, seeding_for: std::time::Duration) -> Option<String> {
+        if let Some(ratio) = self.seed_ratio {
+            let reached = if size == 0 { f64::INFINITY } else { uploaded as f64 / size as f64 };
+            if reached >= ratio {
+                return Some(format!("share ratio {:.2} reached (target {:.2})", reached, ratio));
+            }
+        }
+        if let Some(limit) = self.seed_time {
+            if seeding_for >= limit {
+                return Some(format!("seeded for {}s", limit.as_secs()));
+            }
+        }
+        None
+    }
+}
+
+/// Downloads a file using a torrent file via QUIC, with default `DownloadOptions`.
 pub async fn download_file_quic_torrent(
     torrent_path: &str,
     output_path: &str,
     tracker_server: &str,
     tracker_port: u16,
-) -> Result<(), Box<dyn std::error::Error>> {
-    crate::log_client!("[download_file_quic_torrent] ENTRY - torrent_path={}, output_path={}, tracker_server={}, tracker_port={}", 
-        torrent_path, output_path, tracker_server, tracker_port);
+) -> Result<()> {
+    download_file_quic_torrent_with_options(
+        torrent_path,
+        output_path,
+        tracker_server,
+        tracker_port,
+        &DownloadOptions::default(),
+    ).await
+}
+
+/// Downloads a file using a torrent file, fetching pieces from the swarm.
+///
+/// Starts a peer listener so other downloaders can fetch verified pieces
+/// from this client, announces its port to the tracker, and downloads
+/// pieces from every peer in the announce response (and from the tracker's
+/// seed directory). Each piece is checked against the torrent's SHA-1 hash.
+/// Peers found later through PEX or local discovery are fetched from too.
+/// Pieces already present in `output_path` are kept.
+pub async fn download_file_quic_torrent_with_options(
+    torrent_path: &str,
+    output_path: &str,
+    tracker_server: &str,
+    tracker_port: u16,
+    options: &DownloadOptions,
+) -> Result<()> {
+    crate::log_client!("[download_file_quic_torrent] ENTRY - torrent_path={}, output_path={}, tracker_server={}, tracker_port={}, options={:?}", 
+        torrent_path, output_path, tracker_server, tracker_port, options);
     
     crate::log_client!("[download_file_quic_torrent] Parsing torrent file: {}", torrent_path);
     let torrent = TorrentFile::from_file(torrent_path)?;
@@ -265,46 +659,217 @@ pub async fn download_file_quic_torrent(
     
     let peer_id = format!("-ST0001-{}", rand::random::<u64>());
     crate::log_client!("[download_file_quic_torrent] Generated peer_id: {}", peer_id);
-    crate::log_client!("Generated peer_id: {}", peer_id);
+    
+    // Open the output file; pieces that already verify don't need downloading
+    let store = crate::peer_wire::PieceStore::open(torrent.clone(), std::path::Path::new(output_path))
+        .map_err(std::io::Error::other)?;
+    let store = std::sync::Arc::new(store);
+    println!("Pieces: {} ({} bytes each), {} already present", 
+        store.piece_count(), torrent.piece_length, store.have().iter().filter(|&&h| h).count());
+    
+    // Serve our verified pieces to the rest of the swarm while downloading
+    let listener = crate::peer_wire::PeerListener::bind(options.peer_port, &peer_id)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let listener = std::sync::Arc::new(listener);
+    listener.add_torrent(std::sync::Arc::clone(&store));
+    println!("Listening for peers on port {}", listener.port());
+    
+    // Announce on the local network; peers found there join the pool as they show up
+    let lsd = match &options.lsd {
+        Some(config) => match crate::lsd::LocalDiscovery::start(config.clone(), listener.port()).await {
+            Ok((lsd, mut discovered)) => {
+                lsd.add_torrent(&torrent.info_hash);
+                let listener = std::sync::Arc::clone(&listener);
+                tokio::spawn(async move {
+                    while let Some(peer) = discovered.recv().await {
+                        let info = crate::messages::PeerInfo { ip: peer.addr.ip().to_string(), port: peer.addr.port(), peer_id: None };
+                        if listener.add_peers(&peer.info_hash, &[info], crate::pex::PeerSource::Local) > 0 {
+                            println!("Found local peer {}", peer.addr);
+                        }
+                    }
+                });
+                println!("Local peer discovery on {}", config.group);
+                Some(lsd)
+            }
+            Err(e) => {
+                // Not fatal: the network may not allow multicast
+                crate::log_client!("[download_file_quic_torrent] Local discovery unavailable: {}", e);
+                println!("Local peer discovery unavailable: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    
+    // Join the DHT first, so a download can go ahead without the tracker
+    let dht = match &options.dht {
+        Some(config) => {
+            let dht = crate::dht::Dht::start(config.clone()).await.map_err(|e| Error::transport(format!("DHT: {}", e)))?;
+            let contacts = dht.bootstrap().await.map_err(|e| Error::transport(format!("DHT bootstrap: {}", e)))?;
+            println!("DHT node {} on {} ({} contacts)", dht.id(), dht.local_addr(), contacts);
+            Some(dht)
+        }
+        None => None,
+    };
     
     // Announce to QUIC tracker
     crate::log_client!("[download_file_quic_torrent] Announcing to QUIC tracker: {}:{}", tracker_server, tracker_port);
     println!("Announcing to QUIC tracker: {}:{}", tracker_server, tracker_port);
-    let _peers = announce_to_quic_tracker(
+    let announce = |event: Option<&'static str>| announce_with_response(
         tracker_server,
         tracker_port,
         &torrent.info_hash,
         &peer_id,
-        6881,
-        0,
-        0,
-        torrent.length as u64,
-    ).await?;
-    crate::log_client!("[download_file_quic_torrent] Announce complete - peers_count={}", _peers.len());
-    println!("Announced successfully");
-    
-    // Extract filename
-    let filename = std::path::Path::new(&torrent.name)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or(&torrent.name);
-    
-    crate::log_client!("[download_file_quic_torrent] Extracted filename: {}", filename);
-    
-    // Download file via QUIC
-    crate::log_client!("[download_file_quic_torrent] Starting file download via QUIC");
-    println!("Downloading file from QUIC server: {}:{}", tracker_server, tracker_port);
-    download_file_quic(tracker_server, tracker_port, filename, output_path).await?;
+        listener.port(),
+        store.uploaded(),
+        store.downloaded(),
+        store.left(),
+        event,
+    );
+    // Reported instead of the download's failure if no other source delivers anything
+    let mut tracker_error = None;
+    let mut response = match announce(Some("started")).await {
+        Ok(response) => response,
+        Err(e) if dht.is_some() || lsd.is_some() || !torrent.url_list.is_empty() => {
+            crate::log_client!("[download_file_quic_torrent] Tracker announce failed, continuing with the DHT, local discovery and web seeds: {}", e);
+            println!("Tracker unavailable ({}); continuing with the DHT, local discovery and web seeds", e);
+            tracker_error = Some(e);
+            crate::messages::TrackerAnnounceResponse { interval: 60, peers: Vec::new(), complete: 0, incomplete: 0 }
+        }
+        Err(e) => return Err(e),
+    };
+    crate::log_client!("[download_file_quic_torrent] Announce complete - peers_count={}, interval={}", response.peers.len(), response.interval);
+    println!("Announced successfully: {} peers", response.peers.len());
+    let interval = std::time::Duration::from_secs(response.interval.max(1));
+    
+    if let Some(dht) = &dht {
+        match dht.announce(&torrent.info_hash, listener.port()).await {
+            Ok(found) => {
+                println!("DHT: {} peers", found.len());
+                response.peers.extend(found);
+            }
+            Err(e) => crate::log_client!("[download_file_quic_torrent] DHT announce failed: {}", e),
+        }
+    }
+    
+    let result = if store.is_complete() {
+        println!("All pieces already present");
+        Ok(())
+    } else {
+        let mode = if options.sequential {
+            crate::piece_picker::PickMode::Sequential
+        } else {
+            crate::piece_picker::PickMode::RarestFirst
+        };
+        let result = listener.download(std::sync::Arc::clone(&store), &response.peers, (tracker_server, tracker_port), mode).await
+            .map_err(|e| e.to_string());
+        if let Ok(reports) = &result {
+            for report in reports {
+                match &report.error {
+                    Some(e) => println!("  Dropped source {}: {}", report.source, e),
+                    None if report.pieces > 0 => println!("  {} pieces from {}", report.pieces, report.source),
+                    None => {}
+                }
+            }
+            crate::log_client!("[download_file_quic_torrent] Announcing completed - uploaded={}, downloaded={}", store.uploaded(), store.downloaded());
+            if let Err(e) = announce(Some("completed")).await {
+                crate::log_client!("[download_file_quic_torrent] Completed announce failed: {}", e);
+            }
+        }
+        result.map(|_| ())
+    };
+    
+    if result.is_ok() && options.seed {
+        let dht = dht.as_ref().map(|dht| (dht, listener.port()));
+        seed_until_done(&store, options, interval, &announce, dht).await;
+    }
+    
+    // We stop serving when we return, so leave the swarm
+    drop(lsd);
+    listener.close();
+    if let Err(e) = announce(Some("stopped")).await {
+        crate::log_client!("[download_file_quic_torrent] Stopped announce failed: {}", e);
+    }
+    if let (Err(e), Some(tracker_error)) = (&result, tracker_error) {
+        if store.downloaded() == 0 {
+            crate::log_client!("[download_file_quic_torrent] No other source delivered anything ({}); reporting the tracker failure", e);
+            return Err(tracker_error);
+        }
+    }
+    result.map_err(Error::Torrent)?;
     
     crate::log_client!("[download_file_quic_torrent] Download complete");
     println!("File saved to: {}", output_path);
     println!("Download complete!");
     
-    crate::log_client!("[download_file_quic_torrent] EXIT - success=true, output_path={}", output_path);
+    crate::log_client!("[download_file_quic_torrent] EXIT - success=true, output_path={}, downloaded={}, uploaded={}", 
+        output_path, store.downloaded(), store.uploaded());
     
     Ok(())
 }
 
+/// Keeps serving a complete torrent until a seeding limit is reached or Ctrl+C.
+///
+/// Re-announces every `interval` so the tracker keeps listing us and sees
+/// current transfer totals, and to the DHT (with our peer port) every
+/// `dht::DHT_REANNOUNCE_INTERVAL`. The caller sends the `stopped` announce.
+async fn seed_until_done<F, Fut>(
+    store: &crate::peer_wire::PieceStore,
+    options: &DownloadOptions,
+    interval: std::time::Duration,
+    announce: &F,
+    dht: Option<(&std::sync::Arc<crate::dht::Dht>, u16)>,
+) where
+    F: Fn(Option<&'static str>) -> Fut,
+    Fut: std::future::Future<Output = Result<crate::messages::TrackerAnnounceResponse>>,
+{
+    let started = std::time::Instant::now();
+    let mut next_announce = started + interval;
+    let mut next_dht_announce = started + crate::dht::DHT_REANNOUNCE_INTERVAL;
+    crate::log_client!("[seed_until_done] Seeding - info_hash={}, interval={}s, ratio={:?}, time={:?}", 
+        store.info_hash(), interval.as_secs(), options.seed_ratio, options.seed_time);
+    println!("Seeding (press Ctrl+C to stop)...");
+    
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        if let Some(reason) = options.seed_limit_reached(store.uploaded(), store.torrent().length as u64, started.elapsed()) {
+            crate::log_client!("[seed_until_done] Stopping: {}", reason);
+            println!("Stopped seeding: {}", reason);
+            break;
+        }
+        if std::time::Instant::now() >= next_announce {
+            match announce(None).await {
+                Ok(response) => {
+                    crate::log_client!("[seed_until_done] Re-announced - peers={}, uploaded={}", response.peers.len(), store.uploaded());
+                    next_announce = std::time::Instant::now() + std::time::Duration::from_secs(response.interval.max(1));
+                }
+                Err(e) => {
+                    crate::log_client!("[seed_until_done] Re-announce failed: {}", e);
+                    next_announce = std::time::Instant::now() + interval;
+                }
+            }
+        }
+        if let Some((dht, port)) = dht {
+            if std::time::Instant::now() >= next_dht_announce {
+                if let Err(e) = dht.announce(store.info_hash(), port).await {
+                    crate::log_client!("[seed_until_done] DHT re-announce failed: {}", e);
+                }
+                next_dht_announce = std::time::Instant::now() + crate::dht::DHT_REANNOUNCE_INTERVAL;
+            }
+        }
+        // Wake up at least once a second to check the limits
+        tokio::select! {
+            _ = &mut ctrl_c => {
+                println!("Stopped seeding: interrupted");
+                break;
+            }
+            _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+        }
+    }
+    println!("Uploaded {} bytes while online", store.uploaded());
+}
+
 /// Sends an AI query to a QUIC AI service server.
 ///
 /// # Arguments
@@ -326,7 +891,7 @@ pub async fn send_ai_query(
     temperature: Option<f64>,
     max_tokens: Option<usize>,
     top_p: Option<f64>,
-) -> Result<crate::messages::AiResponse, Box<dyn std::error::Error>> {
+) -> Result<crate::messages::AiResponse> {
     crate::log_client!("[send_ai_query] ENTRY - server={}, port={}, query_len={}", 
         server, port, query.len());
     crate::log_client_sent!("Sending AI query to {}:{} - query: {}", server, port, query);
@@ -341,15 +906,16 @@ pub async fn send_ai_query(
         query: query.to_string(),
         context,
         parameters: Some(parameters),
+        stream: None,
     };
     
     crate::log_client!("[CLIENT] REQUEST TYPE: AiRequest");
     crate::log_client!("[CLIENT] Function: client::send_ai_query()");
     crate::log_client!("[CLIENT] Routing to: Server -> quic_tracker::handle_ai_request() -> ai_processor::process_query_sync()");
     crate::log_client!("[CLIENT] Processing module: AI Processing Module");
-    crate::log_client!("[send_ai_query] Created AiRequest, creating QUIC client");
-    let client = crate::quic_client::QuicClient::new()?;
-    crate::log_client!("[send_ai_query] QUIC client created, sending AI request to {}:{}", server, port);
+    crate::log_client!("[send_ai_query] Created AiRequest, using the shared QUIC client");
+    let client = crate::quic_client::QuicClient::shared()?;
+    crate::log_client!("[send_ai_query] QUIC client ready, sending AI request to {}:{}", server, port);
     
     let response: crate::messages::AiResponse = 
         client.send_message(server, port, &request).await?;
@@ -361,3 +927,53 @@ pub async fn send_ai_query(
     Ok(response)
 }
 
+/// Sends an AI query on a framed stream, handing the answer to `on_token`
+/// piece by piece as the server streams it.
+///
+/// # Arguments
+/// * `server` - Server hostname or IP address
+/// * `port` - Server port (default 7001 for tracker/AI service)
+/// * `query` - The user's query text
+/// * `context` - Optional conversation context (previous messages)
+/// * `on_token` - Called with each `AiToken`, in order
+///
+/// # Returns
+/// The final AI response; its answer is the streamed tokens concatenated
+pub async fn send_ai_query_streaming<F>(
+    server: &str,
+    port: u16,
+    query: &str,
+    context: Option<Vec<crate::messages::MessageContext>>,
+    mut on_token: F,
+) -> Result<crate::messages::AiResponse>
+where
+    F: FnMut(&crate::messages::AiToken),
+{
+    crate::log_client!("[send_ai_query_streaming] ENTRY - server={}, port={}, query_len={}", 
+        server, port, query.len());
+    crate::log_client_sent!("Sending streaming AI query to {}:{} - query: {}", server, port, query);
+    
+    let request = crate::messages::AiRequest {
+        query: query.to_string(),
+        context,
+        parameters: None,
+        stream: Some(true),
+    };
+    
+    let client = crate::quic_client::QuicClient::shared()?;
+    let mut stream = client.open_stream(server, port).await?;
+    let mut tokens = 0usize;
+    let response: crate::messages::AiResponse = stream.request(&request, |frame| {
+        if frame.kind == crate::protocol::MessageKind::AiToken {
+            on_token(&frame.decode()?);
+            tokens += 1;
+        }
+        Ok(())
+    }).await?;
+    stream.finish().await?;
+    
+    crate::log_client!("[send_ai_query_streaming] Received AI response after {} token(s) - answer_len={}", 
+        tokens, response.answer.len());
+    Ok(response)
+}
+
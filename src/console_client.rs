@@ -93,11 +93,9 @@ fn wrap_text(text: &str, width: usize) -> Vec<String> {
     let mut current_line = String::new();
     
     for word in text.split_whitespace() {
-        if current_line.len() + word.len() + 1 > width {
-            if !current_line.is_empty() {
-                lines.push(current_line);
-                current_line = String::new();
-            }
+        if current_line.len() + word.len() + 1 > width && !current_line.is_empty() {
+            lines.push(current_line);
+            current_line = String::new();
         }
         if !current_line.is_empty() {
             current_line.push(' ');
@@ -222,7 +220,7 @@ async fn handle_download_command(
         .filter(|arg| !arg.starts_with("--") && !arg.starts_with("-"))
         .collect();
     
-    let torrent_path = if let Some(path) = filtered_args.get(0) {
+    let torrent_path = if let Some(path) = filtered_args.first() {
         let mut path_str = path.to_string();
         // Auto-add .torrent extension if not present
         if !path_str.ends_with(".torrent") {
@@ -0,0 +1,934 @@
+//! # Kademlia DHT
+//!
+//! Trackerless peer discovery, following BitTorrent's mainline DHT (BEP 5)
+//! with JSON `DhtMessage`s instead of bencode.
+//!
+//! Every node has a random 160-bit id; torrents are found under their info
+//! hash. A node keeps a `RoutingTable` of up to `K` contacts per distance
+//! bucket and answers four queries:
+//!
+//! - `ping` - liveness check;
+//! - `find_node` - the `K` closest contacts to a target id;
+//! - `get_peers` - peers stored for an info hash (or the closest contacts),
+//!   plus a `token`;
+//! - `announce_peer` - stores the sender as a peer; only accepted with a
+//!   token the node handed to the sender's IP in the last ~10 minutes.
+//!
+//! Lookups are iterative: query the `ALPHA` closest unqueried contacts,
+//! merge the contacts they return, and stop once the `K` closest have all
+//! answered (`Lookup`).
+//!
+//! Messages travel as one UDP datagram each (`DhtTransport::Udp`), or as one
+//! bidirectional QUIC stream per query, like tracker requests
+//! (`DhtTransport::Quic`). Nodes join through configurable bootstrap
+//! addresses; any running node can serve as one, so many nodes can run in
+//! one process on loopback.
+//!
+//! The routing table, tokens, peer store and lookup state are plain data
+//! driven by the caller's `Instant`s; only `Dht` does I/O.
+
+use crate::messages::{DhtBody, DhtMessage, DhtNodeInfo, PeerInfo};
+use crate::quic_utils::{create_client_config, create_server_config};
+use quinn::Endpoint;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// Contacts per routing table bucket, and the size of lookup results.
+pub const K: usize = 8;
+
+/// Queries in flight per lookup.
+pub const ALPHA: usize = 3;
+
+/// Default UDP port for DHT nodes (the peer listener uses 6881 and up).
+pub const DEFAULT_DHT_PORT: u16 = 6890;
+
+/// How long to wait for an answer to a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the token secret changes; tokens stay valid for two periods.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long an announced peer is kept without a fresh announce.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often a seeding client announces itself again.
+pub const DHT_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Most peers returned for one `get_peers`.
+const MAX_PEERS_PER_RESPONSE: usize = 50;
+
+/// Largest message accepted, in bytes.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Most queries a lookup sends before giving up.
+const MAX_LOOKUP_QUERIES: usize = 64;
+
+/// Errors crossing task boundaries must be `Send`.
+pub type DhtResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A 160-bit node id or info hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// Parses 40 hex digits (the format of info hashes).
+    pub fn from_hex(s: &str) -> Option<Self> {
+        let bytes = hex::decode(s).ok()?;
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// XOR distance; compares as a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0u8; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// Routing table bucket for `other`: the number of leading bits it
+    /// shares with `self`. `None` for `self`.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let first = distance.iter().position(|&b| b != 0)?;
+        Some(first * 8 + distance[first].leading_zeros() as usize)
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// A node's id and address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeContact {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeContact {
+    pub fn to_info(&self) -> DhtNodeInfo {
+        DhtNodeInfo { id: self.id.to_hex(), ip: self.addr.ip().to_string(), port: self.addr.port() }
+    }
+
+    /// `None` if the id or IP doesn't parse.
+    pub fn from_info(info: &DhtNodeInfo) -> Option<Self> {
+        let ip: IpAddr = info.ip.parse().ok()?;
+        Some(Self { id: NodeId::from_hex(&info.id)?, addr: SocketAddr::new(ip, info.port) })
+    }
+}
+
+/// Outcome of `RoutingTable::insert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertResult {
+    Added,
+    /// Already known; moved to the most recently seen position
+    Updated,
+    /// The bucket is full; ping `oldest` and `replace` it if it doesn't answer
+    BucketFull { oldest: NodeContact },
+    /// Our own id
+    Ignored,
+}
+
+/// Kademlia routing table: one bucket of up to `K` contacts per shared-prefix length.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    /// Each bucket is ordered least recently seen first
+    buckets: Vec<Vec<(NodeContact, Instant)>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self { own_id, buckets: vec![Vec::new(); 160] }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that `contact` was seen (it answered, or queried us).
+    pub fn insert(&mut self, contact: NodeContact, now: Instant) -> InsertResult {
+        let Some(index) = self.own_id.bucket_index(&contact.id) else { return InsertResult::Ignored };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|(c, _)| c.id == contact.id) {
+            bucket.remove(position);
+            bucket.push((contact, now));
+            return InsertResult::Updated;
+        }
+        if bucket.len() < K {
+            bucket.push((contact, now));
+            return InsertResult::Added;
+        }
+        InsertResult::BucketFull { oldest: bucket[0].0 }
+    }
+
+    /// Swaps an unresponsive contact for a new one.
+    pub fn replace(&mut self, old: &NodeId, contact: NodeContact, now: Instant) {
+        self.remove(old);
+        self.insert(contact, now);
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.own_id.bucket_index(id) {
+            self.buckets[index].retain(|(c, _)| c.id != *id);
+        }
+    }
+
+    /// Removes the contact at `addr`, e.g. after it failed to answer.
+    pub fn remove_addr(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|(c, _)| c.addr != addr);
+        }
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.own_id.bucket_index(id)
+            .is_some_and(|index| self.buckets[index].iter().any(|(c, _)| c.id == *id))
+    }
+
+    /// Up to `count` known contacts, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeContact> {
+        let mut contacts: Vec<NodeContact> = self.buckets.iter().flatten().map(|(c, _)| *c).collect();
+        contacts.sort_by_key(|c| c.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+/// Issues and checks `announce_peer` tokens: a hash of the requester's IP
+/// and a secret that rotates every `TOKEN_ROTATION`.
+#[derive(Debug, Clone)]
+pub struct TokenManager {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+impl TokenManager {
+    pub fn new(now: Instant) -> Self {
+        Self::with_secret(rand::random(), now)
+    }
+
+    /// Like `new`, with a fixed initial secret for reproducible tokens.
+    pub fn with_secret(secret: [u8; 16], now: Instant) -> Self {
+        Self { current: secret, previous: secret, rotated_at: now }
+    }
+
+    /// The token for `ip`.
+    pub fn token(&mut self, ip: IpAddr, now: Instant) -> String {
+        self.rotate(now);
+        Self::hash(&self.current, ip)
+    }
+
+    /// True if `token` was issued to `ip` by this or the previous secret.
+    pub fn validate(&mut self, token: &str, ip: IpAddr, now: Instant) -> bool {
+        self.rotate(now);
+        token == Self::hash(&self.current, ip) || token == Self::hash(&self.previous, ip)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        while now.duration_since(self.rotated_at) >= TOKEN_ROTATION {
+            self.previous = self.current;
+            // Derive the next secret so a fixed initial secret stays reproducible
+            let next = Sha1::digest(self.current);
+            self.current.copy_from_slice(&next[..16]);
+            self.rotated_at += TOKEN_ROTATION;
+        }
+    }
+
+    fn hash(secret: &[u8; 16], ip: IpAddr) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.to_string().as_bytes());
+        hex::encode(&hasher.finalize()[..8])
+    }
+}
+
+/// Peers announced to this node, per info hash, expiring after `PEER_TTL`.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStore {
+    peers: HashMap<String, HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, info_hash: &str, addr: SocketAddr, now: Instant) {
+        self.peers.entry(info_hash.to_string()).or_default().insert(addr, now);
+    }
+
+    /// Up to `max` unexpired peers for `info_hash`.
+    pub fn get(&mut self, info_hash: &str, now: Instant, max: usize) -> Vec<SocketAddr> {
+        self.expire(now);
+        self.peers.get(info_hash)
+            .map(|peers| peers.keys().copied().take(max).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LookupState {
+    Pending,
+    Querying,
+    /// Answered; carries the `get_peers` token, if any
+    Responded(Option<String>),
+    Failed,
+}
+
+/// State of one iterative lookup for `target`.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    target: NodeId,
+    /// Contacts by distance to the target
+    nodes: BTreeMap<[u8; 20], (NodeContact, LookupState)>,
+    peers: BTreeSet<SocketAddr>,
+    queries: usize,
+}
+
+impl Lookup {
+    /// Starts a lookup from the contacts we already know.
+    pub fn new(target: NodeId, seeds: &[NodeContact]) -> Self {
+        let mut lookup = Self { target, nodes: BTreeMap::new(), peers: BTreeSet::new(), queries: 0 };
+        lookup.add_nodes(seeds);
+        lookup
+    }
+
+    pub fn target(&self) -> NodeId {
+        self.target
+    }
+
+    /// Picks contacts to query next, marking them in flight.
+    ///
+    /// # Returns
+    /// Up to `ALPHA` minus the queries in flight of the closest unqueried
+    /// contacts among the `K` closest live ones
+    pub fn next_queries(&mut self) -> Vec<NodeContact> {
+        let in_flight = self.nodes.values().filter(|(_, s)| *s == LookupState::Querying).count();
+        let budget = ALPHA.saturating_sub(in_flight).min(MAX_LOOKUP_QUERIES.saturating_sub(self.queries));
+        let picked: Vec<[u8; 20]> = self.nodes.iter()
+            .filter(|(_, (_, s))| *s != LookupState::Failed)
+            .take(K)
+            .filter(|(_, (_, s))| *s == LookupState::Pending)
+            .take(budget)
+            .map(|(distance, _)| *distance)
+            .collect();
+        self.queries += picked.len();
+        picked.into_iter()
+            .map(|distance| {
+                let entry = self.nodes.get_mut(&distance).unwrap();
+                entry.1 = LookupState::Querying;
+                entry.0
+            })
+            .collect()
+    }
+
+    /// Records an answer: the contacts and peers it listed, and its token.
+    pub fn on_response(&mut self, from: &NodeContact, nodes: &[NodeContact], peers: &[SocketAddr], token: Option<String>) {
+        let distance = from.id.distance(&self.target);
+        self.nodes.insert(distance, (*from, LookupState::Responded(token)));
+        self.add_nodes(nodes);
+        self.peers.extend(peers.iter().copied());
+    }
+
+    /// Records a query that timed out or failed.
+    pub fn on_failure(&mut self, from: &NodeContact) {
+        if let Some(entry) = self.nodes.get_mut(&from.id.distance(&self.target)) {
+            entry.1 = LookupState::Failed;
+        }
+    }
+
+    /// True once the `K` closest live contacts have all answered, or nothing is left to try.
+    pub fn is_done(&self) -> bool {
+        let mut live = self.nodes.values().filter(|(_, s)| *s != LookupState::Failed).take(K).peekable();
+        if live.peek().is_none() {
+            return !self.nodes.values().any(|(_, s)| *s == LookupState::Querying);
+        }
+        let closest_answered = live.all(|(_, s)| matches!(s, LookupState::Responded(_)));
+        let exhausted = self.queries >= MAX_LOOKUP_QUERIES
+            && !self.nodes.values().any(|(_, s)| *s == LookupState::Querying);
+        closest_answered || exhausted
+    }
+
+    /// The closest contacts that answered, with their tokens.
+    pub fn closest_responded(&self, count: usize) -> Vec<(NodeContact, Option<String>)> {
+        self.nodes.values()
+            .filter_map(|(contact, state)| match state {
+                LookupState::Responded(token) => Some((*contact, token.clone())),
+                _ => None,
+            })
+            .take(count)
+            .collect()
+    }
+
+    /// Peers collected from `get_peers` answers.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.iter().copied().collect()
+    }
+
+    fn add_nodes(&mut self, nodes: &[NodeContact]) {
+        for node in nodes {
+            self.nodes.entry(node.id.distance(&self.target)).or_insert((*node, LookupState::Pending));
+        }
+    }
+}
+
+/// How DHT messages travel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhtTransport {
+    /// One datagram per message
+    Udp,
+    /// One bidirectional stream per query, answered on the same stream
+    Quic,
+}
+
+/// DHT node settings.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Address to bind; port 0 picks a free port
+    pub bind: SocketAddr,
+    pub transport: DhtTransport,
+    /// `host:port` of nodes to join through
+    pub bootstrap: Vec<String>,
+    /// Fixed node id (random if `None`)
+    pub node_id: Option<NodeId>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], DEFAULT_DHT_PORT)),
+            transport: DhtTransport::Udp,
+            bootstrap: Vec::new(),
+            node_id: None,
+        }
+    }
+}
+
+enum Transport {
+    Udp {
+        socket: Arc<UdpSocket>,
+        /// Queries awaiting an answer, by transaction id, with the node asked
+        pending: Mutex<HashMap<u64, (SocketAddr, oneshot::Sender<DhtMessage>)>>,
+    },
+    Quic {
+        endpoint: Endpoint,
+        connections: Mutex<HashMap<SocketAddr, quinn::Connection>>,
+    },
+}
+
+/// A running DHT node.
+pub struct Dht {
+    id: NodeId,
+    local_addr: SocketAddr,
+    bootstrap: Vec<String>,
+    transport: Transport,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<TokenManager>,
+    peers: Mutex<PeerStore>,
+    next_transaction: AtomicU64,
+    receiver: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+        if let Transport::Quic { endpoint, .. } = &self.transport {
+            endpoint.close(0u32.into(), b"closing");
+        }
+    }
+}
+
+impl Dht {
+    /// Binds the node and starts answering queries. Call `bootstrap` to join the network.
+    pub async fn start(config: DhtConfig) -> DhtResult<Arc<Self>> {
+        let id = config.node_id.unwrap_or_else(NodeId::random);
+        let transport = match config.transport {
+            DhtTransport::Udp => Transport::Udp {
+                socket: Arc::new(UdpSocket::bind(config.bind).await?),
+                pending: Mutex::new(HashMap::new()),
+            },
+            DhtTransport::Quic => {
+                let server_config = create_server_config().map_err(|e| e.to_string())?;
+                let mut endpoint = Endpoint::server(server_config, config.bind)?;
+                endpoint.set_default_client_config(create_client_config().map_err(|e| e.to_string())?);
+                Transport::Quic { endpoint, connections: Mutex::new(HashMap::new()) }
+            }
+        };
+        let local_addr = match &transport {
+            Transport::Udp { socket, .. } => socket.local_addr()?,
+            Transport::Quic { endpoint, .. } => endpoint.local_addr()?,
+        };
+
+        let dht = Arc::new(Self {
+            id,
+            local_addr,
+            bootstrap: config.bootstrap,
+            transport,
+            table: Mutex::new(RoutingTable::new(id)),
+            tokens: Mutex::new(TokenManager::new(Instant::now())),
+            peers: Mutex::new(PeerStore::new()),
+            next_transaction: AtomicU64::new(rand::random::<u32>() as u64),
+            receiver: Mutex::new(None),
+        });
+
+        let weak = Arc::downgrade(&dht);
+        let receiver = match &dht.transport {
+            Transport::Udp { socket, .. } => tokio::spawn(receive_udp(Arc::clone(socket), weak)),
+            Transport::Quic { endpoint, .. } => tokio::spawn(accept_quic(endpoint.clone(), weak)),
+        };
+        *dht.receiver.lock().unwrap() = Some(receiver);
+
+        crate::log_client!("[Dht::start] Node {} listening on {} ({:?})", id, local_addr, config.transport);
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of contacts in the routing table.
+    pub fn node_count(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Joins the network: pings the bootstrap nodes, then looks up our own id
+    /// to fill the routing table.
+    ///
+    /// # Returns
+    /// Number of contacts in the routing table afterwards
+    pub async fn bootstrap(self: &Arc<Self>) -> DhtResult<usize> {
+        let mut tasks = tokio::task::JoinSet::new();
+        for address in self.bootstrap.clone() {
+            let dht = Arc::clone(self);
+            tasks.spawn(async move {
+                let addrs: Vec<SocketAddr> = match tokio::net::lookup_host(&address).await {
+                    Ok(addrs) => addrs.collect(),
+                    Err(e) => {
+                        crate::log_client!("[Dht::bootstrap] Cannot resolve {}: {}", address, e);
+                        return;
+                    }
+                };
+                for addr in addrs.into_iter().filter(|a| *a != dht.local_addr) {
+                    if let Err(e) = dht.query(addr, DhtBody::Ping).await {
+                        crate::log_client!("[Dht::bootstrap] Bootstrap node {} did not answer: {}", addr, e);
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        self.find_node(self.id).await;
+        let count = self.node_count();
+        crate::log_client!("[Dht::bootstrap] Node {} knows {} contacts", self.id, count);
+        Ok(count)
+    }
+
+    /// Iteratively looks up the `K` contacts closest to `target`.
+    pub async fn find_node(self: &Arc<Self>, target: NodeId) -> Vec<NodeContact> {
+        let lookup = self.lookup(target, None).await;
+        lookup.closest_responded(K).into_iter().map(|(contact, _)| contact).collect()
+    }
+
+    /// Iteratively looks up peers for `info_hash` (40 hex digits).
+    pub async fn get_peers(self: &Arc<Self>, info_hash: &str) -> DhtResult<Vec<PeerInfo>> {
+        let target = NodeId::from_hex(info_hash).ok_or_else(|| format!("Invalid info hash: {}", info_hash))?;
+        let lookup = self.lookup(target, Some(info_hash)).await;
+        Ok(peer_infos(&lookup.peers()))
+    }
+
+    /// Looks up peers for `info_hash` and announces us as a peer on `port`
+    /// to the closest nodes that handed out a token.
+    ///
+    /// # Returns
+    /// The peers found by the lookup
+    pub async fn announce(self: &Arc<Self>, info_hash: &str, port: u16) -> DhtResult<Vec<PeerInfo>> {
+        let target = NodeId::from_hex(info_hash).ok_or_else(|| format!("Invalid info hash: {}", info_hash))?;
+        let lookup = self.lookup(target, Some(info_hash)).await;
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for (contact, token) in lookup.closest_responded(K) {
+            let Some(token) = token else { continue };
+            let dht = Arc::clone(self);
+            let body = DhtBody::AnnouncePeer { info_hash: info_hash.to_string(), port, token };
+            tasks.spawn(async move { dht.query(contact.addr, body).await.is_ok_and(|r| matches!(r.body, DhtBody::Pong)) });
+        }
+        let mut stored = 0;
+        while let Some(joined) = tasks.join_next().await {
+            if joined.unwrap_or(false) {
+                stored += 1;
+            }
+        }
+        crate::log_client!("[Dht::announce] info_hash={}, port={}, stored on {} nodes, {} peers found",
+            info_hash, port, stored, lookup.peers().len());
+        Ok(peer_infos(&lookup.peers()))
+    }
+
+    /// Runs an iterative `find_node` (or `get_peers`, if `info_hash` is set) lookup.
+    async fn lookup(self: &Arc<Self>, target: NodeId, info_hash: Option<&str>) -> Lookup {
+        let seeds = self.table.lock().unwrap().closest(&target, K);
+        let mut lookup = Lookup::new(target, &seeds);
+        let mut tasks = tokio::task::JoinSet::new();
+        loop {
+            for contact in lookup.next_queries() {
+                let dht = Arc::clone(self);
+                let body = match info_hash {
+                    Some(info_hash) => DhtBody::GetPeers { info_hash: info_hash.to_string() },
+                    None => DhtBody::FindNode { target: target.to_hex() },
+                };
+                tasks.spawn(async move { (contact, dht.query(contact.addr, body).await) });
+            }
+            let Some(joined) = tasks.join_next().await else { break };
+            let Ok((contact, result)) = joined else { continue };
+            match result {
+                Ok(DhtMessage { body: DhtBody::Nodes { nodes }, .. }) => {
+                    lookup.on_response(&contact, &contacts(&nodes), &[], None);
+                }
+                Ok(DhtMessage { body: DhtBody::Peers { token, peers, nodes }, .. }) => {
+                    let peers: Vec<SocketAddr> = peers.iter()
+                        .filter_map(|p| Some(SocketAddr::new(p.ip.parse().ok()?, p.port)))
+                        .collect();
+                    lookup.on_response(&contact, &contacts(&nodes), &peers, Some(token));
+                }
+                Ok(other) => {
+                    crate::log_client!("[Dht::lookup] Unexpected answer from {}: {:?}", contact.addr, other.body);
+                    lookup.on_failure(&contact);
+                }
+                Err(_) => lookup.on_failure(&contact),
+            }
+            if lookup.is_done() {
+                break;
+            }
+        }
+        tasks.abort_all();
+        lookup
+    }
+
+    /// Sends a query and waits up to `QUERY_TIMEOUT` for the answer.
+    ///
+    /// Nodes that answer go into the routing table; nodes that don't are dropped from it.
+    async fn query(self: &Arc<Self>, addr: SocketAddr, body: DhtBody) -> DhtResult<DhtMessage> {
+        let t = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let message = DhtMessage { t, id: self.id.to_hex(), body };
+        let result = tokio::time::timeout(QUERY_TIMEOUT, self.exchange(addr, &message)).await
+            .unwrap_or_else(|_| Err("DHT query timed out".into()));
+        if let Transport::Udp { pending, .. } = &self.transport {
+            pending.lock().unwrap().remove(&t);
+        }
+
+        match result {
+            Ok(response) => {
+                if let DhtBody::Error { code, message } = &response.body {
+                    return Err(format!("DHT error {} from {}: {}", code, addr, message).into());
+                }
+                if let Some(id) = NodeId::from_hex(&response.id) {
+                    self.observe(NodeContact { id, addr });
+                }
+                Ok(response)
+            }
+            Err(e) => {
+                self.table.lock().unwrap().remove_addr(addr);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends `message` to `addr` and returns the answer.
+    async fn exchange(&self, addr: SocketAddr, message: &DhtMessage) -> DhtResult<DhtMessage> {
+        let bytes = serde_json::to_vec(message)?;
+        match &self.transport {
+            Transport::Udp { socket, pending } => {
+                let (tx, rx) = oneshot::channel();
+                pending.lock().unwrap().insert(message.t, (addr, tx));
+                socket.send_to(&bytes, addr).await?;
+                Ok(rx.await?)
+            }
+            Transport::Quic { endpoint, connections } => {
+                // Connecting can take until the query times out; don't hold up
+                // queries to other nodes meanwhile
+                let existing = connections.lock().unwrap().get(&addr)
+                    .filter(|c| c.close_reason().is_none())
+                    .cloned();
+                let connection = match existing {
+                    Some(connection) => connection,
+                    None => {
+                        let connection = endpoint.connect(addr, "localhost")?.await?;
+                        connections.lock().unwrap().insert(addr, connection.clone());
+                        connection
+                    }
+                };
+                let (mut send, mut recv) = connection.open_bi().await?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+                let answer = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+                let answer: DhtMessage = serde_json::from_slice(&answer)?;
+                if answer.t != message.t {
+                    return Err(format!("Transaction id mismatch: sent {}, got {}", message.t, answer.t).into());
+                }
+                Ok(answer)
+            }
+        }
+    }
+
+    /// Adds a live contact to the routing table; if its bucket is full the
+    /// least recently seen contact is pinged and replaced if it doesn't answer.
+    fn observe(self: &Arc<Self>, contact: NodeContact) {
+        let result = self.table.lock().unwrap().insert(contact, Instant::now());
+        if let InsertResult::BucketFull { oldest } = result {
+            let dht = Arc::clone(self);
+            tokio::spawn(async move {
+                if dht.query(oldest.addr, DhtBody::Ping).await.is_err() {
+                    dht.table.lock().unwrap().replace(&oldest.id, contact, Instant::now());
+                }
+            });
+        }
+    }
+
+    /// Answers a query from `from`.
+    fn handle_query(self: &Arc<Self>, query: DhtMessage, from: SocketAddr) -> DhtMessage {
+        let now = Instant::now();
+        if let Some(id) = NodeId::from_hex(&query.id) {
+            self.observe(NodeContact { id, addr: from });
+        }
+        let body = match query.body {
+            DhtBody::Ping => DhtBody::Pong,
+            DhtBody::FindNode { target } => match NodeId::from_hex(&target) {
+                Some(target) => DhtBody::Nodes { nodes: self.closest_infos(&target) },
+                None => dht_error(203, "Invalid target"),
+            },
+            DhtBody::GetPeers { info_hash } => match NodeId::from_hex(&info_hash) {
+                Some(target) => {
+                    let peers = self.peers.lock().unwrap().get(&info_hash, now, MAX_PEERS_PER_RESPONSE);
+                    DhtBody::Peers {
+                        token: self.tokens.lock().unwrap().token(from.ip(), now),
+                        peers: peer_infos(&peers),
+                        nodes: self.closest_infos(&target),
+                    }
+                }
+                None => dht_error(203, "Invalid info hash"),
+            },
+            DhtBody::AnnouncePeer { info_hash, port, token } => {
+                if NodeId::from_hex(&info_hash).is_none() {
+                    dht_error(203, "Invalid info hash")
+                } else if !self.tokens.lock().unwrap().validate(&token, from.ip(), now) {
+                    dht_error(203, "Invalid token")
+                } else {
+                    crate::log_client!("[Dht::handle_query] Stored peer {}:{} for {}", from.ip(), port, info_hash);
+                    self.peers.lock().unwrap().add(&info_hash, SocketAddr::new(from.ip(), port), now);
+                    DhtBody::Pong
+                }
+            }
+            _ => dht_error(204, "Not a query"),
+        };
+        DhtMessage { t: query.t, id: self.id.to_hex(), body }
+    }
+
+    fn closest_infos(&self, target: &NodeId) -> Vec<DhtNodeInfo> {
+        self.table.lock().unwrap().closest(target, K).iter().map(NodeContact::to_info).collect()
+    }
+}
+
+fn is_query(body: &DhtBody) -> bool {
+    matches!(body, DhtBody::Ping | DhtBody::FindNode { .. } | DhtBody::GetPeers { .. } | DhtBody::AnnouncePeer { .. })
+}
+
+fn dht_error(code: u16, message: &str) -> DhtBody {
+    DhtBody::Error { code, message: message.to_string() }
+}
+
+fn contacts(nodes: &[DhtNodeInfo]) -> Vec<NodeContact> {
+    nodes.iter().filter_map(NodeContact::from_info).collect()
+}
+
+fn peer_infos(peers: &[SocketAddr]) -> Vec<PeerInfo> {
+    peers.iter().map(|addr| PeerInfo { ip: addr.ip().to_string(), port: addr.port(), peer_id: None }).collect()
+}
+
+/// Receives datagrams: answers queries and hands answers to the waiting `query`.
+async fn receive_udp(socket: Arc<UdpSocket>, dht: Weak<Dht>) {
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors from unreachable nodes surface here on some platforms
+                crate::log_client!("[Dht] UDP receive error: {}", e);
+                continue;
+            }
+        };
+        let Some(dht) = dht.upgrade() else { break };
+        let message: DhtMessage = match serde_json::from_slice(&buf[..len]) {
+            Ok(message) => message,
+            Err(e) => {
+                crate::log_client!("[Dht] Ignoring malformed message from {}: {}", from, e);
+                continue;
+            }
+        };
+        if is_query(&message.body) {
+            let answer = dht.handle_query(message, from);
+            if let Ok(bytes) = serde_json::to_vec(&answer) {
+                let _ = socket.send_to(&bytes, from).await;
+            }
+        } else if let Transport::Udp { pending, .. } = &dht.transport {
+            // Only the node we asked can answer; anyone else is guessing transaction ids
+            let mut pending = pending.lock().unwrap();
+            match pending.get(&message.t) {
+                Some((asked, _)) if *asked == from => {
+                    if let Some((_, waiting)) = pending.remove(&message.t) {
+                        let _ = waiting.send(message);
+                    }
+                }
+                Some((asked, _)) => {
+                    crate::log_client!("[Dht] Ignoring answer to transaction {} from {} (asked {})", message.t, from, asked);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Accepts QUIC connections and answers one query per stream.
+async fn accept_quic(endpoint: Endpoint, dht: Weak<Dht>) {
+    while let Some(connecting) = endpoint.accept().await {
+        let dht = dht.clone();
+        tokio::spawn(async move {
+            let Ok(connection) = connecting.await else { return };
+            let from = connection.remote_address();
+            while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                let Some(dht) = dht.upgrade() else { return };
+                tokio::spawn(async move {
+                    let Ok(bytes) = recv.read_to_end(MAX_MESSAGE_SIZE).await else { return };
+                    let answer = match serde_json::from_slice::<DhtMessage>(&bytes) {
+                        Ok(query) if is_query(&query.body) => dht.handle_query(query, from),
+                        Ok(other) => DhtMessage { t: other.t, id: dht.id.to_hex(), body: dht_error(204, "Not a query") },
+                        Err(_) => DhtMessage { t: 0, id: dht.id.to_hex(), body: dht_error(203, "Malformed message") },
+                    };
+                    if let Ok(bytes) = serde_json::to_vec(&answer) {
+                        let _ = send.write_all(&bytes).await;
+                        let _ = send.finish().await;
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts `count` nodes on loopback, each bootstrapping from the first.
+    async fn start_network(count: usize, transport: DhtTransport, extra_bootstrap: &[SocketAddr]) -> Vec<Arc<Dht>> {
+        let loopback = SocketAddr::from(([127, 0, 0, 1], 0));
+        let first = Dht::start(DhtConfig { bind: loopback, transport, ..DhtConfig::default() }).await.unwrap();
+        let mut bootstrap: Vec<String> = extra_bootstrap.iter().map(SocketAddr::to_string).collect();
+        bootstrap.push(first.local_addr().to_string());
+
+        let mut nodes = vec![first];
+        for _ in 1..count {
+            let config = DhtConfig { bind: loopback, transport, bootstrap: bootstrap.clone(), node_id: None };
+            let node = Dht::start(config).await.unwrap();
+            node.bootstrap().await.unwrap();
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    async fn announce_is_found(count: usize, transport: DhtTransport, extra_bootstrap: &[SocketAddr]) {
+        let nodes = start_network(count, transport, extra_bootstrap).await;
+        for node in &nodes[1..] {
+            assert!(node.node_count() > 0, "node {} found no contacts", node.id());
+        }
+
+        let info_hash = NodeId::random().to_hex();
+        nodes[1].announce(&info_hash, 7777).await.unwrap();
+
+        let peers = nodes[count - 1].get_peers(&info_hash).await.unwrap();
+        assert!(
+            peers.iter().any(|p| p.ip == "127.0.0.1" && p.port == 7777),
+            "announced peer not found: {:?}", peers
+        );
+        assert!(nodes[count / 2].get_peers(&NodeId::random().to_hex()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn announce_is_found_over_udp() {
+        announce_is_found(12, DhtTransport::Udp, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn announce_is_found_over_quic() {
+        announce_is_found(12, DhtTransport::Quic, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_does_not_hold_up_other_queries() {
+        // Bound but silent: connecting to it hangs until the query times out
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        announce_is_found(4, DhtTransport::Quic, &[silent.local_addr().unwrap()]).await;
+    }
+
+    #[tokio::test]
+    async fn udp_answers_from_other_hosts_are_ignored() {
+        let asked = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = DhtConfig { bind: SocketAddr::from(([127, 0, 0, 1], 0)), ..DhtConfig::default() };
+        let node = Dht::start(config).await.unwrap();
+
+        let querying = Arc::clone(&node);
+        let asked_addr = asked.local_addr().unwrap();
+        let query = tokio::spawn(async move { querying.query(asked_addr, DhtBody::Ping).await });
+
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let (len, from) = asked.recv_from(&mut buf).await.unwrap();
+        let ping: DhtMessage = serde_json::from_slice(&buf[..len]).unwrap();
+        let answer = |id: NodeId| serde_json::to_vec(&DhtMessage { t: ping.t, id: id.to_hex(), body: DhtBody::Pong }).unwrap();
+
+        let spoofed_id = NodeId::random();
+        spoofer.send_to(&answer(spoofed_id), from).await.unwrap();
+        let real_id = NodeId::random();
+        asked.send_to(&answer(real_id), from).await.unwrap();
+
+        let response = query.await.unwrap().unwrap();
+        assert_eq!(response.id, real_id.to_hex());
+    }
+}
@@ -0,0 +1,143 @@
+//! # Errors
+//!
+//! The error type of the client (`client`, `quic_client`, `session`) and
+//! server (`quic_tracker`) modules.
+//!
+//! An `ErrorResponse` from the server arrives as `Error::Remote` with the
+//! server's code, so callers can tell a refused request (`FILE_NOT_FOUND`,
+//! `INVALID_RANGE`, ...) from a connection or decoding failure without
+//! parsing messages.
+
+use crate::messages::ErrorResponse;
+
+/// Everything that can go wrong talking to a server or serving clients.
+#[derive(Debug)]
+pub enum Error {
+    /// The QUIC connection couldn't be set up or failed mid-request;
+    /// `connection_lost` is set if the connection itself is gone (as
+    /// opposed to a single stream)
+    Transport { message: String, connection_lost: bool },
+    /// TLS or certificate setup failed
+    Tls(String),
+    /// A message couldn't be encoded or decoded, or broke the protocol
+    Protocol(String),
+    /// The server answered with an `ErrorResponse`
+    Remote { code: String, message: String },
+    /// Local file or socket I/O failed
+    Io(std::io::Error),
+    /// A torrent is malformed, or its download couldn't be completed
+    Torrent(String),
+    /// Something took longer than allowed
+    Timeout(String),
+    /// A `Session` call named an unknown torrent, or one in the wrong state
+    Session(String),
+}
+
+/// Result of the client and server functions.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn transport(message: impl Into<String>) -> Self {
+        Error::Transport { message: message.into(), connection_lost: false }
+    }
+
+    /// The server's error code, for `Error::Remote`.
+    pub fn remote_code(&self) -> Option<&str> {
+        match self {
+            Error::Remote { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// True if the connection the request went out on is gone, so a
+    /// retry on a new connection may succeed.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, Error::Transport { connection_lost: true, .. })
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport { message, .. } => write!(f, "Transport error: {}", message),
+            Error::Tls(message) => write!(f, "TLS error: {}", message),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Remote { code, message } => write!(f, "Server error {}: {}", code, message),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Torrent(message) => write!(f, "Torrent error: {}", message),
+            Error::Timeout(message) => write!(f, "Timed out: {}", message),
+            Error::Session(message) => write!(f, "Session error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorResponse> for Error {
+    fn from(e: ErrorResponse) -> Self {
+        Error::Remote { code: e.code.unwrap_or_else(|| "UNKNOWN".to_string()), message: e.error }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<std::net::AddrParseError> for Error {
+    fn from(e: std::net::AddrParseError) -> Self {
+        Error::transport(format!("Invalid address: {}", e))
+    }
+}
+
+impl From<quinn::ConnectError> for Error {
+    fn from(e: quinn::ConnectError) -> Self {
+        Error::transport(e.to_string())
+    }
+}
+
+impl From<quinn::ConnectionError> for Error {
+    fn from(e: quinn::ConnectionError) -> Self {
+        Error::Transport { message: e.to_string(), connection_lost: true }
+    }
+}
+
+impl From<quinn::WriteError> for Error {
+    fn from(e: quinn::WriteError) -> Self {
+        let connection_lost = matches!(e, quinn::WriteError::ConnectionLost(_));
+        Error::Transport { message: e.to_string(), connection_lost }
+    }
+}
+
+impl From<quinn::ReadError> for Error {
+    fn from(e: quinn::ReadError) -> Self {
+        let connection_lost = matches!(e, quinn::ReadError::ConnectionLost(_));
+        Error::Transport { message: e.to_string(), connection_lost }
+    }
+}
+
+impl From<crate::protocol::ProtocolError> for Error {
+    fn from(e: crate::protocol::ProtocolError) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
+
+impl From<crate::protocol::CodecError> for Error {
+    fn from(e: crate::protocol::CodecError) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
@@ -0,0 +1,61 @@
+//! # Server Events
+//!
+//! Changes the server pushes to subscribed clients: swarms gaining or
+//! losing peers, files appearing in or leaving the seed directory, and
+//! work-distribution nodes being registered.
+//!
+//! Whatever changes the server's state publishes a `ServerEvent` on the
+//! server's `EventBus`; each subscription stream holds a receiver and
+//! forwards the events its `SubscribeRequest` asked for. Publishing never
+//! waits for subscribers: each one has a queue of `EVENT_QUEUE_SIZE`
+//! events, and one that reads too slowly (its QUIC stream is flow
+//! controlled) loses the oldest and is told how many with
+//! `ServerEvent::Dropped`.
+
+use crate::messages::{ServerEvent, SubscribeRequest};
+use tokio::sync::broadcast;
+
+/// Events queued per subscriber before the oldest are dropped.
+pub const EVENT_QUEUE_SIZE: usize = 256;
+
+/// Fans server events out to every subscription.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        Self { sender }
+    }
+
+    /// Sends `event` to every current subscriber (none may be listening).
+    pub fn publish(&self, event: ServerEvent) {
+        let subscribers = self.sender.send(event).unwrap_or(0);
+        if subscribers > 0 {
+            crate::log_server!("[EVENTS] Event published to {} subscription(s)", subscribers);
+        }
+    }
+
+    /// A receiver for the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// True if `subscription` asked for `event`; `Dropped` always goes through.
+pub fn matches(event: &ServerEvent, subscription: &SubscribeRequest) -> bool {
+    match event {
+        ServerEvent::Swarm { info_hash, .. } => subscription.swarms.iter().any(|h| h == "*" || h == info_hash),
+        ServerEvent::SeedFile { .. } => subscription.seed_files,
+        ServerEvent::WorkNode { .. } => subscription.work_nodes,
+        ServerEvent::Dropped { .. } => true,
+    }
+}
@@ -0,0 +1,254 @@
+//! # Framed Streams
+//!
+//! By default a request stream carries exactly one message each way and is
+//! read to EOF. A framed stream instead starts with `FRAMED_STREAM_MARKER`
+//! and then carries any number of frames, each an unsigned LEB128 varint
+//! length followed by that many bytes of one encoded envelope:
+//!
+//! ```text
+//! 0xF0 | len | envelope | len | envelope | ...
+//! ```
+//!
+//! The marker can't begin a JSON document (it isn't even valid as the first
+//! byte of UTF-8 text), so the server tells the two modes apart from the
+//! first byte and older clients keep working unchanged. Servers announce
+//! support with `protocol::FEATURE_FRAMED_STREAMS` in their `HelloResponse`.
+//!
+//! On a framed stream the client may send several requests without waiting
+//! (they are answered in order), and the server may send event messages
+//! (such as `ai_token`) with the request's id before the final response.
+//!
+//! The peer wire (`peer_wire`) frames its messages the same way, without
+//! the marker.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// First byte of a framed stream.
+pub const FRAMED_STREAM_MARKER: u8 = 0xF0;
+
+/// Largest frame accepted.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Why `read_frame` refused a frame over its size limit; carried inside the
+/// `InvalidData` error it returns (see `is_too_large`).
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: u64,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Frame of {} bytes exceeds the limit of {} bytes", self.size, self.limit)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// True if `error` is `read_frame` refusing a frame over its size limit
+/// (the rest of the frame is still unread).
+pub fn is_too_large(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<FrameTooLarge>())
+}
+
+/// Most bytes a `u64` varint takes.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Largest last byte of a `MAX_VARINT_LEN` varint: it holds only the top
+/// bit of a `u64` (anything more would be shifted out of the value).
+const MAX_LAST_VARINT_BYTE: u8 = 0x01;
+
+/// Encodes `value` as an unsigned LEB128 varint.
+pub fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decodes an unsigned LEB128 varint from the start of `bytes`.
+///
+/// # Returns
+/// The value and the number of bytes it took; `None` if `bytes` ends first
+/// or the varint is longer than a `u64` allows
+pub fn decode_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().take(MAX_VARINT_LEN).enumerate() {
+        if i == MAX_VARINT_LEN - 1 && byte > MAX_LAST_VARINT_BYTE {
+            return None;
+        }
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Prefixes `payload` with its length.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + MAX_VARINT_LEN);
+    encode_varint(payload.len() as u64, &mut frame);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Writes one frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&encode_frame(payload)).await
+}
+
+/// Reads one frame.
+///
+/// # Arguments
+/// * `max_size` - Largest payload accepted; longer frames are an error
+///   (`is_too_large`)
+///
+/// # Returns
+/// The payload, or `None` if the stream ended cleanly between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_size: usize) -> std::io::Result<Option<Vec<u8>>> {
+    match read_frame_length(reader, max_size).await? {
+        Some(length) => {
+            let mut payload = vec![0u8; length];
+            reader.read_exact(&mut payload).await?;
+            Ok(Some(payload))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads the length that starts a frame, leaving its payload unread (for
+/// readers that time the payload separately).
+///
+/// # Returns
+/// The payload length, or `None` if the stream ended cleanly between frames
+pub async fn read_frame_length<R: AsyncRead + Unpin>(reader: &mut R, max_size: usize) -> std::io::Result<Option<usize>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut length = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte).await? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Stream ended inside a frame length"));
+        }
+        if i == MAX_VARINT_LEN - 1 && byte[0] > MAX_LAST_VARINT_BYTE {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame length overflows"));
+        }
+        length |= ((byte[0] & 0x7F) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            if length > max_size as u64 {
+                return Err(Error::new(ErrorKind::InvalidData, FrameTooLarge { size: length, limit: max_size }));
+            }
+            return Ok(Some(length as usize));
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Frame length varint too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_varint(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn varints_round_trip_at_byte_boundaries() {
+        let cases = [
+            (0, 1),
+            (1, 1),
+            (0x7F, 1),
+            (0x80, 2),
+            (0x3FFF, 2),
+            (0x4000, 3),
+            (u32::MAX as u64, 5),
+            (1 << 63, 10),
+            (u64::MAX, 10),
+        ];
+        for (value, len) in cases {
+            let bytes = varint(value);
+            assert_eq!(bytes.len(), len, "{:#x}", value);
+            assert_eq!(decode_varint(&bytes), Some((value, len)), "{:#x}", value);
+        }
+        assert_eq!(varint(300), vec![0xAC, 0x02]);
+        assert_eq!(*varint(u64::MAX).last().unwrap(), MAX_LAST_VARINT_BYTE);
+        // Bytes after the varint are left alone
+        assert_eq!(decode_varint(&[0xAC, 0x02, 0xFF]), Some((300, 2)));
+    }
+
+    #[test]
+    fn truncated_or_overlong_varints_are_refused() {
+        assert_eq!(decode_varint(&[]), None);
+        assert_eq!(decode_varint(&[0x80, 0x80]), None);
+
+        // A 10th byte above 0x01 would lose the value's high bits
+        let mut overflow = vec![0xFF; MAX_VARINT_LEN - 1];
+        overflow.push(0x02);
+        assert_eq!(decode_varint(&overflow), None);
+        let mut too_long = vec![0x80; MAX_VARINT_LEN];
+        too_long.push(0x00);
+        assert_eq!(decode_varint(&too_long), None);
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_until_clean_eof() {
+        let mut stream = Vec::new();
+        for payload in [&b"first"[..], b"", &[7u8; 300]] {
+            write_frame(&mut stream, payload).await.unwrap();
+        }
+        let mut reader = &stream[..];
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap(), Some(vec![7u8; 300]));
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn frames_up_to_max_size_are_read() {
+        let max_size = 200;
+        let frame = encode_frame(&[1u8; 200]);
+        assert_eq!(read_frame(&mut &frame[..], max_size).await.unwrap(), Some(vec![1u8; 200]));
+
+        let frame = encode_frame(&[1u8; 201]);
+        let error = read_frame(&mut &frame[..], max_size).await.unwrap_err();
+        assert!(is_too_large(&error), "{}", error);
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let too_large = error.get_ref().unwrap().downcast_ref::<FrameTooLarge>().unwrap();
+        assert_eq!((too_large.size, too_large.limit), (201, max_size));
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_frame_is_an_error() {
+        // Inside the length
+        let error = read_frame(&mut &[0x80u8][..], MAX_FRAME_SIZE).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        // Inside the payload
+        let frame = encode_frame(b"payload");
+        let error = read_frame(&mut &frame[..4], MAX_FRAME_SIZE).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn overflowing_frame_lengths_are_refused() {
+        // 2^64 + 5 would wrap to 5 and desync the stream
+        let mut length = vec![0x85];
+        length.extend([0x80; MAX_VARINT_LEN - 2]);
+        length.push(0x02);
+        length.extend(b"hello");
+        let error = read_frame(&mut &length[..], MAX_FRAME_SIZE).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(!is_too_large(&error));
+
+        // The largest length still decodes, and is then too large
+        let error = read_frame(&mut &varint(u64::MAX)[..], MAX_FRAME_SIZE).await.unwrap_err();
+        assert!(is_too_large(&error), "{}", error);
+    }
+}
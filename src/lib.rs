@@ -19,6 +19,16 @@ pub fn bencode_int(i: i64) -> Vec<u8> {
     format!("i{}e", i).into_bytes()
 }
 
+/// Encodes a list into bencode format.
+pub fn bencode_list(l: &[BencodeValue]) -> Vec<u8> {
+    let mut result = vec![b'l'];
+    for value in l {
+        result.extend_from_slice(&value.encode());
+    }
+    result.push(b'e');
+    result
+}
+
 /// Encodes a dictionary into bencode format.
 pub fn bencode_dict(d: &BTreeMap<Vec<u8>, BencodeValue>) -> Vec<u8> {
     let mut result = vec![b'd'];
@@ -30,11 +40,12 @@ pub fn bencode_dict(d: &BTreeMap<Vec<u8>, BencodeValue>) -> Vec<u8> {
     result
 }
 
-/// Represents a bencode value (string, integer, or dictionary).
+/// Represents a bencode value (string, integer, list, or dictionary).
 #[derive(Clone)]
 pub enum BencodeValue {
     String(Vec<u8>),
     Int(i64),
+    List(Vec<BencodeValue>),
     Dict(BTreeMap<Vec<u8>, BencodeValue>),
 }
 
@@ -43,6 +54,7 @@ impl BencodeValue {
         match self {
             BencodeValue::String(s) => bencode_string(s),
             BencodeValue::Int(i) => bencode_int(*i),
+            BencodeValue::List(l) => bencode_list(l),
             BencodeValue::Dict(d) => bencode_dict(d),
         }
     }
@@ -62,6 +74,22 @@ pub fn decode_bencode(data: &[u8]) -> Result<(BencodeValue, usize), Box<dyn std:
             let num = num_str.parse::<i64>()?;
             Ok((BencodeValue::Int(num), end + 1))
         }
+        b'l' => {
+            let mut list = Vec::new();
+            let mut pos = 1;
+            
+            while pos < data.len() && data[pos] != b'e' {
+                let (value, value_len) = decode_bencode(&data[pos..])?;
+                pos += value_len;
+                list.push(value);
+            }
+            
+            if pos >= data.len() {
+                return Err("Missing 'e' terminator for list".into());
+            }
+            
+            Ok((BencodeValue::List(list), pos + 1))
+        }
         b'd' => {
             let mut map = BTreeMap::new();
             let mut pos = 1;
@@ -112,10 +140,26 @@ pub mod quic_utils;
 pub mod quic_tracker;
 pub mod quic_client;
 pub mod messages;
+pub mod error;
+pub mod protocol;
+pub mod framing;
 pub mod client;
 pub mod console_client;
 pub mod ai_processor;
 pub mod work_distribution;
+pub mod events;
+pub mod router;
+pub mod seed;
+pub mod peer_wire;
+pub mod peer_state;
+pub mod piece_picker;
+pub mod choker;
+pub mod pex;
+pub mod dht;
+pub mod lsd;
+pub mod web_seed;
+pub mod budget;
+pub mod session;
 
 // Logging macros
 #[macro_export]
@@ -1,12 +1,34 @@
 //! # Logging System
 //!
 //! Provides comprehensive logging with timestamps and two-column format.
+//!
+//! Lines logged while a request is being sent or handled (inside
+//! `with_request_id`) are prefixed with `[req=<id>]`, so the client's and
+//! server's lines for one request can be matched up.
 
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 use chrono::Local;
 
+tokio::task_local! {
+    /// Id of the request the current task is sending or handling.
+    static REQUEST_ID: String;
+}
+
+/// Runs `future` with its log lines tagged with `request_id`.
+///
+/// Tasks spawned by `future` are not tagged; wrap them too if they log for
+/// the same request.
+pub async fn with_request_id<F: std::future::Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// The id of the request the current task is sending or handling, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
 /// Logger that writes to a file with formatted output.
 pub struct TorrentLogger {
     file: Arc<Mutex<std::fs::File>>,
@@ -27,6 +49,10 @@ impl TorrentLogger {
     fn write_log(&self, side: &str, message: &str, arrow: &str) -> Result<(), Box<dyn std::error::Error>> {
         let now = Local::now();
         let timestamp = now.format("%Y-%m-%d %H:%M:%S%.3f");
+        let message = match current_request_id() {
+            Some(id) if !message.is_empty() => format!("[req={}] {}", id, message),
+            _ => message.to_string(),
+        };
         
         // Format log line
         let log_line = format!("{:<25} | {:<50} {} {:<50}", 
@@ -89,6 +115,8 @@ pub fn init_logger(log_file: &str) -> Result<(), Box<dyn std::error::Error>> {
         .write(true)
         .truncate(false)
         .open(log_file)?;
+    // Same columns as `write_log`, with the arrow column left blank
+    #[allow(clippy::write_literal)]
     writeln!(file, "{:<25} | {:<50} {} {:<50}", "TIMESTAMP", "SERVER", "", "CLIENT")?;
     writeln!(file, "{}", "-".repeat(130))?;
     drop(file); // Close file before creating logger
@@ -0,0 +1,522 @@
+//! # Local Service Discovery (LSD)
+//!
+//! Finds peers on the same network without a tracker or the DHT, following
+//! BEP 14: every client periodically multicasts the info hashes it is
+//! downloading or seeding, with the port of its QUIC peer listener, to
+//! `239.192.152.143:6771`, and listens on that group for other clients'
+//! announcements.
+//!
+//! An announcement is an HTTP-style datagram:
+//!
+//! ```text
+//! BT-SEARCH * HTTP/1.1\r\n
+//! Host: 239.192.152.143:6771\r\n
+//! Port: 6881\r\n
+//! Infohash: 0123456789abcdef0123456789abcdef01234567\r\n
+//! cookie: 5f3a9c2e\r\n
+//! \r\n
+//! \r\n
+//! ```
+//!
+//! with one `Infohash` header per torrent (up to
+//! `MAX_INFO_HASHES_PER_ANNOUNCE`). The `cookie` is random per client and
+//! lets it ignore its own announcements. The peer's address is the
+//! datagram's source IP with the announced port.
+//!
+//! Each torrent is announced when it's added and then every
+//! `LSD_ANNOUNCE_INTERVAL`. An announcement from a client we haven't heard
+//! from yet is answered with one of ours (at most one per
+//! `MIN_LSD_ANNOUNCE_GAP`), so a newcomer doesn't wait a whole interval.
+//!
+//! `LsdConfig` picks the group and interface; enabling `loopback` delivers
+//! announcements to other clients on the same host, which is how several
+//! clients on one machine (or tests) find each other.
+//!
+//! Message formatting, parsing and the announce schedule are plain data
+//! driven by the caller's `Instant`s; only `LocalDiscovery` does I/O.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// The BEP 14 IPv4 multicast group.
+pub const LSD_MULTICAST_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// How often each torrent is announced again.
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Soonest a torrent is announced again to answer a new peer's announcement.
+pub const MIN_LSD_ANNOUNCE_GAP: Duration = Duration::from_secs(10);
+
+/// Most `Infohash` headers in one announcement, keeping it within one
+/// unfragmented datagram.
+pub const MAX_INFO_HASHES_PER_ANNOUNCE: usize = 20;
+
+/// Largest datagram read; longer ones are truncated and fail to parse.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Discovered peers waiting for the consumer before new ones are dropped.
+const DISCOVERED_QUEUE: usize = 256;
+
+/// Clients remembered as already heard from; the longest unheard are
+/// forgotten beyond this.
+const MAX_KNOWN_CLIENTS: usize = 1024;
+
+/// How long a client counts as heard from (it announces every
+/// `LSD_ANNOUNCE_INTERVAL`).
+const KNOWN_CLIENT_TTL: Duration = Duration::from_secs(2 * 5 * 60);
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+/// Errors crossing task boundaries must be `Send`.
+pub type LsdResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Local discovery settings.
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// Multicast group and port to announce to and listen on
+    pub group: SocketAddrV4,
+    /// Local interface to join the group on and send from
+    /// (`UNSPECIFIED` lets the OS choose)
+    pub interface: Ipv4Addr,
+    /// Deliver our announcements to other sockets on this host too
+    pub loopback: bool,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            group: LSD_MULTICAST_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            loopback: false,
+        }
+    }
+}
+
+/// A parsed announcement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    /// The announcing client's peer listener port
+    pub port: u16,
+    /// Lowercase hex info hashes
+    pub info_hashes: Vec<String>,
+    pub cookie: Option<String>,
+}
+
+/// Formats an announcement datagram.
+///
+/// # Arguments
+/// * `group` - The group it is sent to, for the `Host` header
+/// * `announce` - Port, info hashes and cookie to announce
+///
+/// # Returns
+/// The datagram text
+pub fn format_announce(group: SocketAddrV4, announce: &LsdAnnounce) -> String {
+    let mut message = format!("{}\r\nHost: {}\r\nPort: {}\r\n", REQUEST_LINE, group, announce.port);
+    for info_hash in &announce.info_hashes {
+        message.push_str(&format!("Infohash: {}\r\n", info_hash));
+    }
+    if let Some(cookie) = &announce.cookie {
+        message.push_str(&format!("cookie: {}\r\n", cookie));
+    }
+    message.push_str("\r\n\r\n");
+    message
+}
+
+/// Parses an announcement datagram.
+///
+/// Header names are case-insensitive and unknown headers are ignored.
+/// Info hashes that aren't 40 hex digits are skipped.
+///
+/// # Returns
+/// The announcement; `None` if this isn't a `BT-SEARCH` with a valid
+/// non-zero `Port` and at least one info hash
+pub fn parse_announce(datagram: &[u8]) -> Option<LsdAnnounce> {
+    let text = std::str::from_utf8(datagram).ok()?;
+    let mut lines = text.split("\r\n");
+    if lines.next()?.trim() != REQUEST_LINE {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse::<u16>().ok().filter(|&port| port != 0),
+            "infohash" if value.len() == 40 && value.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                let info_hash = value.to_ascii_lowercase();
+                if !info_hashes.contains(&info_hash) {
+                    info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if info_hashes.is_empty() {
+        return None;
+    }
+    Some(LsdAnnounce { port: port?, info_hashes, cookie })
+}
+
+/// When each torrent is next announced.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    interval: Duration,
+    /// Info hash to its next announce; `None` means as soon as possible
+    torrents: BTreeMap<String, Option<Instant>>,
+}
+
+impl AnnounceSchedule {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, torrents: BTreeMap::new() }
+    }
+
+    /// Adds a torrent, to be announced right away.
+    ///
+    /// # Returns
+    /// `true` if it wasn't scheduled yet
+    pub fn add(&mut self, info_hash: &str) -> bool {
+        if self.torrents.contains_key(info_hash) {
+            return false;
+        }
+        self.torrents.insert(info_hash.to_string(), None);
+        true
+    }
+
+    pub fn remove(&mut self, info_hash: &str) {
+        self.torrents.remove(info_hash);
+    }
+
+    pub fn contains(&self, info_hash: &str) -> bool {
+        self.torrents.contains_key(info_hash)
+    }
+
+    /// Takes the torrents due for an announcement and schedules their next one.
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let interval = self.interval;
+        self.torrents.iter_mut()
+            .filter(|(_, next)| next.is_none_or(|next| next <= now))
+            .map(|(info_hash, next)| {
+                *next = Some(now + interval);
+                info_hash.clone()
+            })
+            .collect()
+    }
+
+    /// Moves a torrent's next announcement forward, to answer a new peer;
+    /// never sooner than `MIN_LSD_ANNOUNCE_GAP` after its previous one.
+    ///
+    /// # Returns
+    /// `true` if the announcement was moved
+    pub fn announce_soon(&mut self, info_hash: &str, now: Instant) -> bool {
+        let interval = self.interval;
+        let Some(Some(next)) = self.torrents.get_mut(info_hash) else { return false };
+        let soon = next.checked_sub(interval)
+            .map_or(now, |last| (last + MIN_LSD_ANNOUNCE_GAP).max(now));
+        if soon >= *next {
+            return false;
+        }
+        *next = soon;
+        true
+    }
+
+    /// When the next torrent is due; `None` if there are none.
+    pub fn next_due(&self, now: Instant) -> Option<Instant> {
+        self.torrents.values()
+            .map(|next| next.unwrap_or(now))
+            .min()
+    }
+}
+
+/// A peer found through an announcement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalPeer {
+    pub info_hash: String,
+    /// Source IP of the announcement with its announced port
+    pub addr: SocketAddr,
+}
+
+/// Announces our torrents on the local network and reports peers announcing
+/// the same ones.
+pub struct LocalDiscovery {
+    config: LsdConfig,
+    socket: Arc<UdpSocket>,
+    /// Our peer listener port
+    port: u16,
+    cookie: String,
+    /// Cookies of the clients we have heard from, with when we last did
+    known: Mutex<HashMap<String, Instant>>,
+    schedule: Mutex<AnnounceSchedule>,
+    /// Wakes the announce task when a torrent is added
+    wake: Arc<Notify>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl LocalDiscovery {
+    /// Joins the multicast group and starts announcing and listening.
+    ///
+    /// # Arguments
+    /// * `config` - Group, interface and loopback settings
+    /// * `port` - The peer listener port to announce
+    ///
+    /// # Returns
+    /// The running discovery, and a channel of peers announcing torrents
+    /// added with `add_torrent`; it closes when the discovery is dropped
+    pub async fn start(config: LsdConfig, port: u16) -> LsdResult<(Arc<Self>, mpsc::Receiver<LocalPeer>)> {
+        let socket = Arc::new(open_socket(&config)?);
+        let cookie = format!("{:016x}", rand::random::<u64>());
+        let discovery = Arc::new(Self {
+            config,
+            socket,
+            port,
+            cookie,
+            known: Mutex::new(HashMap::new()),
+            schedule: Mutex::new(AnnounceSchedule::new(LSD_ANNOUNCE_INTERVAL)),
+            wake: Arc::new(Notify::new()),
+            tasks: Mutex::new(Vec::new()),
+        });
+
+        let (found, discovered) = mpsc::channel(DISCOVERED_QUEUE);
+        let weak = Arc::downgrade(&discovery);
+        let receiver = tokio::spawn(receive_announces(Arc::clone(&discovery.socket), weak.clone(), found));
+        let announcer = tokio::spawn(announce_loop(Arc::clone(&discovery.wake), weak));
+        discovery.tasks.lock().unwrap().extend([receiver, announcer]);
+
+        crate::log_client!("[LocalDiscovery::start] Listening on {} (interface {}, loopback {}), announcing port {}",
+            discovery.config.group, discovery.config.interface, discovery.config.loopback, port);
+        Ok((discovery, discovered))
+    }
+
+    pub fn config(&self) -> &LsdConfig {
+        &self.config
+    }
+
+    /// Starts announcing a torrent, right away and then every `LSD_ANNOUNCE_INTERVAL`.
+    pub fn add_torrent(&self, info_hash: &str) {
+        if self.schedule.lock().unwrap().add(&info_hash.to_ascii_lowercase()) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Stops announcing a torrent and reporting its peers.
+    pub fn remove_torrent(&self, info_hash: &str) {
+        self.schedule.lock().unwrap().remove(&info_hash.to_ascii_lowercase());
+    }
+
+    /// Sends announcements for the torrents that are due.
+    async fn announce_due(&self) {
+        let due = self.schedule.lock().unwrap().due(Instant::now());
+        for info_hashes in due.chunks(MAX_INFO_HASHES_PER_ANNOUNCE) {
+            let announce = LsdAnnounce {
+                port: self.port,
+                info_hashes: info_hashes.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            let message = format_announce(self.config.group, &announce);
+            match self.socket.send_to(message.as_bytes(), SocketAddr::V4(self.config.group)).await {
+                Ok(_) => crate::log_client!("[LocalDiscovery] Announced {} torrents to {}: {:?}",
+                    info_hashes.len(), self.config.group, info_hashes),
+                Err(e) => crate::log_client!("[LocalDiscovery] Announce to {} failed: {}", self.config.group, e),
+            }
+        }
+    }
+
+    /// The peers in an announcement we should report: not our own, and for
+    /// torrents we announce. A client we haven't heard from before gets an
+    /// early announcement of those torrents in return.
+    fn peers_from(&self, announce: LsdAnnounce, from: SocketAddr) -> Vec<LocalPeer> {
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return Vec::new();
+        }
+        // Without a cookie, tell clients apart by address
+        let client = announce.cookie.clone().unwrap_or_else(|| SocketAddr::new(from.ip(), announce.port).to_string());
+        let now = Instant::now();
+        let newcomer = self.heard_from(client, now);
+
+        let mut schedule = self.schedule.lock().unwrap();
+        let mut answer = false;
+        let mut peers = Vec::new();
+        for info_hash in announce.info_hashes {
+            if !schedule.contains(&info_hash) {
+                continue;
+            }
+            answer |= newcomer && schedule.announce_soon(&info_hash, now);
+            peers.push(LocalPeer { info_hash, addr: SocketAddr::new(from.ip(), announce.port) });
+        }
+        if answer {
+            self.wake.notify_one();
+        }
+        peers
+    }
+
+    /// Records an announcement from `client`.
+    ///
+    /// # Returns
+    /// `true` if we hadn't heard from it within `KNOWN_CLIENT_TTL`
+    fn heard_from(&self, client: String, now: Instant) -> bool {
+        let mut known = self.known.lock().unwrap();
+        let newcomer = known.insert(client, now)
+            .is_none_or(|seen| now.duration_since(seen) > KNOWN_CLIENT_TTL);
+        if known.len() > MAX_KNOWN_CLIENTS {
+            known.retain(|_, seen| now.duration_since(*seen) <= KNOWN_CLIENT_TTL);
+            while known.len() > MAX_KNOWN_CLIENTS {
+                let Some(oldest) = known.iter().min_by_key(|(_, seen)| **seen).map(|(client, _)| client.clone()) else { break };
+                known.remove(&oldest);
+            }
+        }
+        newcomer
+    }
+}
+
+/// Binds a socket to the group's port and joins the group.
+///
+/// `SO_REUSEADDR` lets several clients on one host listen on the same port.
+fn open_socket(config: &LsdConfig) -> LsdResult<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.group.port())).into())?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    if !config.interface.is_unspecified() {
+        socket.set_multicast_if_v4(&config.interface)?;
+    }
+    socket.set_multicast_loop_v4(config.loopback)?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Announces torrents as they become due, until the discovery is dropped.
+async fn announce_loop(wake: Arc<Notify>, discovery: Weak<LocalDiscovery>) {
+    loop {
+        let next = {
+            let Some(discovery) = discovery.upgrade() else { break };
+            discovery.announce_due().await;
+            let now = Instant::now();
+            let next = discovery.schedule.lock().unwrap().next_due(now);
+            next.unwrap_or(now + LSD_ANNOUNCE_INTERVAL)
+        };
+        tokio::select! {
+            _ = tokio::time::sleep_until(next.into()) => {}
+            _ = wake.notified() => {}
+        }
+    }
+}
+
+/// Reads announcements and forwards the peers they report.
+async fn receive_announces(socket: Arc<UdpSocket>, discovery: Weak<LocalDiscovery>, found: mpsc::Sender<LocalPeer>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                crate::log_client!("[LocalDiscovery] Receive error: {}", e);
+                continue;
+            }
+        };
+        let Some(discovery) = discovery.upgrade() else { break };
+        let Some(announce) = parse_announce(&buf[..len]) else {
+            crate::log_client!("[LocalDiscovery] Ignoring malformed announcement from {}", from);
+            continue;
+        };
+        if !matches!(from.ip(), IpAddr::V4(_)) {
+            continue;
+        }
+        for peer in discovery.peers_from(announce, from) {
+            crate::log_client!("[LocalDiscovery] Peer {} for {}", peer.addr, peer.info_hash);
+            // A full queue means the consumer is behind; the peer announces again later
+            if let Err(mpsc::error::TrySendError::Closed(_)) = found.try_send(peer) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A group of our own on a random port, so tests don't hear real clients
+    /// (or each other).
+    fn loopback_config() -> LsdConfig {
+        let port = 20000 + rand::random::<u16>() % 40000;
+        LsdConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), port),
+            interface: Ipv4Addr::LOCALHOST,
+            loopback: true,
+        }
+    }
+
+    async fn next_peer(discovered: &mut mpsc::Receiver<LocalPeer>) -> LocalPeer {
+        tokio::time::timeout(Duration::from_secs(5), discovered.recv())
+            .await
+            .expect("no announcement within 5 s")
+            .expect("discovery stopped")
+    }
+
+    #[test]
+    fn announcements_round_trip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec!["0123456789abcdef0123456789abcdef01234567".to_string()],
+            cookie: Some("5f3a9c2e".to_string()),
+        };
+        let datagram = format_announce(LSD_MULTICAST_GROUP, &announce);
+        assert_eq!(parse_announce(datagram.as_bytes()), Some(announce));
+        assert_eq!(parse_announce(b"NOTIFY * HTTP/1.1\r\nPort: 1\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn clients_on_one_host_find_each_other() {
+        let config = loopback_config();
+        let info_hash = "0123456789abcdef0123456789abcdef01234567";
+        let other_hash = "89abcdef0123456789abcdef0123456789abcdef";
+
+        let (first, mut first_found) = LocalDiscovery::start(config.clone(), 7001).await.unwrap();
+        let (second, mut second_found) = LocalDiscovery::start(config, 7002).await.unwrap();
+        // Both are listening before either announce task runs
+        first.add_torrent(info_hash);
+        second.add_torrent(other_hash);
+        second.add_torrent(info_hash);
+
+        let peer = next_peer(&mut first_found).await;
+        assert_eq!(peer, LocalPeer { info_hash: info_hash.to_string(), addr: SocketAddr::from(([127, 0, 0, 1], 7002)) });
+        let peer = next_peer(&mut second_found).await;
+        assert_eq!(peer, LocalPeer { info_hash: info_hash.to_string(), addr: SocketAddr::from(([127, 0, 0, 1], 7001)) });
+
+        // Neither reports its own announcements or torrents it doesn't have
+        assert!(first_found.try_recv().is_err());
+        assert!(second_found.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn known_clients_are_bounded() {
+        let (discovery, _found) = LocalDiscovery::start(loopback_config(), 7003).await.unwrap();
+        let start = Instant::now();
+        for n in 0..MAX_KNOWN_CLIENTS + 100 {
+            assert!(discovery.heard_from(format!("cookie-{}", n), start + Duration::from_millis(n as u64)));
+        }
+        assert_eq!(discovery.known.lock().unwrap().len(), MAX_KNOWN_CLIENTS);
+        // The longest unheard were forgotten, the latest are still known
+        assert!(discovery.heard_from("cookie-0".to_string(), start + Duration::from_secs(2)));
+        assert!(!discovery.heard_from(format!("cookie-{}", MAX_KNOWN_CLIENTS + 99), start + Duration::from_secs(2)));
+        // and anyone unheard for a TTL counts as new again
+        assert!(discovery.heard_from("cookie-0".to_string(), start + KNOWN_CLIENT_TTL * 2));
+    }
+}
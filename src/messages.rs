@@ -13,6 +13,10 @@ pub struct AiRequest {
     pub context: Option<Vec<MessageContext>>,
     /// Optional parameters for AI processing
     pub parameters: Option<AiParameters>,
+    /// Send the answer as `AiToken` events before the final `AiResponse`
+    /// (only on framed streams; ignored otherwise)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
 }
 
 /// Context for a message in the conversation.
@@ -44,6 +48,14 @@ pub struct AiResponse {
     pub metadata: Option<ResponseMetadata>,
 }
 
+/// One piece of a streamed AI answer; the pieces concatenated in `index`
+/// order make up `AiResponse::answer`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AiToken {
+    pub index: usize,
+    pub token: String,
+}
+
 /// Metadata about the AI response.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResponseMetadata {
@@ -93,20 +105,391 @@ pub struct TrackerAnnounceResponse {
 pub struct PeerInfo {
     pub ip: String,
     pub port: u16,
+    /// Peer id the peer announced with, checked against its handshake
+    pub peer_id: Option<String>,
 }
 
 /// File request message.
+///
+/// `offset` and `length` select a byte range of the file. Omitting both
+/// requests the whole file; omitting only `length` reads to the end.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileRequest {
     pub file: String,
+    /// Byte offset to start reading from (default 0)
+    pub offset: Option<u64>,
+    /// Maximum number of bytes to return (default: rest of the file)
+    pub length: Option<u64>,
+    /// Serve the seed file registered for this info hash instead of `file`
+    pub info_hash: Option<String>,
+}
+
+impl FileRequest {
+    /// Creates a request for the whole file.
+    pub fn whole(file: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            offset: None,
+            length: None,
+            info_hash: None,
+        }
+    }
+
+    /// Creates a request for `length` bytes (or the rest of the file) starting at `offset`.
+    pub fn range(file: &str, offset: u64, length: Option<u64>) -> Self {
+        Self {
+            file: file.to_string(),
+            offset: Some(offset),
+            length,
+            info_hash: None,
+        }
+    }
+}
+
+/// Peer wire message, exchanged between clients on a long-lived QUIC stream.
+///
+/// Each message travels as a MessagePack frame (see `peer_wire`).
+/// A connection starts with both sides sending `Handshake`, then `Bitfield`.
+/// Both sides start out choking and not interested; `peer_state` holds the
+/// rules for what may be sent when.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerMessage {
+    /// Identifies the torrent and the peer; must be the first message
+    Handshake {
+        protocol: String,
+        info_hash: String,
+        peer_id: String,
+    },
+    /// Pieces the sender has, packed MSB-first (bit 7 of byte 0 is piece 0);
+    /// only valid as the first message after the handshake
+    Bitfield {
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+    },
+    /// The sender has verified piece `index`
+    Have { index: u32 },
+    /// The sender wants pieces the receiver has
+    Interested,
+    /// The sender no longer wants anything from the receiver
+    NotInterested,
+    /// The sender won't serve requests; queued requests are discarded
+    Choke,
+    /// The sender will serve requests
+    Unchoke,
+    /// Asks for `length` bytes starting at `begin` within piece `index`
+    Request { index: u32, begin: u32, length: u32 },
+    /// Withdraws an earlier `Request`
+    Cancel { index: u32, begin: u32, length: u32 },
+    /// Block of piece data answering a `Request`
+    Piece {
+        index: u32,
+        begin: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// The sender won't serve the requested block
+    Reject { index: u32, begin: u32, length: u32 },
+    /// Peer exchange: peers the sender connected to or lost since its last
+    /// `Pex` on this stream (see `pex` for the limits)
+    Pex { added: Vec<PexPeer>, dropped: Vec<PexPeer> },
+}
+
+/// A peer listed in a `PeerMessage::Pex`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexPeer {
+    pub ip: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+    /// `pex::PEX_FLAG_*` bits
+    #[serde(default)]
+    pub flags: u8,
 }
 
 /// File response message.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileResponse {
+    /// File contents (a number array in JSON, a byte string in binary codecs)
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
     pub filename: String,
+    /// Number of bytes in `data`
     pub size: usize,
+    /// Offset of the first byte of `data` within the file
+    pub offset: Option<u64>,
+    /// Total size of the file on the server
+    pub total_size: Option<u64>,
 }
 
 
+
+/// Seed directory listing request.
+///
+/// Returns the files the server can serve, optionally filtered by a glob
+/// `pattern` (`*` and `?`) and paginated with `offset`/`limit`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListFilesRequest {
+    /// Marks the message as a listing request; must be `true`
+    pub list_files: bool,
+    /// Optional glob pattern applied to file names (e.g. `*.txt`)
+    pub pattern: Option<String>,
+    /// Index of the first entry to return (default 0)
+    pub offset: Option<usize>,
+    /// Maximum number of entries to return (server default and cap apply)
+    pub limit: Option<usize>,
+}
+
+/// A single file in the server's seed directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedFileInfo {
+    /// File name, usable as `FileRequest::file`
+    pub name: String,
+    /// File size in bytes
+    pub size: u64,
+    /// Last modification time (seconds since the Unix epoch)
+    pub mtime: u64,
+    /// Hex-encoded SHA-256 of the file contents
+    pub sha256: String,
+    /// Name of the matching `.torrent` file, if present
+    pub torrent: Option<String>,
+    /// Info hash from the matching `.torrent` file, if it could be parsed
+    pub info_hash: Option<String>,
+}
+
+/// Seed directory listing response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListFilesResponse {
+    pub files: Vec<SeedFileInfo>,
+    /// Number of files matching the pattern across all pages
+    pub total: usize,
+    /// Index of the first entry in `files`
+    pub offset: usize,
+    /// Offset of the next page, or `None` if this is the last page
+    pub next_offset: Option<usize>,
+}
+
+/// Publish (upload) request carrying one chunk of a file.
+///
+/// A file is uploaded as a sequence of chunks with increasing `offset`.
+/// The server appends each chunk to a staging file and, once `size` bytes
+/// have arrived, verifies `sha256` and moves the file into the seed directory.
+/// Re-sending a chunk at an earlier offset truncates the staged data there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishRequest {
+    /// Destination path relative to the seed directory
+    pub publish: String,
+    /// Shared secret authorizing the upload
+    pub token: String,
+    /// Hex-encoded SHA-256 of the complete file
+    pub sha256: String,
+    /// Total size of the file in bytes
+    pub size: u64,
+    /// Offset of `data` within the file
+    pub offset: u64,
+    /// Chunk contents (a number array in JSON, a byte string in binary codecs)
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    /// Generate a `.torrent` for the file and register it with the tracker
+    pub create_torrent: Option<bool>,
+}
+
+/// Publish response, sent after every chunk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishResponse {
+    /// Destination path relative to the seed directory
+    pub path: String,
+    /// Number of bytes staged so far
+    pub received: u64,
+    /// True once the file has been verified and moved into the seed directory
+    pub complete: bool,
+    /// Info hash of the generated torrent, if one was requested
+    pub info_hash: Option<String>,
+}
+
+/// Handshake a client sends as its first request on a new connection.
+///
+/// Lists are of names rather than enums so that a peer running a newer
+/// version can announce types and features this one doesn't know.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    /// Newest protocol version the sender speaks
+    pub version: u32,
+    /// Oldest protocol version the sender still speaks
+    pub min_version: u32,
+    /// Envelope `type`s the sender can send or handle
+    #[serde(default)]
+    pub message_types: Vec<String>,
+    /// Body encodings the sender supports, preferred first
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// Optional features the sender offers (`protocol::FEATURE_*`)
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Implementation name and version, for logs
+    pub agent: Option<String>,
+}
+
+/// Server's answer to `Hello`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloResponse {
+    /// Version used on this connection: the newest both sides speak
+    pub version: u32,
+    /// Oldest protocol version the server speaks
+    pub min_version: u32,
+    /// Newest protocol version the server speaks
+    pub max_version: u32,
+    /// Request `type`s the server handles
+    #[serde(default)]
+    pub message_types: Vec<String>,
+    /// Body encodings the server supports, preferred first
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// Codec picked for the rest of this connection (JSON if absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Optional features enabled on the server (`protocol::FEATURE_*`)
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Implementation name and version, for logs
+    pub agent: Option<String>,
+}
+
+/// Asks the server to push events on this (framed) stream; see `events`.
+///
+/// The server answers with a `SubscribeResponse` and then keeps sending
+/// `ServerEvent`s with the request's id until the client stops reading.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    /// Info hashes to receive swarm changes for (`"*"` for every swarm)
+    #[serde(default)]
+    pub swarms: Vec<String>,
+    /// Receive files published to, added to or removed from the seed directory
+    #[serde(default)]
+    pub seed_files: bool,
+    /// Receive work-distribution node changes
+    #[serde(default)]
+    pub work_nodes: bool,
+}
+
+/// Server's acknowledgement of a `SubscribeRequest`: what it will send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeResponse {
+    pub swarms: Vec<String>,
+    pub seed_files: bool,
+    /// False if the server has work distribution disabled
+    pub work_nodes: bool,
+    /// Events the server holds for a subscriber that reads slowly before it
+    /// drops the oldest (reported with `ServerEvent::Dropped`)
+    pub queue_size: usize,
+}
+
+/// How a swarm changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwarmChange {
+    /// A peer announced for the first time
+    Joined,
+    /// A known peer announced again
+    Updated,
+    /// A peer announced `stopped`
+    Left,
+    /// The swarm was removed (its seed file went away)
+    Retired,
+}
+
+/// How a file in the seed directory changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedFileChange {
+    /// Uploaded with `PublishRequest`
+    Published,
+    /// Found by a seed directory scan and now seeded by the server
+    Added,
+    /// Modified since the last scan, and seeded under a new info hash
+    Changed,
+    /// Gone from the seed directory
+    Removed,
+}
+
+/// An event pushed to a subscriber.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// The swarm for `info_hash` changed; `complete`/`incomplete` count its
+    /// peers afterwards
+    Swarm {
+        info_hash: String,
+        change: SwarmChange,
+        /// The peer that joined, updated or left (`None` when retired)
+        peer: Option<PeerInfo>,
+        complete: u64,
+        incomplete: u64,
+    },
+    /// A file in the seed directory changed
+    SeedFile {
+        name: String,
+        change: SeedFileChange,
+        size: Option<u64>,
+        /// Swarm the server seeds the file in, if it does
+        info_hash: Option<String>,
+    },
+    /// A work-distribution node was registered, or registered again with
+    /// new details
+    WorkNode {
+        node_id: String,
+        ip: String,
+        port: u16,
+        capabilities: Vec<String>,
+        weight: f64,
+    },
+    /// Events were lost: `missed` of them because the subscriber read too
+    /// slowly, or an unknown number (`None`) while the client resubscribed
+    /// after losing its connection
+    Dropped { missed: Option<u64> },
+}
+
+/// DHT message, one per UDP datagram (or QUIC stream); see `dht`.
+///
+/// Queries and responses are matched by the transaction id `t`; `id` is the
+/// sender's node id (40 hex digits).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DhtMessage {
+    pub t: u64,
+    pub id: String,
+    pub body: DhtBody,
+}
+
+/// Kademlia queries and their responses (BEP 5 semantics).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DhtBody {
+    /// Is the node alive?
+    Ping,
+    /// The `K` nodes closest to `target` that the receiver knows
+    FindNode { target: String },
+    /// Peers for `info_hash`, or the closest nodes if the receiver has none
+    GetPeers { info_hash: String },
+    /// Stores the sender as a peer on `port`; `token` comes from an earlier `get_peers` answer
+    AnnouncePeer { info_hash: String, port: u16, token: String },
+    /// Answers `ping` and `announce_peer`
+    Pong,
+    /// Answers `find_node`
+    Nodes { nodes: Vec<DhtNodeInfo> },
+    /// Answers `get_peers`; `token` authorizes a later `announce_peer`
+    Peers {
+        token: String,
+        peers: Vec<PeerInfo>,
+        nodes: Vec<DhtNodeInfo>,
+    },
+    /// The query was malformed or refused
+    Error { code: u16, message: String },
+}
+
+/// Contact information for a DHT node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtNodeInfo {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+}
@@ -0,0 +1,491 @@
+//! # Peer Connection State
+//!
+//! The BitTorrent per-connection state machine, kept free of any I/O.
+//!
+//! `PeerConnectionState` tracks the four choke/interest flags, the pieces the
+//! remote peer has, the blocks we have requested from it and the blocks it has
+//! requested from us. Incoming messages go through `receive`, which validates
+//! them and returns a `PeerEvent` for the driver to act on; outgoing messages
+//! are built by methods such as `request` or `unchoke`, which update the state
+//! and return the `PeerMessage` to send (or `None` if nothing changed).
+//! `peer_wire` drives one of these per stream.
+
+use crate::messages::{PeerMessage, PexPeer};
+use crate::peer_wire::{pack_bitfield, unpack_bitfield};
+use std::collections::VecDeque;
+
+/// Requests a peer may have queued with us; later ones are ignored until
+/// the queue drains.
+pub const MAX_PEER_REQUESTS: usize = 250;
+
+/// A block within a piece: `length` bytes starting at `begin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self { index, begin, length }
+    }
+}
+
+/// What an incoming message means for the driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer sent its bitfield; `peer_pieces()` is now populated
+    Bitfield,
+    /// The peer verified a new piece
+    Have(u32),
+    /// The peer wants pieces from us
+    Interested,
+    /// The peer no longer wants pieces from us
+    NotInterested,
+    /// The peer choked us; our outstanding requests to it were dropped
+    Choked(Vec<BlockRequest>),
+    /// The peer unchoked us; we may send requests
+    Unchoked,
+    /// The peer queued a request (see `next_peer_request`)
+    Request(BlockRequest),
+    /// The peer asked for a block while we choke it; the request was discarded
+    RequestIgnored(BlockRequest),
+    /// The peer asked for a block with `MAX_PEER_REQUESTS` already queued;
+    /// the request was discarded
+    RequestOverflow(BlockRequest),
+    /// The peer withdrew a queued request
+    Cancel(BlockRequest),
+    /// A block we requested arrived
+    Block { block: BlockRequest, data: Vec<u8> },
+    /// A block arrived that we no longer (or never) requested, e.g. after a cancel
+    Unsolicited(BlockRequest),
+    /// The peer won't serve a block we requested; it no longer counts as having the piece
+    Rejected(BlockRequest),
+    /// The peer sent a peer exchange update (limits are checked by `pex::PexState`)
+    Pex { added: Vec<PexPeer>, dropped: Vec<PexPeer> },
+}
+
+/// A message that breaks the protocol; the connection should be dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerProtocolError {
+    /// Handshakes are only valid as the first message on a stream
+    UnexpectedHandshake,
+    /// A bitfield arrived after other messages, or twice
+    UnexpectedBitfield,
+    /// The bitfield has the wrong length or sets bits past the last piece
+    InvalidBitfield,
+    /// A piece index beyond the torrent
+    InvalidPieceIndex(u32),
+    /// We tried to request while the peer chokes us
+    RequestWhileChoked,
+    /// We tried to request a piece the peer doesn't have
+    PeerLacksPiece(u32),
+    /// We tried to request a block that is already outstanding
+    DuplicateRequest(BlockRequest),
+}
+
+impl std::fmt::Display for PeerProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerProtocolError::UnexpectedHandshake => write!(f, "Handshake received after the connection was established"),
+            PeerProtocolError::UnexpectedBitfield => write!(f, "Bitfield is only allowed as the first message"),
+            PeerProtocolError::InvalidBitfield => write!(f, "Bitfield does not match the torrent's piece count"),
+            PeerProtocolError::InvalidPieceIndex(index) => write!(f, "Invalid piece index: {}", index),
+            PeerProtocolError::RequestWhileChoked => write!(f, "Cannot request while choked by the peer"),
+            PeerProtocolError::PeerLacksPiece(index) => write!(f, "Peer does not have piece {}", index),
+            PeerProtocolError::DuplicateRequest(block) => write!(f, "Block already requested: {:?}", block),
+        }
+    }
+}
+
+impl std::error::Error for PeerProtocolError {}
+
+/// Protocol state of one peer connection.
+///
+/// Both sides start choked and not interested, as in BitTorrent.
+#[derive(Debug, Clone)]
+pub struct PeerConnectionState {
+    piece_count: usize,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    peer_pieces: Vec<bool>,
+    messages_received: u64,
+    bitfield_received: bool,
+    pending_requests: Vec<BlockRequest>,
+    peer_requests: VecDeque<BlockRequest>,
+}
+
+impl PeerConnectionState {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            piece_count,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_pieces: vec![false; piece_count],
+            messages_received: 0,
+            bitfield_received: false,
+            pending_requests: Vec::new(),
+            peer_requests: VecDeque::new(),
+        }
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// Pieces the peer has announced, by index.
+    pub fn peer_pieces(&self) -> &[bool] {
+        &self.peer_pieces
+    }
+
+    pub fn peer_has(&self, index: usize) -> bool {
+        self.peer_pieces.get(index).copied().unwrap_or(false)
+    }
+
+    /// True once the peer has every piece.
+    pub fn peer_is_seed(&self) -> bool {
+        self.peer_pieces.iter().all(|&has| has)
+    }
+
+    /// Blocks we requested that haven't been answered.
+    pub fn pending_requests(&self) -> &[BlockRequest] {
+        &self.pending_requests
+    }
+
+    /// Number of requests from the peer waiting to be served.
+    pub fn queued_peer_requests(&self) -> usize {
+        self.peer_requests.len()
+    }
+
+    /// Builds the bitfield message announcing our pieces.
+    pub fn bitfield(&self, our_pieces: &[bool]) -> PeerMessage {
+        PeerMessage::Bitfield { pieces: pack_bitfield(our_pieces) }
+    }
+
+    /// Builds the `have` message for a piece we just verified.
+    pub fn have(&self, index: u32) -> PeerMessage {
+        PeerMessage::Have { index }
+    }
+
+    pub fn interested(&mut self) -> Option<PeerMessage> {
+        if self.am_interested {
+            return None;
+        }
+        self.am_interested = true;
+        Some(PeerMessage::Interested)
+    }
+
+    pub fn not_interested(&mut self) -> Option<PeerMessage> {
+        if !self.am_interested {
+            return None;
+        }
+        self.am_interested = false;
+        Some(PeerMessage::NotInterested)
+    }
+
+    /// Becomes interested iff the peer has a piece missing from `our_pieces`.
+    pub fn update_interest(&mut self, our_pieces: &[bool]) -> Option<PeerMessage> {
+        let wanted = self.peer_pieces.iter()
+            .enumerate()
+            .any(|(index, &has)| has && !our_pieces.get(index).copied().unwrap_or(false));
+        if wanted {
+            self.interested()
+        } else {
+            self.not_interested()
+        }
+    }
+
+    /// Chokes the peer, discarding the requests it has queued with us.
+    pub fn choke(&mut self) -> Option<PeerMessage> {
+        if self.am_choking {
+            return None;
+        }
+        self.am_choking = true;
+        self.peer_requests.clear();
+        Some(PeerMessage::Choke)
+    }
+
+    pub fn unchoke(&mut self) -> Option<PeerMessage> {
+        if !self.am_choking {
+            return None;
+        }
+        self.am_choking = false;
+        Some(PeerMessage::Unchoke)
+    }
+
+    /// Requests a block from the peer.
+    pub fn request(&mut self, block: BlockRequest) -> Result<PeerMessage, PeerProtocolError> {
+        if block.index as usize >= self.piece_count {
+            return Err(PeerProtocolError::InvalidPieceIndex(block.index));
+        }
+        if self.peer_choking {
+            return Err(PeerProtocolError::RequestWhileChoked);
+        }
+        if !self.peer_has(block.index as usize) {
+            return Err(PeerProtocolError::PeerLacksPiece(block.index));
+        }
+        if self.pending_requests.contains(&block) {
+            return Err(PeerProtocolError::DuplicateRequest(block));
+        }
+        self.pending_requests.push(block);
+        Ok(PeerMessage::Request { index: block.index, begin: block.begin, length: block.length })
+    }
+
+    /// Withdraws an outstanding request; `None` if it wasn't outstanding.
+    pub fn cancel(&mut self, block: BlockRequest) -> Option<PeerMessage> {
+        let position = self.pending_requests.iter().position(|b| *b == block)?;
+        self.pending_requests.remove(position);
+        Some(PeerMessage::Cancel { index: block.index, begin: block.begin, length: block.length })
+    }
+
+    /// Takes the oldest request the peer queued with us, if we aren't choking it.
+    pub fn next_peer_request(&mut self) -> Option<BlockRequest> {
+        if self.am_choking {
+            return None;
+        }
+        self.peer_requests.pop_front()
+    }
+
+    /// Applies an incoming message.
+    pub fn receive(&mut self, message: PeerMessage) -> Result<PeerEvent, PeerProtocolError> {
+        let first = self.messages_received == 0;
+        self.messages_received += 1;
+
+        match message {
+            PeerMessage::Handshake { .. } => Err(PeerProtocolError::UnexpectedHandshake),
+            PeerMessage::Bitfield { pieces } => {
+                if !first || self.bitfield_received {
+                    return Err(PeerProtocolError::UnexpectedBitfield);
+                }
+                if pieces.len() != self.piece_count.div_ceil(8) || pack_bitfield(&unpack_bitfield(&pieces, self.piece_count)) != pieces {
+                    return Err(PeerProtocolError::InvalidBitfield);
+                }
+                self.peer_pieces = unpack_bitfield(&pieces, self.piece_count);
+                self.bitfield_received = true;
+                Ok(PeerEvent::Bitfield)
+            }
+            PeerMessage::Have { index } => {
+                self.check_index(index)?;
+                self.peer_pieces[index as usize] = true;
+                Ok(PeerEvent::Have(index))
+            }
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                Ok(PeerEvent::Interested)
+            }
+            PeerMessage::NotInterested => {
+                self.peer_interested = false;
+                Ok(PeerEvent::NotInterested)
+            }
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+                Ok(PeerEvent::Choked(std::mem::take(&mut self.pending_requests)))
+            }
+            PeerMessage::Unchoke => {
+                self.peer_choking = false;
+                Ok(PeerEvent::Unchoked)
+            }
+            PeerMessage::Request { index, begin, length } => {
+                self.check_index(index)?;
+                let block = BlockRequest::new(index, begin, length);
+                if self.am_choking {
+                    return Ok(PeerEvent::RequestIgnored(block));
+                }
+                if !self.peer_requests.contains(&block) {
+                    if self.peer_requests.len() >= MAX_PEER_REQUESTS {
+                        return Ok(PeerEvent::RequestOverflow(block));
+                    }
+                    self.peer_requests.push_back(block);
+                }
+                Ok(PeerEvent::Request(block))
+            }
+            PeerMessage::Cancel { index, begin, length } => {
+                self.check_index(index)?;
+                let block = BlockRequest::new(index, begin, length);
+                self.peer_requests.retain(|b| *b != block);
+                Ok(PeerEvent::Cancel(block))
+            }
+            PeerMessage::Piece { index, begin, data } => {
+                self.check_index(index)?;
+                let block = BlockRequest::new(index, begin, data.len() as u32);
+                match self.pending_requests.iter().position(|b| *b == block) {
+                    Some(position) => {
+                        self.pending_requests.remove(position);
+                        Ok(PeerEvent::Block { block, data })
+                    }
+                    None => Ok(PeerEvent::Unsolicited(block)),
+                }
+            }
+            PeerMessage::Reject { index, begin, length } => {
+                self.check_index(index)?;
+                let block = BlockRequest::new(index, begin, length);
+                self.pending_requests.retain(|b| *b != block);
+                self.peer_pieces[index as usize] = false;
+                Ok(PeerEvent::Rejected(block))
+            }
+            PeerMessage::Pex { added, dropped } => Ok(PeerEvent::Pex { added, dropped }),
+        }
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), PeerProtocolError> {
+        if (index as usize) < self.piece_count {
+            Ok(())
+        } else {
+            Err(PeerProtocolError::InvalidPieceIndex(index))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection to a peer that has every one of `piece_count` pieces.
+    fn connected_to_seed(piece_count: usize) -> PeerConnectionState {
+        let mut state = PeerConnectionState::new(piece_count);
+        let pieces = pack_bitfield(&vec![true; piece_count]);
+        assert_eq!(state.receive(PeerMessage::Bitfield { pieces }), Ok(PeerEvent::Bitfield));
+        state
+    }
+
+    fn request_message(block: BlockRequest) -> PeerMessage {
+        PeerMessage::Request { index: block.index, begin: block.begin, length: block.length }
+    }
+
+    #[test]
+    fn starts_choked_and_not_interested() {
+        let state = PeerConnectionState::new(4);
+        assert!(state.am_choking());
+        assert!(state.peer_choking());
+        assert!(!state.am_interested());
+        assert!(!state.peer_interested());
+    }
+
+    #[test]
+    fn choke_and_unchoke_only_send_on_change() {
+        let mut state = PeerConnectionState::new(4);
+        assert!(state.choke().is_none());
+        assert!(matches!(state.unchoke(), Some(PeerMessage::Unchoke)));
+        assert!(state.unchoke().is_none());
+        assert!(matches!(state.choke(), Some(PeerMessage::Choke)));
+        assert!(state.am_choking());
+    }
+
+    #[test]
+    fn requests_need_an_unchoke_and_the_piece() {
+        let mut state = PeerConnectionState::new(4);
+        let block = BlockRequest::new(1, 0, 16384);
+        assert_eq!(state.request(block).err(), Some(PeerProtocolError::RequestWhileChoked));
+
+        assert_eq!(state.receive(PeerMessage::Unchoke), Ok(PeerEvent::Unchoked));
+        assert_eq!(state.request(block).err(), Some(PeerProtocolError::PeerLacksPiece(1)));
+
+        assert_eq!(state.receive(PeerMessage::Have { index: 1 }), Ok(PeerEvent::Have(1)));
+        assert!(state.request(block).is_ok());
+        assert_eq!(state.request(block).err(), Some(PeerProtocolError::DuplicateRequest(block)));
+        assert_eq!(state.pending_requests(), &[block]);
+    }
+
+    #[test]
+    fn cancel_withdraws_a_pending_request() {
+        let mut state = connected_to_seed(4);
+        state.receive(PeerMessage::Unchoke).unwrap();
+        let block = BlockRequest::new(2, 0, 16384);
+        state.request(block).unwrap();
+
+        assert!(matches!(state.cancel(block), Some(PeerMessage::Cancel { index: 2, .. })));
+        assert!(state.cancel(block).is_none());
+        assert!(state.pending_requests().is_empty());
+
+        // The block arriving after the cancel no longer counts as requested
+        let data = vec![0u8; 16384];
+        assert_eq!(state.receive(PeerMessage::Piece { index: 2, begin: 0, data }), Ok(PeerEvent::Unsolicited(block)));
+    }
+
+    #[test]
+    fn being_choked_drops_pending_requests() {
+        let mut state = connected_to_seed(4);
+        state.receive(PeerMessage::Unchoke).unwrap();
+        let block = BlockRequest::new(0, 0, 16384);
+        state.request(block).unwrap();
+
+        assert_eq!(state.receive(PeerMessage::Choke), Ok(PeerEvent::Choked(vec![block])));
+        assert!(state.pending_requests().is_empty());
+    }
+
+    #[test]
+    fn peer_requests_are_queued_only_while_unchoked() {
+        let mut state = PeerConnectionState::new(4);
+        let block = BlockRequest::new(3, 0, 16384);
+        assert_eq!(state.receive(request_message(block)), Ok(PeerEvent::RequestIgnored(block)));
+        assert_eq!(state.queued_peer_requests(), 0);
+
+        state.unchoke();
+        assert_eq!(state.receive(request_message(block)), Ok(PeerEvent::Request(block)));
+        assert_eq!(state.receive(PeerMessage::Cancel { index: 3, begin: 0, length: 16384 }), Ok(PeerEvent::Cancel(block)));
+        assert_eq!(state.next_peer_request(), None);
+
+        state.receive(request_message(block)).unwrap();
+        state.choke();
+        assert_eq!(state.queued_peer_requests(), 0);
+    }
+
+    #[test]
+    fn peer_request_queue_is_capped() {
+        let mut state = PeerConnectionState::new(1);
+        state.unchoke();
+        for begin in 0..MAX_PEER_REQUESTS as u32 {
+            let block = BlockRequest::new(0, begin, 1);
+            assert_eq!(state.receive(request_message(block)), Ok(PeerEvent::Request(block)));
+        }
+        let overflow = BlockRequest::new(0, MAX_PEER_REQUESTS as u32, 1);
+        assert_eq!(state.receive(request_message(overflow)), Ok(PeerEvent::RequestOverflow(overflow)));
+        assert_eq!(state.queued_peer_requests(), MAX_PEER_REQUESTS);
+
+        assert_eq!(state.next_peer_request(), Some(BlockRequest::new(0, 0, 1)));
+        assert_eq!(state.receive(request_message(overflow)), Ok(PeerEvent::Request(overflow)));
+    }
+
+    #[test]
+    fn invalid_piece_indexes_are_errors() {
+        let mut state = connected_to_seed(4);
+        assert_eq!(state.receive(PeerMessage::Have { index: 4 }), Err(PeerProtocolError::InvalidPieceIndex(4)));
+        assert_eq!(state.receive(PeerMessage::Request { index: 9, begin: 0, length: 1 }), Err(PeerProtocolError::InvalidPieceIndex(9)));
+        assert_eq!(state.receive(PeerMessage::Cancel { index: 9, begin: 0, length: 1 }), Err(PeerProtocolError::InvalidPieceIndex(9)));
+        assert_eq!(state.receive(PeerMessage::Reject { index: 9, begin: 0, length: 1 }), Err(PeerProtocolError::InvalidPieceIndex(9)));
+        assert_eq!(state.receive(PeerMessage::Piece { index: 9, begin: 0, data: vec![0] }), Err(PeerProtocolError::InvalidPieceIndex(9)));
+        state.receive(PeerMessage::Unchoke).unwrap();
+        assert_eq!(state.request(BlockRequest::new(4, 0, 1)).err(), Some(PeerProtocolError::InvalidPieceIndex(4)));
+    }
+
+    #[test]
+    fn bitfield_must_come_first_and_match_the_piece_count() {
+        let mut state = PeerConnectionState::new(10);
+        assert_eq!(state.receive(PeerMessage::Bitfield { pieces: vec![0xFF] }), Err(PeerProtocolError::InvalidBitfield));
+
+        let mut state = PeerConnectionState::new(10);
+        // Bits past piece 9 must be zero
+        assert_eq!(state.receive(PeerMessage::Bitfield { pieces: vec![0xFF, 0xFF] }), Err(PeerProtocolError::InvalidBitfield));
+
+        let mut state = PeerConnectionState::new(10);
+        state.receive(PeerMessage::Interested).unwrap();
+        assert_eq!(state.receive(PeerMessage::Bitfield { pieces: vec![0, 0] }), Err(PeerProtocolError::UnexpectedBitfield));
+    }
+}
@@ -0,0 +1,1485 @@
+//! # Peer Wire Protocol
+//!
+//! Client-to-client piece exchange over QUIC.
+//!
+//! Every downloading client runs a `PeerListener` on the port it announces to
+//! the tracker. A downloader connects to the peers from the announce response,
+//! opens one long-lived bidirectional stream per torrent and exchanges
+//! `PeerMessage`s on it, MessagePack-encoded, as varint length-prefixed
+//! frames (the framing of `framing`, without the framed-stream marker):
+//!
+//! ```text
+//! downloader                         peer
+//!     | --- Handshake -------------->  |
+//!     | <-------------- Handshake ---  |
+//!     | --- Bitfield --------------->  |
+//!     | <--------------- Bitfield ---  |
+//!     | --- Interested ------------->  |
+//!     | <---------------- Unchoke ---  |
+//!     | --- Request ---------------->  |
+//!     | <------------ Piece/Reject --  |
+//!     | <---> Have (either side, after verifying a piece)
+//! ```
+//!
+//! The choke/interest rules live in `peer_state::PeerConnectionState`. Which
+//! peers we unchoke is decided per torrent by a `choker::Choker`, rechoked
+//! every `RECHOKE_INTERVAL` from the bytes each peer sent us (or, once the
+//! torrent is complete, the bytes we sent it).
+//!
+//! The tracker itself is not a wire peer: when it seeds a torrent (peer id
+//! `quic_tracker::SERVER_PEER_ID`) pieces are fetched from it with ranged
+//! `FileRequest`s instead.
+
+use crate::client::TorrentFile;
+use crate::messages::{FileRequest, FileResponse, PeerInfo, PeerMessage, PexPeer};
+use crate::peer_state::{BlockRequest, PeerConnectionState, PeerEvent};
+use crate::piece_picker::{PickMode, PiecePicker, BLOCK_SIZE};
+use crate::budget::{Budget, ConnectionPermit};
+use crate::choker::{Choker, ChokerConfig, PeerRates, RECHOKE_INTERVAL};
+use crate::pex::{PeerPool, PeerSource, PexState, PEX_FLAG_REACHABLE, PEX_FLAG_SEED};
+use crate::quic_tracker::SERVER_PEER_ID;
+use crate::quic_utils::{create_client_config, create_server_config};
+use crate::protocol::Codec;
+use crate::web_seed::{fetch_range, file_url, Backoff, HttpUrl};
+use quinn::{Endpoint, RecvStream, SendStream};
+use serde::{de::DeserializeOwned, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+
+/// Protocol name sent in every handshake.
+pub const PROTOCOL: &str = "quic-torrent/2";
+
+/// Port the client tries first for its peer listener.
+pub const DEFAULT_PEER_PORT: u16 = 6881;
+
+/// Largest block a peer will serve for a single `Request` (downloaders ask
+/// for `BLOCK_SIZE` blocks).
+pub const MAX_BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+/// Requests kept outstanding per peer.
+const PIPELINE_DEPTH: usize = 8;
+
+/// Pieces a peer may send that fail their hash check before it is dropped.
+const MAX_HASH_FAILURES: u32 = 3;
+
+/// Largest frame accepted from a peer: a `MAX_BLOCK_SIZE` block plus room
+/// for the message around it (and for bitfields and PEX messages).
+const MAX_FRAME_SIZE: usize = MAX_BLOCK_SIZE as usize + 16 * 1024;
+
+/// Frames read ahead of the stream's driver, per stream.
+const READ_AHEAD_FRAMES: usize = 8;
+
+/// How long to wait for a peer to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the rest of a frame may take once its header has arrived.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a downloader stays connected to a peer that has nothing it needs.
+/// Kept below QUIC's 30 s idle timeout.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How often each stream checks whether a PEX message is due.
+const PEX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most peers a download fetches from at once.
+pub const MAX_DOWNLOAD_PEERS: usize = 30;
+
+/// Errors crossing task boundaries must be `Send`.
+pub type PeerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Writes one MessagePack frame.
+pub async fn write_frame<T: Serialize>(send: &mut SendStream, message: &T) -> PeerResult<()> {
+    let payload = Codec::MessagePack.encode(message)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(format!("Frame too large: {} bytes", payload.len()).into());
+    }
+    crate::framing::write_frame(send, &payload).await?;
+    Ok(())
+}
+
+/// Reads one MessagePack frame.
+///
+/// Waits as long as the connection stays up for the next frame to start;
+/// once it has, the rest must arrive within `READ_TIMEOUT`.
+///
+/// # Returns
+/// `None` if the stream was closed cleanly before the next frame
+pub async fn read_frame<T: DeserializeOwned>(recv: &mut RecvStream) -> PeerResult<Option<T>> {
+    let length = match crate::framing::read_frame_length(recv, MAX_FRAME_SIZE).await {
+        Ok(Some(length)) => length,
+        Ok(None) => return Ok(None),
+        Err(e) if crate::framing::is_too_large(&e) => return Err(format!("Peer sent an oversized frame: {}", e).into()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut payload = vec![0u8; length];
+    match tokio::time::timeout(READ_TIMEOUT, recv.read_exact(&mut payload)).await {
+        Err(_) => return Err("Timed out reading frame from peer".into()),
+        Ok(Err(e)) => return Err(e.into()),
+        Ok(Ok(())) => {}
+    }
+    Ok(Some(Codec::MessagePack.decode(&payload)?))
+}
+
+/// Packs per-piece flags into a bitfield (MSB-first, padded with zero bits).
+pub fn pack_bitfield(have: &[bool]) -> Vec<u8> {
+    let mut packed = vec![0u8; have.len().div_ceil(8)];
+    for (index, _) in have.iter().enumerate().filter(|(_, &has)| has) {
+        packed[index / 8] |= 0x80 >> (index % 8);
+    }
+    packed
+}
+
+/// Unpacks a bitfield into `piece_count` per-piece flags; missing bytes read as zero.
+pub fn unpack_bitfield(packed: &[u8], piece_count: usize) -> Vec<bool> {
+    (0..piece_count)
+        .map(|index| packed.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0))
+        .collect()
+}
+
+/// Moves a stream's incoming frames onto a channel.
+///
+/// Reading a frame isn't cancel-safe, so drivers that also wait on other
+/// events `select!` on the channel instead of the stream. The channel is
+/// bounded, so a slow driver stops the reads (and QUIC flow control
+/// stops the peer).
+fn spawn_reader(mut recv: RecvStream) -> mpsc::Receiver<PeerResult<PeerMessage>> {
+    let (tx, rx) = mpsc::channel(READ_AHEAD_FRAMES);
+    tokio::spawn(async move {
+        loop {
+            match read_frame::<PeerMessage>(&mut recv).await {
+                Ok(Some(message)) => {
+                    if tx.send(Ok(message)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// A torrent's data file on disk plus the set of verified pieces.
+pub struct PieceStore {
+    torrent: TorrentFile,
+    path: PathBuf,
+    file: Mutex<fs::File>,
+    have: RwLock<Vec<bool>>,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    completed: broadcast::Sender<u32>,
+    buffers: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl PieceStore {
+    /// Opens (or creates) the data file for `torrent` at `path`.
+    ///
+    /// An existing file is checked piece by piece, so an interrupted
+    /// download resumes with the pieces that already verify.
+    pub fn open(torrent: TorrentFile, path: &Path) -> std::io::Result<Self> {
+        let expected_pieces = torrent.length.div_ceil(torrent.piece_length.max(1));
+        if torrent.piece_length == 0 || torrent.pieces.len() != expected_pieces {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Torrent has {} piece hashes, expected {}", torrent.pieces.len(), expected_pieces),
+            ));
+        }
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let existed = path.exists();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        file.set_len(torrent.length as u64)?;
+
+        let store = Self {
+            have: RwLock::new(vec![false; torrent.pieces.len()]),
+            torrent,
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            completed: broadcast::channel(1024).0,
+            buffers: Mutex::new(HashMap::new()),
+        };
+
+        if existed {
+            let mut verified = 0;
+            for index in 0..store.piece_count() {
+                let data = store.read_raw(index, 0, store.piece_size(index))?;
+                if store.hash_matches(index, &data) {
+                    store.have.write().unwrap()[index] = true;
+                    verified += 1;
+                }
+            }
+            crate::log_client!("[PieceStore::open] Existing file {} has {} of {} pieces",
+                store.path.display(), verified, store.piece_count());
+        }
+
+        Ok(store)
+    }
+
+    pub fn torrent(&self) -> &TorrentFile {
+        &self.torrent
+    }
+
+    pub fn info_hash(&self) -> &str {
+        &self.torrent.info_hash
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.torrent.pieces.len()
+    }
+
+    /// Size of piece `index` in bytes (the last piece may be short).
+    pub fn piece_size(&self, index: usize) -> usize {
+        let start = index * self.torrent.piece_length;
+        self.torrent.piece_length.min(self.torrent.length.saturating_sub(start))
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.have.read().unwrap().get(index).copied().unwrap_or(false)
+    }
+
+    /// Verified pieces as per-piece flags.
+    pub fn have(&self) -> Vec<bool> {
+        self.have.read().unwrap().clone()
+    }
+
+    /// Verified pieces as a packed bitfield.
+    pub fn bitfield(&self) -> Vec<u8> {
+        pack_bitfield(&self.have.read().unwrap())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.read().unwrap().iter().all(|&has| has)
+    }
+
+    /// Bytes still missing from the file.
+    pub fn left(&self) -> u64 {
+        let have = self.have.read().unwrap();
+        (0..have.len())
+            .filter(|&index| !have[index])
+            .map(|index| self.piece_size(index) as u64)
+            .sum()
+    }
+
+    /// Bytes received from peers in this session.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes served to peers in this session.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the indices of newly verified pieces, for `have` broadcasts.
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.completed.subscribe()
+    }
+
+    /// Reads `length` bytes at `begin` within a verified piece, for serving to a peer.
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> std::io::Result<Vec<u8>> {
+        if !self.has_piece(index) {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Piece {} not available", index)));
+        }
+        if length == 0 || length > MAX_BLOCK_SIZE as usize || begin + length > self.piece_size(index) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid block: piece={}, begin={}, length={}", index, begin, length),
+            ));
+        }
+        let data = self.read_raw(index, begin, length)?;
+        self.uploaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    /// Verifies a complete piece against the torrent and writes it to disk.
+    ///
+    /// # Returns
+    /// `false` if the data doesn't match the piece hash (nothing is written)
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> std::io::Result<bool> {
+        if index >= self.piece_count() || data.len() != self.piece_size(index) || !self.hash_matches(index, data) {
+            return Ok(false);
+        }
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start((index * self.torrent.piece_length) as u64))?;
+            file.write_all(data)?;
+        }
+        self.have.write().unwrap()[index] = true;
+        self.downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+        let _ = self.completed.send(index as u32);
+        Ok(true)
+    }
+
+    /// Buffers a block of a piece that is still being downloaded.
+    pub fn write_block(&self, index: usize, begin: usize, data: &[u8]) -> std::io::Result<()> {
+        if index >= self.piece_count() || begin + data.len() > self.piece_size(index) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid block: piece={}, begin={}, length={}", index, begin, data.len()),
+            ));
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffer = buffers.entry(index).or_insert_with(|| vec![0u8; self.piece_size(index)]);
+        buffer[begin..begin + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Verifies and writes a piece assembled with `write_block`, dropping its buffer.
+    ///
+    /// # Returns
+    /// `false` if the piece doesn't match its hash
+    pub fn finish_piece(&self, index: usize) -> std::io::Result<bool> {
+        let buffer = self.buffers.lock().unwrap().remove(&index);
+        match buffer {
+            Some(data) => self.write_piece(index, &data),
+            None => Ok(false),
+        }
+    }
+
+    fn read_raw(&self, index: usize, begin: usize, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((index * self.torrent.piece_length + begin) as u64))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn hash_matches(&self, index: usize, data: &[u8]) -> bool {
+        Sha1::digest(data).as_slice() == self.torrent.pieces[index].as_slice()
+    }
+}
+
+/// Torrents a listener serves, by info hash.
+type ServedTorrents = Arc<RwLock<HashMap<String, Arc<ServedTorrent>>>>;
+
+/// Bytes received from each remote peer id, across all torrents.
+type DownloadCounters = Arc<Mutex<HashMap<String, u64>>>;
+
+/// A torrent the listener serves, with the choker deciding who gets data.
+struct ServedTorrent {
+    store: Arc<PieceStore>,
+    uploads: Mutex<UploadSlots>,
+    /// Stream keys currently unchoked; every serving stream watches this
+    unchoked: watch::Sender<BTreeSet<usize>>,
+    /// Peers known from the tracker, PEX and incoming connections
+    pool: Mutex<PeerPool>,
+    /// Signalled when the pool gains a peer worth downloading from
+    pool_changed: Notify,
+    /// The listener's shared limits
+    budget: Arc<Budget>,
+}
+
+struct UploadSlots {
+    next_key: usize,
+    peers: HashMap<usize, ServedPeer>,
+    choker: Choker,
+}
+
+/// A stream we serve, with its transfer totals at the last rechoke.
+struct ServedPeer {
+    peer_id: String,
+    interested: bool,
+    uploaded: u64,
+    uploaded_at_rechoke: u64,
+    downloaded_at_rechoke: u64,
+}
+
+impl ServedTorrent {
+    fn new(store: Arc<PieceStore>, peer_id: &str, budget: Arc<Budget>) -> Self {
+        Self {
+            store,
+            uploads: Mutex::new(UploadSlots {
+                next_key: 0,
+                peers: HashMap::new(),
+                choker: Choker::new(ChokerConfig::default()),
+            }),
+            unchoked: watch::channel(BTreeSet::new()).0,
+            pool: Mutex::new(PeerPool::new(peer_id)),
+            pool_changed: Notify::new(),
+            budget,
+        }
+    }
+
+    /// Records an open stream with a peer in the pool.
+    fn peer_connected(&self, peer: PexPeer, source: PeerSource) {
+        if self.pool.lock().unwrap().connected(peer, source) {
+            self.pool_changed.notify_one();
+        }
+    }
+
+    /// Adds the peers from a PEX message to the pool.
+    fn learn_pex(&self, added: Vec<PexPeer>, dropped: Vec<PexPeer>, from: SocketAddr) {
+        let mut pool = self.pool.lock().unwrap();
+        let new = added.into_iter().filter(|peer| pool.add(peer.clone(), PeerSource::Pex)).count();
+        for peer in &dropped {
+            pool.remove_dropped(peer);
+        }
+        crate::log_client!("[ServedTorrent::learn_pex] {} new peers from {} ({} dropped), pool size {}",
+            new, from, dropped.len(), pool.len());
+        if new > 0 {
+            self.pool_changed.notify_one();
+        }
+    }
+
+    /// Builds the next PEX message for the stream with `remote`, if one is due.
+    fn pex_message(&self, pex: &mut PexState, remote: SocketAddr) -> Option<PeerMessage> {
+        let connected: Vec<PexPeer> = self.pool.lock().unwrap().connected_peers().into_iter()
+            .filter(|peer| crate::pex::peer_addr(peer) != Some(remote))
+            .collect();
+        pex.outgoing(Instant::now(), &connected)
+    }
+
+    /// Adds a serving stream; it starts choked.
+    fn register(&self, peer_id: &str, downloaded: &DownloadCounters) -> (usize, watch::Receiver<BTreeSet<usize>>) {
+        let downloaded_from = downloaded.lock().unwrap().get(peer_id).copied().unwrap_or(0);
+        let mut uploads = self.uploads.lock().unwrap();
+        let key = uploads.next_key;
+        uploads.next_key += 1;
+        uploads.peers.insert(key, ServedPeer {
+            peer_id: peer_id.to_string(),
+            interested: false,
+            uploaded: 0,
+            uploaded_at_rechoke: 0,
+            downloaded_at_rechoke: downloaded_from,
+        });
+        (key, self.unchoked.subscribe())
+    }
+
+    fn unregister(&self, key: usize) {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.peers.remove(&key);
+        uploads.choker.remove_peer(key);
+        self.unchoked.send_replace(uploads.choker.unchoked());
+    }
+
+    /// Records a change of interest; a newly interested peer gets a free slot at once.
+    fn set_interested(&self, key: usize, interested: bool) {
+        let mut uploads = self.uploads.lock().unwrap();
+        if let Some(peer) = uploads.peers.get_mut(&key) {
+            peer.interested = interested;
+        }
+        if interested && uploads.choker.peer_interested(key, Instant::now()) {
+            self.unchoked.send_replace(uploads.choker.unchoked());
+        }
+    }
+
+    fn record_upload(&self, key: usize, bytes: u64) {
+        if let Some(peer) = self.uploads.lock().unwrap().peers.get_mut(&key) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    /// Recalculates the unchoked set from the rates since the last rechoke.
+    fn rechoke(&self, elapsed: Duration, downloaded: &DownloadCounters) {
+        let downloaded = downloaded.lock().unwrap().clone();
+        let seconds = elapsed.as_secs_f64().max(1.0);
+        let mut uploads = self.uploads.lock().unwrap();
+        let rates: Vec<PeerRates> = uploads.peers.iter_mut()
+            .map(|(&key, peer)| {
+                let downloaded_from = downloaded.get(&peer.peer_id).copied().unwrap_or(0);
+                let rates = PeerRates {
+                    peer: key,
+                    interested: peer.interested,
+                    download_rate: downloaded_from.saturating_sub(peer.downloaded_at_rechoke) as f64 / seconds,
+                    upload_rate: peer.uploaded.saturating_sub(peer.uploaded_at_rechoke) as f64 / seconds,
+                };
+                peer.uploaded_at_rechoke = peer.uploaded;
+                peer.downloaded_at_rechoke = downloaded_from;
+                rates
+            })
+            .collect();
+        let seeding = self.store.is_complete();
+        let unchoked = uploads.choker.rechoke(Instant::now(), &rates, seeding);
+        crate::log_client!("[ServedTorrent::rechoke] info_hash={}, peers={}, seeding={}, unchoked={:?}, optimistic={:?}",
+            self.store.info_hash(), rates.len(), seeding, unchoked, uploads.choker.optimistic());
+        self.unchoked.send_replace(unchoked);
+    }
+}
+
+/// QUIC endpoint that serves pieces to other peers and makes this client's
+/// outgoing peer connections.
+pub struct PeerListener {
+    endpoint: Endpoint,
+    port: u16,
+    peer_id: String,
+    torrents: ServedTorrents,
+    downloaded: DownloadCounters,
+    budget: Arc<Budget>,
+}
+
+impl PeerListener {
+    /// Binds the listener to `port`, or the next free port in `port..port+10`,
+    /// or finally any free port, and starts accepting peers.
+    pub fn bind(port: u16, peer_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::bind_with_budget(port, peer_id, Arc::new(Budget::unlimited()))
+    }
+
+    /// Like `bind`, with connection and bandwidth limits shared by every
+    /// torrent the listener serves and downloads.
+    pub fn bind_with_budget(port: u16, peer_id: &str, budget: Arc<Budget>) -> Result<Self, Box<dyn std::error::Error>> {
+        let candidates = (0..10u16).filter_map(|i| port.checked_add(i)).chain(std::iter::once(0));
+        let mut last_error = None;
+        let mut bound = None;
+        for candidate in candidates {
+            match Endpoint::server(create_server_config()?, SocketAddr::from(([0, 0, 0, 0], candidate))) {
+                Ok(endpoint) => {
+                    bound = Some(endpoint);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let mut endpoint = match (bound, last_error) {
+            (Some(endpoint), _) => endpoint,
+            (None, Some(e)) => return Err(Box::new(e)),
+            (None, None) => return Err("No port available for peer listener".into()),
+        };
+        endpoint.set_default_client_config(create_client_config()?);
+        let port = endpoint.local_addr()?.port();
+
+        let listener = Self {
+            endpoint,
+            port,
+            peer_id: peer_id.to_string(),
+            torrents: Arc::new(RwLock::new(HashMap::new())),
+            downloaded: Arc::new(Mutex::new(HashMap::new())),
+            budget,
+        };
+
+        let endpoint = listener.endpoint.clone();
+        let torrents = Arc::clone(&listener.torrents);
+        let downloaded = Arc::clone(&listener.downloaded);
+        let peer_id = listener.peer_id.clone();
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let torrents = Arc::clone(&torrents);
+                let downloaded = Arc::clone(&downloaded);
+                let peer_id = peer_id.clone();
+                tokio::spawn(async move {
+                    match connecting.await {
+                        Ok(connection) => serve_connection(connection, torrents, downloaded, peer_id).await,
+                        Err(e) => crate::log_client!("[PeerListener] Incoming peer connection failed: {}", e),
+                    }
+                });
+            }
+        });
+
+        // Rechoke every served torrent until the listener is closed
+        let torrents = Arc::downgrade(&listener.torrents);
+        let downloaded = Arc::clone(&listener.downloaded);
+        tokio::spawn(async move {
+            let mut last = Instant::now();
+            loop {
+                tokio::time::sleep(RECHOKE_INTERVAL).await;
+                let Some(torrents) = torrents.upgrade() else { break };
+                let served: Vec<Arc<ServedTorrent>> = torrents.read().unwrap().values().cloned().collect();
+                for torrent in served {
+                    torrent.rechoke(last.elapsed(), &downloaded);
+                }
+                last = Instant::now();
+            }
+        });
+
+        crate::log_client!("[PeerListener::bind] Listening for peers on port {} (peer_id={})", port, listener.peer_id);
+        Ok(listener)
+    }
+
+    /// Port the listener is bound to; announce this to the tracker.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn budget(&self) -> &Arc<Budget> {
+        &self.budget
+    }
+
+    /// Starts serving a torrent's verified pieces to peers.
+    pub fn add_torrent(&self, store: Arc<PieceStore>) {
+        self.served(store);
+    }
+
+    /// The served torrent for `store`, added if needed.
+    fn served(&self, store: Arc<PieceStore>) -> Arc<ServedTorrent> {
+        let mut torrents = self.torrents.write().unwrap();
+        let torrent = torrents.entry(store.info_hash().to_string())
+            .or_insert_with(|| Arc::new(ServedTorrent::new(store, &self.peer_id, Arc::clone(&self.budget))));
+        Arc::clone(torrent)
+    }
+
+    /// Adds peers found outside the tracker (e.g. by local discovery) to a
+    /// served torrent's pool, waking its download if any are new.
+    ///
+    /// # Returns
+    /// How many of the peers were new
+    pub fn add_peers(&self, info_hash: &str, peers: &[PeerInfo], source: PeerSource) -> usize {
+        let Some(torrent) = self.torrents.read().unwrap().get(info_hash).cloned() else { return 0 };
+        let mut pool = torrent.pool.lock().unwrap();
+        let new = peers.iter()
+            .filter(|peer| pool.add(PexPeer { ip: peer.ip.clone(), port: peer.port, peer_id: peer.peer_id.clone(), flags: 0 }, source))
+            .count();
+        crate::log_client!("[PeerListener::add_peers] {} new of {} peers from {:?} for {}, pool size {}",
+            new, peers.len(), source, info_hash, pool.len());
+        if new > 0 {
+            torrent.pool_changed.notify_one();
+        }
+        new
+    }
+
+    /// Stops serving a torrent; open peer streams for it end at their next request.
+    pub fn remove_torrent(&self, info_hash: &str) {
+        self.torrents.write().unwrap().remove(info_hash);
+    }
+
+    /// Closes all peer connections and stops accepting new ones.
+    pub fn close(&self) {
+        self.endpoint.close(0u32.into(), b"closing");
+    }
+
+    /// Downloads every missing piece of `store` from the given peers.
+    ///
+    /// All sources are fetched from concurrently, with a shared `PiecePicker`
+    /// choosing blocks (see `piece_picker` for the order and endgame).
+    /// Peers announced as `SERVER_PEER_ID` are fetched from with ranged
+    /// `FileRequest`s. If the tracker didn't list itself as a seeder,
+    /// `tracker` is tried the same way as a fallback. The torrent's web
+    /// seeds are fetched from over HTTP (see `web_seed`).
+    ///
+    /// The other peers go into the torrent's `PeerPool`; peers that show up
+    /// there later (from PEX or by connecting to us) are fetched from too,
+    /// up to `MAX_DOWNLOAD_PEERS` at a time.
+    ///
+    /// # Returns
+    /// What each source delivered (or why it was dropped), once every piece
+    /// is in; an error if the sources ran out first
+    pub async fn download(
+        &self,
+        store: Arc<PieceStore>,
+        peers: &[PeerInfo],
+        tracker: (&str, u16),
+        mode: PickMode,
+    ) -> PeerResult<Vec<SourceReport>> {
+        let served = self.served(Arc::clone(&store));
+        let mut servers: Vec<Source> = Vec::new();
+        {
+            let mut pool = served.pool.lock().unwrap();
+            for peer in peers {
+                if peer.peer_id.as_deref() == Some(SERVER_PEER_ID) {
+                    servers.push(Source::Server { ip: peer.ip.clone(), port: peer.port });
+                } else {
+                    pool.add(PexPeer { ip: peer.ip.clone(), port: peer.port, peer_id: peer.peer_id.clone(), flags: 0 }, PeerSource::Tracker);
+                }
+            }
+        }
+        if servers.is_empty() {
+            servers.push(Source::Server { ip: tracker.0.to_string(), port: tracker.1 });
+        }
+        let torrent = store.torrent();
+        servers.extend(torrent.url_list.iter().map(|url| Source::WebSeed(file_url(url, &torrent.name))));
+
+        crate::log_client!("[PeerListener::download] info_hash={}, missing_pieces={}, servers={}, pool_peers={}, mode={:?}",
+            store.info_hash(), store.have().iter().filter(|&&h| !h).count(), servers.len(), served.pool.lock().unwrap().len(), mode);
+
+        let picker = Arc::new(Mutex::new(PiecePicker::new(
+            store.have(),
+            torrent.piece_length as u32,
+            torrent.length as u64,
+            mode,
+        )));
+        let cancels: Arc<Cancels> = Arc::default();
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut reports = Vec::new();
+        let mut next_key = 0;
+        let mut active_peers = 0;
+        let mut spawn = |tasks: &mut tokio::task::JoinSet<_>, source: Source, permit: Option<ConnectionPermit>| {
+            let key = next_key;
+            next_key += 1;
+            let served = Arc::clone(&served);
+            let picker = Arc::clone(&picker);
+            let cancels = Arc::clone(&cancels);
+            let endpoint = self.endpoint.clone();
+            let peer_id = self.peer_id.clone();
+            let downloaded = Arc::clone(&self.downloaded);
+            tasks.spawn(async move {
+                // Held until the source is done with
+                let _permit = permit;
+                let label = source.to_string();
+                let result = match &source {
+                    Source::Peer(peer) => fetch_from_peer(peer, &endpoint, &peer_id, &served, &picker, &cancels, key, &downloaded).await,
+                    Source::Server { ip, port } => fetch_from_server(ip, *port, &served, &picker, &cancels, key).await,
+                    Source::WebSeed(url) => fetch_from_web_seed(url, &served, &picker, &cancels, key).await,
+                };
+                (label, matches!(source, Source::Peer(_)), result)
+            });
+        };
+        for source in servers {
+            spawn(&mut tasks, source, None);
+        }
+
+        loop {
+            // Start on peers the pool has learned about since, as far as the
+            // shared connection budget allows
+            let mut waiting_for_budget = false;
+            let released = self.budget.connection_released();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if !store.is_complete() && active_peers < MAX_DOWNLOAD_PEERS {
+                let candidates: Vec<(PexPeer, ConnectionPermit)> = {
+                    let mut pool = served.pool.lock().unwrap();
+                    let untried = pool.untried();
+                    let permits = self.budget.try_connections((MAX_DOWNLOAD_PEERS - active_peers).min(untried));
+                    waiting_for_budget = permits.len() < untried;
+                    pool.take_candidates(permits.len()).into_iter().zip(permits).collect()
+                };
+                for (peer, permit) in candidates {
+                    crate::log_client!("[PeerListener::download] Adding peer {}:{} (peer_id={:?})", peer.ip, peer.port, peer.peer_id);
+                    active_peers += 1;
+                    spawn(&mut tasks, Source::Peer(PeerInfo { ip: peer.ip, port: peer.port, peer_id: peer.peer_id }), Some(permit));
+                }
+            }
+            if tasks.is_empty() && !(waiting_for_budget && active_peers < MAX_DOWNLOAD_PEERS) {
+                break;
+            }
+
+            let joined = tokio::select! {
+                joined = tasks.join_next(), if !tasks.is_empty() => joined,
+                _ = served.pool_changed.notified() => continue,
+                _ = &mut released, if waiting_for_budget => continue,
+            };
+            match joined {
+                Some(Ok((label, is_peer, result))) => {
+                    if is_peer {
+                        active_peers -= 1;
+                    }
+                    match result {
+                        Ok(pieces) => {
+                            crate::log_client!("[PeerListener::download] Source {} finished: {} pieces", label, pieces);
+                            reports.push(SourceReport { source: label, pieces, error: None });
+                        }
+                        Err(e) => {
+                            crate::log_client!("[PeerListener::download] Source {} dropped: {}", label, e);
+                            reports.push(SourceReport { source: label, pieces: 0, error: Some(e.to_string()) });
+                        }
+                    }
+                }
+                Some(Err(e)) => crate::log_client!("ERROR: [PeerListener::download] Source task failed: {}", e),
+                None => break,
+            }
+        }
+
+        if store.is_complete() {
+            Ok(reports)
+        } else {
+            let missing = store.have().iter().filter(|&&h| !h).count();
+            Err(format!("Download incomplete: {} of {} pieces missing and no source has them", missing, store.piece_count()).into())
+        }
+    }
+}
+
+/// The outcome of one download source.
+#[derive(Debug, Clone)]
+pub struct SourceReport {
+    /// The source, e.g. `peer 10.0.0.2:6881` or `web seed http://...`
+    pub source: String,
+    /// Pieces completed with its data
+    pub pieces: usize,
+    /// Why it was dropped, if it was
+    pub error: Option<String>,
+}
+
+/// Where pieces can be fetched from.
+enum Source {
+    /// Another client speaking the peer wire
+    Peer(PeerInfo),
+    /// The tracker's seed directory, read with ranged `FileRequest`s
+    Server { ip: String, port: u16 },
+    /// A BEP 19 web seed: the file's HTTP URL, read with `Range` requests
+    WebSeed(String),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Peer(peer) => write!(f, "peer {}:{}", peer.ip, peer.port),
+            Source::Server { ip, port } => write!(f, "server {}:{}", ip, port),
+            Source::WebSeed(url) => write!(f, "web seed {}", url),
+        }
+    }
+}
+
+/// Per peer source (by picker key), where to send blocks another source
+/// delivered first so the peer's request for them can be cancelled.
+type Cancels = Mutex<HashMap<usize, mpsc::UnboundedSender<BlockRequest>>>;
+
+/// Stores an arrived block and verifies its piece once the piece is complete.
+///
+/// Peers the block was also requested from (in endgame) are sent it on
+/// `cancels`.
+///
+/// # Returns
+/// `Some(true)` if this block completed a piece that verified, `Some(false)`
+/// if the completed piece failed its hash check (it will be fetched again),
+/// `None` otherwise (including duplicates from endgame)
+fn accept_block(
+    store: &PieceStore,
+    picker: &Mutex<PiecePicker>,
+    cancels: &Cancels,
+    source: usize,
+    block: BlockRequest,
+    data: &[u8],
+) -> std::io::Result<Option<bool>> {
+    {
+        let mut picker = picker.lock().unwrap();
+        if !picker.is_wanted(block) {
+            return Ok(None);
+        }
+        // Buffer before marking received, so whoever completes the piece sees every block
+        store.write_block(block.index as usize, block.begin as usize, data)?;
+        let others = picker.block_received(source, block);
+        if !others.is_empty() {
+            let cancels = cancels.lock().unwrap();
+            for peer in others {
+                if let Some(cancel) = cancels.get(&peer) {
+                    let _ = cancel.send(block);
+                }
+            }
+        }
+        if !picker.piece_ready(block.index) {
+            return Ok(None);
+        }
+    }
+
+    let verified = store.finish_piece(block.index as usize)?;
+    let mut picker = picker.lock().unwrap();
+    if verified {
+        picker.piece_verified(block.index);
+    } else {
+        picker.piece_failed(block.index);
+    }
+    Ok(Some(verified))
+}
+
+/// Connects to a peer, handshakes, and fetches blocks until none are left for it.
+///
+/// Keeps up to `PIPELINE_DEPTH` requests outstanding. Stays connected while
+/// the peer might still get something we need (its `have` messages extend
+/// what we can request), tells it about every piece we verify from any
+/// source, and cancels requests for blocks another source delivered first.
+/// Exchanges PEX messages with the peer while connected.
+///
+/// # Returns
+/// Number of pieces completed with this peer's data
+#[allow(clippy::too_many_arguments)]
+async fn fetch_from_peer(
+    peer: &PeerInfo,
+    endpoint: &Endpoint,
+    peer_id: &str,
+    torrent: &ServedTorrent,
+    picker: &Mutex<PiecePicker>,
+    cancels: &Cancels,
+    key: usize,
+    downloaded: &DownloadCounters,
+) -> PeerResult<usize> {
+    let store = &torrent.store;
+    let addr: SocketAddr = format!("{}:{}", peer.ip, peer.port).parse()?;
+    crate::log_client_sent!("[fetch_from_peer] Connecting to peer {} (peer_id={:?})", addr, peer.peer_id);
+    let connection = tokio::time::timeout(CONNECT_TIMEOUT, endpoint.connect(addr, "localhost")?)
+        .await
+        .map_err(|_| "Connection timeout")??;
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    write_frame(&mut send, &PeerMessage::Handshake {
+        protocol: PROTOCOL.to_string(),
+        info_hash: store.info_hash().to_string(),
+        peer_id: peer_id.to_string(),
+    }).await?;
+
+    let remote_id = match read_frame::<PeerMessage>(&mut recv).await? {
+        Some(PeerMessage::Handshake { protocol, info_hash, peer_id: remote_id }) => {
+            if protocol != PROTOCOL {
+                return Err(format!("Unsupported peer protocol: {}", protocol).into());
+            }
+            if info_hash != store.info_hash() {
+                return Err(format!("Peer answered for a different torrent: {}", info_hash).into());
+            }
+            if let Some(expected) = &peer.peer_id {
+                if *expected != remote_id {
+                    return Err(format!("Peer id mismatch: announced {}, handshake {}", expected, remote_id).into());
+                }
+            }
+            crate::log_client!("[fetch_from_peer] Handshake complete with {} (peer_id={})", addr, remote_id);
+            remote_id
+        }
+        Some(other) => return Err(format!("Expected handshake, got {:?}", other).into()),
+        None => return Err("Peer closed the connection during handshake".into()),
+    };
+
+    let mut state = PeerConnectionState::new(store.piece_count());
+    let mut completed = store.subscribe();
+    write_frame(&mut send, &state.bitfield(&store.have())).await?;
+    let mut frames = spawn_reader(recv);
+
+    let pex_peer = |state: &PeerConnectionState| PexPeer {
+        ip: addr.ip().to_string(),
+        port: addr.port(),
+        peer_id: Some(remote_id.clone()),
+        flags: PEX_FLAG_REACHABLE | if state.peer_is_seed() { PEX_FLAG_SEED } else { 0 },
+    };
+    torrent.peer_connected(pex_peer(&state), PeerSource::Tracker);
+    let mut pex = PexState::new();
+    let mut pex_poll = tokio::time::interval(PEX_POLL_INTERVAL);
+    let (cancel, mut cancelled) = mpsc::unbounded_channel();
+    cancels.lock().unwrap().insert(key, cancel);
+
+    let mut pieces = 0;
+    let mut hash_failures = 0;
+    let mut idle_since = Instant::now();
+    let result: PeerResult<()> = async {
+        loop {
+            if store.is_complete() {
+                return Ok(());
+            }
+
+            // Tell the peer whether it has anything we still need
+            if let Some(message) = state.update_interest(&store.have()) {
+                write_frame(&mut send, &message).await?;
+            }
+
+            // Keep the request pipeline full
+            if !state.peer_choking() && state.pending_requests().len() < PIPELINE_DEPTH {
+                let picked = picker.lock().unwrap()
+                    .pick(key, state.peer_pieces(), PIPELINE_DEPTH - state.pending_requests().len());
+                for block in picked {
+                    let message = state.request(block)?;
+                    write_frame(&mut send, &message).await?;
+                    idle_since = Instant::now();
+                }
+            }
+
+            if state.pending_requests().is_empty() && idle_since.elapsed() > PEER_IDLE_TIMEOUT {
+                crate::log_client!("[fetch_from_peer] Nothing left to fetch from {}", addr);
+                return Ok(());
+            }
+
+            tokio::select! {
+                frame = frames.recv() => {
+                    let message = match frame {
+                        Some(frame) => frame?,
+                        None => return Err("Peer closed the connection".into()),
+                    };
+                    match state.receive(message)? {
+                        PeerEvent::Block { block, data } => {
+                            idle_since = Instant::now();
+                            // Feeds the choker's reciprocation for this peer
+                            *downloaded.lock().unwrap().entry(remote_id.clone()).or_default() += data.len() as u64;
+                            torrent.budget.downloaded(data.len() as u64).await;
+                            match accept_block(store, picker, cancels, key, block, &data)? {
+                                Some(true) => {
+                                    crate::log_client!("[fetch_from_peer] Piece {} completed from {}", block.index, addr);
+                                    pieces += 1;
+                                }
+                                Some(false) => {
+                                    hash_failures += 1;
+                                    crate::log_client!("[fetch_from_peer] Piece {} failed hash check ({} failures with {})", block.index, hash_failures, addr);
+                                    if hash_failures >= MAX_HASH_FAILURES {
+                                        return Err(format!("{} pieces failed hash check", hash_failures).into());
+                                    }
+                                }
+                                None => {}
+                            }
+                        }
+                        PeerEvent::Rejected(block) => {
+                            crate::log_client!("[fetch_from_peer] {} rejected piece {}", addr, block.index);
+                            let mut picker = picker.lock().unwrap();
+                            picker.release(key, block);
+                            picker.remove_have(block.index);
+                        }
+                        PeerEvent::Choked(dropped) => {
+                            crate::log_client!("[fetch_from_peer] Choked by {}", addr);
+                            let mut picker = picker.lock().unwrap();
+                            for block in dropped {
+                                picker.release(key, block);
+                            }
+                        }
+                        PeerEvent::Unchoked => crate::log_client!("[fetch_from_peer] Unchoked by {}", addr),
+                        PeerEvent::Bitfield => {
+                            picker.lock().unwrap().add_peer(state.peer_pieces());
+                            torrent.pool.lock().unwrap().set_flags(addr, pex_peer(&state).flags);
+                            idle_since = Instant::now();
+                        }
+                        PeerEvent::Have(index) => {
+                            picker.lock().unwrap().add_have(index);
+                            torrent.pool.lock().unwrap().set_flags(addr, pex_peer(&state).flags);
+                            idle_since = Instant::now();
+                        }
+                        PeerEvent::Pex { added, dropped } => receive_pex(torrent, &mut pex, added, dropped, addr),
+                        _ => {}
+                    }
+                }
+                // Another source delivered a block we requested too (endgame)
+                Some(block) = cancelled.recv() => {
+                    if let Some(message) = state.cancel(block) {
+                        crate::log_client!("[fetch_from_peer] Cancelling piece {} [{}] at {}", block.index, block.begin, addr);
+                        write_frame(&mut send, &message).await?;
+                    }
+                }
+                piece = completed.recv() => {
+                    if let Ok(index) = piece {
+                        write_frame(&mut send, &state.have(index)).await?;
+                    }
+                }
+                _ = pex_poll.tick() => {
+                    if let Some(message) = torrent.pex_message(&mut pex, addr) {
+                        crate::log_client_sent!("[fetch_from_peer] Sending {:?} to {}", message, addr);
+                        write_frame(&mut send, &message).await?;
+                    }
+                }
+                // Recheck for blocks freed up by other sources
+                _ = tokio::time::sleep(Duration::from_millis(200)) => {}
+            }
+        }
+    }.await;
+
+    // Return our outstanding blocks and this peer's pieces to the picker
+    cancels.lock().unwrap().remove(&key);
+    picker.lock().unwrap().remove_peer(key, state.peer_pieces());
+    torrent.pool.lock().unwrap().disconnected(addr);
+    result?;
+    let _ = send.finish().await;
+    Ok(pieces)
+}
+
+/// Fetches blocks from the tracker's seed directory with ranged `FileRequest`s.
+///
+/// Contiguous blocks of a piece are fetched with a single request.
+///
+/// # Returns
+/// Number of pieces completed with the server's data
+async fn fetch_from_server(
+    ip: &str,
+    port: u16,
+    served: &ServedTorrent,
+    picker: &Mutex<PiecePicker>,
+    cancels: &Cancels,
+    key: usize,
+) -> PeerResult<usize> {
+    let (store, budget) = (&served.store, &served.budget);
+    let client = crate::quic_client::QuicClient::shared().map_err(|e| e.to_string())?;
+    let torrent = store.torrent();
+    // Older torrents carry a Windows-style path as the name
+    let filename = torrent.name.rsplit(['/', '\\']).next().unwrap_or(&torrent.name).to_string();
+    let available = vec![true; store.piece_count()];
+    let blocks_per_piece = (torrent.piece_length as u32).div_ceil(BLOCK_SIZE) as usize;
+    picker.lock().unwrap().add_peer(&available);
+
+    let mut pieces = 0;
+    let result: PeerResult<()> = async {
+        loop {
+            let picked = picker.lock().unwrap().pick(key, &available, blocks_per_piece);
+            if picked.is_empty() {
+                let done = {
+                    let picker = picker.lock().unwrap();
+                    picker.is_complete() || !picker.has_outstanding()
+                };
+                if done {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                continue;
+            }
+
+            for run in contiguous_runs(&picked) {
+                let first = run[0];
+                let length: u64 = run.iter().map(|b| b.length as u64).sum();
+                let offset = first.index as u64 * torrent.piece_length as u64 + first.begin as u64;
+                let request = FileRequest {
+                    info_hash: Some(store.info_hash().to_string()),
+                    ..FileRequest::range(&filename, offset, Some(length))
+                };
+                let response = client.send_message::<_, FileResponse>(ip, port, &request).await
+                    .map_err(|e| e.to_string())?;
+                if response.data.len() as u64 != length {
+                    return Err(format!("Server returned {} of {} bytes at offset {}", response.data.len(), length, offset).into());
+                }
+                budget.downloaded(length).await;
+
+                let mut position = 0;
+                for block in run {
+                    let data = &response.data[position..position + block.length as usize];
+                    position += block.length as usize;
+                    match accept_block(store, picker, cancels, key, *block, data)? {
+                        Some(true) => {
+                            crate::log_client!("[fetch_from_server] Piece {} completed from {}:{}", block.index, ip, port);
+                            pieces += 1;
+                        }
+                        Some(false) => return Err(format!("Piece {} from server failed hash check", block.index).into()),
+                        None => {}
+                    }
+                }
+            }
+        }
+    }.await;
+
+    picker.lock().unwrap().remove_peer(key, &available);
+    result?;
+    Ok(pieces)
+}
+
+/// Fetches blocks from a web seed with HTTP `Range` requests.
+///
+/// Like `fetch_from_server`, but a failed request doesn't end the source:
+/// its blocks are handed back to the picker and the web seed is retried
+/// after a `Backoff` delay, until it fails `MAX_WEB_SEED_FAILURES` times in
+/// a row. A web seed that ignores `Range` is dropped after its first answer.
+///
+/// # Returns
+/// Number of pieces completed with the web seed's data
+async fn fetch_from_web_seed(
+    url: &str,
+    served: &ServedTorrent,
+    picker: &Mutex<PiecePicker>,
+    cancels: &Cancels,
+    key: usize,
+) -> PeerResult<usize> {
+    let (store, budget) = (&served.store, &served.budget);
+    let http_url = HttpUrl::parse(url)?;
+    let torrent = store.torrent();
+    let available = vec![true; store.piece_count()];
+    let blocks_per_piece = (torrent.piece_length as u32).div_ceil(BLOCK_SIZE) as usize;
+    picker.lock().unwrap().add_peer(&available);
+
+    let mut backoff = Backoff::default();
+    let mut pieces = 0;
+    let result: PeerResult<()> = async {
+        loop {
+            // Wait out a backoff, unless the other sources finish first
+            while !backoff.ready(Instant::now()) {
+                if picker.lock().unwrap().is_complete() {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+
+            let picked = picker.lock().unwrap().pick(key, &available, blocks_per_piece);
+            if picked.is_empty() {
+                let done = {
+                    let picker = picker.lock().unwrap();
+                    picker.is_complete() || !picker.has_outstanding()
+                };
+                if done {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                continue;
+            }
+
+            for run in contiguous_runs(&picked) {
+                let first = run[0];
+                let length: u64 = run.iter().map(|b| b.length as u64).sum();
+                let offset = first.index as u64 * torrent.piece_length as u64 + first.begin as u64;
+                let response = match fetch_range(&http_url, offset, length).await {
+                    Ok(response) => {
+                        backoff.success();
+                        budget.downloaded(length).await;
+                        response
+                    }
+                    Err(e) => {
+                        // Let the other sources have this request's blocks meanwhile
+                        let mut picker = picker.lock().unwrap();
+                        for block in picker.requested_from(key) {
+                            picker.release(key, block);
+                        }
+                        drop(picker);
+                        let retry = backoff.failure(Instant::now());
+                        crate::log_client!("[fetch_from_web_seed] Request to {} failed ({} in a row): {}", url, backoff.failures(), e);
+                        match retry {
+                            Some(at) => {
+                                crate::log_client!("[fetch_from_web_seed] Retrying {} in {:.0}s", url, at.saturating_duration_since(Instant::now()).as_secs_f64());
+                                break;
+                            }
+                            None => return Err(format!("giving up after {} failures: {}", backoff.failures(), e).into()),
+                        }
+                    }
+                };
+
+                let mut position = 0;
+                for block in run {
+                    let data = &response.data[position..position + block.length as usize];
+                    position += block.length as usize;
+                    match accept_block(store, picker, cancels, key, *block, data)? {
+                        Some(true) => {
+                            crate::log_client!("[fetch_from_web_seed] Piece {} completed from {}", block.index, url);
+                            pieces += 1;
+                        }
+                        Some(false) => return Err(format!("Piece {} from web seed failed hash check", block.index).into()),
+                        None => {}
+                    }
+                }
+                if !response.ranged {
+                    // Every further request would download the file from its start again
+                    crate::log_client!("[fetch_from_web_seed] {} ignores Range requests, dropping it", url);
+                    return Err("server ignores Range requests".into());
+                }
+            }
+        }
+    }.await;
+
+    picker.lock().unwrap().remove_peer(key, &available);
+    result?;
+    Ok(pieces)
+}
+
+/// Splits picked blocks into runs of consecutive blocks within one piece.
+fn contiguous_runs(blocks: &[BlockRequest]) -> Vec<&[BlockRequest]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=blocks.len() {
+        let continues = i < blocks.len()
+            && blocks[i].index == blocks[i - 1].index
+            && blocks[i].begin == blocks[i - 1].begin + blocks[i - 1].length;
+        if !continues {
+            runs.push(&blocks[start..i]);
+            start = i;
+        }
+    }
+    runs
+}
+
+/// Serves pieces on every stream a peer opens.
+async fn serve_connection(
+    connection: quinn::Connection,
+    torrents: ServedTorrents,
+    downloaded: DownloadCounters,
+    peer_id: String,
+) {
+    let remote_addr = connection.remote_address();
+    crate::log_client!("[serve_connection] Peer connected from {}", remote_addr);
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        let torrents = Arc::clone(&torrents);
+        let downloaded = Arc::clone(&downloaded);
+        let peer_id = peer_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(send, recv, torrents, downloaded, &peer_id, remote_addr).await {
+                crate::log_client!("[serve_stream] Stream from {} ended: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+/// Answers one peer stream: handshake and bitfield, then requests until the peer closes it.
+///
+/// Whether the peer is choked follows the torrent's choker. Queued requests
+/// are answered one at a time after taking in everything the peer has
+/// already sent, so a `cancel` still reaches requests that are waiting.
+async fn serve_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    torrents: ServedTorrents,
+    downloaded: DownloadCounters,
+    peer_id: &str,
+    remote_addr: SocketAddr,
+) -> PeerResult<()> {
+    let (info_hash, remote_id) = match read_frame::<PeerMessage>(&mut recv).await? {
+        Some(PeerMessage::Handshake { protocol, info_hash, peer_id: remote_id }) => {
+            if protocol != PROTOCOL {
+                return Err(format!("Unsupported peer protocol: {}", protocol).into());
+            }
+            if remote_id == peer_id {
+                return Err("Refusing connection to self".into());
+            }
+            crate::log_client!("[serve_stream] Handshake from {} (peer_id={}, info_hash={})", remote_addr, remote_id, info_hash);
+            (info_hash, remote_id)
+        }
+        Some(other) => return Err(format!("Expected handshake, got {:?}", other).into()),
+        None => return Ok(()),
+    };
+
+    let torrent = torrents.read().unwrap().get(&info_hash).cloned()
+        .ok_or_else(|| format!("Not serving info_hash {}", info_hash))?;
+    let (key, mut unchoked) = torrent.register(&remote_id, &downloaded);
+    // Peers connect from the endpoint they listen on, so this is their peer address
+    let remote_addr = SocketAddr::new(remote_addr.ip().to_canonical(), remote_addr.port());
+    torrent.peer_connected(PexPeer {
+        ip: remote_addr.ip().to_string(),
+        port: remote_addr.port(),
+        peer_id: Some(remote_id),
+        flags: 0,
+    }, PeerSource::Incoming);
+    let result = serve_registered_stream(&mut send, recv, &torrents, &torrent, key, &mut unchoked, peer_id, remote_addr).await;
+    torrent.pool.lock().unwrap().disconnected(remote_addr);
+    torrent.unregister(key);
+    result
+}
+
+/// The part of `serve_stream` that runs while the stream holds a choker key.
+#[allow(clippy::too_many_arguments)]
+async fn serve_registered_stream(
+    send: &mut SendStream,
+    recv: RecvStream,
+    torrents: &ServedTorrents,
+    torrent: &ServedTorrent,
+    key: usize,
+    unchoked: &mut watch::Receiver<BTreeSet<usize>>,
+    peer_id: &str,
+    remote_addr: SocketAddr,
+) -> PeerResult<()> {
+    let store = &torrent.store;
+    let info_hash = store.info_hash().to_string();
+    let mut state = PeerConnectionState::new(store.piece_count());
+    let mut completed = store.subscribe();
+    write_frame(send, &PeerMessage::Handshake {
+        protocol: PROTOCOL.to_string(),
+        info_hash: info_hash.clone(),
+        peer_id: peer_id.to_string(),
+    }).await?;
+    write_frame(send, &state.bitfield(&store.have())).await?;
+    let mut frames = spawn_reader(recv);
+    let mut pex = PexState::new();
+    let mut pex_poll = tokio::time::interval(PEX_POLL_INTERVAL);
+
+    loop {
+        // Take in everything the peer already sent before serving the next request
+        loop {
+            match frames.try_recv() {
+                Ok(frame) => handle_served_message(&mut state, torrent, key, &mut pex, frame?, remote_addr)?,
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    let _ = send.finish().await;
+                    return Ok(());
+                }
+            }
+        }
+        apply_choke_decision(&mut state, send, unchoked, key, remote_addr).await?;
+
+        if let Some(block) = state.next_peer_request() {
+            // The torrent may have been removed since the handshake
+            if !torrents.read().unwrap().contains_key(&info_hash) {
+                return Err(format!("Stopped serving info_hash {}", info_hash).into());
+            }
+            let reply = match store.read_block(block.index as usize, block.begin as usize, block.length as usize) {
+                Ok(data) => {
+                    torrent.budget.uploading(data.len() as u64).await;
+                    crate::log_client!("[serve_stream] Serving piece {} [{}+{}] to {}", block.index, block.begin, data.len(), remote_addr);
+                    torrent.record_upload(key, data.len() as u64);
+                    PeerMessage::Piece { index: block.index, begin: block.begin, data }
+                }
+                Err(e) => {
+                    crate::log_client!("[serve_stream] Rejecting request from {}: {}", remote_addr, e);
+                    PeerMessage::Reject { index: block.index, begin: block.begin, length: block.length }
+                }
+            };
+            write_frame(send, &reply).await?;
+            continue;
+        }
+
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => handle_served_message(&mut state, torrent, key, &mut pex, frame?, remote_addr)?,
+                None => {
+                    let _ = send.finish().await;
+                    return Ok(());
+                }
+            },
+            piece = completed.recv() => {
+                if let Ok(index) = piece {
+                    write_frame(send, &state.have(index)).await?;
+                }
+            }
+            // Picked up by apply_choke_decision at the top of the loop
+            _ = unchoked.changed() => {}
+            _ = pex_poll.tick() => {
+                if let Some(message) = torrent.pex_message(&mut pex, remote_addr) {
+                    crate::log_client_sent!("[serve_stream] Sending {:?} to {}", message, remote_addr);
+                    write_frame(send, &message).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Applies a message from a peer we serve, reporting interest changes to the choker.
+fn handle_served_message(
+    state: &mut PeerConnectionState,
+    torrent: &ServedTorrent,
+    key: usize,
+    pex: &mut PexState,
+    message: PeerMessage,
+    remote_addr: SocketAddr,
+) -> PeerResult<()> {
+    match state.receive(message)? {
+        PeerEvent::Interested => {
+            crate::log_client!("[serve_stream] {} is interested", remote_addr);
+            torrent.set_interested(key, true);
+        }
+        PeerEvent::NotInterested => {
+            crate::log_client!("[serve_stream] {} is not interested", remote_addr);
+            torrent.set_interested(key, false);
+        }
+        PeerEvent::RequestIgnored(block) => {
+            crate::log_client!("[serve_stream] Ignoring request for piece {} from choked peer {}", block.index, remote_addr);
+        }
+        PeerEvent::RequestOverflow(block) => {
+            crate::log_client!("[serve_stream] Ignoring request for piece {} from {}: {} requests already queued", 
+                block.index, remote_addr, crate::peer_state::MAX_PEER_REQUESTS);
+        }
+        PeerEvent::Cancel(block) => {
+            crate::log_client!("[serve_stream] {} cancelled piece {}", remote_addr, block.index);
+        }
+        PeerEvent::Bitfield | PeerEvent::Have(_) => {
+            let flags = if state.peer_is_seed() { PEX_FLAG_SEED } else { 0 };
+            torrent.pool.lock().unwrap().set_flags(remote_addr, flags);
+        }
+        PeerEvent::Pex { added, dropped } => receive_pex(torrent, pex, added, dropped, remote_addr),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Feeds a peer's PEX message into the pool, unless it breaks the PEX limits.
+fn receive_pex(torrent: &ServedTorrent, pex: &mut PexState, added: Vec<PexPeer>, dropped: Vec<PexPeer>, from: SocketAddr) {
+    match pex.incoming(Instant::now(), &added, &dropped) {
+        Ok(()) => torrent.learn_pex(added, dropped, from),
+        Err(e) => crate::log_client!("[receive_pex] Ignoring PEX from {}: {}", from, e),
+    }
+}
+
+/// Sends `choke`/`unchoke` if the choker's latest decision differs from the stream's state.
+async fn apply_choke_decision(
+    state: &mut PeerConnectionState,
+    send: &mut SendStream,
+    unchoked: &mut watch::Receiver<BTreeSet<usize>>,
+    key: usize,
+    remote_addr: SocketAddr,
+) -> PeerResult<()> {
+    let unchoke = unchoked.borrow_and_update().contains(&key);
+    let message = if unchoke { state.unchoke() } else { state.choke() };
+    if let Some(message) = message {
+        crate::log_client!("[serve_stream] {:?} {}", message, remote_addr);
+        write_frame(send, &message).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,450 @@
+//! # Peer Exchange (PEX)
+//!
+//! Lets connected clients tell each other which other peers they are
+//! connected to, so a swarm keeps growing (and survives) without asking the
+//! tracker.
+//!
+//! Each stream sends a `PeerMessage::Pex` with the peers that joined or left
+//! since its previous one, modelled on BEP 11:
+//!
+//! - at most one message per `PEX_INTERVAL` on a stream, the first one as
+//!   soon as the connection is up;
+//! - at most `MAX_PEX_PEERS` added and `MAX_PEX_PEERS` dropped entries per
+//!   message (the rest go out next time).
+//!
+//! Incoming messages that break these limits are ignored. Peers learned this
+//! way go into the torrent's `PeerPool`, next to the tracker's results, where
+//! the downloader picks them up.
+//!
+//! Nothing here does I/O or reads the clock; callers pass `Instant`s.
+
+use crate::messages::{PeerMessage, PexPeer};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two `Pex` messages on a stream.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Incoming messages closer together than this are ignored; a little less
+/// than `PEX_INTERVAL` to allow for timer jitter on the sender.
+pub const MIN_PEX_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Most added (and most dropped) peers in one message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Most peers a `PeerPool` remembers per torrent.
+pub const MAX_POOL_PEERS: usize = 500;
+
+/// The peer has every piece.
+pub const PEX_FLAG_SEED: u8 = 0x02;
+
+/// We reached the peer by connecting to it, so others can too.
+pub const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+/// Why an incoming `Pex` was ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PexViolation {
+    /// Sent sooner than `MIN_PEX_RECEIVE_INTERVAL` after the previous one
+    TooFrequent,
+    /// More than `MAX_PEX_PEERS` added or dropped entries
+    TooLarge { added: usize, dropped: usize },
+}
+
+impl std::fmt::Display for PexViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PexViolation::TooFrequent => write!(f, "PEX message sent too soon after the previous one"),
+            PexViolation::TooLarge { added, dropped } => write!(f, "PEX message too large: {} added, {} dropped (max {} each)", added, dropped, MAX_PEX_PEERS),
+        }
+    }
+}
+
+impl std::error::Error for PexViolation {}
+
+/// The parsed address of a PEX entry, if its `ip` is valid.
+pub fn peer_addr(peer: &PexPeer) -> Option<SocketAddr> {
+    let ip: std::net::IpAddr = peer.ip.parse().ok()?;
+    Some(SocketAddr::new(ip, peer.port))
+}
+
+/// PEX bookkeeping for one stream.
+#[derive(Debug, Clone, Default)]
+pub struct PexState {
+    /// What the remote peer currently knows from us, by address
+    advertised: BTreeMap<SocketAddr, PexPeer>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// When the next message may be sent (`now` if it already may).
+    pub fn next_send(&self, now: Instant) -> Instant {
+        self.last_sent.map_or(now, |last| (last + PEX_INTERVAL).max(now))
+    }
+
+    /// Builds the next `Pex` message, if one is due and anything changed.
+    ///
+    /// # Arguments
+    /// * `now` - Current time
+    /// * `connected` - The peers we are connected to now, excluding the remote peer
+    ///
+    /// # Returns
+    /// The message to send; `None` if it's too early or there is no news
+    pub fn outgoing(&mut self, now: Instant, connected: &[PexPeer]) -> Option<PeerMessage> {
+        if self.next_send(now) > now {
+            return None;
+        }
+
+        let current: BTreeMap<SocketAddr, &PexPeer> = connected.iter()
+            .filter_map(|peer| Some((peer_addr(peer)?, peer)))
+            .collect();
+        // New peers, and known ones whose flags changed
+        let added: Vec<(SocketAddr, PexPeer)> = current.iter()
+            .filter(|(addr, peer)| self.advertised.get(addr) != Some(peer))
+            .take(MAX_PEX_PEERS)
+            .map(|(&addr, &peer)| (addr, peer.clone()))
+            .collect();
+        let dropped: Vec<(SocketAddr, PexPeer)> = self.advertised.iter()
+            .filter(|(addr, _)| !current.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(&addr, peer)| (addr, peer.clone()))
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for (addr, peer) in &added {
+            self.advertised.insert(*addr, peer.clone());
+        }
+        for (addr, _) in &dropped {
+            self.advertised.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(PeerMessage::Pex {
+            added: added.into_iter().map(|(_, peer)| peer).collect(),
+            dropped: dropped.into_iter().map(|(_, peer)| peer).collect(),
+        })
+    }
+
+    /// Checks an incoming `Pex` against the size and frequency limits.
+    ///
+    /// A message that breaks them is to be ignored; it doesn't count
+    /// towards the frequency limit.
+    pub fn incoming(&mut self, now: Instant, added: &[PexPeer], dropped: &[PexPeer]) -> Result<(), PexViolation> {
+        if added.len() > MAX_PEX_PEERS || dropped.len() > MAX_PEX_PEERS {
+            return Err(PexViolation::TooLarge { added: added.len(), dropped: dropped.len() });
+        }
+        if self.last_received.is_some_and(|last| now.duration_since(last) < MIN_PEX_RECEIVE_INTERVAL) {
+            return Err(PexViolation::TooFrequent);
+        }
+        self.last_received = Some(now);
+        Ok(())
+    }
+}
+
+/// Where the pool learned about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    /// An announce response
+    Tracker,
+    /// Another peer's `Pex` message
+    Pex,
+    /// The peer connected to us
+    Incoming,
+    /// A local service discovery announcement
+    Local,
+}
+
+#[derive(Debug, Clone)]
+struct PoolEntry {
+    peer: PexPeer,
+    source: PeerSource,
+    /// Open streams with this peer, in either direction
+    connections: usize,
+    /// We already tried downloading from this peer
+    attempted: bool,
+}
+
+/// The peers known for one torrent, from every source.
+#[derive(Debug, Clone)]
+pub struct PeerPool {
+    own_peer_id: String,
+    capacity: usize,
+    peers: BTreeMap<SocketAddr, PoolEntry>,
+}
+
+impl PeerPool {
+    /// Creates an empty pool; entries carrying `own_peer_id` are never added.
+    pub fn new(own_peer_id: &str) -> Self {
+        Self::with_capacity(own_peer_id, MAX_POOL_PEERS)
+    }
+
+    pub fn with_capacity(own_peer_id: &str, capacity: usize) -> Self {
+        Self {
+            own_peer_id: own_peer_id.to_string(),
+            capacity,
+            peers: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Remembers a peer.
+    ///
+    /// # Returns
+    /// `true` if the peer is new, i.e. worth a download attempt
+    pub fn add(&mut self, peer: PexPeer, source: PeerSource) -> bool {
+        if peer.peer_id.as_deref() == Some(self.own_peer_id.as_str()) {
+            return false;
+        }
+        let Some(addr) = peer_addr(&peer) else { return false };
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            // Learn the id if the first source didn't have it
+            if entry.peer.peer_id.is_none() {
+                entry.peer.peer_id = peer.peer_id;
+            }
+            return false;
+        }
+        if self.peers.len() >= self.capacity {
+            return false;
+        }
+        self.peers.insert(addr, PoolEntry { peer, source, connections: 0, attempted: false });
+        true
+    }
+
+    /// Forgets a peer another peer reported as dropped, unless we are
+    /// connected to it or heard of it elsewhere.
+    pub fn remove_dropped(&mut self, peer: &PexPeer) {
+        if let Some(addr) = peer_addr(peer) {
+            if self.peers.get(&addr).is_some_and(|e| e.source == PeerSource::Pex && e.connections == 0) {
+                self.peers.remove(&addr);
+            }
+        }
+    }
+
+    /// Records an open stream with `peer`, adding it if needed, and updates its flags.
+    ///
+    /// # Returns
+    /// `true` if the peer was new to the pool
+    pub fn connected(&mut self, peer: PexPeer, source: PeerSource) -> bool {
+        let Some(addr) = peer_addr(&peer) else { return false };
+        match self.peers.get_mut(&addr) {
+            Some(entry) => {
+                entry.connections += 1;
+                entry.peer = peer;
+                false
+            }
+            None => {
+                // Connected peers are always tracked, even past capacity
+                self.peers.insert(addr, PoolEntry { peer, source, connections: 1, attempted: false });
+                true
+            }
+        }
+    }
+
+    /// Updates the flags advertised for a connected peer.
+    pub fn set_flags(&mut self, addr: SocketAddr, flags: u8) {
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.peer.flags = flags;
+        }
+    }
+
+    /// Records that a stream with the peer at `addr` closed.
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.connections = entry.connections.saturating_sub(1);
+        }
+    }
+
+    /// Peers with at least one open stream, for `PexState::outgoing`.
+    pub fn connected_peers(&self) -> Vec<PexPeer> {
+        self.peers.values().filter(|e| e.connections > 0).map(|e| e.peer.clone()).collect()
+    }
+
+    /// Peers not handed out by `take_candidates` yet.
+    pub fn untried(&self) -> usize {
+        self.peers.values().filter(|e| !e.attempted).count()
+    }
+
+    /// Marks peers as attempted so each is handed out once.
+    ///
+    /// # Returns
+    /// Up to `max` peers we haven't tried downloading from yet
+    pub fn take_candidates(&mut self, max: usize) -> Vec<PexPeer> {
+        self.peers.values_mut()
+            .filter(|e| !e.attempted)
+            .take(max)
+            .map(|e| {
+                e.attempted = true;
+                e.peer.clone()
+            })
+            .collect()
+    }
+
+    /// Marks a peer as attempted without handing it out (e.g. it was dialled directly).
+    pub fn mark_attempted(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.attempted = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u32) -> PexPeer {
+        PexPeer { ip: format!("10.0.{}.{}", n / 250, n % 250 + 1), port: 6881, peer_id: Some(format!("peer-{}", n)), flags: 0 }
+    }
+
+    fn peers(range: std::ops::Range<u32>) -> Vec<PexPeer> {
+        range.map(peer).collect()
+    }
+
+    fn diff(message: Option<PeerMessage>) -> (Vec<PexPeer>, Vec<PexPeer>) {
+        match message {
+            Some(PeerMessage::Pex { added, dropped }) => (added, dropped),
+            other => panic!("expected a PEX message, got {:?}", other),
+        }
+    }
+
+    fn ids(peers: &[PexPeer]) -> Vec<&str> {
+        let mut ids: Vec<&str> = peers.iter().map(|p| p.peer_id.as_deref().unwrap()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn incoming_messages_closer_than_the_minimum_are_refused() {
+        let start = Instant::now();
+        let mut state = PexState::new();
+        assert_eq!(state.incoming(start, &peers(0..3), &[]), Ok(()));
+        let early = start + MIN_PEX_RECEIVE_INTERVAL - Duration::from_secs(1);
+        assert_eq!(state.incoming(early, &peers(3..4), &[]), Err(PexViolation::TooFrequent));
+        // The refused message doesn't restart the wait
+        assert_eq!(state.incoming(start + MIN_PEX_RECEIVE_INTERVAL, &peers(3..4), &[]), Ok(()));
+    }
+
+    #[test]
+    fn oversized_incoming_messages_are_refused() {
+        let start = Instant::now();
+        let mut state = PexState::new();
+        let too_many = peers(0..MAX_PEX_PEERS as u32 + 1);
+        assert_eq!(state.incoming(start, &too_many, &[]), Err(PexViolation::TooLarge { added: MAX_PEX_PEERS + 1, dropped: 0 }));
+        assert_eq!(state.incoming(start, &[], &too_many), Err(PexViolation::TooLarge { added: 0, dropped: MAX_PEX_PEERS + 1 }));
+        // Neither counted towards the frequency limit
+        assert_eq!(state.incoming(start, &too_many[..MAX_PEX_PEERS], &[]), Ok(()));
+    }
+
+    #[test]
+    fn outgoing_messages_are_truncated_and_the_rest_sent_later() {
+        let start = Instant::now();
+        let mut state = PexState::new();
+        let connected = peers(0..MAX_PEX_PEERS as u32 + 20);
+        let (added, dropped) = diff(state.outgoing(start, &connected));
+        assert_eq!((added.len(), dropped.len()), (MAX_PEX_PEERS, 0));
+
+        assert!(state.outgoing(start + PEX_INTERVAL / 2, &connected).is_none(), "sent before PEX_INTERVAL");
+        assert_eq!(state.next_send(start), start + PEX_INTERVAL);
+        let (rest, _) = diff(state.outgoing(start + PEX_INTERVAL, &connected));
+        assert_eq!(rest.len(), 20);
+        let mut all = added;
+        all.extend(rest);
+        assert_eq!(ids(&all), ids(&connected));
+    }
+
+    #[test]
+    fn outgoing_messages_carry_the_diff_since_the_last_one() {
+        let start = Instant::now();
+        let mut state = PexState::new();
+        let (added, _) = diff(state.outgoing(start, &peers(0..4)));
+        assert_eq!(ids(&added), ids(&peers(0..4)));
+
+        // Nothing changed: nothing to send
+        assert!(state.outgoing(start + PEX_INTERVAL, &peers(0..4)).is_none());
+
+        // Peer 0 left, peer 4 joined and peer 1 became a seed
+        let mut connected = peers(1..5);
+        connected[0].flags = PEX_FLAG_SEED;
+        let (added, dropped) = diff(state.outgoing(start + 2 * PEX_INTERVAL, &connected));
+        assert_eq!(ids(&added), vec!["peer-1", "peer-4"]);
+        assert_eq!(ids(&dropped), vec!["peer-0"]);
+
+        // Peers without a usable address are never advertised
+        let bad = PexPeer { ip: "not an ip".into(), ..peer(9) };
+        assert!(state.outgoing(start + 3 * PEX_INTERVAL, &[connected.clone(), vec![bad]].concat()).is_none());
+    }
+
+    #[test]
+    fn pool_is_capped_except_for_connected_peers() {
+        let mut pool = PeerPool::new("me");
+        for n in 0..MAX_POOL_PEERS as u32 + 10 {
+            pool.add(peer(n), PeerSource::Pex);
+        }
+        assert_eq!(pool.len(), MAX_POOL_PEERS);
+        assert!(!pool.add(peer(MAX_POOL_PEERS as u32 + 20), PeerSource::Tracker));
+        assert!(pool.connected(peer(MAX_POOL_PEERS as u32 + 20), PeerSource::Incoming));
+        assert_eq!(pool.len(), MAX_POOL_PEERS + 1);
+    }
+
+    #[test]
+    fn pool_skips_ourselves_duplicates_and_bad_addresses() {
+        let mut pool = PeerPool::with_capacity("me", 10);
+        assert!(!pool.add(PexPeer { peer_id: Some("me".into()), ..peer(0) }, PeerSource::Pex));
+        assert!(!pool.add(PexPeer { ip: "nowhere".into(), ..peer(1) }, PeerSource::Pex));
+        assert!(pool.add(PexPeer { peer_id: None, ..peer(2) }, PeerSource::Tracker));
+        // A second source only fills in the missing id
+        assert!(!pool.add(peer(2), PeerSource::Pex));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take_candidates(1)[0].peer_id.as_deref(), Some("peer-2"));
+    }
+
+    #[test]
+    fn candidates_are_handed_out_once() {
+        let mut pool = PeerPool::new("me");
+        for n in 0..5 {
+            pool.add(peer(n), PeerSource::Tracker);
+        }
+        pool.mark_attempted(peer_addr(&peer(2)).unwrap());
+        assert_eq!(pool.untried(), 4);
+
+        let first = pool.take_candidates(3);
+        let second = pool.take_candidates(3);
+        assert_eq!((first.len(), second.len()), (3, 1));
+        let all = [first, second].concat();
+        assert_eq!(ids(&all), vec!["peer-0", "peer-1", "peer-3", "peer-4"]);
+        assert!(pool.take_candidates(3).is_empty());
+        assert_eq!(pool.untried(), 0);
+
+        // Re-adding a known peer doesn't make it a candidate again
+        assert!(!pool.add(peer(0), PeerSource::Pex));
+        assert!(pool.take_candidates(3).is_empty());
+    }
+
+    #[test]
+    fn dropped_peers_are_forgotten_only_if_pex_was_the_only_source() {
+        let mut pool = PeerPool::new("me");
+        pool.add(peer(0), PeerSource::Pex);
+        pool.add(peer(1), PeerSource::Tracker);
+        pool.add(peer(2), PeerSource::Pex);
+        pool.connected(peer(2), PeerSource::Pex);
+        for n in 0..3 {
+            pool.remove_dropped(&peer(n));
+        }
+        assert_eq!(pool.len(), 2);
+        assert_eq!(ids(&pool.connected_peers()), vec!["peer-2"]);
+        pool.disconnected(peer_addr(&peer(2)).unwrap());
+        assert!(pool.connected_peers().is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+//! # Piece Picker
+//!
+//! Decides which blocks to request from which peer.
+//!
+//! Pieces are split into `BLOCK_SIZE` blocks. The picker tracks how many
+//! connected peers have each piece (from bitfields and `have` messages) and
+//! hands out blocks in this order:
+//!
+//! 1. Missing blocks of pieces that are already partly downloaded, so
+//!    started pieces complete (and can be verified and shared) quickly.
+//! 2. Blocks of a new piece: the rarest one the peer has, ties broken at
+//!    random (`PickMode::RarestFirst`), or the lowest index
+//!    (`PickMode::Sequential`, for streaming).
+//! 3. Endgame: once every missing block has been requested, blocks already
+//!    requested from other peers are requested again. When one copy arrives
+//!    `block_received` names the other peers, so their requests can be
+//!    cancelled.
+//!
+//! Peers are identified by a caller-chosen `usize`. The picker does no I/O.
+
+use crate::peer_state::BlockRequest;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+
+/// Size of a requested block (the last block of a piece may be shorter).
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// How pieces are ordered when starting a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickMode {
+    /// Rarest piece first, random among equally rare pieces
+    RarestFirst,
+    /// Lowest piece index first, for playing a file while it downloads
+    Sequential,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    /// Requested from these peers (more than one only in endgame)
+    Requested(Vec<usize>),
+    Received,
+}
+
+/// Picks blocks to request, rarest-first with endgame.
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    mode: PickMode,
+    have: Vec<bool>,
+    availability: Vec<u32>,
+    partial: BTreeMap<u32, Vec<BlockState>>,
+    rng: StdRng,
+}
+
+impl PiecePicker {
+    /// Creates a picker for a torrent, skipping pieces already in `have`.
+    pub fn new(have: Vec<bool>, piece_length: u32, total_length: u64, mode: PickMode) -> Self {
+        Self::with_rng(have, piece_length, total_length, mode, StdRng::from_entropy())
+    }
+
+    /// Like `new`, with a fixed seed for reproducible tie-breaking.
+    pub fn with_seed(have: Vec<bool>, piece_length: u32, total_length: u64, mode: PickMode, seed: u64) -> Self {
+        Self::with_rng(have, piece_length, total_length, mode, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(have: Vec<bool>, piece_length: u32, total_length: u64, mode: PickMode, rng: StdRng) -> Self {
+        Self {
+            availability: vec![0; have.len()],
+            have,
+            piece_length,
+            total_length,
+            mode,
+            partial: BTreeMap::new(),
+            rng,
+        }
+    }
+
+    pub fn mode(&self) -> PickMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PickMode) {
+        self.mode = mode;
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+
+    /// Number of connected peers known to have piece `index`.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|&h| h)
+    }
+
+    /// True once no missing block is left unrequested.
+    pub fn in_endgame(&self) -> bool {
+        !self.is_complete() && !self.has_unrequested_blocks()
+    }
+
+    /// Counts a peer's bitfield towards piece availability.
+    pub fn add_peer(&mut self, peer_pieces: &[bool]) {
+        for (count, _) in self.availability.iter_mut().zip(peer_pieces).filter(|(_, &has)| has) {
+            *count += 1;
+        }
+    }
+
+    /// Counts a `have` message towards piece availability.
+    pub fn add_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Undoes a `have`, e.g. when a peer rejects a request for the piece.
+    pub fn remove_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Forgets a departed peer: removes its pieces from the availability
+    /// counts and returns its outstanding blocks to the pool.
+    pub fn remove_peer(&mut self, peer: usize, peer_pieces: &[bool]) {
+        for (count, _) in self.availability.iter_mut().zip(peer_pieces).filter(|(_, &has)| has) {
+            *count = count.saturating_sub(1);
+        }
+        let blocks: Vec<BlockRequest> = self.requested_from(peer);
+        for block in blocks {
+            self.release(peer, block);
+        }
+    }
+
+    /// True while any block is requested and hasn't arrived.
+    pub fn has_outstanding(&self) -> bool {
+        self.partial.values().flatten().any(|s| matches!(s, BlockState::Requested(_)))
+    }
+
+    /// Blocks currently requested from `peer`.
+    pub fn requested_from(&self, peer: usize) -> Vec<BlockRequest> {
+        self.partial.iter()
+            .flat_map(|(&index, blocks)| blocks.iter().enumerate().filter_map(move |(b, state)| match state {
+                BlockState::Requested(peers) if peers.contains(&peer) => Some(self.block(index, b)),
+                _ => None,
+            }))
+            .collect()
+    }
+
+    /// Picks up to `max` blocks to request from `peer`, marking them requested.
+    pub fn pick(&mut self, peer: usize, peer_pieces: &[bool], max: usize) -> Vec<BlockRequest> {
+        let mut picked = Vec::new();
+        let has = |index: u32| peer_pieces.get(index as usize).copied().unwrap_or(false);
+
+        // 1. Finish pieces that are already under way
+        let started: Vec<u32> = self.partial.keys().copied().filter(|&i| has(i)).collect();
+        for index in started {
+            self.take_missing(peer, index, max, &mut picked);
+        }
+
+        // 2. Start new pieces
+        while picked.len() < max {
+            let Some(index) = self.choose_new_piece(peer_pieces) else { break };
+            let blocks = self.block_count(index);
+            self.partial.insert(index, vec![BlockState::Missing; blocks]);
+            self.take_missing(peer, index, max, &mut picked);
+        }
+
+        // 3. Endgame: duplicate blocks already requested from other peers
+        if picked.len() < max && !self.has_unrequested_blocks() {
+            let indices: Vec<u32> = self.partial.keys().copied().filter(|&i| has(i)).collect();
+            'pieces: for index in indices {
+                let blocks = self.partial.get_mut(&index).unwrap();
+                for (b, state) in blocks.iter_mut().enumerate() {
+                    if picked.len() >= max {
+                        break 'pieces;
+                    }
+                    if let BlockState::Requested(peers) = state {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                            picked.push((index, b));
+                        }
+                    }
+                }
+            }
+        }
+
+        picked.into_iter().map(|(index, b)| self.block(index, b)).collect()
+    }
+
+    /// Records an arrived block.
+    ///
+    /// # Returns
+    /// The other peers the block was also requested from (send them a cancel)
+    pub fn block_received(&mut self, peer: usize, block: BlockRequest) -> Vec<usize> {
+        let Some(state) = self.block_state(block) else { return Vec::new() };
+        match std::mem::replace(state, BlockState::Received) {
+            BlockState::Requested(peers) => peers.into_iter().filter(|&p| p != peer).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// True if `block` still needs to arrive (false once received or its piece is done).
+    pub fn is_wanted(&self, block: BlockRequest) -> bool {
+        match self.partial.get(&block.index) {
+            Some(blocks) => blocks.get(self.block_offset_index(block)) != Some(&BlockState::Received),
+            None => !self.have.get(block.index as usize).copied().unwrap_or(true),
+        }
+    }
+
+    /// True once every block of piece `index` has arrived and it can be verified.
+    pub fn piece_ready(&self, index: u32) -> bool {
+        self.partial.get(&index).is_some_and(|blocks| blocks.iter().all(|s| *s == BlockState::Received))
+    }
+
+    /// Marks a piece verified and written.
+    pub fn piece_verified(&mut self, index: u32) {
+        self.partial.remove(&index);
+        if let Some(have) = self.have.get_mut(index as usize) {
+            *have = true;
+        }
+    }
+
+    /// Marks a piece that failed its hash check for download from scratch.
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+    }
+
+    /// Returns a block requested from `peer` to the pool (rejected, choked or disconnected).
+    pub fn release(&mut self, peer: usize, block: BlockRequest) {
+        if let Some(state) = self.block_state(block) {
+            if let BlockState::Requested(peers) = state {
+                peers.retain(|&p| p != peer);
+                if peers.is_empty() {
+                    *state = BlockState::Missing;
+                }
+            }
+        }
+    }
+
+    fn take_missing(&mut self, peer: usize, index: u32, max: usize, picked: &mut Vec<(u32, usize)>) {
+        if let Some(blocks) = self.partial.get_mut(&index) {
+            for (b, state) in blocks.iter_mut().enumerate() {
+                if picked.len() >= max {
+                    return;
+                }
+                if *state == BlockState::Missing {
+                    *state = BlockState::Requested(vec![peer]);
+                    picked.push((index, b));
+                }
+            }
+        }
+    }
+
+    fn choose_new_piece(&mut self, peer_pieces: &[bool]) -> Option<u32> {
+        let candidates = (0..self.have.len())
+            .filter(|&i| !self.have[i] && peer_pieces.get(i).copied().unwrap_or(false) && !self.partial.contains_key(&(i as u32)));
+
+        match self.mode {
+            PickMode::Sequential => candidates.min().map(|i| i as u32),
+            PickMode::RarestFirst => {
+                let candidates: Vec<usize> = candidates.collect();
+                let rarest = candidates.iter().map(|&i| self.availability[i]).min()?;
+                let tied: Vec<usize> = candidates.into_iter().filter(|&i| self.availability[i] == rarest).collect();
+                Some(tied[self.rng.gen_range(0..tied.len())] as u32)
+            }
+        }
+    }
+
+    fn has_unrequested_blocks(&self) -> bool {
+        let untouched = (0..self.have.len()).any(|i| !self.have[i] && !self.partial.contains_key(&(i as u32)));
+        untouched || self.partial.values().flatten().any(|s| *s == BlockState::Missing)
+    }
+
+    fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        (self.piece_length as u64).min(self.total_length.saturating_sub(start)) as u32
+    }
+
+    fn block_count(&self, index: u32) -> usize {
+        self.piece_size(index).div_ceil(BLOCK_SIZE) as usize
+    }
+
+    fn block(&self, index: u32, b: usize) -> BlockRequest {
+        let begin = b as u32 * BLOCK_SIZE;
+        BlockRequest::new(index, begin, BLOCK_SIZE.min(self.piece_size(index) - begin))
+    }
+
+    fn block_offset_index(&self, block: BlockRequest) -> usize {
+        (block.begin / BLOCK_SIZE) as usize
+    }
+
+    fn block_state(&mut self, block: BlockRequest) -> Option<&mut BlockState> {
+        let b = self.block_offset_index(block);
+        if !block.begin.is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+        self.partial.get_mut(&block.index)?.get_mut(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A picker for `pieces` pieces of `blocks` full blocks each.
+    fn picker(pieces: usize, blocks: u32, mode: PickMode, seed: u64) -> PiecePicker {
+        let piece_length = blocks * BLOCK_SIZE;
+        PiecePicker::with_seed(vec![false; pieces], piece_length, pieces as u64 * piece_length as u64, mode, seed)
+    }
+
+    fn indices(blocks: &[BlockRequest]) -> Vec<u32> {
+        blocks.iter().map(|b| b.index).collect()
+    }
+
+    #[test]
+    fn rarest_piece_is_started_first() {
+        let mut picker = picker(4, 1, PickMode::RarestFirst, 1);
+        picker.add_peer(&[true, true, true, true]);
+        picker.add_peer(&[true, true, false, true]);
+        picker.add_peer(&[true, false, false, true]);
+        picker.add_have(3);
+        // Availability: 3, 2, 1, 4
+        let all = [true; 4];
+        assert_eq!(indices(&picker.pick(0, &all, 4)), vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn rarest_first_only_picks_pieces_the_peer_has() {
+        let mut picker = picker(3, 1, PickMode::RarestFirst, 1);
+        picker.add_peer(&[false, true, true]);
+        assert_eq!(indices(&picker.pick(0, &[true, false, false], 3)), vec![0]);
+        assert!(picker.pick(0, &[true, false, false], 3).is_empty());
+    }
+
+    #[test]
+    fn ties_are_broken_by_the_seed() {
+        let order = |seed| indices(&picker(8, 1, PickMode::RarestFirst, seed).pick(0, &[true; 8], 8));
+        assert_eq!(order(7), order(7));
+        let mut sorted = order(7);
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<u32>>());
+        assert!((0..10).map(order).any(|o| o != order(7)), "every seed gave the same order");
+    }
+
+    #[test]
+    fn sequential_ignores_availability() {
+        let mut picker = picker(4, 1, PickMode::Sequential, 1);
+        picker.add_peer(&[true, true, false, true]);
+        assert_eq!(indices(&picker.pick(0, &[true; 4], 4)), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn started_pieces_are_finished_first() {
+        let mut picker = picker(3, 2, PickMode::Sequential, 1);
+        let all = [true; 3];
+        let first = picker.pick(0, &all, 1);
+        assert_eq!(first, vec![BlockRequest::new(0, 0, BLOCK_SIZE)]);
+        // Another peer gets the rest of piece 0 before piece 1
+        assert_eq!(picker.pick(1, &all, 2), vec![BlockRequest::new(0, BLOCK_SIZE, BLOCK_SIZE), BlockRequest::new(1, 0, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn last_block_is_short() {
+        let mut picker = PiecePicker::with_seed(vec![false; 2], 2 * BLOCK_SIZE, 3 * BLOCK_SIZE as u64 + 100, PickMode::Sequential, 1);
+        let picked = picker.pick(0, &[true, true], 4);
+        assert_eq!(picked.last(), Some(&BlockRequest::new(1, BLOCK_SIZE, 100)));
+        assert_eq!(picked.len(), 4);
+    }
+
+    #[test]
+    fn endgame_requests_blocks_again_and_names_peers_to_cancel() {
+        let mut picker = picker(1, 2, PickMode::RarestFirst, 1);
+        let all = [true];
+        let blocks = picker.pick(0, &all, 2);
+        assert_eq!(blocks.len(), 2);
+        assert!(picker.in_endgame());
+
+        // Every block is out, so a second peer gets duplicates; a third too
+        assert_eq!(picker.pick(1, &all, 4), blocks);
+        assert_eq!(picker.pick(2, &all, 1), vec![blocks[0]]);
+        // Never the same block twice to one peer
+        assert!(picker.pick(1, &all, 4).is_empty());
+
+        let mut others = picker.block_received(1, blocks[0]);
+        others.sort();
+        assert_eq!(others, vec![0, 2]);
+        assert!(!picker.is_wanted(blocks[0]));
+        // A late copy is a duplicate with no one left to cancel
+        assert!(picker.block_received(0, blocks[0]).is_empty());
+        assert_eq!(picker.block_received(0, blocks[1]), vec![1]);
+        assert!(picker.piece_ready(0));
+    }
+
+    #[test]
+    fn block_received_without_duplicates_cancels_nothing() {
+        let mut picker = picker(2, 1, PickMode::Sequential, 1);
+        let blocks = picker.pick(0, &[true, true], 1);
+        assert!(picker.block_received(0, blocks[0]).is_empty());
+        assert!(picker.piece_ready(0));
+        picker.piece_verified(0);
+        assert!(!picker.is_wanted(blocks[0]));
+        assert!(!picker.in_endgame());
+    }
+
+    #[test]
+    fn failed_piece_is_picked_again() {
+        let mut picker = picker(1, 2, PickMode::Sequential, 1);
+        let blocks = picker.pick(0, &[true], 2);
+        for block in &blocks {
+            picker.block_received(0, *block);
+        }
+        picker.piece_failed(0);
+        assert!(picker.is_wanted(blocks[0]));
+        assert_eq!(picker.pick(1, &[true], 2), blocks);
+    }
+
+    #[test]
+    fn released_block_is_picked_again() {
+        let mut picker = picker(1, 2, PickMode::Sequential, 1);
+        let blocks = picker.pick(0, &[true], 2);
+        picker.release(0, blocks[1]);
+        assert_eq!(picker.requested_from(0), vec![blocks[0]]);
+        assert_eq!(picker.pick(1, &[true], 1), vec![blocks[1]]);
+
+        // Releasing one of two requesters keeps the block requested
+        picker.pick(2, &[true], 2);
+        picker.release(1, blocks[1]);
+        assert!(picker.requested_from(1).is_empty());
+        assert_eq!(picker.requested_from(2), blocks);
+        assert_eq!(picker.pick(3, &[true], 2), blocks, "endgame still duplicates both blocks");
+    }
+
+    #[test]
+    fn removed_peer_returns_its_blocks_and_availability() {
+        let mut picker = picker(2, 1, PickMode::RarestFirst, 1);
+        let pieces = [true, true];
+        picker.add_peer(&pieces);
+        let blocks = picker.pick(0, &pieces, 2);
+        assert!(picker.has_outstanding());
+
+        picker.remove_peer(0, &pieces);
+        assert_eq!(picker.availability(0), 0);
+        assert!(!picker.has_outstanding());
+        assert!(!picker.in_endgame());
+        let mut again = picker.pick(1, &pieces, 2);
+        again.sort_by_key(|b| b.index);
+        let mut blocks = blocks;
+        blocks.sort_by_key(|b| b.index);
+        assert_eq!(again, blocks);
+    }
+
+    #[test]
+    fn pieces_already_had_are_skipped() {
+        let mut picker = PiecePicker::with_seed(vec![true, false, true], BLOCK_SIZE, 3 * BLOCK_SIZE as u64, PickMode::Sequential, 1);
+        assert_eq!(indices(&picker.pick(0, &[true; 3], 3)), vec![1]);
+        assert!(!picker.is_wanted(BlockRequest::new(0, 0, BLOCK_SIZE)));
+        picker.block_received(0, BlockRequest::new(1, 0, BLOCK_SIZE));
+        picker.piece_verified(1);
+        assert!(picker.is_complete());
+    }
+}
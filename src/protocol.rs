@@ -0,0 +1,1025 @@
+//! # Message Envelope
+//!
+//! Requests and responses travel wrapped in a versioned envelope naming
+//! their type:
+//!
+//! ```json
+//! { "v": 1, "type": "file", "id": "5f3a9c-7", "body": { "file": "hello_world.txt", ... } }
+//! ```
+//!
+//! The server routes on `type` alone and answers with an envelope of the
+//! matching response type (or `error`) carrying the same `id`.
+//!
+//! The `id` is the request id (see `new_request_id`): unique across
+//! processes, so the client's and server's log lines for a request can be
+//! joined on it. The server assigns one to requests that come without it
+//! and echoes that instead.
+//!
+//! A request may also carry `timeout_ms`, the time the client will wait
+//! for it. The server stops working on it once that (or its own limit) has
+//! passed and answers with a `TIMEOUT` error; work it hands on to other
+//! nodes gets the time remaining (see `with_deadline`). Requests larger
+//! than their type allows (`MessageKind::max_request_size`) are refused
+//! with `REQUEST_TOO_LARGE`.
+//!
+//! Older clients send the bare message (no `type`/`body`). Those are still
+//! accepted: the kind is picked from the message's top-level keys (see
+//! `legacy_kind`) and the reply is sent bare as well, so they see no change.
+//!
+//! A client opens each connection with a `hello` request listing the
+//! protocol versions, message types, codecs and features it supports; the
+//! server answers with its own lists and the version both will speak, or an
+//! `UNSUPPORTED_VERSION` error if their version ranges don't overlap.
+//!
+//! The handshake also picks the codec for the rest of the connection: the
+//! first of the client's codecs the server supports (`HelloResponse::codec`).
+//! JSON is the default and what the `hello` itself and legacy messages use;
+//! MessagePack (`msgpack`) is the compact alternative, which matters for
+//! file data and large peer lists.
+//!
+//! A `subscribe` request on a framed stream turns the stream over to the
+//! server: after the `subscribe_response` it pushes `event` messages with
+//! the request's id for as long as the client keeps reading.
+//!
+//! Servers may handle request types beyond the built-in ones (see
+//! `router`); those travel in the same envelope, named by their `type`.
+//! A request may carry an `auth` token for servers that require one.
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use crate::messages::{
+    AiRequest, AiResponse, AiToken, ErrorResponse, FileRequest, FileResponse, Hello, HelloResponse, ListFilesRequest,
+    ListFilesResponse, PublishRequest, PublishResponse, ServerEvent, SubscribeRequest, SubscribeResponse,
+    TrackerAnnounceRequest, TrackerAnnounceResponse,
+};
+
+/// Envelope version sent by this implementation; newer versions are refused.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this implementation still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The JSON codec, supported by every version.
+pub const CODEC_JSON: &str = "json";
+
+/// The MessagePack codec (`rmp-serde`, structs encoded as maps).
+pub const CODEC_MSGPACK: &str = "msgpack";
+
+/// Local AI processing of `ai` requests.
+pub const FEATURE_AI: &str = "ai";
+
+/// Delegation of `ai` requests to other nodes.
+pub const FEATURE_WORK_DISTRIBUTION: &str = "work_distribution";
+
+/// Uploads with `publish` requests.
+pub const FEATURE_PUBLISH: &str = "publish";
+
+/// Torrents generated for (and seeded from) the seed directory.
+pub const FEATURE_AUTO_TORRENTS: &str = "auto_torrents";
+
+/// Length-prefixed framed streams (see `framing`).
+pub const FEATURE_FRAMED_STREAMS: &str = "framed_streams";
+
+/// Event subscriptions (`subscribe` requests; see `events`).
+pub const FEATURE_SUBSCRIPTIONS: &str = "subscriptions";
+
+/// Requests must carry the server's `auth` token (`hello` excepted).
+pub const FEATURE_AUTH: &str = "auth";
+
+/// Largest request of any type; a server stops reading a request past this
+/// before it even knows the type.
+pub const MAX_REQUEST_SIZE: usize = MAX_PUBLISH_REQUEST_SIZE;
+
+/// Largest `publish` request: a chunk of file data, which JSON spells out
+/// as an array of numbers (up to four bytes per byte).
+const MAX_PUBLISH_REQUEST_SIZE: usize = 8 * 1024 * 1024;
+
+/// Largest `ai` request (query and context).
+const MAX_AI_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Largest request of the other types, which carry a few short fields (and
+/// of types added with a `router::Handler`, unless it says otherwise).
+pub const MAX_SMALL_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Every request and response type, as named in the envelope's `type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Hello,
+    Announce,
+    File,
+    ListFiles,
+    Publish,
+    Ai,
+    Subscribe,
+    HelloResponse,
+    AnnounceResponse,
+    FileResponse,
+    ListFilesResponse,
+    PublishResponse,
+    AiResponse,
+    SubscribeResponse,
+    AiToken,
+    Event,
+    Error,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 17] = [
+        MessageKind::Hello,
+        MessageKind::Announce,
+        MessageKind::File,
+        MessageKind::ListFiles,
+        MessageKind::Publish,
+        MessageKind::Ai,
+        MessageKind::Subscribe,
+        MessageKind::HelloResponse,
+        MessageKind::AnnounceResponse,
+        MessageKind::FileResponse,
+        MessageKind::ListFilesResponse,
+        MessageKind::PublishResponse,
+        MessageKind::AiResponse,
+        MessageKind::SubscribeResponse,
+        MessageKind::AiToken,
+        MessageKind::Event,
+        MessageKind::Error,
+    ];
+
+    /// The name used in the envelope's `type`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKind::Hello => "hello",
+            MessageKind::Announce => "announce",
+            MessageKind::File => "file",
+            MessageKind::ListFiles => "list_files",
+            MessageKind::Publish => "publish",
+            MessageKind::Ai => "ai",
+            MessageKind::Subscribe => "subscribe",
+            MessageKind::HelloResponse => "hello_response",
+            MessageKind::AnnounceResponse => "announce_response",
+            MessageKind::FileResponse => "file_response",
+            MessageKind::ListFilesResponse => "list_files_response",
+            MessageKind::PublishResponse => "publish_response",
+            MessageKind::AiResponse => "ai_response",
+            MessageKind::SubscribeResponse => "subscribe_response",
+            MessageKind::AiToken => "ai_token",
+            MessageKind::Event => "event",
+            MessageKind::Error => "error",
+        }
+    }
+
+    /// Looks up a kind by its envelope name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == name)
+    }
+
+    /// The Rust type carried in the body, as shown in logs.
+    pub fn type_name(self) -> &'static str {
+        match self {
+            MessageKind::Hello => "Hello",
+            MessageKind::Announce => "TrackerAnnounceRequest",
+            MessageKind::File => "FileRequest",
+            MessageKind::ListFiles => "ListFilesRequest",
+            MessageKind::Publish => "PublishRequest",
+            MessageKind::Ai => "AiRequest",
+            MessageKind::Subscribe => "SubscribeRequest",
+            MessageKind::HelloResponse => "HelloResponse",
+            MessageKind::AnnounceResponse => "TrackerAnnounceResponse",
+            MessageKind::FileResponse => "FileResponse",
+            MessageKind::ListFilesResponse => "ListFilesResponse",
+            MessageKind::PublishResponse => "PublishResponse",
+            MessageKind::AiResponse => "AiResponse",
+            MessageKind::SubscribeResponse => "SubscribeResponse",
+            MessageKind::AiToken => "AiToken",
+            MessageKind::Event => "ServerEvent",
+            MessageKind::Error => "ErrorResponse",
+        }
+    }
+
+    pub fn is_request(self) -> bool {
+        self.response().is_some()
+    }
+
+    /// The response kind answering a request kind (`None` for responses).
+    pub fn response(self) -> Option<Self> {
+        match self {
+            MessageKind::Hello => Some(MessageKind::HelloResponse),
+            MessageKind::Announce => Some(MessageKind::AnnounceResponse),
+            MessageKind::File => Some(MessageKind::FileResponse),
+            MessageKind::ListFiles => Some(MessageKind::ListFilesResponse),
+            MessageKind::Publish => Some(MessageKind::PublishResponse),
+            MessageKind::Ai => Some(MessageKind::AiResponse),
+            MessageKind::Subscribe => Some(MessageKind::SubscribeResponse),
+            _ => None,
+        }
+    }
+
+    /// The server module handling a request kind, as shown in logs.
+    pub fn module(self) -> &'static str {
+        match self {
+            MessageKind::Hello => "Handshake",
+            MessageKind::Announce => "Tracker Module",
+            MessageKind::File | MessageKind::ListFiles => "File Serving Module",
+            MessageKind::Publish => "File Publishing Module",
+            MessageKind::Ai => "AI Processing Module",
+            MessageKind::Subscribe => "Event Subscriptions",
+            _ => "Client",
+        }
+    }
+
+    /// The server function handling a request kind, as shown in logs.
+    pub fn handler(self) -> &'static str {
+        match self {
+            MessageKind::Hello => "quic_tracker::handle_hello()",
+            MessageKind::Announce => "quic_tracker::handle_announce_request()",
+            MessageKind::File => "quic_tracker::handle_file_request()",
+            MessageKind::ListFiles => "quic_tracker::handle_list_files_request()",
+            MessageKind::Publish => "quic_tracker::handle_publish_request()",
+            MessageKind::Ai => "quic_tracker::handle_ai_request() -> ai_processor::process_query_sync()",
+            MessageKind::Subscribe => "quic_tracker::handle_subscribe_request()",
+            _ => "none",
+        }
+    }
+
+    /// False for messages whose payload must stay out of the logs (the
+    /// publish token and raw file data).
+    pub fn loggable(self) -> bool {
+        !matches!(self, MessageKind::Publish)
+    }
+
+    /// Largest request of this type a server accepts, in encoded bytes
+    /// (envelope included).
+    pub fn max_request_size(self) -> usize {
+        match self {
+            MessageKind::Publish => MAX_PUBLISH_REQUEST_SIZE,
+            MessageKind::Ai => MAX_AI_REQUEST_SIZE,
+            _ => MAX_SMALL_REQUEST_SIZE,
+        }
+    }
+}
+
+impl std::fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A message type that can travel in an envelope.
+pub trait Message: Serialize + DeserializeOwned {
+    const KIND: MessageKind;
+}
+
+impl Message for Hello {
+    const KIND: MessageKind = MessageKind::Hello;
+}
+
+impl Message for HelloResponse {
+    const KIND: MessageKind = MessageKind::HelloResponse;
+}
+
+impl Message for TrackerAnnounceRequest {
+    const KIND: MessageKind = MessageKind::Announce;
+}
+
+impl Message for FileRequest {
+    const KIND: MessageKind = MessageKind::File;
+}
+
+impl Message for ListFilesRequest {
+    const KIND: MessageKind = MessageKind::ListFiles;
+}
+
+impl Message for PublishRequest {
+    const KIND: MessageKind = MessageKind::Publish;
+}
+
+impl Message for AiRequest {
+    const KIND: MessageKind = MessageKind::Ai;
+}
+
+impl Message for TrackerAnnounceResponse {
+    const KIND: MessageKind = MessageKind::AnnounceResponse;
+}
+
+impl Message for FileResponse {
+    const KIND: MessageKind = MessageKind::FileResponse;
+}
+
+impl Message for ListFilesResponse {
+    const KIND: MessageKind = MessageKind::ListFilesResponse;
+}
+
+impl Message for PublishResponse {
+    const KIND: MessageKind = MessageKind::PublishResponse;
+}
+
+impl Message for AiResponse {
+    const KIND: MessageKind = MessageKind::AiResponse;
+}
+
+impl Message for AiToken {
+    const KIND: MessageKind = MessageKind::AiToken;
+}
+
+impl Message for SubscribeRequest {
+    const KIND: MessageKind = MessageKind::Subscribe;
+}
+
+impl Message for SubscribeResponse {
+    const KIND: MessageKind = MessageKind::SubscribeResponse;
+}
+
+impl Message for ServerEvent {
+    const KIND: MessageKind = MessageKind::Event;
+}
+
+impl Message for ErrorResponse {
+    const KIND: MessageKind = MessageKind::Error;
+}
+
+/// How envelopes are encoded on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Codec {
+    /// Every codec this implementation supports, preferred first.
+    pub const ALL: [Codec; 2] = [Codec::MessagePack, Codec::Json];
+
+    /// The codec's name in `Hello::codecs`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::Json => CODEC_JSON,
+            Codec::MessagePack => CODEC_MSGPACK,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.as_str() == name)
+    }
+
+    /// The codec for a connection: the first of the client's codecs (in its
+    /// order of preference) that this implementation supports, else JSON.
+    pub fn negotiate(client_codecs: &[String]) -> Self {
+        client_codecs.iter()
+            .find_map(|name| Self::from_name(name))
+            .unwrap_or_default()
+    }
+
+    /// The codec the server picked in its `HelloResponse` (JSON for servers
+    /// predating codec negotiation).
+    pub fn from_hello(hello: &HelloResponse) -> Self {
+        hello.codec.as_deref().and_then(Self::from_name).unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(CodecError::from),
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(CodecError::from),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(CodecError::from),
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(CodecError::from),
+        }
+    }
+
+    /// `bytes` as JSON text, for logs (binary codecs are transcoded).
+    pub fn to_log_string(self, bytes: &[u8]) -> String {
+        match self {
+            Codec::Json => String::from_utf8_lossy(bytes).into_owned(),
+            Codec::MessagePack => match rmp_serde::from_slice::<serde_json::Value>(bytes) {
+                Ok(value) => value.to_string(),
+                Err(_) => format!("<{} bytes of invalid {}>", bytes.len(), self),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A message that couldn't be encoded or decoded with a `Codec`.
+#[derive(Debug)]
+pub struct CodecError(String);
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CodecError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CodecError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+/// A message wrapped for the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<B> {
+    /// Envelope version (`PROTOCOL_VERSION`)
+    pub v: u32,
+    #[serde(rename = "type")]
+    pub kind: MessageKind,
+    /// The request id; echoed by the server, which assigns one if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Milliseconds the client will wait for the response (requests only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Token for servers that require one (requests only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
+    pub body: B,
+}
+
+/// An envelope for a type outside `MessageKind` (see `ReplyStyle::encode_as`).
+#[derive(Serialize)]
+struct NamedEnvelope<'a, B> {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    body: &'a B,
+}
+
+/// The envelope fields, read without decoding the body.
+#[derive(Deserialize)]
+struct EnvelopeHeader {
+    v: Option<u32>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    id: Option<String>,
+    timeout_ms: Option<u64>,
+    auth: Option<String>,
+    body: Option<IgnoredAny>,
+}
+
+/// Just the body of an envelope.
+#[derive(Deserialize)]
+struct EnvelopeBody<B> {
+    body: B,
+}
+
+/// A new request id: a random per-process prefix and a counter
+/// (`"5f3a9c-7"`), so ids from different clients and servers don't collide.
+pub fn new_request_id() -> String {
+    static PREFIX: OnceLock<String> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let prefix = PREFIX.get_or_init(|| format!("{:06x}", rand::random::<u32>() & 0xff_ffff));
+    format!("{}-{}", prefix, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+tokio::task_local! {
+    /// When the request the current task is handling must be answered by.
+    static DEADLINE: tokio::time::Instant;
+}
+
+/// Runs `future` as part of a request that must be answered by `deadline`,
+/// so requests it sends on ask for no more than the time remaining.
+pub async fn with_deadline<F: std::future::Future>(deadline: tokio::time::Instant, future: F) -> F::Output {
+    DEADLINE.scope(deadline, future).await
+}
+
+/// Time left until the deadline of the request the current task is
+/// handling, if it has one (zero once it has passed).
+pub fn remaining_time() -> Option<std::time::Duration> {
+    DEADLINE.try_with(|deadline| deadline.saturating_duration_since(tokio::time::Instant::now())).ok()
+}
+
+/// The newest version two ranges have in common.
+///
+/// # Returns
+/// `None` if the ranges `[min_a, max_a]` and `[min_b, max_b]` don't overlap
+pub fn negotiate_version(min_a: u32, max_a: u32, min_b: u32, max_b: u32) -> Option<u32> {
+    let version = max_a.min(max_b);
+    (version >= min_a.max(min_b)).then_some(version)
+}
+
+/// Envelope names of the request types this implementation handles.
+pub fn request_types() -> Vec<String> {
+    MessageKind::ALL.into_iter()
+        .filter(|kind| kind.is_request())
+        .map(|kind| kind.as_str().to_string())
+        .collect()
+}
+
+/// This implementation's name and version, as sent in `Hello::agent`.
+pub fn agent() -> String {
+    format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// The `Hello` a client of this implementation sends.
+///
+/// # Arguments
+/// * `codec` - The codec the client would like to use; JSON is always
+///   offered as well
+pub fn client_hello(codec: Codec) -> Hello {
+    let mut codecs = vec![codec.as_str().to_string()];
+    if codec != Codec::Json {
+        codecs.push(CODEC_JSON.to_string());
+    }
+    Hello {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        message_types: MessageKind::ALL.iter().map(|kind| kind.as_str().to_string()).collect(),
+        codecs,
+        features: Vec::new(),
+        agent: Some(agent()),
+    }
+}
+
+/// How a request arrived, and so how it must be answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplyStyle {
+    /// A bare message from an older client; answered bare (always JSON)
+    Legacy,
+    /// An envelope; answered with an envelope echoing `id`, in the codec
+    /// the request arrived in
+    Envelope { id: Option<String>, codec: Codec },
+}
+
+impl ReplyStyle {
+    /// The request id, if the request carried one.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            ReplyStyle::Legacy => None,
+            ReplyStyle::Envelope { id, .. } => id.as_deref(),
+        }
+    }
+
+    /// The codec replies are encoded with.
+    pub fn codec(&self) -> Codec {
+        match self {
+            ReplyStyle::Legacy => Codec::Json,
+            ReplyStyle::Envelope { codec, .. } => *codec,
+        }
+    }
+
+    /// Encodes a reply in this style.
+    pub fn encode<T: Message>(&self, message: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            ReplyStyle::Legacy => Codec::Json.encode(message),
+            ReplyStyle::Envelope { id, codec } => encode(message, id.clone(), *codec),
+        }
+    }
+
+    /// Encodes a reply of a type outside `MessageKind`, named `type_name`
+    /// in the envelope.
+    pub fn encode_as<T: Serialize>(&self, type_name: &str, message: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            ReplyStyle::Legacy => Codec::Json.encode(message),
+            ReplyStyle::Envelope { id, codec } => codec.encode(&NamedEnvelope { v: PROTOCOL_VERSION, kind: type_name, id: id.clone(), body: message }),
+        }
+    }
+
+    /// Decodes the body of a request that arrived in this style (the whole
+    /// message, for a legacy request) as a `B`.
+    pub fn decode_body<B: DeserializeOwned>(&self, bytes: &[u8]) -> Result<B, ProtocolError> {
+        let body = match self {
+            ReplyStyle::Legacy => Codec::Json.decode::<B>(bytes),
+            ReplyStyle::Envelope { codec, .. } => codec.decode::<EnvelopeBody<B>>(bytes).map(|envelope| envelope.body),
+        };
+        body.map_err(|e| ProtocolError::new(format!("Invalid request body: {}", e), "INVALID_REQUEST"))
+    }
+}
+
+/// A decoded request.
+#[derive(Debug, Clone)]
+pub enum Request {
+    Hello(Hello),
+    Announce(TrackerAnnounceRequest),
+    File(FileRequest),
+    ListFiles(ListFilesRequest),
+    Publish(PublishRequest),
+    Ai(AiRequest),
+    Subscribe(SubscribeRequest),
+}
+
+impl Request {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Request::Hello(_) => MessageKind::Hello,
+            Request::Announce(_) => MessageKind::Announce,
+            Request::File(_) => MessageKind::File,
+            Request::ListFiles(_) => MessageKind::ListFiles,
+            Request::Publish(_) => MessageKind::Publish,
+            Request::Ai(_) => MessageKind::Ai,
+            Request::Subscribe(_) => MessageKind::Subscribe,
+        }
+    }
+}
+
+/// Why a message couldn't be decoded; `code` goes into `ErrorResponse::code`.
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub message: String,
+    pub code: &'static str,
+}
+
+impl ProtocolError {
+    pub fn new(message: impl Into<String>, code: &'static str) -> Self {
+        Self { message: message.into(), code }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Wraps `message` in an envelope and encodes it with `codec`.
+pub fn encode<T: Message>(message: &T, id: Option<String>, codec: Codec) -> Result<Vec<u8>, CodecError> {
+    encode_request(message, id, None, None, codec)
+}
+
+/// Wraps a request in an envelope asking for an answer within `timeout_ms`
+/// (and carrying the `auth` token, if any), and encodes it with `codec`.
+pub fn encode_request<T: Message>(
+    message: &T,
+    id: Option<String>,
+    timeout_ms: Option<u64>,
+    auth: Option<String>,
+    codec: Codec,
+) -> Result<Vec<u8>, CodecError> {
+    codec.encode(&Envelope { v: PROTOCOL_VERSION, kind: T::KIND, id, timeout_ms, auth, body: message })
+}
+
+/// Reads the envelope fields, if `bytes` is an envelope in `codec`.
+fn decode_header(bytes: &[u8], codec: Codec) -> Option<EnvelopeHeader> {
+    codec.decode::<EnvelopeHeader>(bytes).ok()
+        .filter(|header| header.kind.is_some() && header.body.is_some())
+}
+
+/// Decodes a request, enveloped or legacy.
+///
+/// # Arguments
+/// * `bytes` - The raw request
+/// * `codec` - The connection's codec; JSON envelopes (such as a `hello`)
+///   are accepted on any connection
+///
+/// # Returns
+/// How to reply (known even when decoding fails, so the error can be sent
+/// in the right style), the request's type name, `timeout_ms` and `auth`,
+/// and the request or the error to report. A type outside `MessageKind` is
+/// an `UNKNOWN_REQUEST` error here; a server may still have a handler for it
+pub fn decode_request(bytes: &[u8], codec: Codec) -> DecodedRequest {
+    let header = decode_header(bytes, codec).map(|header| (header, codec))
+        .or_else(|| decode_header(bytes, Codec::Json).map(|header| (header, Codec::Json)));
+    let (EnvelopeHeader { v, kind, id, timeout_ms, auth, .. }, codec) = match header {
+        Some(header) => header,
+        // Not an envelope (or not even JSON; `decode_legacy` reports that)
+        None => {
+            let request = decode_legacy(bytes)
+                .and_then(|request| check_request_size(request.kind(), bytes.len()).map(|_| request));
+            let type_name = request.as_ref().ok().map(|request| request.kind().as_str().to_string());
+            return DecodedRequest { style: ReplyStyle::Legacy, type_name, timeout_ms: None, auth: None, request };
+        }
+    };
+    let style = ReplyStyle::Envelope { id, codec };
+    if let Some(v) = v.filter(|&v| v > PROTOCOL_VERSION) {
+        let error = ProtocolError::new(
+            format!("Unsupported envelope version {} (this server speaks {})", v, PROTOCOL_VERSION),
+            "UNSUPPORTED_VERSION",
+        );
+        return DecodedRequest { style, type_name: kind, timeout_ms, auth, request: Err(error) };
+    }
+    let name = kind.unwrap_or_default();
+    let request = match MessageKind::from_name(&name).filter(|kind| kind.is_request()) {
+        Some(kind) => check_request_size(kind, bytes.len()).and_then(|_| decode_request_body(bytes, kind, codec)),
+        None => Err(ProtocolError::new(format!("Unknown request type: {}", name), "UNKNOWN_REQUEST")),
+    };
+    DecodedRequest { style, type_name: Some(name), timeout_ms, auth, request }
+}
+
+/// Refuses a request of type `kind` longer than the type allows.
+fn check_request_size(kind: MessageKind, size: usize) -> Result<(), ProtocolError> {
+    if size > kind.max_request_size() {
+        return Err(ProtocolError::new(
+            format!("{} request of {} bytes exceeds the limit of {} bytes", kind, size, kind.max_request_size()),
+            "REQUEST_TOO_LARGE",
+        ));
+    }
+    Ok(())
+}
+
+/// A request as read by `decode_request`.
+#[derive(Debug)]
+pub struct DecodedRequest {
+    /// How to reply
+    pub style: ReplyStyle,
+    /// The envelope's `type` (or the kind of a legacy request), if known
+    pub type_name: Option<String>,
+    /// Milliseconds the client will wait for the response, if it said
+    pub timeout_ms: Option<u64>,
+    /// The client's `auth` token, if it sent one
+    pub auth: Option<String>,
+    /// The request, or the error to report
+    pub request: Result<Request, ProtocolError>,
+}
+
+/// Decodes the body of a request envelope of type `kind`.
+fn decode_request_body(bytes: &[u8], kind: MessageKind, codec: Codec) -> Result<Request, ProtocolError> {
+    fn body<B: DeserializeOwned>(bytes: &[u8], codec: Codec) -> Result<B, CodecError> {
+        codec.decode::<EnvelopeBody<B>>(bytes).map(|envelope| envelope.body)
+    }
+    let request = match kind {
+        MessageKind::Hello => body(bytes, codec).map(Request::Hello),
+        MessageKind::Announce => body(bytes, codec).map(Request::Announce),
+        MessageKind::File => body(bytes, codec).map(Request::File),
+        MessageKind::ListFiles => body(bytes, codec).map(Request::ListFiles),
+        MessageKind::Publish => body(bytes, codec).map(Request::Publish),
+        MessageKind::Ai => body(bytes, codec).map(Request::Ai),
+        MessageKind::Subscribe => body(bytes, codec).map(Request::Subscribe),
+        _ => return Err(ProtocolError::new(format!("Not a request type: {}", kind), "UNKNOWN_REQUEST")),
+    };
+    request.map_err(|e| ProtocolError::new(format!("Invalid {} body: {}", kind, e), "INVALID_REQUEST"))
+}
+
+/// Reads the type and id of an envelope without decoding its body.
+pub fn peek(bytes: &[u8], codec: Codec) -> Result<(MessageKind, Option<String>), ProtocolError> {
+    match decode_header(bytes, codec) {
+        Some(EnvelopeHeader { kind: Some(name), id, .. }) => MessageKind::from_name(&name)
+            .map(|kind| (kind, id))
+            .ok_or_else(|| ProtocolError::new(format!("Unknown message type: {}", name), "INVALID_RESPONSE")),
+        _ => Err(ProtocolError::new(format!("Not a {} message envelope", codec), "INVALID_RESPONSE")),
+    }
+}
+
+/// Decodes the body of an envelope carrying a `T`.
+pub fn decode_body<T: Message>(bytes: &[u8], codec: Codec) -> Result<T, ProtocolError> {
+    codec.decode::<EnvelopeBody<T>>(bytes)
+        .map(|envelope| envelope.body)
+        .map_err(|e| ProtocolError::new(format!("Invalid {} body: {}", T::KIND, e), "INVALID_RESPONSE"))
+}
+
+/// The kind of a bare (pre-envelope) request, from its top-level keys.
+///
+/// Keys are checked from the most to the least specific, so an AI query
+/// carrying a `file` key in its parameters is still an AI query.
+pub fn legacy_kind(object: &serde_json::Map<String, serde_json::Value>) -> Option<MessageKind> {
+    let has = |key: &str| object.contains_key(key);
+    if has("info_hash") && has("peer_id") {
+        Some(MessageKind::Announce)
+    } else if has("publish") {
+        Some(MessageKind::Publish)
+    } else if has("list_files") {
+        Some(MessageKind::ListFiles)
+    } else if has("query") {
+        Some(MessageKind::Ai)
+    } else if has("file") {
+        Some(MessageKind::File)
+    } else {
+        None
+    }
+}
+
+fn decode_legacy(bytes: &[u8]) -> Result<Request, ProtocolError> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(bytes)
+        .map_err(|_| ProtocolError::new("Request is not a JSON object", "INVALID_REQUEST"))?;
+    let kind = legacy_kind(&object)
+        .ok_or_else(|| ProtocolError::new("Unknown request type", "UNKNOWN_REQUEST"))?;
+    let value = serde_json::Value::Object(object);
+    let request = match kind {
+        MessageKind::Announce => serde_json::from_value(value).map(Request::Announce),
+        MessageKind::File => serde_json::from_value(value).map(Request::File),
+        MessageKind::ListFiles => serde_json::from_value(value).map(Request::ListFiles),
+        MessageKind::Publish => serde_json::from_value(value).map(Request::Publish),
+        _ => serde_json::from_value(value).map(Request::Ai),
+    };
+    request.map_err(|e| ProtocolError::new(format!("Invalid {}: {}", kind.type_name(), e), "INVALID_REQUEST"))
+}
+
+/// A decoded response: the expected message or the server's error.
+#[derive(Debug)]
+pub enum Reply<R> {
+    Ok(R),
+    Error(ErrorResponse),
+}
+
+/// Decodes the response to a request of type `R`'s request.
+///
+/// # Arguments
+/// * `bytes` - The raw response
+/// * `id` - The id the request was sent with; an envelope echoing another id
+///   is refused (any id is accepted for a request sent without one, as the
+///   server assigns it)
+/// * `codec` - The connection's codec; bare responses and errors about
+///   undecodable requests are always JSON
+///
+/// # Returns
+/// The kind found on the wire and the decoded reply
+pub fn decode_response<R: Message>(bytes: &[u8], id: Option<&str>, codec: Codec) -> Result<(MessageKind, Reply<R>), ProtocolError> {
+    let invalid = |e: CodecError| ProtocolError::new(format!("Invalid response: {}", e), "INVALID_RESPONSE");
+    let header = decode_header(bytes, codec).map(|header| (header, codec))
+        .or_else(|| decode_header(bytes, Codec::Json).map(|header| (header, Codec::Json)));
+    let (EnvelopeHeader { kind, id: response_id, .. }, codec) = match header {
+        Some(header) => header,
+        None => {
+            // Bare response from a server predating the envelope. Errors are
+            // checked first: a response type whose fields are all optional
+            // would otherwise accept an error's body
+            if R::KIND != MessageKind::Error {
+                if let Ok(error) = serde_json::from_slice::<ErrorResponse>(bytes) {
+                    return Ok((MessageKind::Error, Reply::Error(error)));
+                }
+            }
+            return match serde_json::from_slice::<R>(bytes) {
+                Ok(response) => Ok((R::KIND, Reply::Ok(response))),
+                Err(e) => Err(invalid(e.into())),
+            };
+        }
+    };
+    if id.is_some() && response_id.is_some() && response_id.as_deref() != id {
+        return Err(ProtocolError::new(
+            format!("Response id {:?} does not match request id {:?}", response_id, id),
+            "INVALID_RESPONSE",
+        ));
+    }
+    let name = kind.unwrap_or_default();
+    match MessageKind::from_name(&name) {
+        Some(kind) if kind == R::KIND => {
+            let body = codec.decode::<EnvelopeBody<R>>(bytes).map_err(invalid)?.body;
+            Ok((kind, Reply::Ok(body)))
+        }
+        Some(MessageKind::Error) => {
+            let body = codec.decode::<EnvelopeBody<ErrorResponse>>(bytes).map_err(invalid)?.body;
+            Ok((MessageKind::Error, Reply::Error(body)))
+        }
+        _ => Err(ProtocolError::new(
+            format!("Expected a {} response, got {}", R::KIND, name),
+            "INVALID_RESPONSE",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn decode_json(value: serde_json::Value) -> DecodedRequest {
+        decode_request(&serde_json::to_vec(&value).unwrap(), Codec::Json)
+    }
+
+    fn kind_of(decoded: &DecodedRequest) -> Result<MessageKind, &'static str> {
+        decoded.request.as_ref().map(Request::kind).map_err(|e| e.code)
+    }
+
+    #[test]
+    fn legacy_query_with_a_file_key_is_an_ai_request() {
+        let decoded = decode_json(json!({ "query": "Summarize this", "file": "notes.txt" }));
+        assert_eq!(decoded.style, ReplyStyle::Legacy);
+        assert_eq!(decoded.type_name.as_deref(), Some("ai"));
+        match decoded.request {
+            Ok(Request::Ai(request)) => assert_eq!(request.query, "Summarize this"),
+            other => panic!("expected an AI request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn legacy_shapes_map_to_their_kind() {
+        let cases = [
+            (json!({ "info_hash": "ab", "peer_id": "p", "port": 6881 }), MessageKind::Announce),
+            (json!({ "info_hash": "ab", "peer_id": "p", "port": 6881, "file": "a.txt" }), MessageKind::Announce),
+            (json!({ "publish": "a.txt", "token": "t", "sha256": "00", "size": 1, "offset": 0, "data": [7] }), MessageKind::Publish),
+            (json!({ "list_files": true, "pattern": "*.txt" }), MessageKind::ListFiles),
+            (json!({ "list_files": true, "query": "q" }), MessageKind::ListFiles),
+            (json!({ "query": "Hello" }), MessageKind::Ai),
+            (json!({ "file": "a.txt" }), MessageKind::File),
+            (json!({ "file": "a.txt", "info_hash": "ab" }), MessageKind::File),
+        ];
+        for (value, kind) in cases {
+            assert_eq!(legacy_kind(value.as_object().unwrap()), Some(kind), "{}", value);
+            let decoded = decode_json(value.clone());
+            assert_eq!(kind_of(&decoded), Ok(kind), "{}", value);
+            assert_eq!(decoded.type_name.as_deref(), Some(kind.as_str()));
+        }
+    }
+
+    #[test]
+    fn unknown_or_invalid_legacy_requests_are_refused() {
+        assert_eq!(legacy_kind(json!({ "name": "x" }).as_object().unwrap()), None);
+        assert_eq!(kind_of(&decode_json(json!({ "name": "x" }))), Err("UNKNOWN_REQUEST"));
+        assert_eq!(kind_of(&decode_request(b"not json", Codec::Json)), Err("INVALID_REQUEST"));
+        // The right kind, but missing a required field
+        assert_eq!(kind_of(&decode_json(json!({ "info_hash": "ab", "peer_id": "p" }))), Err("INVALID_REQUEST"));
+    }
+
+    #[test]
+    fn envelope_type_wins_over_body_keys() {
+        let cases = [
+            ("file", json!({ "file": "a.txt", "query": "q", "list_files": true }), MessageKind::File),
+            ("ai", json!({ "query": "q", "file": "a.txt", "publish": "b.txt" }), MessageKind::Ai),
+            ("list_files", json!({ "list_files": true, "info_hash": "ab", "peer_id": "p" }), MessageKind::ListFiles),
+        ];
+        for (kind, body, expected) in cases {
+            let decoded = decode_json(json!({ "v": 1, "type": kind, "id": "c-1", "body": body }));
+            assert_eq!(decoded.style, ReplyStyle::Envelope { id: Some("c-1".into()), codec: Codec::Json });
+            assert_eq!(kind_of(&decoded), Ok(expected), "{}", kind);
+        }
+        // A body that doesn't fit its type is invalid, not rerouted
+        let decoded = decode_json(json!({ "v": 1, "type": "announce", "body": { "file": "a.txt" } }));
+        assert_eq!(kind_of(&decoded), Err("INVALID_REQUEST"));
+    }
+
+    #[test]
+    fn envelopes_with_unknown_types_or_versions_are_refused() {
+        let decoded = decode_json(json!({ "v": 1, "type": "custom_thing", "body": {} }));
+        assert_eq!(kind_of(&decoded), Err("UNKNOWN_REQUEST"));
+        assert_eq!(decoded.type_name.as_deref(), Some("custom_thing"));
+        let decoded = decode_json(json!({ "v": PROTOCOL_VERSION + 1, "type": "file", "body": { "file": "a.txt" } }));
+        assert_eq!(kind_of(&decoded), Err("UNSUPPORTED_VERSION"));
+        let decoded = decode_json(json!({ "v": 1, "type": "file_response", "body": {} }));
+        assert_eq!(kind_of(&decoded), Err("UNKNOWN_REQUEST"));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let file = "a".repeat(MAX_SMALL_REQUEST_SIZE);
+        assert_eq!(kind_of(&decode_json(json!({ "file": file }))), Err("REQUEST_TOO_LARGE"));
+        assert_eq!(kind_of(&decode_json(json!({ "v": 1, "type": "file", "body": { "file": file } }))), Err("REQUEST_TOO_LARGE"));
+    }
+
+    #[test]
+    fn requests_round_trip_in_every_codec() {
+        let request = FileRequest { file: "a.txt".into(), offset: Some(3), length: Some(5), info_hash: None };
+        for codec in Codec::ALL {
+            let bytes = encode_request(&request, Some("c-2".into()), Some(1500), Some("secret".into()), codec).unwrap();
+            let decoded = decode_request(&bytes, codec);
+            assert_eq!(decoded.style, ReplyStyle::Envelope { id: Some("c-2".into()), codec });
+            assert_eq!((decoded.timeout_ms, decoded.auth.as_deref()), (Some(1500), Some("secret")));
+            match decoded.request {
+                Ok(Request::File(file)) => assert_eq!((file.file.as_str(), file.offset, file.length), ("a.txt", Some(3), Some(5))),
+                other => panic!("{}: expected a file request, got {:?}", codec, other),
+            }
+        }
+    }
+
+    fn file_response() -> FileResponse {
+        FileResponse { data: vec![1, 2, 3], filename: "a.txt".into(), size: 3, offset: None, total_size: Some(3) }
+    }
+
+    #[test]
+    fn response_with_another_id_is_refused() {
+        let bytes = encode(&file_response(), Some("c-3".into()), Codec::Json).unwrap();
+        let error = decode_response::<FileResponse>(&bytes, Some("c-4"), Codec::Json).unwrap_err();
+        assert_eq!(error.code, "INVALID_RESPONSE");
+
+        for id in [Some("c-3"), None] {
+            match decode_response::<FileResponse>(&bytes, id, Codec::Json) {
+                Ok((MessageKind::FileResponse, Reply::Ok(response))) => assert_eq!(response.data, vec![1, 2, 3]),
+                other => panic!("{:?}: expected the file response, got {:?}", id, other),
+            }
+        }
+    }
+
+    #[test]
+    fn responses_decode_errors_and_refuse_other_types() {
+        let error = ErrorResponse { error: "No such file".into(), code: Some("NOT_FOUND".into()) };
+        let bytes = encode(&error, Some("c-5".into()), Codec::MessagePack).unwrap();
+        match decode_response::<FileResponse>(&bytes, Some("c-5"), Codec::MessagePack) {
+            Ok((MessageKind::Error, Reply::Error(error))) => assert_eq!(error.code.as_deref(), Some("NOT_FOUND")),
+            other => panic!("expected the error, got {:?}", other),
+        }
+
+        // Bare responses from servers predating the envelope
+        let bare = serde_json::to_vec(&error).unwrap();
+        assert!(matches!(decode_response::<FileResponse>(&bare, Some("c-6"), Codec::Json), Ok((MessageKind::Error, Reply::Error(_)))));
+        let bare = serde_json::to_vec(&file_response()).unwrap();
+        assert!(matches!(decode_response::<FileResponse>(&bare, Some("c-6"), Codec::Json), Ok((MessageKind::FileResponse, Reply::Ok(_)))));
+
+        let bytes = encode(&file_response(), None, Codec::Json).unwrap();
+        let error = decode_response::<ListFilesResponse>(&bytes, None, Codec::Json).unwrap_err();
+        assert_eq!(error.code, "INVALID_RESPONSE");
+    }
+}
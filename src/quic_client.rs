@@ -1,76 +1,505 @@
 //! # QUIC Client
 //!
 //! Functions for connecting to QUIC endpoints and sending/receiving JSON messages.
+//!
+//! Each connection starts with a `Hello` handshake; requests then go out
+//! wrapped in a `protocol::Envelope` with a request id, and the response
+//! is matched on its envelope type rather than guessed from its fields.
+//! The client's log lines for a request are tagged with its id (see
+//! `logger::with_request_id`); a request sent while one is being handled
+//! (work a server delegates) goes out under that request's id.
+//! Servers that predate the handshake get bare messages instead.
+//!
+//! Connections are pooled per server address: the first request to a
+//! server connects and runs the handshake, later ones open a new stream on
+//! the same connection. A connection found closed is replaced, and a
+//! request that fails because its pooled connection was lost is retried
+//! once on a fresh one.
+//!
+//! The handshake also settles the codec: the client asks for its preferred
+//! one (`with_codec`, or the `QUIC_CODEC` environment variable; JSON by
+//! default) and uses whatever the server picked for the connection.
+//!
+//! Requests can carry a timeout (`with_timeout`, or `QUIC_TIMEOUT_MS`) that
+//! the server works within; a request sent while one is being handled asks
+//! for no more than that request's remaining time. They also carry the
+//! token for servers that require one (`with_auth_token`, or
+//! `QUIC_AUTH_TOKEN`).
+//!
+//! `open_stream` opens a framed stream (see `framing`) instead, on which
+//! several requests can be pipelined and events such as `AiToken` arrive
+//! ahead of the final response.
+//!
+//! `subscribe` turns a framed stream into a `Subscription` to server events
+//! (see `events`), which subscribes again by itself if the connection is
+//! lost.
 
-use quinn::{Endpoint, ClientConfig};
+use quinn::Endpoint;
 use crate::quic_utils::create_client_config;
-use crate::messages::ErrorResponse;
-use serde::{Serialize, Deserialize};
-use std::sync::Arc;
+use crate::messages::{ErrorResponse, HelloResponse, ServerEvent, SubscribeRequest, SubscribeResponse};
+use crate::error::{Error, Result};
+use crate::protocol::{Codec, Message, MessageKind, Reply};
+use crate::framing::{FRAMED_STREAM_MARKER, MAX_FRAME_SIZE};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
-/// Detect request type from JSON string
-fn detect_request_type(json: &str) -> &'static str {
-    if json.contains("\"info_hash\"") && json.contains("\"peer_id\"") {
-        "TrackerAnnounceRequest"
-    } else if json.contains("\"file\"") {
-        "FileRequest"
-    } else if json.contains("\"query\"") {
-        "AiRequest"
-    } else if json.contains("\"type\"") {
-        "CustomJSON"
-    } else {
-        "UnknownRequest"
-    }
+/// A pooled connection and the outcome of its handshake.
+#[derive(Clone)]
+struct PooledConnection {
+    connection: quinn::Connection,
+    /// The server's `HelloResponse`; `None` for servers predating the handshake
+    hello: Option<HelloResponse>,
 }
 
-/// Detect response type from JSON string
-fn detect_response_type(json: &str) -> &'static str {
-    if json.contains("\"peers\"") && json.contains("\"interval\"") {
-        "TrackerAnnounceResponse"
-    } else if json.contains("\"data\"") && json.contains("\"filename\"") {
-        "FileResponse"
-    } else if json.contains("\"answer\"") && json.contains("\"metadata\"") {
-        "AiResponse"
-    } else if json.contains("\"error\"") {
-        "ErrorResponse"
-    } else {
-        "UnknownResponse"
-    }
-}
+/// One pool slot per server address; its lock is held while connecting so
+/// concurrent requests to a new server share a single handshake.
+type PoolSlot = Arc<tokio::sync::Mutex<Option<PooledConnection>>>;
+
+/// Extra time a client waits past a request's timeout for the server's
+/// `TIMEOUT` error.
+const RESPONSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Attempts a `Subscription` makes to subscribe again after losing its
+/// stream, waiting twice as long after each failure (from one second).
+const RESUBSCRIBE_ATTEMPTS: u32 = 5;
+
+/// The client shared by the `client` module's functions.
+static SHARED_CLIENT: OnceLock<QuicClient> = OnceLock::new();
 
 /// Connects to a QUIC endpoint and sends/receives JSON messages.
 pub struct QuicClient {
     endpoint: Endpoint,
+    /// Codec asked for in the handshake
+    codec: Codec,
+    /// How long to wait for a response (sent to the server as `timeout_ms`)
+    timeout: Option<Duration>,
+    /// Sent with each request as `auth`
+    auth_token: Option<String>,
+    /// Live connections by server address
+    pool: Mutex<HashMap<SocketAddr, PoolSlot>>,
 }
 
 impl QuicClient {
     /// Creates a new QUIC client.
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let client_config = create_client_config()?;
+    ///
+    /// The client asks servers for the codec named in `QUIC_CODEC` (`json`
+    /// or `msgpack`), or JSON if it isn't set, and gives requests the
+    /// timeout in `QUIC_TIMEOUT_MS` (none if it isn't set) and the token in
+    /// `QUIC_AUTH_TOKEN`.
+    pub fn new() -> Result<Self> {
+        let client_config = create_client_config().map_err(|e| Error::Tls(e.to_string()))?;
         // Use 0.0.0.0:0 to bind to all interfaces (both IPv4 and IPv6)
         let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
         endpoint.set_default_client_config(client_config);
         
-        Ok(Self { endpoint })
+        let codec = match std::env::var("QUIC_CODEC") {
+            Ok(name) => Codec::from_name(&name)
+                .ok_or_else(|| Error::Protocol(format!("Unknown codec in QUIC_CODEC: {} (expected json or msgpack)", name)))?,
+            Err(_) => Codec::Json,
+        };
+        let timeout = match std::env::var("QUIC_TIMEOUT_MS") {
+            Ok(ms) => Some(ms.parse().map(Duration::from_millis)
+                .map_err(|_| Error::Protocol(format!("Invalid QUIC_TIMEOUT_MS: {} (expected milliseconds)", ms)))?),
+            Err(_) => None,
+        };
+        let auth_token = std::env::var("QUIC_AUTH_TOKEN").ok();
+        Ok(Self { endpoint, codec, timeout, auth_token, pool: Mutex::new(HashMap::new()) })
+    }
+    
+    /// The process-wide client, created on first use, so that repeated
+    /// calls share its connection pool.
+    ///
+    /// Must first be called from within the Tokio runtime the client will
+    /// be used on.
+    pub fn shared() -> Result<&'static QuicClient> {
+        if let Some(client) = SHARED_CLIENT.get() {
+            return Ok(client);
+        }
+        let client = Self::new()?;
+        Ok(SHARED_CLIENT.get_or_init(|| client))
+    }
+    
+    /// Asks servers for `codec` instead (they may still pick JSON).
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+    
+    /// The codec this client asks for.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+    
+    /// Gives up on requests not answered within `timeout`, and asks servers
+    /// to do the same.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    
+    /// How long requests are given, if limited.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    
+    /// Sends `token` with every request, for servers that require it.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
     }
     
-    /// Connects to a QUIC server and sends a JSON message, receiving a response.
+    /// The time the next request gets: this client's timeout, or less if the
+    /// calling task is handling a request due sooner.
+    fn request_timeout(&self) -> Option<Duration> {
+        [self.timeout, crate::protocol::remaining_time()].into_iter().flatten().min()
+    }
+    
+    /// Sends a JSON message on a new stream of the pooled connection to the
+    /// server (connecting first if needed), receiving a response.
+    ///
+    /// After the `Hello` handshake the message goes out in an envelope (bare
+    /// to servers predating the handshake). Fails without sending anything
+    /// if the server's protocol version is incompatible or it doesn't list
+    /// `T`'s type. An `ErrorResponse` from the server is returned as
+    /// `Error::Remote` unless `R` is `ErrorResponse` itself.
+    ///
+    /// The request gets a new id, or the id of the request the calling task
+    /// is handling (`logger::current_request_id`). With a timeout (see
+    /// `with_timeout`), connecting counts against it too, and the request
+    /// fails with `Error::Timeout` if the server doesn't answer in time.
     pub async fn send_message<T, R>(
         &self,
         server: &str,
         port: u16,
         message: &T,
-    ) -> Result<R, Box<dyn std::error::Error>>
+    ) -> Result<R>
+    where
+        T: Message,
+        R: Message,
+    {
+        let request_id = crate::logger::current_request_id().unwrap_or_else(crate::protocol::new_request_id);
+        let timeout = self.request_timeout();
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let request = async {
+            crate::log_client!("[QuicClient::send_message] ENTRY - server={}, port={}, request_id={}, timeout={:?}", 
+                server, port, request_id, timeout);
+            let (pooled, reused) = self.pooled(server, port).await?;
+            let error = match self.send_on(&pooled, server, port, message, &request_id, deadline).await {
+                Err(e) if reused && e.is_connection_lost() => e.to_string(),
+                result => return result,
+            };
+            
+            // The server went away since the connection was pooled (restart, idle timeout)
+            crate::log_client!("[QuicClient::send_message] Pooled connection to {}:{} lost ({}), reconnecting", server, port, error);
+            self.evict(server, port, &pooled.connection);
+            let (pooled, _) = self.pooled(server, port).await?;
+            self.send_on(&pooled, server, port, message, &request_id, deadline).await
+        };
+        crate::logger::with_request_id(request_id.clone(), async {
+            match timeout {
+                // A little longer than the server has, so its TIMEOUT error can arrive
+                Some(timeout) => tokio::time::timeout(timeout + RESPONSE_GRACE, request).await
+                    .map_err(|_| Error::Timeout(format!("No {} response from {}:{} within {} ms", T::KIND, server, port, timeout.as_millis())))?,
+                None => request.await,
+            }
+        }).await
+    }
+    
+    /// Sends `message` on a new stream of a pooled connection, telling the
+    /// server the time left until `deadline`.
+    async fn send_on<T, R>(
+        &self,
+        pooled: &PooledConnection,
+        server: &str,
+        port: u16,
+        message: &T,
+        request_id: &str,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<R>
     where
-        T: Serialize,
-        R: for<'de> Deserialize<'de>,
+        T: Message,
+        R: Message,
     {
-        crate::log_client!("[QuicClient::send_message] ENTRY - server={}, port={}", server, port);
+        if let Some(hello) = &pooled.hello {
+            if !hello.message_types.iter().any(|t| t == T::KIND.as_str()) {
+                return Err(Error::Protocol(format!("Server {}:{} does not support {} requests (supports: {})", 
+                    server, port, T::KIND, hello.message_types.join(", "))));
+            }
+        }
+        
+        // Servers predating the handshake only understand bare JSON messages
+        let codec = pooled.hello.as_ref().map(Codec::from_hello).unwrap_or_default();
+        let timeout_ms = deadline.map(|deadline| (deadline.saturating_duration_since(tokio::time::Instant::now()).as_millis() as u64).max(1));
+        let (payload, id) = match pooled.hello {
+            Some(_) => (crate::protocol::encode_request(message, Some(request_id.to_string()), timeout_ms, self.auth_token.clone(), codec)?, Some(request_id)),
+            None => (serde_json::to_vec(message)?, None),
+        };
+        let id_label = id.unwrap_or("legacy");
+        
+        crate::log_client!("[CLIENT] ===== OUTGOING REQUEST =====");
+        crate::log_client!("[CLIENT] REQUEST TYPE: {} (type={}, id={}, codec={}, timeout_ms={:?})", 
+            T::KIND.type_name(), T::KIND, id_label, codec, timeout_ms);
+        crate::log_client!("[CLIENT] Function: quic_client::send_message()");
+        crate::log_client!("[CLIENT] Target: {}:{}", server, port);
+        crate::log_client!("[CLIENT] JSON payload length: {}", payload.len());
+        if T::KIND.loggable() {
+            crate::log_client!("[CLIENT] JSON payload: {}", codec.to_log_string(&payload));
+        }
+        crate::log_client!("[CLIENT] Data sent to: Server {}:{}", server, port);
+        
+        let buffer = Self::exchange(&pooled.connection, &payload).await?;
+        
+        crate::log_client!("[QuicClient::send_message] Deserializing response - buffer_len={}", buffer.len());
+        let (kind, reply) = crate::protocol::decode_response::<R>(&buffer, id, codec)?;
+        crate::log_client!("[CLIENT] ===== INCOMING RESPONSE =====");
+        crate::log_client!("[CLIENT] RESPONSE TYPE: {} (type={}, id={}, codec={})", kind.type_name(), kind, id_label, codec);
+        crate::log_client!("[CLIENT] Source: {}:{}", server, port);
+        crate::log_client!("[CLIENT] Response length: {}", buffer.len());
+        crate::log_client!("[CLIENT] Data received from: Server {}:{}", server, port);
+        
+        match reply {
+            Reply::Ok(response) => {
+                crate::log_client!("[CLIENT] Response deserialized successfully");
+                crate::log_client!("[QuicClient::send_message] EXIT - Return: success, response_size={}", buffer.len());
+                Ok(response)
+            }
+            Reply::Error(error) => {
+                let code = error.code.as_deref().unwrap_or("UNKNOWN");
+                crate::log_client!("[QuicClient::send_message] EXIT - Return: server error {}: {}", code, error.error);
+                Err(error.into())
+            }
+        }
+    }
+    
+    /// Sends raw bytes on a new connection (skipping the `Hello` handshake)
+    /// and returns everything the server writes back, without encoding or
+    /// decoding anything.
+    pub async fn send_raw(
+        &self,
+        server: &str,
+        port: u16,
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        crate::log_client!("[QuicClient::send_raw] ENTRY - server={}, port={}", server, port);
+        let conn = self.connect(server, port).await?;
+        Self::exchange(&conn, payload).await
+    }
+    
+    /// Opens a framed stream on the pooled connection to the server.
+    ///
+    /// # Returns
+    /// The stream, or an error if the server doesn't offer
+    /// `protocol::FEATURE_FRAMED_STREAMS` (servers predating the handshake
+    /// never do)
+    pub async fn open_stream(
+        &self,
+        server: &str,
+        port: u16,
+    ) -> Result<MessageStream> {
+        crate::log_client!("[QuicClient::open_stream] ENTRY - server={}, port={}", server, port);
+        let (PooledConnection { connection: conn, hello }, _) = self.pooled(server, port).await?;
+        let hello = match hello {
+            Some(hello) if hello.features.iter().any(|f| f == crate::protocol::FEATURE_FRAMED_STREAMS) => hello,
+            _ => return Err(Error::Protocol(format!("Server {}:{} does not support framed streams", server, port))),
+        };
+        
+        let codec = Codec::from_hello(&hello);
+        let (mut send, recv) = conn.open_bi().await?;
+        send.write_all(&[FRAMED_STREAM_MARKER]).await?;
+        crate::log_client!("[QuicClient::open_stream] Framed stream opened to {}:{} (codec {})", server, port, codec);
+        Ok(MessageStream {
+            _connection: conn,
+            send,
+            recv,
+            codec,
+            timeout: self.timeout,
+            auth_token: self.auth_token.clone(),
+            message_types: hello.message_types,
+            target: format!("{}:{}", server, port),
+        })
+    }
+    
+    /// Subscribes to server events on a framed stream.
+    ///
+    /// # Arguments
+    /// * `request` - The events to receive
+    ///
+    /// # Returns
+    /// The subscription once the server has acknowledged it, or an error if
+    /// the server doesn't support subscriptions or rejects the request
+    pub async fn subscribe(
+        &self,
+        server: &str,
+        port: u16,
+        request: SubscribeRequest,
+    ) -> Result<Subscription<'_>> {
+        crate::log_client!("[QuicClient::subscribe] ENTRY - server={}, port={}, swarms={:?}, seed_files={}, work_nodes={}", 
+            server, port, request.swarms, request.seed_files, request.work_nodes);
+        let (stream, accepted) = self.open_subscription(server, port, &request).await?;
+        Ok(Subscription {
+            client: self,
+            server: server.to_string(),
+            port,
+            request,
+            stream,
+            accepted,
+        })
+    }
+    
+    /// Opens a framed stream and sends `request` on it.
+    ///
+    /// # Returns
+    /// The stream and the server's acknowledgement
+    async fn open_subscription(
+        &self,
+        server: &str,
+        port: u16,
+        request: &SubscribeRequest,
+    ) -> Result<(MessageStream, SubscribeResponse)> {
+        let mut stream = self.open_stream(server, port).await?;
+        // A subscription lasts until it is closed; the request timeout doesn't apply
+        stream.timeout = None;
+        let accepted = stream.request::<_, SubscribeResponse, _>(request, |_| Ok(())).await?;
+        crate::log_client!("[QuicClient::subscribe] Subscribed to {}:{} - swarms={:?}, seed_files={}, work_nodes={}, queue_size={}", 
+            server, port, accepted.swarms, accepted.seed_files, accepted.work_nodes, accepted.queue_size);
+        Ok((stream, accepted))
+    }
+    
+    /// Asks the server for its capabilities (the answer to the pooled
+    /// connection's handshake).
+    ///
+    /// # Returns
+    /// The server's `HelloResponse`, or `None` for a server predating the handshake
+    pub async fn hello(
+        &self,
+        server: &str,
+        port: u16,
+    ) -> Result<Option<HelloResponse>> {
+        Ok(self.pooled(server, port).await?.0.hello)
+    }
+    
+    /// The pooled connection to a server, connecting and running the
+    /// handshake if there is none or it has closed.
+    ///
+    /// # Returns
+    /// The connection, and whether it was reused from the pool
+    async fn pooled(
+        &self,
+        server: &str,
+        port: u16,
+    ) -> Result<(PooledConnection, bool)> {
+        let addr: SocketAddr = format!("{}:{}", server, port).parse()?;
+        let slot = Arc::clone(self.pool.lock().unwrap().entry(addr).or_default());
+        let mut slot = slot.lock().await;
+        if let Some(pooled) = slot.as_ref() {
+            match pooled.connection.close_reason() {
+                None => {
+                    crate::log_client!("[QuicClient::pooled] Reusing connection to {} (id={})", addr, pooled.connection.stable_id());
+                    return Ok((pooled.clone(), true));
+                }
+                Some(reason) => {
+                    crate::log_client!("[QuicClient::pooled] Pooled connection to {} closed ({}), reconnecting", addr, reason);
+                }
+            }
+        }
+        
+        *slot = None;
+        let connection = self.connect(server, port).await?;
+        let hello = self.handshake(&connection, server, port).await?;
+        crate::log_client!("[QuicClient::pooled] Pooled new connection to {} (id={})", addr, connection.stable_id());
+        let pooled = PooledConnection { connection, hello };
+        *slot = Some(pooled.clone());
+        Ok((pooled, false))
+    }
+    
+    /// Closes `connection` and drops it from the pool, unless it has
+    /// already been replaced there.
+    fn evict(&self, server: &str, port: u16, connection: &quinn::Connection) {
+        connection.close(0u32.into(), b"connection lost");
+        let Ok(addr) = format!("{}:{}", server, port).parse::<SocketAddr>() else {
+            return;
+        };
+        let Some(slot) = self.pool.lock().unwrap().get(&addr).cloned() else {
+            return;
+        };
+        // A busy slot is already being reconnected by another request
+        let Ok(mut slot) = slot.try_lock() else {
+            return;
+        };
+        if slot.as_ref().is_some_and(|pooled| pooled.connection.stable_id() == connection.stable_id()) {
+            *slot = None;
+        }
+    }
+    
+    /// Sends `Hello` as the first request on a new connection.
+    ///
+    /// # Returns
+    /// The server's answer, or `None` if the server doesn't know `hello`
+    /// (it predates the envelope and must be sent bare messages); an error
+    /// if the server rejects our protocol version
+    async fn handshake(
+        &self,
+        conn: &quinn::Connection,
+        server: &str,
+        port: u16,
+    ) -> Result<Option<HelloResponse>> {
+        let id = crate::protocol::new_request_id();
+        crate::logger::with_request_id(id.clone(), self.send_hello(conn, server, port, &id)).await
+    }
+    
+    /// Sends the `Hello` of `handshake` with the given request id.
+    async fn send_hello(
+        &self,
+        conn: &quinn::Connection,
+        server: &str,
+        port: u16,
+        id: &str,
+    ) -> Result<Option<HelloResponse>> {
+        let hello = crate::protocol::client_hello(self.codec);
+        crate::log_client!("[QuicClient::handshake] Sending Hello to {}:{} - versions {}-{}, codecs {:?}", 
+            server, port, hello.min_version, hello.version, hello.codecs);
+        // The hello is always JSON: the codec isn't settled yet
+        let payload = crate::protocol::encode(&hello, Some(id.to_string()), Codec::Json)?;
+        let buffer = Self::exchange(conn, &payload).await?;
         
+        match crate::protocol::decode_response::<HelloResponse>(&buffer, Some(id), Codec::Json)?.1 {
+            Reply::Ok(response) => {
+                if crate::protocol::negotiate_version(hello.min_version, hello.version, response.min_version, response.max_version).is_none() {
+                    return Err(Error::Protocol(format!("Incompatible protocol version: server {}:{} speaks {}-{}, this client {}-{}", 
+                        server, port, response.min_version, response.max_version, hello.min_version, hello.version)));
+                }
+                crate::log_client!("[QuicClient::handshake] Server {}:{} ({}) speaks version {}, codec {} - codecs {:?}, features {:?}", 
+                    server, port, response.agent.as_deref().unwrap_or("unknown"), response.version, 
+                    Codec::from_hello(&response), response.codecs, response.features);
+                Ok(Some(response))
+            }
+            Reply::Error(error) if error.code.as_deref() == Some("UNKNOWN_REQUEST") => {
+                crate::log_client!("[QuicClient::handshake] Server {}:{} predates the handshake, falling back to bare messages", 
+                    server, port);
+                Ok(None)
+            }
+            Reply::Error(error) => {
+                // Typically UNSUPPORTED_VERSION: our version range is outside the server's
+                crate::log_client!("[QuicClient::handshake] Server {}:{} rejected the handshake: {} ({})", 
+                    server, port, error.error, error.code.as_deref().unwrap_or("UNKNOWN"));
+                Err(error.into())
+            }
+        }
+    }
+    
+    /// Opens a connection to the server, retrying once on failure or timeout.
+    async fn connect(
+        &self,
+        server: &str,
+        port: u16,
+    ) -> Result<quinn::Connection> {
+
         let addr = format!("{}:{}", server, port).parse()?;
-        crate::log_client!("[QuicClient::send_message] Parsed address: {}", addr);
+        crate::log_client!("[QuicClient::connect] Parsed address: {}", addr);
         
-        crate::log_client!("[QuicClient::send_message] Connecting to {}:{}", server, port);
+        crate::log_client!("[QuicClient::connect] Connecting to {}:{}", server, port);
         let connection = self.endpoint.connect(addr, server)?;
         
         // FALLBACK SHUNT: Extended timeout and retry logic for ALPN negotiation
@@ -80,66 +509,68 @@ impl QuicClient {
             connection
         ).await {
             Ok(Ok(conn)) => {
-                crate::log_client!("[QuicClient::send_message] Connection established (first attempt)");
+                crate::log_client!("[QuicClient::connect] Connection established (first attempt)");
                 conn
             }
             Ok(Err(e)) => {
                 // Connection failed - log and retry once
-                crate::log_client!("[QuicClient::send_message] Connection failed: {:?}, attempting retry", e);
+                crate::log_client!("[QuicClient::connect] Connection failed: {:?}, attempting retry", e);
                 let retry_connection = self.endpoint.connect(addr, server)?;
                 match tokio::time::timeout(
                     std::time::Duration::from_secs(20),
                     retry_connection
                 ).await {
                     Ok(Ok(conn)) => {
-                        crate::log_client!("[QuicClient::send_message] Connection established (retry)");
+                        crate::log_client!("[QuicClient::connect] Connection established (retry)");
                         conn
                     }
-                    Ok(Err(e)) => return Err(Box::new(e)),
-                    Err(_) => return Err("Connection timeout on retry".into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err(Error::Timeout(format!("Connecting to {}:{} (retry)", server, port))),
                 }
             }
             Err(_) => {
                 // Timeout - try one more time
-                crate::log_client!("[QuicClient::send_message] Connection timeout, retrying...");
+                crate::log_client!("[QuicClient::connect] Connection timeout, retrying...");
                 let retry_connection = self.endpoint.connect(addr, server)?;
                 match tokio::time::timeout(
                     std::time::Duration::from_secs(20),
                     retry_connection
                 ).await {
                     Ok(Ok(conn)) => {
-                        crate::log_client!("[QuicClient::send_message] Connection established (timeout retry)");
+                        crate::log_client!("[QuicClient::connect] Connection established (timeout retry)");
                         conn
                     }
-                    Ok(Err(e)) => return Err(Box::new(e)),
-                    Err(_) => return Err("Connection timeout on final retry".into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err(Error::Timeout(format!("Connecting to {}:{} (final retry)", server, port))),
                 }
             }
         };
         
-        crate::log_client!("[QuicClient::send_message] Connection established");
-        
+        crate::log_client!("[QuicClient::connect] Connection established");
+        Ok(conn)
+    }
+    
+    /// Sends `payload` on a new stream of `conn` and reads the response to EOF.
+    async fn exchange(
+        conn: &quinn::Connection,
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
         // Open a bidirectional stream
-        crate::log_client!("[QuicClient::send_message] Opening bidirectional stream");
+        crate::log_client!("[QuicClient::exchange] Opening bidirectional stream");
         let (mut send, mut recv) = conn.open_bi().await?;
-        crate::log_client!("[QuicClient::send_message] Stream opened");
-        
-        // Serialize and send the message
-        let json = serde_json::to_string(message)?;
-        
-        // Detect and log request type
-        let request_type = detect_request_type(&json);
-        crate::log_client!("[CLIENT] ===== OUTGOING REQUEST =====");
-        crate::log_client!("[CLIENT] REQUEST TYPE: {}", request_type);
-        crate::log_client!("[CLIENT] Function: quic_client::send_message()");
-        crate::log_client!("[CLIENT] Target: {}:{}", server, port);
-        crate::log_client!("[CLIENT] JSON payload length: {}", json.len());
-        crate::log_client!("[CLIENT] JSON payload: {}", json);
-        crate::log_client!("[CLIENT] Data sent to: Server {}:{}", server, port);
+        crate::log_client!("[QuicClient::exchange] Stream opened");
         
-        send.write_all(json.as_bytes()).await?;
-        send.finish().await?;
-        crate::log_client!("[QuicClient::send_message] Message sent, waiting for response");
+        let sent = match send.write_all(payload).await {
+            Ok(()) => send.finish().await,
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(()) => crate::log_client!("[QuicClient::exchange] Message sent, waiting for response"),
+            // The server refused the rest of the request (too large, too slow); its error follows
+            Err(quinn::WriteError::Stopped(code)) => 
+                crate::log_client!("[QuicClient::exchange] Server stopped reading the request (code {}), waiting for its response", code),
+            Err(e) => return Err(e.into()),
+        }
         
         // Read the response
         let mut buffer = Vec::new();
@@ -151,34 +582,19 @@ impl QuicClient {
                     buffer.extend_from_slice(&chunk[..size]);
                     chunks_received += 1;
                     if chunks_received % 10 == 0 {
-                        crate::log_client!("[QuicClient::send_message] Received chunk {} - buffer_len={}", 
+                        crate::log_client!("[QuicClient::exchange] Received chunk {} - buffer_len={}", 
                             chunks_received, buffer.len());
                     }
                 }
                 None => {
-                    crate::log_client!("[QuicClient::send_message] Stream closed, received {} chunks, total_bytes={}", 
+                    crate::log_client!("[QuicClient::exchange] Stream closed, received {} chunks, total_bytes={}", 
                         chunks_received, buffer.len());
                     break;
                 }
             }
         }
         
-        crate::log_client!("[QuicClient::send_message] Deserializing response - buffer_len={}", buffer.len());
-        // Deserialize the response
-        let response: R = serde_json::from_slice(&buffer)?;
-        
-        // Detect and log response type from raw buffer
-        let response_str = String::from_utf8_lossy(&buffer);
-        let response_type = detect_response_type(&response_str);
-        crate::log_client!("[CLIENT] ===== INCOMING RESPONSE =====");
-        crate::log_client!("[CLIENT] RESPONSE TYPE: {}", response_type);
-        crate::log_client!("[CLIENT] Source: {}:{}", server, port);
-        crate::log_client!("[CLIENT] Response length: {}", buffer.len());
-        crate::log_client!("[CLIENT] Data received from: Server {}:{}", server, port);
-        crate::log_client!("[CLIENT] Response deserialized successfully");
-        crate::log_client!("[QuicClient::send_message] EXIT - Return: success, response_size={}", buffer.len());
-        
-        Ok(response)
+        Ok(buffer)
     }
     
     /// Sends a tracker announce request and receives a response.
@@ -187,7 +603,7 @@ impl QuicClient {
         server: &str,
         port: u16,
         request: &crate::messages::TrackerAnnounceRequest,
-    ) -> Result<crate::messages::TrackerAnnounceResponse, Box<dyn std::error::Error>> {
+    ) -> Result<crate::messages::TrackerAnnounceResponse> {
         self.send_message(server, port, request).await
     }
     
@@ -197,10 +613,216 @@ impl QuicClient {
         server: &str,
         port: u16,
         request: &crate::messages::FileRequest,
-    ) -> Result<crate::messages::FileResponse, Box<dyn std::error::Error>> {
+    ) -> Result<crate::messages::FileResponse> {
         self.send_message(server, port, request).await
     }
 }
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// One message read from a framed stream, decoded as far as its envelope.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub kind: MessageKind,
+    /// Id of the request the message answers
+    pub id: Option<String>,
+    /// The whole encoded envelope
+    pub bytes: Vec<u8>,
+    /// The codec `bytes` are encoded with
+    pub codec: Codec,
+}
+
+impl Frame {
+    /// Decodes the message body as a `T`.
+    pub fn decode<T: Message>(&self) -> Result<T, crate::protocol::ProtocolError> {
+        crate::protocol::decode_body(&self.bytes, self.codec)
+    }
+}
+
+/// A framed stream to a server, opened with `QuicClient::open_stream`.
+///
+/// Requests sent with `send` are answered in order; events for a request
+/// carry its id and arrive before its final response.
+pub struct MessageStream {
+    /// Keeps the connection open as long as the stream
+    _connection: quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    /// Codec the server picked for the connection
+    codec: Codec,
+    /// Sent with each request as `timeout_ms`
+    timeout: Option<Duration>,
+    /// Sent with each request as `auth`
+    auth_token: Option<String>,
+    /// Request types the server listed in its `HelloResponse`
+    message_types: Vec<String>,
+    /// `server:port`, for logging
+    target: String,
+}
 
+impl MessageStream {
+    /// Sends a request without waiting for its response.
+    ///
+    /// # Returns
+    /// The request's id (always a new one, as requests pipelined on a
+    /// stream are told apart by id), which its events and response will carry
+    pub async fn send<T: Message>(&mut self, message: &T) -> Result<String> {
+        if !self.message_types.iter().any(|t| t == T::KIND.as_str()) {
+            return Err(Error::Protocol(format!("Server {} does not support {} requests (supports: {})", 
+                self.target, T::KIND, self.message_types.join(", "))));
+        }
+        let id = crate::protocol::new_request_id();
+        let timeout_ms = self.timeout.map(|timeout| timeout.as_millis() as u64);
+        let payload = crate::protocol::encode_request(message, Some(id.clone()), timeout_ms, self.auth_token.clone(), self.codec)?;
+        
+        let (send, codec, target) = (&mut self.send, self.codec, &self.target);
+        crate::logger::with_request_id(id.clone(), async {
+            crate::log_client!("[CLIENT] ===== OUTGOING REQUEST (framed) =====");
+            crate::log_client!("[CLIENT] REQUEST TYPE: {} (type={}, id={}, codec={})", T::KIND.type_name(), T::KIND, id, codec);
+            crate::log_client!("[CLIENT] Function: quic_client::MessageStream::send()");
+            crate::log_client!("[CLIENT] Target: {}", target);
+            crate::log_client!("[CLIENT] JSON payload length: {}", payload.len());
+            if T::KIND.loggable() {
+                crate::log_client!("[CLIENT] JSON payload: {}", codec.to_log_string(&payload));
+            }
+            crate::framing::write_frame(send, &payload).await
+        }).await?;
+        Ok(id)
+    }
+    
+    /// Reads the next message from the server.
+    ///
+    /// # Returns
+    /// The message, or `None` once the server has closed the stream
+    pub async fn recv(&mut self) -> Result<Option<Frame>> {
+        let bytes = match crate::framing::read_frame(&mut self.recv, MAX_FRAME_SIZE).await? {
+            Some(bytes) => bytes,
+            None => {
+                crate::log_client!("[MessageStream::recv] Stream closed by {}", self.target);
+                return Ok(None);
+            }
+        };
+        let (kind, id) = crate::protocol::peek(&bytes, self.codec)?;
+        crate::log_client!("[CLIENT] INCOMING FRAME: {} (type={}, id={}) - {} bytes from {}", 
+            kind.type_name(), kind, id.as_deref().unwrap_or("-"), bytes.len(), self.target);
+        Ok(Some(Frame { kind, id, bytes, codec: self.codec }))
+    }
+    
+    /// Sends a request and waits for its response, handing every other
+    /// message read meanwhile (its events, and responses to requests sent
+    /// earlier with `send`) to `on_event`.
+    ///
+    /// An `ErrorResponse` to the request is returned as `Error::Remote`
+    /// unless `R` is `ErrorResponse` itself.
+    pub async fn request<T, R, F>(
+        &mut self,
+        message: &T,
+        mut on_event: F,
+    ) -> Result<R>
+    where
+        T: Message,
+        R: Message,
+        F: FnMut(&Frame) -> Result<()>,
+    {
+        let id = self.send(message).await?;
+        while let Some(frame) = self.recv().await? {
+            if frame.id.as_deref() != Some(id.as_str()) {
+                on_event(&frame)?;
+                continue;
+            }
+            if frame.kind == R::KIND {
+                return Ok(frame.decode()?);
+            }
+            if frame.kind == MessageKind::Error {
+                return Err(frame.decode::<ErrorResponse>()?.into());
+            }
+            if frame.kind.is_request() || Some(frame.kind) == T::KIND.response() {
+                return Err(Error::Protocol(format!("Expected {} response, got {}", R::KIND, frame.kind)));
+            }
+            on_event(&frame)?;
+        }
+        Err(Error::transport(format!("Stream to {} closed before the {} response (id={})", self.target, R::KIND, id)))
+    }
+    
+    /// Tells the server no more requests follow; responses still pending
+    /// can be read with `recv` until it returns `None`.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.send.finish().await?;
+        Ok(())
+    }
+}
+
+/// Server events pushed on a framed stream, opened with
+/// `QuicClient::subscribe`.
+///
+/// If the stream or its connection is lost, `next` subscribes again with
+/// the same request (on a new connection if need be) and reports the gap
+/// with `ServerEvent::Dropped`, as events published meanwhile are missed.
+pub struct Subscription<'a> {
+    client: &'a QuicClient,
+    server: String,
+    port: u16,
+    request: SubscribeRequest,
+    stream: MessageStream,
+    /// The server's acknowledgement: the events it sends
+    pub accepted: SubscribeResponse,
+}
+
+impl Subscription<'_> {
+    /// Waits for the next event.
+    ///
+    /// # Returns
+    /// The event; `ServerEvent::Dropped` (with no count) after subscribing
+    /// again. An error if the server ends the subscription with one, or it
+    /// can't be renewed within `RESUBSCRIBE_ATTEMPTS` attempts
+    pub async fn next(&mut self) -> Result<ServerEvent> {
+        loop {
+            match self.stream.recv().await {
+                Ok(Some(frame)) => match frame.kind {
+                    MessageKind::Event => return Ok(frame.decode()?),
+                    MessageKind::Error => return Err(frame.decode::<ErrorResponse>()?.into()),
+                    kind => {
+                        crate::log_client!("[Subscription::next] Ignoring unexpected {} frame from {}", kind, self.stream.target);
+                        continue;
+                    }
+                },
+                Ok(None) => {
+                    crate::log_client!("[Subscription::next] Subscription stream closed by {}", self.stream.target);
+                }
+                Err(Error::Protocol(e)) => return Err(Error::Protocol(e)),
+                Err(e) => {
+                    crate::log_client!("[Subscription::next] Subscription stream to {} lost: {}", self.stream.target, e);
+                }
+            }
+            self.resubscribe().await?;
+            return Ok(ServerEvent::Dropped { missed: None });
+        }
+    }
+    
+    /// Subscribes again after the stream was lost, backing off between
+    /// attempts; a server's refusal is final.
+    async fn resubscribe(&mut self) -> Result<()> {
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            crate::log_client!("[Subscription::resubscribe] Attempt {}/{} to {}:{}", attempt, RESUBSCRIBE_ATTEMPTS, self.server, self.port);
+            match self.client.open_subscription(&self.server, self.port, &self.request).await {
+                Ok((stream, accepted)) => {
+                    self.stream = stream;
+                    self.accepted = accepted;
+                    return Ok(());
+                }
+                Err(e) if e.remote_code().is_some() || attempt == RESUBSCRIBE_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    crate::log_client!("[Subscription::resubscribe] Failed: {} - retrying in {:?}", e, backoff);
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+    
+    /// Ends the subscription.
+    pub async fn close(mut self) -> Result<()> {
+        self.stream.finish().await
+    }
+}
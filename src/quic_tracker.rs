@@ -7,30 +7,23 @@
 //! - Connection migration
 //! - Reduced latency compared to TCP
 
-use quinn::{Endpoint, ServerConfig};
+use quinn::Endpoint;
 use crate::quic_utils::create_server_config;
-use crate::messages::{TrackerAnnounceRequest, TrackerAnnounceResponse, PeerInfo, FileRequest, FileResponse, ErrorResponse, AiRequest, AiResponse, ResponseMetadata};
-use crate::ai_processor::{AiProcessor, AiProcessingConfig};
+use crate::messages::{Hello, HelloResponse, TrackerAnnounceRequest, TrackerAnnounceResponse, PeerInfo, FileRequest, FileResponse, ErrorResponse, AiRequest, AiResponse, AiToken, ResponseMetadata, ListFilesRequest, ListFilesResponse, PublishRequest, PublishResponse, SubscribeRequest, SubscribeResponse, ServerEvent, SwarmChange, SeedFileChange};
+use crate::protocol::{Codec, DecodedRequest, Message, MessageKind, ReplyStyle, Request, MAX_REQUEST_SIZE};
+use crate::framing::FRAMED_STREAM_MARKER;
+use crate::ai_processor::AiProcessor;
 use crate::work_distribution::{WorkDistributionManager, NodeCapability};
+use crate::seed::SeedDirectory;
+use crate::events::EventBus;
+use crate::router::{BoxFuture, Handler, RateLimit, RequestContext, RequestLogger, Router, TokenAuth};
+use crate::error::{Error, Result};
 
-/// Detect request type from JSON string
-fn detect_request_type(json: &str) -> &'static str {
-    if json.contains("\"info_hash\"") && json.contains("\"peer_id\"") {
-        "TrackerAnnounceRequest"
-    } else if json.contains("\"file\"") {
-        "FileRequest"
-    } else if json.contains("\"query\"") {
-        "AiRequest"
-    } else if json.contains("\"type\"") {
-        "CustomJSON"
-    } else {
-        "UnknownRequest"
-    }
-}
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::path::PathBuf;
 
 #[derive(Clone, Debug)]
 pub struct Peer {
@@ -45,6 +38,119 @@ pub struct Peer {
 #[derive(Default)]
 pub struct TrackerState {
     peers: HashMap<String, Vec<Peer>>, // info_hash -> peers
+    seed_files: HashMap<String, String>, // info_hash -> seed file name (server-seeded swarms)
+    events: EventBus, // swarm changes for subscribers
+}
+
+/// Peer ID the server announces under when it seeds files from its seed directory.
+pub const SERVER_PEER_ID: &str = "-QT0001-SERVERSEED00";
+
+impl TrackerState {
+    /// Adds (or refreshes) a peer in the swarm for `info_hash`.
+    ///
+    /// # Returns
+    /// `true` if the peer was already in the swarm
+    pub fn upsert_peer(&mut self, info_hash: &str, peer: Peer) -> bool {
+        let peers = self.peers.entry(info_hash.to_string()).or_default();
+        let was_present = peers.iter().any(|p| p.peer_id == peer.peer_id);
+        peers.retain(|p| p.peer_id != peer.peer_id);
+        peers.push(peer.clone());
+        let change = if was_present { SwarmChange::Updated } else { SwarmChange::Joined };
+        self.publish_swarm_change(info_hash, change, Some(&peer));
+        was_present
+    }
+
+    /// Removes a peer from the swarm for `info_hash`, e.g. after a `stopped` announce.
+    pub fn remove_peer(&mut self, info_hash: &str, peer_id: &str) -> Option<Peer> {
+        let peers = self.peers.get_mut(info_hash)?;
+        let index = peers.iter().position(|p| p.peer_id == peer_id)?;
+        let peer = peers.remove(index);
+        self.publish_swarm_change(info_hash, SwarmChange::Left, Some(&peer));
+        Some(peer)
+    }
+
+    /// Registers the server itself as a seeder for `info_hash`, backed by seed file `name`.
+    pub fn register_server_seed(&mut self, info_hash: &str, name: &str, ip: &str, port: u16) {
+        self.seed_files.insert(info_hash.to_string(), name.to_string());
+        self.upsert_peer(info_hash, Peer {
+            peer_id: SERVER_PEER_ID.to_string(),
+            ip: ip.to_string(),
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+        });
+    }
+
+    /// Removes a server-seeded swarm, e.g. after its file was deleted from the seed directory.
+    pub fn retire_swarm(&mut self, info_hash: &str) {
+        self.seed_files.remove(info_hash);
+        if self.peers.remove(info_hash).is_some() {
+            self.publish_swarm_change(info_hash, SwarmChange::Retired, None);
+        }
+    }
+
+    /// Returns the seed file backing a server-seeded swarm.
+    pub fn seed_file(&self, info_hash: &str) -> Option<String> {
+        self.seed_files.get(info_hash).cloned()
+    }
+
+    /// Returns the peers currently in the swarm for `info_hash`.
+    pub fn peers(&self, info_hash: &str) -> Vec<Peer> {
+        self.peers.get(info_hash).cloned().unwrap_or_default()
+    }
+
+    /// The bus the server's events are published on.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Tells subscribers about a change to the swarm for `info_hash`.
+    fn publish_swarm_change(&self, info_hash: &str, change: SwarmChange, peer: Option<&Peer>) {
+        let peers = self.peers.get(info_hash).map(Vec::as_slice).unwrap_or_default();
+        self.events.publish(ServerEvent::Swarm {
+            info_hash: info_hash.to_string(),
+            change,
+            peer: peer.map(|p| PeerInfo { ip: p.ip.clone(), port: p.port, peer_id: Some(p.peer_id.clone()) }),
+            complete: peers.iter().filter(|p| p.left == 0).count() as u64,
+            incomplete: peers.iter().filter(|p| p.left > 0).count() as u64,
+        });
+    }
+}
+
+/// Server state shared by every connection, handed to request handlers
+/// (see `router::RequestContext::app`).
+pub struct AppState {
+    pub state: Arc<RwLock<TrackerState>>,
+    pub seed: Arc<SeedDirectory>,
+    pub config: Arc<TrackerConfig>,
+    /// Local AI processing, if enabled
+    pub ai_processor: Option<Arc<RwLock<AiProcessor>>>,
+    /// Delegation of AI work to other nodes, if enabled
+    pub work_dist: Option<Arc<WorkDistributionManager>>,
+}
+
+impl AppState {
+    /// The bus server events are published on (see `events`).
+    pub fn events(&self) -> EventBus {
+        self.state.read().unwrap().events().clone()
+    }
+}
+
+/// What every stream of a connection shares.
+#[derive(Clone)]
+struct ServerContext {
+    app: Arc<AppState>,
+    router: Arc<Router>,
+    /// Codec picked by the connection's `Hello` (JSON until then)
+    codec: Arc<RwLock<Codec>>,
+}
+
+impl ServerContext {
+    /// How long a client may take to send a request.
+    fn read_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.app.config.read_timeout_secs)
+    }
 }
 
 /// Handles a QUIC connection from a client.
@@ -52,124 +158,368 @@ pub struct TrackerState {
 /// QUIC supports multiple bidirectional streams per connection.
 /// This function:
 /// 1. Accepts bidirectional streams from the connection
-/// 2. Reads JSON requests from the stream
-/// 3. Processes announce or file requests
+/// 2. Reads JSON requests from the stream: one read to EOF, or a sequence
+///    of frames if the stream starts with `framing::FRAMED_STREAM_MARKER`;
+///    a request must arrive within `TrackerConfig::read_timeout_secs` and
+///    stay within `protocol::MAX_REQUEST_SIZE` (`TIMEOUT` and
+///    `REQUEST_TOO_LARGE` errors otherwise)
+/// 3. Routes each request on its message type through `router`
 /// 4. Sends JSON responses back through the stream
 pub async fn handle_quic_connection(
     connection: quinn::Connection,
-    state: Arc<RwLock<TrackerState>>,
-    ai_processor: Option<Arc<RwLock<AiProcessor>>>,
-    work_dist: Option<Arc<WorkDistributionManager>>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    app: Arc<AppState>,
+    router: Arc<Router>,
+) -> Result<()> {
     let remote_addr = connection.remote_address();
     crate::log_server!("New QUIC connection established from: {}", remote_addr);
+    let context = ServerContext { app, router, codec: Arc::new(RwLock::new(Codec::Json)) };
     
     while let Ok(stream) = connection.accept_bi().await {
         crate::log_server!("New bidirectional stream opened from: {}", remote_addr);
         let (mut send, mut recv) = stream;
-        let state = Arc::clone(&state);
-        let ai_proc = ai_processor.clone();
-        let work_dist_clone = work_dist.clone();
+        let context = context.clone();
         
         tokio::spawn(async move {
-            // Read request
-            let mut buffer = Vec::new();
-            loop {
-                let mut chunk = vec![0u8; 4096];
-                match recv.read(&mut chunk).await {
-                    Ok(Some(size)) => {
-                        buffer.extend_from_slice(&chunk[..size]);
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        crate::log_server!("ERROR: Error reading QUIC stream from {}: {}", remote_addr, e);
-                        return;
-                    }
+            // The first byte tells a framed stream from a single request read to EOF
+            let read_timeout = context.read_timeout();
+            let mut first = [0u8; 1];
+            match tokio::time::timeout(read_timeout, recv.read(&mut first)).await {
+                Ok(Ok(Some(1))) => {}
+                Ok(Ok(_)) => {
+                    crate::log_server!("Empty request received from: {}", remote_addr);
+                    return;
+                }
+                Ok(Err(e)) => {
+                    crate::log_server!("ERROR: Error reading QUIC stream from {}: {}", remote_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    crate::log_server!("ERROR: Stream from {} idle for {:?}, closing it", remote_addr, read_timeout);
+                    let _ = recv.stop(STREAM_REFUSED);
+                    let _ = send.finish().await;
+                    return;
                 }
             }
-            
-            if buffer.is_empty() {
-                crate::log_server!("Empty request received from: {}", remote_addr);
+            if first[0] == FRAMED_STREAM_MARKER {
+                serve_framed_stream(&context, remote_addr, &mut send, &mut recv).await;
                 return;
             }
             
-            crate::log_server!("Received {} bytes from: {}", buffer.len(), remote_addr);
-            
-            // Try to parse as JSON request
-            let request_str = match String::from_utf8(buffer) {
-                Ok(s) => s,
-                Err(e) => {
-                    crate::log_server!("ERROR: Invalid UTF-8 in request from {}: {}", remote_addr, e);
-                    let error = ErrorResponse {
-                        error: "Invalid UTF-8 encoding".to_string(),
-                        code: Some("INVALID_ENCODING".to_string()),
-                    };
-                    let _ = send.write_all(&serde_json::to_string(&error).unwrap().into_bytes()).await;
+            // Read request
+            let buffer = match tokio::time::timeout(read_timeout, read_request(&mut recv, first[0])).await {
+                Ok(Ok(buffer)) => buffer,
+                Ok(Err((error, code))) => {
+                    crate::log_server!("ERROR: Refusing request from {}: {}", remote_addr, error);
+                    let _ = recv.stop(STREAM_REFUSED);
+                    Responder::new(&mut send, false).error(&error, code).await;
+                    return;
+                }
+                Err(_) => {
+                    crate::log_server!("ERROR: Request from {} not received within {:?}", remote_addr, read_timeout);
+                    let _ = recv.stop(STREAM_REFUSED);
+                    let error = format!("Request not received within {} s", read_timeout.as_secs());
+                    Responder::new(&mut send, false).error(&error, "TIMEOUT").await;
                     return;
                 }
             };
             
-            // Detect and log request type
-            let request_type = detect_request_type(&request_str);
-            crate::log_server!("[REQUEST] REQUEST_TYPE: {} - from: {}", request_type, remote_addr);
-            crate::log_server!("[REQUEST] JSON payload: {}", request_str);
-            
-            // Route to appropriate processing module based on request type
-            crate::log_server!("[ROUTING] Incoming request detected - type: {}, from: {}", request_type, remote_addr);
-            
-            // Try to parse as announce request -> Tracker Module
-            if let Ok(announce_req) = serde_json::from_str::<TrackerAnnounceRequest>(&request_str) {
-                crate::log_server!("[ROUTING] Request type: TrackerAnnounceRequest");
-                crate::log_server!("[ROUTING] Routing to: quic_tracker::handle_announce_request()");
-                crate::log_server!("[ROUTING] Processing module: Tracker Module");
-                crate::log_server_received!("Parsed TrackerAnnounceRequest from: {}", remote_addr);
-                handle_announce_request(announce_req, state, &mut send).await;
+            crate::log_server!("Received {} bytes from: {}", buffer.len(), remote_addr);
+            dispatch_request(&context, remote_addr, &buffer, &mut Responder::new(&mut send, false)).await;
+        });
+    }
+    
+    Ok(())
+}
+
+/// Application error code the server stops a request stream's receiving
+/// half with when it won't read the rest (too large, or too slow).
+const STREAM_REFUSED: quinn::VarInt = quinn::VarInt::from_u32(1);
+
+/// Reads a single-request stream to EOF.
+///
+/// # Arguments
+/// * `first` - The byte already read from the stream
+///
+/// # Returns
+/// The request, or the error to answer with if it grows past
+/// `protocol::MAX_REQUEST_SIZE` or the stream fails
+async fn read_request(recv: &mut quinn::RecvStream, first: u8) -> Result<Vec<u8>, (String, &'static str)> {
+    let mut buffer = vec![first];
+    loop {
+        let mut chunk = vec![0u8; 4096];
+        match recv.read(&mut chunk).await {
+            Ok(Some(size)) => {
+                if buffer.len() + size > MAX_REQUEST_SIZE {
+                    return Err((format!("Request exceeds the limit of {} bytes", MAX_REQUEST_SIZE), "REQUEST_TOO_LARGE"));
+                }
+                buffer.extend_from_slice(&chunk[..size]);
             }
-            // Try to parse as file request -> File Serving Module
-            else if let Ok(file_req) = serde_json::from_str::<FileRequest>(&request_str) {
-                crate::log_server!("[ROUTING] Request type: FileRequest");
-                crate::log_server!("[ROUTING] Routing to: quic_tracker::handle_file_request()");
-                crate::log_server!("[ROUTING] Processing module: File Serving Module");
-                crate::log_server_received!("Parsed FileRequest from: {} - file: '{}'", remote_addr, file_req.file);
-                handle_file_request(file_req, &mut send).await;
+            Ok(None) => return Ok(buffer),
+            Err(e) => return Err((format!("Error reading QUIC stream: {}", e), "READ_ERROR")),
+        }
+    }
+}
+
+/// Answers the requests on a framed stream, in order, until the client
+/// finishes its side.
+///
+/// Each request must arrive within `TrackerConfig::read_timeout_secs` of the
+/// previous reply (or of the stream opening), so idle streams are closed.
+async fn serve_framed_stream(
+    context: &ServerContext,
+    remote_addr: std::net::SocketAddr,
+    send: &mut quinn::SendStream,
+    recv: &mut quinn::RecvStream,
+) {
+    crate::log_server!("[FRAMING] Framed stream opened by: {}", remote_addr);
+    let read_timeout = context.read_timeout();
+    let mut served = 0usize;
+    loop {
+        let (error, code) = match tokio::time::timeout(read_timeout, crate::framing::read_frame(recv, MAX_REQUEST_SIZE)).await {
+            Ok(Ok(Some(frame))) => {
+                served += 1;
+                crate::log_server!("[FRAMING] Frame {} from {}: {} bytes", served, remote_addr, frame.len());
+                dispatch_request(context, remote_addr, &frame, &mut Responder::new(send, true)).await;
+                continue;
             }
-            // Try to parse as AI request -> AI Processing Module
-            else if let Ok(ai_req) = serde_json::from_str::<AiRequest>(&request_str) {
-                crate::log_server!("[ROUTING] Request type: AiRequest");
-                crate::log_server!("[ROUTING] Routing to: quic_tracker::handle_ai_request()");
-                crate::log_server!("[ROUTING] Processing module: AI Processing Module");
-                crate::log_server!("[ROUTING] Handler function: handle_ai_request() -> ai_processor::process_query_sync()");
-                handle_ai_request(ai_req, ai_proc, work_dist_clone, &mut send).await;
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) if crate::framing::is_too_large(&e) => (e.to_string(), "REQUEST_TOO_LARGE"),
+            Ok(Err(e)) => (format!("Invalid frame: {}", e), "INVALID_FRAME"),
+            Err(_) => (format!("No request within {} s", read_timeout.as_secs()), "TIMEOUT"),
+        };
+        // The stream can't be resynchronised after a bad frame
+        crate::log_server!("ERROR: Closing framed stream from {}: {} ({})", remote_addr, error, code);
+        let _ = recv.stop(STREAM_REFUSED);
+        let mut reply = Responder::new(send, true);
+        reply.style = ReplyStyle::Envelope { id: None, codec: *context.codec.read().unwrap() };
+        reply.error(&error, code).await;
+        break;
+    }
+    let _ = send.finish().await;
+    crate::log_server!("[FRAMING] Framed stream from {} closed after {} request(s)", remote_addr, served);
+}
+
+/// Decodes one request (enveloped or legacy) and handles it with its log
+/// lines tagged with its request id: the client's, or a new one for requests
+/// without (echoed back if the reply is an envelope).
+///
+/// The handler is dropped with a `TIMEOUT` error once the client's
+/// `timeout_ms` or `TrackerConfig::request_timeout_ms`, whichever is
+/// shorter, has passed. Subscriptions have no deadline: they last until
+/// the client stops reading.
+async fn dispatch_request(
+    context: &ServerContext,
+    remote_addr: std::net::SocketAddr,
+    buffer: &[u8],
+    reply: &mut Responder<'_>,
+) {
+    // Decode the envelope (or a legacy bare message) and route on its type
+    let codec = *context.codec.read().unwrap();
+    let DecodedRequest { style, type_name, timeout_ms, auth, request } = crate::protocol::decode_request(buffer, codec);
+    let request_id = style.id().map(str::to_string).unwrap_or_else(crate::protocol::new_request_id);
+    reply.style = match style {
+        ReplyStyle::Envelope { id: None, codec } => ReplyStyle::Envelope { id: Some(request_id.clone()), codec },
+        style => style,
+    };
+    
+    let timeout = context.app.config.request_timeout(timeout_ms);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let subscription = matches!(request, Ok(Request::Subscribe(_)));
+    let routed_request = RequestContext {
+        app: &context.app,
+        router: &context.router,
+        type_name: type_name.as_deref().unwrap_or_default(),
+        request_id: &request_id,
+        remote_addr,
+        auth: auth.as_deref(),
+        style: reply.style.clone(),
+        framed: reply.framed,
+        bytes: buffer,
+        handler: "",
+        request: None,
+        connection_codec: &context.codec,
+    };
+    if subscription {
+        crate::logger::with_request_id(request_id.clone(), context.router.dispatch(routed_request, request, reply)).await;
+        return;
+    }
+    crate::logger::with_request_id(request_id.clone(), async {
+        let routed = context.router.dispatch(routed_request, request, &mut *reply);
+        if tokio::time::timeout_at(deadline, crate::protocol::with_deadline(deadline, routed)).await.is_err() {
+            crate::log_server!("ERROR: Request from {} not answered within {:?} (client asked for {:?} ms), cancelled", 
+                remote_addr, timeout, timeout_ms);
+            reply.timed_out(timeout).await;
+        }
+    }).await;
+}
+
+/// The router for the server's own request types, with the middleware
+/// `config` asks for: request logging, then the per-client rate limit and
+/// the auth token if set.
+pub fn default_router(config: &TrackerConfig) -> Router {
+    let mut router = Router::new().layer(RequestLogger);
+    if let Some(per_second) = config.rate_limit {
+        router = router.layer(RateLimit::new(per_second, per_second.saturating_mul(2)));
+    }
+    if let Some(token) = &config.auth_token {
+        router = router.layer(TokenAuth::new(token.clone()));
+    }
+    MessageKind::ALL.into_iter()
+        .filter(|kind| kind.is_request())
+        .fold(router, |router, kind| router.route(kind.as_str(), BuiltinHandler(kind)))
+}
+
+/// Hands the server's own request types to their `handle_*` functions.
+struct BuiltinHandler(MessageKind);
+
+impl Handler for BuiltinHandler {
+    fn handle<'a, 'b>(&'a self, mut request: RequestContext<'a>, reply: &'a mut Responder<'b>) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            match request.take_request() {
+                Some(decoded) => route_request(&request, decoded, reply).await,
+                None => reply.error(&format!("Not a {} request", self.0), "INVALID_REQUEST").await,
             }
-            else {
-                // Unknown request type -> Error Handler Module
-                crate::log_server!("[ROUTING] Request type: UnknownRequest");
-                crate::log_server!("[ROUTING] Routing to: Error Handler (send_error)");
-                crate::log_server!("[ROUTING] Processing module: Error Handler Module");
-                crate::log_server!("ERROR: Unknown request type from: {} - request_len={}", remote_addr, request_str.len());
-                let error = ErrorResponse {
-                    error: "Unknown request type".to_string(),
-                    code: Some("UNKNOWN_REQUEST".to_string()),
-                };
-                let _ = send.write_all(&serde_json::to_string(&error).unwrap().into_bytes()).await;
+        })
+    }
+    
+    fn name(&self) -> &str {
+        self.0.handler()
+    }
+    
+    fn max_request_size(&self) -> usize {
+        self.0.max_request_size()
+    }
+}
+
+/// Hands a decoded request to its handler.
+async fn route_request(
+    request: &RequestContext<'_>,
+    decoded: Request,
+    reply: &mut Responder<'_>,
+) {
+    let AppState { state, seed, config, ai_processor, work_dist } = request.app;
+    let remote_addr = request.remote_addr;
+    match decoded {
+        Request::Hello(hello) => {
+            crate::log_server_received!("Parsed Hello from: {} - agent: {:?}", remote_addr, hello.agent);
+            let features = server_features(config, ai_processor.is_some(), work_dist.is_some());
+            handle_hello(hello, request.router.request_types(), features, request.connection_codec, reply).await;
+        }
+        Request::Announce(announce_req) => {
+            crate::log_server_received!("Parsed TrackerAnnounceRequest from: {}", remote_addr);
+            handle_announce_request(announce_req, Arc::clone(state), remote_addr, reply).await;
+        }
+        Request::File(mut file_req) => {
+            // Peers fetching pieces name the torrent; serve the seed file registered for it
+            if let Some(name) = file_req.info_hash.as_deref().and_then(|h| state.read().unwrap().seed_file(h)) {
+                crate::log_server!("[ROUTING] info_hash {:?} maps to seed file '{}'", file_req.info_hash, name);
+                file_req.file = name;
             }
-        });
+            crate::log_server_received!("Parsed FileRequest from: {} - file: '{}'", remote_addr, file_req.file);
+            handle_file_request(file_req, seed, reply).await;
+        }
+        Request::ListFiles(list_req) => {
+            crate::log_server_received!("Parsed ListFilesRequest from: {} - pattern: {:?}", remote_addr, list_req.pattern);
+            handle_list_files_request(list_req, Arc::clone(seed), reply).await;
+        }
+        Request::Publish(publish_req) => {
+            crate::log_server_received!("Parsed PublishRequest from: {} - path: '{}', offset: {}, chunk: {} bytes", 
+                remote_addr, publish_req.publish, publish_req.offset, publish_req.data.len());
+            handle_publish_request(publish_req, Arc::clone(seed), config, Arc::clone(state), reply).await;
+        }
+        Request::Ai(ai_req) => {
+            handle_ai_request(ai_req, ai_processor.clone(), work_dist.clone(), reply).await;
+        }
+        Request::Subscribe(subscribe_req) => {
+            crate::log_server_received!("Parsed SubscribeRequest from: {} - swarms: {:?}, seed_files: {}, work_nodes: {}", 
+                remote_addr, subscribe_req.swarms, subscribe_req.seed_files, subscribe_req.work_nodes);
+            handle_subscribe_request(subscribe_req, request.app.events(), work_dist.is_some(), remote_addr, reply).await;
+        }
+    }
+}
+
+/// Optional features this server offers, as listed in `HelloResponse::features`.
+fn server_features(config: &TrackerConfig, ai: bool, work_dist: bool) -> Vec<String> {
+    let mut features = Vec::new();
+    if ai {
+        features.push(crate::protocol::FEATURE_AI.to_string());
+    }
+    if work_dist {
+        features.push(crate::protocol::FEATURE_WORK_DISTRIBUTION.to_string());
+    }
+    if config.publish_token.is_some() {
+        features.push(crate::protocol::FEATURE_PUBLISH.to_string());
+    }
+    if config.auto_torrents {
+        features.push(crate::protocol::FEATURE_AUTO_TORRENTS.to_string());
     }
+    features.push(crate::protocol::FEATURE_FRAMED_STREAMS.to_string());
+    features.push(crate::protocol::FEATURE_SUBSCRIPTIONS.to_string());
+    if config.auth_token.is_some() {
+        features.push(crate::protocol::FEATURE_AUTH.to_string());
+    }
+    features
+}
+
+/// Answers a client's `Hello` with the server's capabilities and the
+/// protocol version both sides speak.
+async fn handle_hello(
+    req: Hello,
+    message_types: Vec<String>,
+    features: Vec<String>,
+    connection_codec: &RwLock<Codec>,
+    reply: &mut Responder<'_>,
+) {
+    use crate::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
     
-    Ok(())
+    crate::log_server!("[HANDLER] Function: quic_tracker::handle_hello()");
+    crate::log_server!("[HANDLER] Module: Handshake");
+    crate::log_server!("[HANDLER] Client speaks versions {}-{}, codecs {:?}, features {:?}", 
+        req.min_version, req.version, req.codecs, req.features);
+    
+    let version = match crate::protocol::negotiate_version(req.min_version, req.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION) {
+        Some(version) => version,
+        None => {
+            let error = format!("Incompatible protocol version: client speaks {}-{}, server speaks {}-{}", 
+                req.min_version, req.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+            crate::log_server!("ERROR: {}", error);
+            reply.error(&error, "UNSUPPORTED_VERSION").await;
+            return;
+        }
+    };
+    
+    // The hello itself is answered in the codec it came in; the connection
+    // switches for the requests after it
+    let codec = Codec::negotiate(&req.codecs);
+    *connection_codec.write().unwrap() = codec;
+    
+    let response = HelloResponse {
+        version,
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION,
+        message_types,
+        codecs: Codec::ALL.iter().map(|codec| codec.as_str().to_string()).collect(),
+        codec: Some(codec.as_str().to_string()),
+        features,
+        agent: Some(crate::protocol::agent()),
+    };
+    crate::log_server!("Handshake complete: version={}, codec={}, features={:?}", version, codec, response.features);
+    reply.send(&response).await;
 }
 
 async fn handle_announce_request(
     req: TrackerAnnounceRequest,
     state: Arc<RwLock<TrackerState>>,
-    send: &mut quinn::SendStream,
+    remote_addr: std::net::SocketAddr,
+    reply: &mut Responder<'_>,
 ) {
     crate::log_server!("[HANDLER] Function: quic_tracker::handle_announce_request()");
     crate::log_server!("[HANDLER] Module: Tracker Module");
     crate::log_server!("[HANDLER] Processing TrackerAnnounceRequest");
     
     let info_hash = req.info_hash.clone();
-    let peer_ip = req.ip.unwrap_or_else(|| "127.0.0.1".to_string());
+    // Peers that don't state an address are reachable where their announce came from
+    let peer_ip = req.ip.unwrap_or_else(|| remote_addr.ip().to_canonical().to_string());
     
     crate::log_server_received!("Received QUIC announce request from peer_id: {}, info_hash: {}, ip: {}, port: {}, uploaded: {}, downloaded: {}, left: {}, event: {:?}", 
         req.peer_id, info_hash, peer_ip, req.port, 
@@ -187,12 +537,8 @@ async fn handle_announce_request(
     // Update peer list
     {
         let mut state = state.write().unwrap();
-        let peers = state.peers.entry(info_hash.clone()).or_insert_with(Vec::new);
-        let was_present = peers.iter().any(|p| p.peer_id == peer.peer_id);
-        peers.retain(|p| p.peer_id != peer.peer_id);
         if req.event.as_deref() != Some("stopped") {
-            peers.push(peer.clone());
-            if was_present {
+            if state.upsert_peer(&info_hash, peer.clone()) {
                 crate::log_server!("Peer updated (QUIC): peer_id={}, info_hash={}, ip={}, port={}, uploaded={}, downloaded={}, left={}", 
                     peer.peer_id, info_hash, peer.ip, peer.port, peer.uploaded, peer.downloaded, peer.left);
             } else {
@@ -200,10 +546,11 @@ async fn handle_announce_request(
                     peer.peer_id, info_hash, peer.ip, peer.port, peer.uploaded, peer.downloaded, peer.left);
             }
         } else {
+            state.remove_peer(&info_hash, &peer.peer_id);
             crate::log_server!("Peer unregistered (QUIC): peer_id={}, info_hash={}, ip={}, port={}", 
                 peer.peer_id, info_hash, peer.ip, peer.port);
         }
-        let total_peers = peers.len();
+        let total_peers = state.peers.get(&info_hash).map_or(0, Vec::len);
         crate::log_server!("Peer count for info_hash {}: {} peers", info_hash, total_peers);
     }
 
@@ -219,6 +566,7 @@ async fn handle_announce_request(
         .map(|p| PeerInfo {
             ip: p.ip.clone(),
             port: p.port,
+            peer_id: Some(p.peer_id.clone()),
         })
         .collect();
     
@@ -229,85 +577,75 @@ async fn handle_announce_request(
         incomplete: peers_list.iter().filter(|p| p.left > 0).count() as u64,
     };
     
-    let json_response = match serde_json::to_string(&response) {
-        Ok(json) => json,
-        Err(e) => {
-            crate::log_server!("ERROR: Error serializing QUIC announce response: {}", e);
-            let error = ErrorResponse {
-                error: "Internal server error".to_string(),
-                code: Some("SERIALIZATION_ERROR".to_string()),
-            };
-            serde_json::to_string(&error).unwrap()
-        }
-    };
-    
     crate::log_server!("Sending QUIC announce response: {} peers, {} complete, {} incomplete, interval={}s", 
         peer_infos.len(),
         response.complete,
         response.incomplete,
         response.interval);
     
-    let _ = send.write_all(json_response.as_bytes()).await;
-    let _ = send.finish().await;
+    reply.send(&response).await;
 }
 
 async fn handle_file_request(
     req: FileRequest,
-    send: &mut quinn::SendStream,
+    seed: &SeedDirectory,
+    reply: &mut Responder<'_>,
 ) {
     crate::log_server!("[HANDLER] Function: quic_tracker::handle_file_request()");
     crate::log_server!("[HANDLER] Module: File Serving Module");
     crate::log_server!("[HANDLER] Processing FileRequest");
-    crate::log_server_received!("Received QUIC file download request: file='{}'", req.file);
-    
-    // Get current working directory
-    let current_dir = std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from("."));
-    let seed_dir = current_dir.join("seed");
+    crate::log_server_received!("Received QUIC file download request: file='{}', offset={:?}, length={:?}", 
+        req.file, req.offset, req.length);
     
-    // Sanitize filename to prevent directory traversal
-    let sanitized = req.file.replace("..", "").replace("\\", "").replace("/", "");
-    let file_path = if sanitized.is_empty() {
-        crate::log_server!("No filename specified, using default: hello_world.txt");
-        seed_dir.join("hello_world.txt")
-    } else {
-        seed_dir.join(&sanitized)
+    // Resolve the name inside the seed root (rejects traversal and symlink escapes)
+    let file_path = match seed.resolve(&req.file) {
+        Ok(path) => path,
+        Err(e) => {
+            crate::log_server!("ERROR: Rejected file request '{}': {}", req.file, e);
+            reply.error(&e.to_string(), e.code()).await;
+            return;
+        }
     };
     
     crate::log_server!("Resolved file path: {}", file_path.display());
     
-    // Check file size limit (5MB max for JSON transfer to avoid timeouts)
+    // Check file size limit (5MB max per response for JSON transfer to avoid timeouts)
     const MAX_FILE_SIZE: u64 = 5 * 1024 * 1024; // 5MB
     let file_metadata = match fs::metadata(&file_path) {
         Ok(meta) => meta,
         Err(e) => {
             let error_msg = format!("File not found or unreadable: {} - {}", file_path.display(), e);
             crate::log_server!("ERROR: {}", error_msg);
-            let error = ErrorResponse {
-                error: format!("File not found: {}", file_path.display()),
-                code: Some("FILE_NOT_FOUND".to_string()),
-            };
-            let json_error = serde_json::to_string(&error).unwrap();
-            let _ = send.write_all(json_error.as_bytes()).await;
-            let _ = send.finish().await;
+            reply.error(&format!("File not found: {}", file_path.display()), "FILE_NOT_FOUND").await;
             return;
         }
     };
     
-    if file_metadata.len() > MAX_FILE_SIZE {
-        crate::log_server!("WARNING: File too large for JSON transfer: {} ({} bytes > {} bytes)", 
-            req.file, file_metadata.len(), MAX_FILE_SIZE);
-        let error = ErrorResponse {
-            error: format!("File too large: {} ({} bytes). Maximum size: {} bytes. Use chunked transfer for larger files.", 
-                req.file, file_metadata.len(), MAX_FILE_SIZE),
-            code: Some("FILE_TOO_LARGE".to_string()),
-        };
-        let json_error = serde_json::to_string(&error).unwrap();
-        let _ = send.write_all(json_error.as_bytes()).await;
-        let _ = send.finish().await;
+    // Resolve the requested byte range against the file size
+    let total_size = file_metadata.len();
+    let offset = req.offset.unwrap_or(0);
+    if offset > total_size {
+        crate::log_server!("ERROR: Range offset {} beyond end of file {} ({} bytes)", 
+            offset, req.file, total_size);
+        reply.error(&format!("Offset {} is beyond end of file {} ({} bytes)", offset, req.file, total_size), "INVALID_RANGE").await;
         return;
     }
+    let remaining = total_size - offset;
+    let length = req.length.map_or(remaining, |len| len.min(remaining));
     
-    match fs::read(&file_path) {
+    crate::log_server!("Serving range: file={}, offset={}, length={}, total_size={}", 
+        req.file, offset, length, total_size);
+    
+    if length > MAX_FILE_SIZE {
+        crate::log_server!("WARNING: Range too large for JSON transfer: {} ({} bytes > {} bytes)", 
+            req.file, length, MAX_FILE_SIZE);
+        let error = format!("File too large: {} ({} bytes). Maximum size: {} bytes. Use offset/length range requests for larger files.", 
+            req.file, length, MAX_FILE_SIZE);
+        reply.error(&error, "FILE_TOO_LARGE").await;
+        return;
+    }
+    
+    match read_file_range(&file_path, offset, length) {
         Ok(data) => {
             // Warn about large file transfers (>1MB) that may be slow
             if data.len() > 1024 * 1024 {
@@ -315,50 +653,517 @@ async fn handle_file_request(
                     req.file, data.len());
             }
             
-            crate::log_server!("File found: {} ({} bytes), sending via QUIC", file_path.display(), data.len());
+            crate::log_server!("File found: {} ({} of {} bytes from offset {}), sending via QUIC", 
+                file_path.display(), data.len(), total_size, offset);
             
+            let size = data.len();
             let response = FileResponse {
-                data: data.clone(),
+                data,
                 filename: req.file.clone(),
-                size: data.len(),
-            };
-            
-            let json_response = match serde_json::to_string(&response) {
-                Ok(json) => json,
-                Err(e) => {
-                    crate::log_server!("ERROR: Error serializing QUIC file response: {}", e);
-                    let error = ErrorResponse {
-                        error: "Internal server error".to_string(),
-                        code: Some("SERIALIZATION_ERROR".to_string()),
-                    };
-                    serde_json::to_string(&error).unwrap()
-                }
+                size,
+                offset: Some(offset),
+                total_size: Some(total_size),
             };
             
-            let _ = send.write_all(json_response.as_bytes()).await;
-            let _ = send.finish().await;
-            crate::log_server!("File sent successfully via QUIC: {} ({} bytes)", req.file, data.len());
+            reply.send(&response).await;
+            crate::log_server!("File sent successfully via QUIC: {} ({} bytes)", req.file, size);
         }
         Err(e) => {
             let error_msg = format!("File not found or unreadable: {} - {}", file_path.display(), e);
             crate::log_server!("ERROR: {}", error_msg);
-            let error = ErrorResponse {
-                error: format!("File not found: {}", file_path.display()),
-                code: Some("FILE_NOT_FOUND".to_string()),
-            };
-            let json_error = serde_json::to_string(&error).unwrap();
-            crate::log_server!("Sending error response via QUIC: {}", error.error);
-            let _ = send.write_all(json_error.as_bytes()).await;
-            let _ = send.finish().await;
+            reply.error(&format!("File not found: {}", file_path.display()), "FILE_NOT_FOUND").await;
+        }
+    }
+}
+
+async fn handle_list_files_request(
+    req: ListFilesRequest,
+    seed: Arc<SeedDirectory>,
+    reply: &mut Responder<'_>,
+) {
+    crate::log_server!("[HANDLER] Function: quic_tracker::handle_list_files_request()");
+    crate::log_server!("[HANDLER] Module: File Serving Module");
+    crate::log_server!("[HANDLER] Processing ListFilesRequest");
+    
+    // Page size defaults and cap (each entry requires hashing the file once)
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+    
+    if !req.list_files {
+        reply.error("list_files must be true", "INVALID_REQUEST").await;
+        return;
+    }
+    
+    // Walking the tree and hashing files are blocking work; keep them off the async workers
+    let (pattern, requested_offset) = (req.pattern.clone(), req.offset.unwrap_or(0));
+    let limit = req.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let seed_list = Arc::clone(&seed);
+    let listed = tokio::task::spawn_blocking(move || {
+        let names = seed_list.list_file_names(pattern.as_deref())?;
+        let total = names.len();
+        let offset = requested_offset.min(total);
+        let end = (offset + limit).min(total);
+        let (mut files, mut skipped) = (Vec::with_capacity(end - offset), Vec::new());
+        for name in &names[offset..end] {
+            match seed_list.describe_file(name) {
+                Ok(info) => files.push(info),
+                // The file may have been removed since the directory was scanned
+                Err(e) => skipped.push(format!("{}: {}", name, e)),
+            }
+        }
+        Ok::<_, std::io::Error>((files, skipped, total, offset, end))
+    }).await.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    let (files, skipped, total, offset, end) = match listed {
+        Ok(listed) => listed,
+        Err(e) => {
+            crate::log_server!("ERROR: Failed to list seed directory {}: {}", seed.root().display(), e);
+            reply.error("Seed directory unavailable", "SEED_DIR_UNAVAILABLE").await;
+            return;
         }
+    };
+    for skipped in skipped {
+        crate::log_server!("WARNING: Skipping seed file {}", skipped);
     }
+    
+    let response = ListFilesResponse {
+        files,
+        total,
+        offset,
+        next_offset: if end < total { Some(end) } else { None },
+    };
+    
+    crate::log_server!("Sending ListFilesResponse: {} of {} files (offset={}, pattern={:?})", 
+        response.files.len(), total, offset, req.pattern);
+    
+    reply.send(&response).await;
+}
+
+/// Reads `length` bytes starting at `offset` from a file.
+fn read_file_range(path: &std::path::Path, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+    
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0u8; length as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// The sending half of a request stream; replies in the style the request
+/// arrived in (bare for legacy clients, enveloped with the request's id
+/// otherwise), as a frame on framed streams.
+pub struct Responder<'a> {
+    send: &'a mut quinn::SendStream,
+    style: ReplyStyle,
+    framed: bool,
+    /// How far the reply has got, so a cancelled request can still be
+    /// answered (or its stream reset if it was cut off mid-message)
+    progress: ReplyProgress,
+}
+
+/// See `Responder::progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplyProgress {
+    /// Nothing is being written; an error can still be sent
+    Idle,
+    /// A message is being written
+    Writing,
+    /// The final reply is out
+    Done,
+}
+
+impl<'a> Responder<'a> {
+    fn new(send: &'a mut quinn::SendStream, framed: bool) -> Self {
+        Self { send, style: ReplyStyle::Legacy, framed, progress: ReplyProgress::Idle }
+    }
+    
+    /// Sends the final reply to the request; a single-request stream is
+    /// finished after it.
+    pub async fn send<T: Message>(&mut self, message: &T) {
+        let bytes = self.style.encode(message);
+        self.write_reply(T::KIND.type_name(), bytes).await;
+    }
+    
+    /// Sends the final reply of a custom handler, named `type_name` in the
+    /// envelope (see `send`).
+    pub async fn send_as<T: Serialize>(&mut self, type_name: &str, message: &T) {
+        let bytes = self.style.encode_as(type_name, message);
+        self.write_reply(type_name, bytes).await;
+    }
+    
+    /// Writes the encoded final reply, or a `SERIALIZATION_ERROR` if it
+    /// couldn't be encoded.
+    async fn write_reply(&mut self, type_name: &str, bytes: Result<Vec<u8>, crate::protocol::CodecError>) {
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                crate::log_server!("ERROR: Error serializing {}: {}", type_name, e);
+                let error = ErrorResponse {
+                    error: "Internal server error".to_string(),
+                    code: Some("SERIALIZATION_ERROR".to_string()),
+                };
+                self.style.encode(&error).unwrap_or_default()
+            }
+        };
+        crate::log_server!("[RESPONSE] RESPONSE_TYPE: {} - {} bytes ({})", type_name, bytes.len(), self.style.codec());
+        self.progress = ReplyProgress::Writing;
+        if self.framed {
+            let _ = crate::framing::write_frame(self.send, &bytes).await;
+            self.progress = ReplyProgress::Done;
+        } else {
+            let _ = self.send.write_all(&bytes).await;
+            // Finishing waits for the client's acknowledgement; the reply is complete already
+            self.progress = ReplyProgress::Done;
+            let _ = self.send.finish().await;
+        }
+    }
+    
+    /// True if events can be sent ahead of the final reply (framed streams
+    /// with enveloped requests only).
+    pub fn can_stream(&self) -> bool {
+        self.framed && self.style != ReplyStyle::Legacy
+    }
+    
+    /// Sends an event ahead of the final reply; dropped unless `can_stream`.
+    ///
+    /// # Returns
+    /// False if the event couldn't be sent
+    pub async fn event<T: Message>(&mut self, message: &T) -> bool {
+        let bytes = self.style.encode(message);
+        self.write_event(bytes).await
+    }
+    
+    /// Sends an event of a custom handler, named `type_name` in the
+    /// envelope (see `event`).
+    pub async fn event_as<T: Serialize>(&mut self, type_name: &str, message: &T) -> bool {
+        let bytes = self.style.encode_as(type_name, message);
+        self.write_event(bytes).await
+    }
+    
+    async fn write_event(&mut self, bytes: Result<Vec<u8>, crate::protocol::CodecError>) -> bool {
+        if !self.can_stream() {
+            return false;
+        }
+        match bytes {
+            Ok(bytes) => {
+                self.progress = ReplyProgress::Writing;
+                let sent = crate::framing::write_frame(self.send, &bytes).await.is_ok();
+                self.progress = ReplyProgress::Idle;
+                sent
+            }
+            Err(_) => false,
+        }
+    }
+    
+    /// Answers a request whose handler was cancelled at its deadline with a
+    /// `TIMEOUT` error, unless the reply was already sent; a reply cut off
+    /// part way can't be completed, so the stream is reset instead.
+    async fn timed_out(&mut self, timeout: std::time::Duration) {
+        match self.progress {
+            ReplyProgress::Idle => self.error(&format!("Request not answered within {} ms", timeout.as_millis()), "TIMEOUT").await,
+            ReplyProgress::Writing => {
+                crate::log_server!("ERROR: Reply cut off by the deadline, resetting the stream");
+                let _ = self.send.reset(STREAM_REFUSED);
+            }
+            ReplyProgress::Done => {}
+        }
+    }
+    
+    /// Sends an `ErrorResponse` as the reply.
+    pub async fn error(&mut self, error: &str, code: &str) {
+        crate::log_server!("Sending error response via QUIC: {} ({})", error, code);
+        let error = ErrorResponse {
+            error: error.to_string(),
+            code: Some(code.to_string()),
+        };
+        self.send(&error).await;
+    }
+}
+
+/// Streams server events to a client until it stops reading.
+///
+/// The subscription is acknowledged with a `SubscribeResponse`, then every
+/// event the request selects (see `events::matches`) follows as an `event`
+/// frame with the request's id. The stream is flow controlled, so a client
+/// that reads too slowly loses the oldest queued events and gets a
+/// `ServerEvent::Dropped` saying how many.
+async fn handle_subscribe_request(
+    req: SubscribeRequest,
+    events: EventBus,
+    work_dist: bool,
+    remote_addr: std::net::SocketAddr,
+    reply: &mut Responder<'_>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+    
+    crate::log_server!("[HANDLER] Function: quic_tracker::handle_subscribe_request()");
+    crate::log_server!("[HANDLER] Module: Event Subscriptions");
+    
+    if !reply.can_stream() {
+        reply.error("Subscriptions need an enveloped request on a framed stream", "INVALID_REQUEST").await;
+        return;
+    }
+    let subscription = SubscribeRequest {
+        work_nodes: req.work_nodes && work_dist,
+        ..req
+    };
+    if subscription.swarms.is_empty() && !subscription.seed_files && !subscription.work_nodes {
+        reply.error("Subscription selects no events", "INVALID_REQUEST").await;
+        return;
+    }
+    
+    // Subscribe before acknowledging so no event after the ack is missed
+    let mut receiver = events.subscribe();
+    let response = SubscribeResponse {
+        swarms: subscription.swarms.clone(),
+        seed_files: subscription.seed_files,
+        work_nodes: subscription.work_nodes,
+        queue_size: crate::events::EVENT_QUEUE_SIZE,
+    };
+    if !reply.event(&response).await {
+        crate::log_server!("ERROR: Could not acknowledge subscription from {}", remote_addr);
+        return;
+    }
+    crate::log_server!("Subscription started for {}: swarms={:?}, seed_files={}, work_nodes={}", 
+        remote_addr, response.swarms, response.seed_files, response.work_nodes);
+    
+    let (mut sent, mut dropped) = (0u64, 0u64);
+    loop {
+        let event = tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(event) if crate::events::matches(&event, &subscription) => event,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    crate::log_server!("Subscriber {} fell behind, {} events dropped", remote_addr, missed);
+                    dropped += missed;
+                    ServerEvent::Dropped { missed: Some(missed) }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = reply.send.stopped() => break,
+        };
+        if !reply.event(&event).await {
+            break;
+        }
+        sent += 1;
+    }
+    crate::log_server!("Subscription from {} ended: {} events sent, {} dropped", remote_addr, sent, dropped);
+}
+
+/// Compares two secrets without short-circuiting on the first differing byte.
+pub(crate) fn token_matches(expected: &str, provided: &str) -> bool {
+    let (expected, provided) = (expected.as_bytes(), provided.as_bytes());
+    let mut diff = expected.len() ^ provided.len();
+    for (i, &b) in expected.iter().enumerate() {
+        diff |= (b ^ provided.get(i).copied().unwrap_or(0)) as usize;
+    }
+    diff == 0
+}
+
+async fn handle_publish_request(
+    req: PublishRequest,
+    seed: Arc<SeedDirectory>,
+    config: &TrackerConfig,
+    state: Arc<RwLock<TrackerState>>,
+    reply: &mut Responder<'_>,
+) {
+    crate::log_server!("[HANDLER] Function: quic_tracker::handle_publish_request()");
+    crate::log_server!("[HANDLER] Module: File Publishing Module");
+    crate::log_server!("[HANDLER] Processing PublishRequest");
+    
+    // Authenticate
+    let expected_token = match &config.publish_token {
+        Some(token) => token,
+        None => {
+            crate::log_server!("ERROR: Publish rejected - publishing is disabled on this server");
+            reply.error("Publishing is disabled on this server", "PUBLISH_DISABLED").await;
+            return;
+        }
+    };
+    if !token_matches(expected_token, &req.token) {
+        crate::log_server!("ERROR: Publish rejected - invalid token for '{}'", req.publish);
+        reply.error("Invalid publish token", "UNAUTHORIZED").await;
+        return;
+    }
+    
+    // Validate the request
+    let expected_sha256 = req.sha256.to_lowercase();
+    if expected_sha256.len() != 64 || !expected_sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        reply.error("sha256 must be 64 hex characters", "INVALID_REQUEST").await;
+        return;
+    }
+    let destination = match seed.join(&req.publish) {
+        Ok(path) => path,
+        Err(e) => {
+            crate::log_server!("ERROR: Publish rejected - {}", e);
+            reply.error(&e.to_string(), e.code()).await;
+            return;
+        }
+    };
+    let chunk_end = match req.offset.checked_add(req.data.len() as u64) {
+        Some(end) if end <= req.size => end,
+        Some(end) => {
+            reply.error(&format!("Chunk ends at {} beyond declared size {}", end, req.size), "INVALID_RANGE").await;
+            return;
+        }
+        None => {
+            reply.error(&format!("Chunk at offset {} overflows the file size", req.offset), "INVALID_RANGE").await;
+            return;
+        }
+    };
+    
+    // Stage the chunk (file writes and fsync are blocking work; keep them off the async workers)
+    let (seed_stage, sha256_stage) = (Arc::clone(&seed), expected_sha256.clone());
+    let staged = tokio::task::spawn_blocking(move || {
+        let staged = stage_publish_chunk(&seed_stage, &req, &sha256_stage).map_err(|e| e.to_string());
+        (req, staged)
+    }).await;
+    let (req, staging_path) = match staged {
+        Ok((req, Ok(path))) => (req, path),
+        Ok((req, Err(e))) => {
+            crate::log_server!("ERROR: Failed to stage chunk for '{}': {}", req.publish, e);
+            reply.error(&e, "PUBLISH_FAILED").await;
+            return;
+        }
+        Err(e) => {
+            crate::log_server!("ERROR: Staging a publish chunk panicked: {}", e);
+            reply.error("Failed to stage chunk", "PUBLISH_FAILED").await;
+            return;
+        }
+    };
+    crate::log_server!("Staged publish chunk: path={}, offset={}, len={}, received={}/{}", 
+        req.publish, req.offset, req.data.len(), chunk_end, req.size);
+    
+    let mut response = PublishResponse {
+        path: req.publish.clone(),
+        received: chunk_end,
+        complete: false,
+        info_hash: None,
+    };
+    
+    if chunk_end == req.size {
+        // Verify (hashing the whole file) and move it into the seed directory off the async workers
+        let (seed_commit, sha256_commit) = (Arc::clone(&seed), expected_sha256.clone());
+        let committed = tokio::task::spawn_blocking(move || {
+            commit_publish(&seed_commit, &staging_path, &destination, &sha256_commit).map(|()| destination)
+        }).await.unwrap_or_else(|e| Err((format!("Commit panicked: {}", e), "PUBLISH_FAILED")));
+        let destination = match committed {
+            Ok(destination) => destination,
+            Err((message, code)) => {
+                crate::log_server!("ERROR: Publish of '{}' failed: {}", req.publish, message);
+                reply.error(&message, code).await;
+                return;
+            }
+        };
+        response.complete = true;
+        crate::log_server!("Published file: {} ({} bytes, sha256={})", destination.display(), req.size, expected_sha256);
+        
+        if req.create_torrent.unwrap_or(false) {
+            let (seed_torrent, name, announce_url) = (Arc::clone(&seed), req.publish.clone(), config.announce_url());
+            let written = tokio::task::spawn_blocking(move || seed_torrent.write_torrent(&name, &announce_url).map_err(|e| e.to_string()))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            match written {
+                Ok(info_hash) => {
+                    state.write().unwrap().register_server_seed(&info_hash, &req.publish, &config.advertise_ip, config.port);
+                    crate::log_server!("Registered published file as seed: info_hash={}", info_hash);
+                    response.info_hash = Some(info_hash);
+                }
+                Err(e) => {
+                    crate::log_server!("ERROR: Torrent generation failed for '{}': {}", req.publish, e);
+                    reply.error(&format!("File published but torrent generation failed: {}", e), "TORRENT_FAILED").await;
+                    return;
+                }
+            }
+        }
+        state.read().unwrap().events().publish(ServerEvent::SeedFile {
+            name: req.publish.clone(),
+            change: SeedFileChange::Published,
+            size: Some(req.size),
+            info_hash: response.info_hash.clone(),
+        });
+    }
+    
+    reply.send(&response).await;
+}
+
+/// Writes a publish chunk into the staging area and returns the staging file path.
+///
+/// Staging files live in the hidden `.staging` directory of the seed root and
+/// are keyed by destination and expected hash, so retries resume the same file.
+fn stage_publish_chunk(
+    seed: &SeedDirectory,
+    req: &PublishRequest,
+    expected_sha256: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    use sha2::{Digest, Sha256};
+    use std::io::{Seek, SeekFrom, Write};
+    
+    let staging_dir = seed.root().join(".staging");
+    fs::create_dir_all(&staging_dir)?;
+    let key = Sha256::digest(format!("{}\0{}", req.publish, expected_sha256).as_bytes());
+    let staging_path = staging_dir.join(format!("{}.part", hex::encode(&key[..16])));
+    
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&staging_path)?;
+    let staged = file.metadata()?.len();
+    if req.offset > staged {
+        return Err(format!("Expected chunk at offset {} or earlier, got {}", staged, req.offset).into());
+    }
+    // Drop anything past the offset so a retried chunk replaces it
+    file.set_len(req.offset)?;
+    file.seek(SeekFrom::Start(req.offset))?;
+    file.write_all(&req.data)?;
+    file.sync_all()?;
+    Ok(staging_path)
+}
+
+/// Verifies a fully staged file and atomically moves it to its destination.
+fn commit_publish(
+    seed: &SeedDirectory,
+    staging_path: &std::path::Path,
+    destination: &std::path::Path,
+    expected_sha256: &str,
+) -> Result<(), (String, &'static str)> {
+    let actual = crate::seed::sha256_file(staging_path)
+        .map_err(|e| (format!("Failed to hash staged file: {}", e), "PUBLISH_FAILED"))?;
+    if actual != expected_sha256 {
+        let _ = fs::remove_file(staging_path);
+        return Err((format!("SHA-256 mismatch: expected {}, got {}", expected_sha256, actual), "CHECKSUM_MISMATCH"));
+    }
+    
+    // The parent may be new; make sure it does not lead outside the root through a
+    // symlink, both before creating any directory and once they exist
+    let parent = destination.parent().ok_or(("Invalid destination".to_string(), "INVALID_PATH"))?;
+    let existing = parent.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .ok_or(("Invalid destination".to_string(), "INVALID_PATH"))?;
+    let canonical_existing = existing.canonicalize()
+        .map_err(|e| (format!("Failed to resolve directory: {}", e), "PUBLISH_FAILED"))?;
+    if !canonical_existing.starts_with(seed.root()) {
+        return Err(("Destination escapes the seed directory".to_string(), "PATH_OUTSIDE_SEED_DIR"));
+    }
+    fs::create_dir_all(parent)
+        .map_err(|e| (format!("Failed to create directory: {}", e), "PUBLISH_FAILED"))?;
+    let canonical_parent = parent.canonicalize()
+        .map_err(|e| (format!("Failed to resolve directory: {}", e), "PUBLISH_FAILED"))?;
+    if !canonical_parent.starts_with(seed.root()) {
+        return Err(("Destination escapes the seed directory".to_string(), "PATH_OUTSIDE_SEED_DIR"));
+    }
+    
+    // Staging lives inside the seed root, so this rename stays on one filesystem and is atomic
+    fs::rename(staging_path, destination)
+        .map_err(|e| (format!("Failed to move file into place: {}", e), "PUBLISH_FAILED"))
 }
 
 async fn handle_ai_request(
     req: AiRequest,
     ai_processor: Option<Arc<RwLock<AiProcessor>>>,
     work_dist: Option<Arc<WorkDistributionManager>>,
-    send: &mut quinn::SendStream,
+    reply: &mut Responder<'_>,
 ) {
     crate::log_server!("[HANDLER] Function: quic_tracker::handle_ai_request()");
     crate::log_server!("[HANDLER] Module: AI Processing Module");
@@ -422,21 +1227,8 @@ async fn handle_ai_request(
     
     // If local processing succeeded, send response
     if let Some(response) = local_response {
-        let json_response = match serde_json::to_string(&response) {
-            Ok(json) => json,
-            Err(e) => {
-                crate::log_server!("ERROR: Error serializing AI response: {}", e);
-                let error = ErrorResponse {
-                    error: "Internal server error".to_string(),
-                    code: Some("SERIALIZATION_ERROR".to_string()),
-                };
-                serde_json::to_string(&error).unwrap()
-            }
-        };
-        
         crate::log_server!("[HANDLER] handle_ai_request() -> sending AiResponse");
-        let _ = send.write_all(json_response.as_bytes()).await;
-        let _ = send.finish().await;
+        send_ai_response(&req, &response, reply).await;
         crate::log_server!("[HANDLER] handle_ai_request() completed");
         return;
     }
@@ -444,23 +1236,12 @@ async fn handle_ai_request(
     // If local processing failed or not available, try work delegation
     if let Some(work_dist_manager) = work_dist {
         crate::log_server!("[WORK_DIST] Attempting work delegation for AI request");
-        match work_dist_manager.delegate_ai_work(&req, &NodeCapability::AiProcessing).await {
+        // The remote node logs the work under this request's id
+        let request_id = crate::logger::current_request_id().unwrap_or_else(crate::protocol::new_request_id);
+        match work_dist_manager.delegate_ai_work(&req, &NodeCapability::AiProcessing, &request_id).await {
             Ok(response) => {
-                let json_response = match serde_json::to_string(&response) {
-                    Ok(json) => json,
-                    Err(e) => {
-                        crate::log_server!("ERROR: Error serializing delegated AI response: {}", e);
-                        let error = ErrorResponse {
-                            error: "Internal server error".to_string(),
-                            code: Some("SERIALIZATION_ERROR".to_string()),
-                        };
-                        serde_json::to_string(&error).unwrap()
-                    }
-                };
-                
                 crate::log_server!("[WORK_DIST] Delegated work completed successfully");
-                let _ = send.write_all(json_response.as_bytes()).await;
-                let _ = send.finish().await;
+                send_ai_response(&req, &response, reply).await;
                 return;
             }
             Err(e) => {
@@ -470,13 +1251,153 @@ async fn handle_ai_request(
     }
     
     // If all else fails, return error
-    let error = ErrorResponse {
-        error: "AI processing not available".to_string(),
-        code: Some("AI_UNAVAILABLE".to_string()),
-    };
-    let json_error = serde_json::to_string(&error).unwrap();
-    let _ = send.write_all(json_error.as_bytes()).await;
-    let _ = send.finish().await;
+    reply.error("AI processing not available", "AI_UNAVAILABLE").await;
+}
+
+/// Sends an AI answer, first as `AiToken` events if the client asked for a
+/// stream and the stream is framed.
+///
+/// The processors produce the whole answer at once, so the tokens are the
+/// answer's words; a client that streams gets the same text either way.
+async fn send_ai_response(
+    req: &AiRequest,
+    response: &AiResponse,
+    reply: &mut Responder<'_>,
+) {
+    if req.stream == Some(true) {
+        if reply.can_stream() {
+            let mut sent = 0;
+            for (index, token) in response.answer.split_inclusive(' ').enumerate() {
+                if !reply.event(&AiToken { index, token: token.to_string() }).await {
+                    break;
+                }
+                sent += 1;
+            }
+            crate::log_server!("[HANDLER] Streamed {} AiToken event(s) before the AiResponse", sent);
+        } else {
+            crate::log_server!("[HANDLER] Streaming requested on an unframed stream, sending the answer whole");
+        }
+    }
+    reply.send(response).await;
+}
+
+/// A seed file the server is currently seeding.
+struct SeededFile {
+    info_hash: String,
+    size: u64,
+    mtime: Option<std::time::SystemTime>,
+}
+
+/// Brings the server-seeded swarms in line with the seed directory.
+///
+/// Generates torrents for new or changed files, registers their info hashes
+/// with the server as seeder, and retires swarms whose files were removed
+/// or replaced. `seeded` carries the result of the previous scan.
+fn sync_seed_swarms(
+    seed: &SeedDirectory,
+    config: &TrackerConfig,
+    state: &RwLock<TrackerState>,
+    seeded: &mut HashMap<String, SeededFile>,
+) -> std::io::Result<()> {
+    let names = seed.list_file_names(None)?;
+    let announce = config.announce_url();
+    
+    let mut current = HashMap::with_capacity(names.len());
+    for name in names {
+        let metadata = match seed.resolve(&name).and_then(|p| fs::metadata(p).map_err(|_| crate::seed::SeedPathError::NotFound(name.clone()))) {
+            Ok(metadata) => metadata,
+            Err(_) => continue, // Removed while scanning
+        };
+        let (size, mtime) = (metadata.len(), metadata.modified().ok());
+        
+        // Unchanged since the last scan: keep the known info hash
+        let mut change = SeedFileChange::Added;
+        if let Some(previous) = seeded.remove(&name) {
+            if previous.size == size && previous.mtime == mtime {
+                current.insert(name, previous);
+                continue;
+            }
+            crate::log_server!("[SEED_SYNC] Seed file changed: {}", name);
+            state.write().unwrap().retire_swarm(&previous.info_hash);
+            change = SeedFileChange::Changed;
+        }
+        
+        match seed.ensure_torrent(&name, &announce).map_err(|e| e.to_string()) {
+            Ok((info_hash, generated)) => {
+                if generated {
+                    println!("✓ Generated torrent for seed file '{}' (info_hash={})", name, info_hash);
+                }
+                let mut state = state.write().unwrap();
+                state.register_server_seed(&info_hash, &name, &config.advertise_ip, config.port);
+                state.events().publish(ServerEvent::SeedFile {
+                    name: name.clone(),
+                    change,
+                    size: Some(size),
+                    info_hash: Some(info_hash.clone()),
+                });
+                crate::log_server!("[SEED_SYNC] Seeding {} (info_hash={}, generated={})", name, info_hash, generated);
+                current.insert(name, SeededFile { info_hash, size, mtime });
+            }
+            Err(e) => {
+                crate::log_server!("ERROR: [SEED_SYNC] Failed to create torrent for {}: {}", name, e);
+            }
+        }
+    }
+    
+    // Anything left over was removed from the seed directory
+    for (name, removed) in seeded.drain() {
+        crate::log_server!("[SEED_SYNC] Seed file removed, retiring swarm: {} (info_hash={})", name, removed.info_hash);
+        let mut state = state.write().unwrap();
+        state.retire_swarm(&removed.info_hash);
+        state.events().publish(ServerEvent::SeedFile {
+            name,
+            change: SeedFileChange::Removed,
+            size: None,
+            info_hash: Some(removed.info_hash),
+        });
+    }
+    *seeded = current;
+    Ok(())
+}
+
+/// Scans the seed directory once, then keeps rescanning it in the background.
+///
+/// The watch polls every `seed_scan_interval_secs`, which works the same on
+/// every platform and filesystem (including network shares).
+async fn watch_seed_directory(
+    seed: Arc<SeedDirectory>,
+    config: Arc<TrackerConfig>,
+    state: Arc<RwLock<TrackerState>>,
+) {
+    let mut seeded = HashMap::new();
+    let interval = std::time::Duration::from_secs(config.seed_scan_interval_secs);
+    loop {
+        let (seed_scan, config_scan, state_scan) = (Arc::clone(&seed), Arc::clone(&config), Arc::clone(&state));
+        // Hashing is blocking work; keep it off the async workers
+        let result = tokio::task::spawn_blocking(move || {
+            let result = sync_seed_swarms(&seed_scan, &config_scan, &state_scan, &mut seeded);
+            (result, seeded)
+        }).await;
+        match result {
+            Ok((Ok(()), scanned)) => {
+                crate::log_server!("[SEED_SYNC] Seed directory scan complete: {} files seeded", scanned.len());
+                seeded = scanned;
+            }
+            Ok((Err(e), scanned)) => {
+                crate::log_server!("ERROR: [SEED_SYNC] Seed directory scan failed: {}", e);
+                seeded = scanned;
+            }
+            Err(e) => {
+                crate::log_server!("ERROR: [SEED_SYNC] Seed directory scan panicked: {}", e);
+                seeded = HashMap::new();
+            }
+        }
+        
+        if interval.is_zero() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
 }
 
 /// Starts the QUIC tracker server.
@@ -493,7 +1414,7 @@ async fn handle_ai_request(
 /// # Returns
 /// * `Ok(())` if server starts successfully
 /// * `Err` if binding fails or certificate generation fails
-pub async fn run_quic_tracker(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_quic_tracker(port: u16) -> Result<()> {
     run_quic_tracker_with_ai(port, true, true).await
 }
 
@@ -502,11 +1423,104 @@ pub async fn run_quic_tracker_with_ai(
     port: u16,
     enable_ai: bool,
     enable_work_dist: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<()> {
+    run_quic_tracker_with_config(TrackerConfig {
+        port,
+        enable_ai,
+        enable_work_dist,
+        ..TrackerConfig::default()
+    }).await
+}
+
+/// Configuration for the QUIC tracker server.
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// UDP port to listen on
+    pub port: u16,
+    /// Enable local AI processing
+    pub enable_ai: bool,
+    /// Enable delegation of AI work to other nodes
+    pub enable_work_dist: bool,
+    /// Root of the served seed directory (nested subdirectories are served too)
+    pub seed_dir: PathBuf,
+    /// Shared secret required by `PublishRequest`; publishing is disabled when `None`
+    pub publish_token: Option<String>,
+    /// IP address the server registers under when it seeds its own files
+    pub advertise_ip: String,
+    /// Announce URL written into generated torrents (default: `quic://<advertise_ip>:<port>/announce`)
+    pub announce_url: Option<String>,
+    /// Generate `.torrent` files for seed content and seed them from the server
+    pub auto_torrents: bool,
+    /// Seconds between seed directory rescans (0 scans only at startup)
+    pub seed_scan_interval_secs: u64,
+    /// Seconds a client may take to send a request (and, on a framed stream,
+    /// may stay idle between requests)
+    pub read_timeout_secs: u64,
+    /// Longest a request may be worked on, in milliseconds; a client's
+    /// `timeout_ms` can only shorten it
+    pub request_timeout_ms: u64,
+    /// Token every request other than `Hello` must carry in its envelope's
+    /// `auth` field; requests aren't authenticated when `None`
+    pub auth_token: Option<String>,
+    /// Requests per second each client IP may send (bursts of twice that);
+    /// unlimited when `None`
+    pub rate_limit: Option<u32>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            port: 7001,
+            enable_ai: true,
+            enable_work_dist: true,
+            seed_dir: PathBuf::from("seed"),
+            publish_token: None,
+            advertise_ip: "127.0.0.1".to_string(),
+            announce_url: None,
+            auto_torrents: true,
+            seed_scan_interval_secs: 30,
+            read_timeout_secs: 30,
+            request_timeout_ms: 60_000,
+            auth_token: None,
+            rate_limit: None,
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// Announce URL written into generated torrents.
+    pub fn announce_url(&self) -> String {
+        self.announce_url.clone()
+            .unwrap_or_else(|| format!("quic://{}:{}/announce", self.advertise_ip, self.port))
+    }
+    
+    /// How long a request may be worked on, given the client's `timeout_ms`
+    /// (ignored if zero).
+    pub fn request_timeout(&self, client_timeout_ms: Option<u64>) -> std::time::Duration {
+        let ms = client_timeout_ms.filter(|&ms| ms > 0).map_or(self.request_timeout_ms, |ms| ms.min(self.request_timeout_ms));
+        std::time::Duration::from_millis(ms)
+    }
+}
+
+/// Starts the QUIC tracker server with the given configuration
+pub async fn run_quic_tracker_with_config(config: TrackerConfig) -> Result<()> {
+    let router = default_router(&config);
+    run_quic_tracker_with_router(config, router).await
+}
+
+/// Starts the QUIC tracker server, routing requests through `router`
+///
+/// # Arguments
+/// * `config` - Server configuration
+/// * `router` - Handlers and middleware for the server's requests, usually
+///   `default_router(&config)` with custom routes added
+pub async fn run_quic_tracker_with_router(config: TrackerConfig, router: Router) -> Result<()> {
+    let port = config.port;
+    let config = Arc::new(config);
     let state = Arc::new(RwLock::new(TrackerState::default()));
     
     // Initialize AI processor if enabled
-    let ai_processor = if enable_ai {
+    let ai_processor = if config.enable_ai {
         crate::log_server!("[AI_PROCESSOR] Initializing AI processor");
         Some(Arc::new(RwLock::new(AiProcessor::new(None))))
     } else {
@@ -514,29 +1528,34 @@ pub async fn run_quic_tracker_with_ai(
     };
     
     // Initialize work distribution manager if enabled
-    let work_dist = if enable_work_dist {
+    let work_dist = if config.enable_work_dist {
         crate::log_server!("[WORK_DIST] Initializing work distribution manager");
-        Some(Arc::new(WorkDistributionManager::new()))
+        let events = state.read().unwrap().events().clone();
+        Some(Arc::new(WorkDistributionManager::new().with_events(events)))
     } else {
         None
     };
     
-    // Create seed directory if it doesn't exist
-    let current_dir = std::env::current_dir()?;
-    let seed_dir = current_dir.join("seed");
-    fs::create_dir_all(&seed_dir)?;
+    // Create seed directory if it doesn't exist (relative paths are under the working directory)
+    let seed = Arc::new(SeedDirectory::new(&config.seed_dir)?);
+    let seed_dir = seed.root().to_path_buf();
     crate::log_server!("Seed directory ready: {}", seed_dir.display());
     
     // Seed the server with Hello World file on startup if seed folder is empty
     let seed_file = seed_dir.join("hello_world.txt");
-    if !fs::metadata(&seed_file).is_ok() {
+    if fs::metadata(&seed_file).is_err() {
         crate::log_server!("Seeding server with initial file: {}", seed_file.display());
         fs::write(&seed_file, "Hello World!")?;
         println!("✓ Seeded server with '{}' containing: Hello World!", seed_file.display());
     }
     
+    // Generate and register torrents for the seed directory, then keep watching it
+    if config.auto_torrents {
+        tokio::spawn(watch_seed_directory(Arc::clone(&seed), Arc::clone(&config), Arc::clone(&state)));
+    }
+    
     // Create server configuration
-    let server_config = create_server_config()?;
+    let server_config = create_server_config().map_err(|e| Error::Tls(e.to_string()))?;
     
     // Create QUIC endpoint and bind to UDP port
     // Note: QUIC uses UDP, not TCP!
@@ -551,10 +1570,23 @@ pub async fn run_quic_tracker_with_ai(
     println!("Encryption: TLS 1.3 (built into QUIC)");
     println!("Message Format: JSON");
     println!("Server can also serve files (acts as peer)");
+    println!("Seed directory: {}", seed_dir.display());
+    println!("Publishing: {}", if config.publish_token.is_some() { "enabled (token required)" } else { "disabled" });
+    println!("Auto torrents: {}", match (config.auto_torrents, config.seed_scan_interval_secs) {
+        (false, _) => "disabled".to_string(),
+        (true, 0) => "startup scan only".to_string(),
+        (true, secs) => format!("enabled (rescan every {}s)", secs),
+    });
+    println!("Timeouts: {}s to send a request, {} ms to answer it", config.read_timeout_secs, config.request_timeout_ms);
+    println!("Auth: {}", if config.auth_token.is_some() { "token required" } else { "disabled" });
+    println!("Rate limit: {}", config.rate_limit.map_or("disabled".to_string(), |n| format!("{} requests/s per client", n)));
+    println!("Request types: {}", router.request_types().join(", "));
     println!("Logging to: tracker.log");
     println!("========================================");
     
     crate::log_server!("Server started and listening for QUIC connections on port {}", port);
+    let app = Arc::new(AppState { state, seed, config, ai_processor, work_dist });
+    let router = Arc::new(router);
     
     // Accept incoming QUIC connections
     // Each connection can have multiple streams
@@ -571,14 +1603,13 @@ pub async fn run_quic_tracker_with_ai(
                 continue;
             }
         };
-        let state = Arc::clone(&state);
+        let app = Arc::clone(&app);
+        let router = Arc::clone(&router);
         
         // Spawn a task to handle this connection
         // QUIC allows multiple streams per connection, so we handle them all
-        let ai_proc = ai_processor.clone();
-        let work_dist_clone = work_dist.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_quic_connection(connection, state, ai_proc, work_dist_clone).await {
+            if let Err(e) = handle_quic_connection(connection, app, router).await {
                 crate::log_server!("ERROR: QUIC connection handler error: {}", e);
             }
         });
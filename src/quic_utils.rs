@@ -2,7 +2,7 @@
 //!
 //! Common utilities for QUIC server and client setup, including certificate generation.
 
-use quinn::{Endpoint, ServerConfig, ClientConfig};
+use quinn::{ServerConfig, ClientConfig};
 use rustls::{Certificate, PrivateKey, ServerConfig as TlsServerConfig, ClientConfig as TlsClientConfig};
 use std::sync::Arc;
 
@@ -158,7 +158,13 @@ pub fn create_client_config() -> Result<ClientConfig, Box<dyn std::error::Error>
     use quinn::crypto::ClientConfig as CryptoClientConfig;
     let crypto: Arc<dyn CryptoClientConfig> = Arc::new(tls_config);
     
-    let client_config = ClientConfig::new(crypto);
+    let mut client_config = ClientConfig::new(crypto);
+    
+    // Pooled connections and event subscriptions can sit quiet for longer
+    // than the idle timeout; keep-alives stop either side closing them
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
+    client_config.transport_config(Arc::new(transport));
     
     Ok(client_config)
 }
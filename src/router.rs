@@ -0,0 +1,572 @@
+//! # Request Router
+//!
+//! Maps request types (the envelope's `type`) to `Handler`s, and runs
+//! `Middleware` around every handled request.
+//!
+//! The server's own request types are registered by
+//! `quic_tracker::default_router`; other crates add types of their own (or
+//! replace built-in ones) with `Router::route` and start the server with
+//! `quic_tracker::run_quic_tracker_with_router`. Handlers get the server's
+//! shared state (`quic_tracker::AppState`) with every request, and any
+//! state of their own added with `Router::with_extension`:
+//!
+//! ```ignore
+//! struct PeerCount;
+//!
+//! impl Handler for PeerCount {
+//!     fn handle<'a, 'b>(&'a self, request: RequestContext<'a>, reply: &'a mut Responder<'b>) -> BoxFuture<'a, ()> {
+//!         Box::pin(async move {
+//!             let body: PeerCountRequest = match request.body() {
+//!                 Ok(body) => body,
+//!                 Err(e) => return reply.error(&e.message, e.code).await,
+//!             };
+//!             let peers = request.app.state.read().unwrap().peers(&body.info_hash).len();
+//!             reply.send_as("peer_count_response", &PeerCountResponse { peers }).await;
+//!         })
+//!     }
+//! }
+//!
+//! let config = TrackerConfig::default();
+//! let router = default_router(&config).route("peer_count", PeerCount);
+//! run_quic_tracker_with_router(config, router).await?;
+//! ```
+//!
+//! Requests are checked before they reach the router: a built-in type must
+//! decode as its message (also when a custom handler replaces it), and any
+//! type must stay within its handler's `max_request_size`.
+
+use crate::messages::ErrorResponse;
+use crate::protocol::{MessageKind, ProtocolError, ReplyStyle, Request};
+use crate::quic_tracker::{AppState, Responder};
+use serde::de::DeserializeOwned;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The future a `Handler` returns.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Answers requests of the types it is registered for.
+pub trait Handler: Send + Sync + 'static {
+    /// Handles one request, answering it through `reply`.
+    ///
+    /// # Arguments
+    /// * `request` - The request and the server state
+    /// * `reply` - Sends the response (`send`, or `send_as` for types outside
+    ///   `MessageKind`), events ahead of it, or an error
+    fn handle<'a, 'b>(&'a self, request: RequestContext<'a>, reply: &'a mut Responder<'b>) -> BoxFuture<'a, ()>;
+
+    /// Name shown in the server's log when a request is routed here.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Largest request this handler accepts, in encoded bytes; only used for
+    /// types outside `MessageKind`, which have their own limits.
+    fn max_request_size(&self) -> usize {
+        crate::protocol::MAX_SMALL_REQUEST_SIZE
+    }
+}
+
+/// Runs around every handled request, in the order added to the router.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called before the handler.
+    ///
+    /// # Returns
+    /// An error to answer the request with instead of handling it
+    fn before(&self, _request: &RequestContext<'_>) -> Result<(), ErrorResponse> {
+        Ok(())
+    }
+
+    /// Called once the request is answered (or rejected by a later
+    /// middleware), in reverse order, for every middleware whose `before`
+    /// let it through.
+    fn after(&self, _request: &RequestContext<'_>, _elapsed: Duration) {}
+}
+
+/// A request as handed to middleware and its handler.
+pub struct RequestContext<'a> {
+    /// The server's shared state
+    pub app: &'a AppState,
+    /// The router the request came through
+    pub router: &'a Router,
+    /// The envelope's `type`
+    pub type_name: &'a str,
+    /// The request id (the client's, or one the server assigned)
+    pub request_id: &'a str,
+    pub remote_addr: SocketAddr,
+    /// The client's `auth` token, if it sent one
+    pub auth: Option<&'a str>,
+    /// How the request arrived (and will be answered)
+    pub style: ReplyStyle,
+    /// True if the request arrived on a framed stream
+    pub framed: bool,
+    /// The raw request
+    pub bytes: &'a [u8],
+    /// `Handler::name` of the handler the request is routed to
+    pub handler: &'a str,
+    /// The decoded message, for built-in types
+    pub(crate) request: Option<Request>,
+    /// The connection's codec, switched by a `hello`
+    pub(crate) connection_codec: &'a std::sync::RwLock<crate::protocol::Codec>,
+}
+
+impl<'a> RequestContext<'a> {
+    /// Decodes the request's body as a `B`.
+    pub fn body<B: DeserializeOwned>(&self) -> Result<B, ProtocolError> {
+        self.style.decode_body(self.bytes)
+    }
+
+    /// Takes the already decoded message of a built-in request type.
+    pub fn take_request(&mut self) -> Option<Request> {
+        self.request.take()
+    }
+
+    /// A copy of the context without the decoded message.
+    fn without_request(&self) -> RequestContext<'a> {
+        RequestContext { style: self.style.clone(), request: None, ..*self }
+    }
+}
+
+/// Routes requests to handlers by type, through the router's middleware.
+#[derive(Default)]
+pub struct Router {
+    handlers: HashMap<String, Arc<dyn Handler>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    extensions: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Router {
+    /// A router without handlers or middleware.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes requests of type `type_name` to `handler`, replacing any
+    /// handler registered for it before.
+    pub fn route(mut self, type_name: impl Into<String>, handler: impl Handler) -> Self {
+        self.handlers.insert(type_name.into(), Arc::new(handler));
+        self
+    }
+
+    /// Runs `middleware` around every request, after the middleware added
+    /// before it.
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Stores a value handlers can read with `extension` (one per type).
+    pub fn with_extension<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(TypeId::of::<T>(), Box::new(value));
+        self
+    }
+
+    /// The value of type `T` stored with `with_extension`.
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    /// The request types this router handles, as listed in `HelloResponse::message_types`.
+    pub fn request_types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.handlers.keys().cloned().collect();
+        types.sort();
+        types
+    }
+
+    /// True if requests of type `type_name` have a handler.
+    pub fn handles(&self, type_name: &str) -> bool {
+        self.handlers.contains_key(type_name)
+    }
+
+    /// Hands a request to its handler, running the middleware around it.
+    ///
+    /// # Arguments
+    /// * `request` - The request; its `handler` is filled in here
+    /// * `decoded` - The outcome of `protocol::decode_request`: a type it
+    ///   doesn't know (`UNKNOWN_REQUEST`) is still routed if a handler is
+    ///   registered for it, any other error is the reply
+    pub async fn dispatch<'a>(&'a self, mut request: RequestContext<'a>, decoded: Result<Request, ProtocolError>, reply: &mut Responder<'_>) {
+        let handler = match (decoded.as_ref().err(), self.handlers.get(request.type_name)) {
+            (None, Some(handler)) => Ok(handler),
+            (Some(e), Some(handler)) if e.code == "UNKNOWN_REQUEST" => match handler.max_request_size() {
+                limit if request.bytes.len() > limit => Err(ProtocolError::new(
+                    format!("{} request of {} bytes exceeds the limit of {} bytes", request.type_name, request.bytes.len(), limit),
+                    "REQUEST_TOO_LARGE",
+                )),
+                _ => Ok(handler),
+            },
+            (Some(e), _) => Err(e.clone()),
+            (None, None) => Err(ProtocolError::new(format!("Unknown request type: {}", request.type_name), "UNKNOWN_REQUEST")),
+        };
+        let handler = match handler {
+            Ok(handler) => handler,
+            Err(e) => {
+                crate::log_server!("[ROUTING] Not routing {} request: {}", request.type_name, e.code);
+                crate::log_server!("ERROR: Rejected request from: {} - {} - request_len={}", request.remote_addr, e, request.bytes.len());
+                reply.error(&e.message, e.code).await;
+                return;
+            }
+        };
+        request.handler = handler.name();
+        // Middleware sees the request after the handler has taken its message
+        let seen = request.without_request();
+        request.request = decoded.ok();
+        
+        let started = Instant::now();
+        let mut passed = 0;
+        for middleware in &self.middleware {
+            if let Err(error) = middleware.before(&seen) {
+                crate::log_server!("[ROUTING] Request from {} rejected by middleware: {} ({})", 
+                    seen.remote_addr, error.error, error.code.as_deref().unwrap_or("-"));
+                reply.error(&error.error, error.code.as_deref().unwrap_or("REJECTED")).await;
+                break;
+            }
+            passed += 1;
+        }
+        if passed == self.middleware.len() {
+            handler.handle(request, reply).await;
+        }
+        for middleware in self.middleware[..passed].iter().rev() {
+            middleware.after(&seen, started.elapsed());
+        }
+    }
+}
+
+/// Logs each request on its way in (type, payload, handler) and its time
+/// to answer on the way out.
+pub struct RequestLogger;
+
+impl Middleware for RequestLogger {
+    fn before(&self, request: &RequestContext<'_>) -> Result<(), ErrorResponse> {
+        let kind = MessageKind::from_name(request.type_name);
+        let type_name = kind.map_or(request.type_name, |kind| kind.type_name());
+        let framing = match &request.style {
+            ReplyStyle::Legacy => "legacy".to_string(),
+            ReplyStyle::Envelope { id, codec } => format!("envelope, id={}, codec={}", id.as_deref().unwrap_or("-"), codec),
+        };
+        crate::log_server!("[REQUEST] REQUEST_TYPE: {} ({}{}) - from: {}",
+            type_name, framing, if request.framed { ", framed" } else { "" }, request.remote_addr);
+        if kind.is_none_or(|kind| kind.loggable()) {
+            let payload = request.style.codec().to_log_string(request.bytes);
+            let payload = match request.auth {
+                Some(token) if !token.is_empty() => payload.replace(token, "<redacted>"),
+                _ => payload,
+            };
+            crate::log_server!("[REQUEST] JSON payload: {}", payload);
+        } else {
+            // Publish payloads carry the auth token and raw file data
+            crate::log_server!("[REQUEST] JSON payload: <{} bytes, not logged>", request.bytes.len());
+        }
+        crate::log_server!("[ROUTING] Request type: {}", type_name);
+        crate::log_server!("[ROUTING] Routing to: {}", request.handler);
+        crate::log_server!("[ROUTING] Processing module: {}", kind.map_or("Custom Handler", |kind| kind.module()));
+        Ok(())
+    }
+
+    fn after(&self, request: &RequestContext<'_>, elapsed: Duration) {
+        crate::log_server!("[ROUTING] {} request from {} done in {} ms", request.type_name, request.remote_addr, elapsed.as_millis());
+    }
+}
+
+/// Refuses requests without the right `auth` token (`UNAUTHORIZED`).
+pub struct TokenAuth {
+    token: String,
+    /// Request types let through without a token
+    exempt: Vec<String>,
+}
+
+impl TokenAuth {
+    /// Requires `token` on every request but the `hello` handshake (so
+    /// clients can still learn that a token is needed).
+    pub fn new(token: impl Into<String>) -> Self {
+        Self { token: token.into(), exempt: vec![MessageKind::Hello.as_str().to_string()] }
+    }
+
+    /// Lets requests of type `type_name` through without a token as well.
+    pub fn exempt(mut self, type_name: impl Into<String>) -> Self {
+        self.exempt.push(type_name.into());
+        self
+    }
+}
+
+impl Middleware for TokenAuth {
+    fn before(&self, request: &RequestContext<'_>) -> Result<(), ErrorResponse> {
+        if self.exempt.iter().any(|t| t == request.type_name) {
+            return Ok(());
+        }
+        match request.auth {
+            Some(auth) if crate::quic_tracker::token_matches(&self.token, auth) => Ok(()),
+            provided => Err(ErrorResponse {
+                error: if provided.is_some() { "Invalid auth token" } else { "Auth token required" }.to_string(),
+                code: Some("UNAUTHORIZED".to_string()),
+            }),
+        }
+    }
+}
+
+/// Limits how many requests each client address may make (`RATE_LIMITED`
+/// beyond): a token bucket per IP, refilled at `per_second` up to `burst`.
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+/// Most client addresses tracked at once. Past it, full (idle) buckets are
+/// dropped, then the least recently seen quarter if they are all in use.
+const RATE_LIMIT_BUCKETS: usize = 4096;
+
+impl RateLimit {
+    /// Allows `per_second` requests per client address, and bursts of up
+    /// to `burst`.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self { per_second: per_second as f64, burst: burst.max(1) as f64, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token from `ip`'s bucket.
+    ///
+    /// # Returns
+    /// `false` if the bucket is empty
+    fn take(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_BUCKETS && !buckets.contains_key(&ip) {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, (tokens, at)| *tokens + now.duration_since(*at).as_secs_f64() * per_second < burst);
+            if buckets.len() >= RATE_LIMIT_BUCKETS {
+                // A forgotten client starts over with a full bucket, which
+                // only matters if it comes back within `burst / per_second`
+                let mut seen: Vec<Instant> = buckets.values().map(|&(_, at)| at).collect();
+                let (_, &mut cutoff, _) = seen.select_nth_unstable(RATE_LIMIT_BUCKETS / 4);
+                buckets.retain(|_, (_, at)| *at > cutoff);
+            }
+        }
+        let (tokens, at) = buckets.entry(ip).or_insert((self.burst, now));
+        *tokens = (*tokens + now.duration_since(*at).as_secs_f64() * self.per_second).min(self.burst);
+        *at = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &RequestContext<'_>) -> Result<(), ErrorResponse> {
+        if self.take(request.remote_addr.ip(), Instant::now()) {
+            Ok(())
+        } else {
+            Err(ErrorResponse {
+                error: format!("Rate limit of {} requests per second exceeded", self.per_second),
+                code: Some("RATE_LIMITED".to_string()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic_client::QuicClient;
+    use crate::quic_tracker::{default_router, run_quic_tracker_with_router, TrackerConfig};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Answers `echo_response` naming itself, and records the call.
+    struct Echo {
+        name: &'static str,
+        calls: Calls,
+    }
+
+    impl Handler for Echo {
+        fn handle<'a, 'b>(&'a self, _request: RequestContext<'a>, reply: &'a mut Responder<'b>) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                self.calls.lock().unwrap().push(format!("handle {}", self.name));
+                reply.send_as("echo_response", &json!({ "handler": self.name })).await;
+            })
+        }
+    }
+
+    /// Records `before` and `after`, rejecting requests if `reject` is set.
+    struct Recorder {
+        name: &'static str,
+        reject: bool,
+        calls: Calls,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, _request: &RequestContext<'_>) -> Result<(), ErrorResponse> {
+            self.calls.lock().unwrap().push(format!("before {}", self.name));
+            if self.reject {
+                return Err(ErrorResponse { error: format!("{} says no", self.name), code: Some("NOPE".to_string()) });
+            }
+            Ok(())
+        }
+
+        fn after(&self, _request: &RequestContext<'_>, _elapsed: Duration) {
+            self.calls.lock().unwrap().push(format!("after {}", self.name));
+        }
+    }
+
+    /// A server running `router` on a free port, with an empty seed directory.
+    struct Server {
+        port: u16,
+        seed_dir: PathBuf,
+        task: tokio::task::JoinHandle<()>,
+        client: QuicClient,
+    }
+
+    impl Server {
+        async fn start(router: impl FnOnce(&TrackerConfig) -> Router) -> Self {
+            let seed_dir = std::env::temp_dir().join(format!("router-test-{}", rand::random::<u64>()));
+            let port = std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+            let config = TrackerConfig {
+                port,
+                enable_ai: false,
+                enable_work_dist: false,
+                seed_dir: seed_dir.clone(),
+                auto_torrents: false,
+                ..TrackerConfig::default()
+            };
+            let router = router(&config);
+            let task = tokio::spawn(async move {
+                run_quic_tracker_with_router(config, router).await.unwrap();
+            });
+            let server = Self { port, seed_dir, task, client: QuicClient::new().unwrap() };
+            for _ in 0..100 {
+                if server.client.send_raw("127.0.0.1", port, b"{}").await.is_ok() {
+                    return server;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            panic!("server didn't come up");
+        }
+
+        /// Sends an envelope and returns the reply's `type` and `body`.
+        async fn request(&self, type_name: &str, body: Value) -> (String, Value) {
+            let request = json!({ "v": 1, "type": type_name, "id": "router-test", "body": body });
+            let bytes = self.client.send_raw("127.0.0.1", self.port, &serde_json::to_vec(&request).unwrap()).await.unwrap();
+            let mut reply: Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(reply["id"], "router-test");
+            (reply["type"].as_str().unwrap().to_string(), reply["body"].take())
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.task.abort();
+            let _ = std::fs::remove_dir_all(&self.seed_dir);
+        }
+    }
+
+    /// The calls recorded so far, once `count` have come in (`after` runs
+    /// once the reply is out).
+    async fn recorded(calls: &Calls, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            if calls.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        calls.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn custom_types_are_routed_to_their_handler() {
+        let calls = Calls::default();
+        let handler = Echo { name: "ping", calls: Arc::clone(&calls) };
+        let server = Server::start(|config| default_router(config).route("ping", handler)).await;
+
+        assert_eq!(server.request("ping", json!({})).await, ("echo_response".to_string(), json!({ "handler": "ping" })));
+        let (kind, body) = server.request("pong", json!({})).await;
+        assert_eq!((kind.as_str(), &body["code"]), ("error", &json!("UNKNOWN_REQUEST")));
+        assert_eq!(recorded(&calls, 1).await, vec!["handle ping"]);
+    }
+
+    #[tokio::test]
+    async fn custom_handlers_replace_built_in_ones() {
+        let calls = Calls::default();
+        let handler = Echo { name: "listing", calls: Arc::clone(&calls) };
+        let server = Server::start(|config| default_router(config).route("list_files", handler)).await;
+
+        let (kind, body) = server.request("list_files", json!({ "list_files": true })).await;
+        assert_eq!((kind.as_str(), body), ("echo_response", json!({ "handler": "listing" })));
+        // The body must still decode as the built-in message
+        let (kind, body) = server.request("list_files", json!({ "pattern": 7 })).await;
+        assert_eq!((kind.as_str(), &body["code"]), ("error", &json!("INVALID_REQUEST")));
+        assert_eq!(recorded(&calls, 1).await, vec!["handle listing"]);
+    }
+
+    #[tokio::test]
+    async fn middleware_runs_before_in_order_and_after_in_reverse() {
+        let calls = Calls::default();
+        let server = Server::start(|_| {
+            ["a", "b", "c"].into_iter()
+                .fold(Router::new(), |router, name| router.layer(Recorder { name, reject: false, calls: Arc::clone(&calls) }))
+                .route("ping", Echo { name: "ping", calls: Arc::clone(&calls) })
+        }).await;
+
+        assert_eq!(server.request("ping", json!({})).await.0, "echo_response");
+        assert_eq!(recorded(&calls, 7).await, vec![
+            "before a", "before b", "before c", "handle ping", "after c", "after b", "after a",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rejecting_middleware_stops_the_request() {
+        let calls = Calls::default();
+        let server = Server::start(|_| {
+            Router::new()
+                .layer(Recorder { name: "a", reject: false, calls: Arc::clone(&calls) })
+                .layer(Recorder { name: "b", reject: true, calls: Arc::clone(&calls) })
+                .layer(Recorder { name: "c", reject: false, calls: Arc::clone(&calls) })
+                .route("ping", Echo { name: "ping", calls: Arc::clone(&calls) })
+        }).await;
+
+        let (kind, body) = server.request("ping", json!({})).await;
+        assert_eq!(kind, "error");
+        assert_eq!(body, json!({ "error": "b says no", "code": "NOPE" }));
+        // Only the middleware that let the request through sees `after`
+        assert_eq!(recorded(&calls, 3).await, vec!["before a", "before b", "after a"]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.lock().unwrap().len(), 3, "{:?}", calls.lock().unwrap());
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(std::net::Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn rate_limit_refills_at_the_configured_rate() {
+        let limit = RateLimit::new(2, 3);
+        let start = Instant::now();
+        assert!((0..3).all(|_| limit.take(ip(1), start)));
+        assert!(!limit.take(ip(1), start), "burst used up");
+        assert!(limit.take(ip(2), start), "other clients have their own bucket");
+        assert!(limit.take(ip(1), start + Duration::from_millis(500)));
+        assert!(!limit.take(ip(1), start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn rate_limit_buckets_stay_bounded_when_all_are_busy() {
+        let limit = RateLimit::new(1, 2);
+        let start = Instant::now();
+        for n in 0..RATE_LIMIT_BUCKETS as u32 * 3 {
+            // Every client drains its bucket, so none is idle
+            let now = start + Duration::from_micros(n as u64);
+            limit.take(ip(n), now);
+            limit.take(ip(n), now);
+            assert!(limit.buckets.lock().unwrap().len() <= RATE_LIMIT_BUCKETS);
+        }
+        let last = ip(RATE_LIMIT_BUCKETS as u32 * 3 - 1);
+        assert!(!limit.take(last, start + Duration::from_millis(20)), "recent clients are kept");
+    }
+}
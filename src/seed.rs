@@ -0,0 +1,523 @@
+//! # Seed Directory
+//!
+//! The server's seed root: safe resolution of client-supplied paths and a
+//! catalog of the files it can serve. Each catalog entry carries size,
+//! modification time, SHA-256 and, when a matching `.torrent` file sits next
+//! to it, the torrent's info hash.
+
+use crate::client::TorrentFile;
+use crate::messages::SeedFileInfo;
+use crate::BencodeValue;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+lazy_static::lazy_static! {
+    /// SHA-256 cache keyed by path, invalidated when size or mtime change.
+    static ref HASH_CACHE: Mutex<HashMap<PathBuf, (u64, u64, String)>> = Mutex::new(HashMap::new());
+}
+
+/// Returns true if `name` matches the glob `pattern`.
+///
+/// Supports `*` (any run of characters, including none) and `?` (exactly one character).
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Position of the last '*' seen and the name position it was matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // Let the last '*' swallow one more character and retry
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Computes the hex-encoded SHA-256 of a file, streaming its contents.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Smallest piece length used for generated torrents (matches the bundled seed torrents).
+pub const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Largest piece length used for generated torrents.
+pub const MAX_PIECE_LENGTH: usize = 4 * 1024 * 1024;
+
+/// Picks a piece length that keeps the piece count of a generated torrent near 2048.
+pub fn piece_length_for(size: u64) -> usize {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && size / piece_length as u64 > 2048 {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// Builds single-file torrent metainfo for a file.
+///
+/// # Arguments
+/// * `path` - File to hash
+/// * `name` - Value of the `name` field in the info dictionary
+/// * `announce` - Tracker announce URL
+/// * `piece_length` - Piece length in bytes
+///
+/// # Returns
+/// Tuple of (bencoded metainfo, hex-encoded info hash)
+pub fn create_torrent(
+    path: &Path,
+    name: &str,
+    announce: &str,
+    piece_length: usize,
+) -> std::io::Result<(Vec<u8>, String)> {
+    let mut file = fs::File::open(path)?;
+    let mut pieces = Vec::new();
+    let mut length: u64 = 0;
+    let mut buffer = vec![0u8; piece_length];
+    loop {
+        // Fill a whole piece (read may return short counts)
+        let mut filled = 0;
+        while filled < piece_length {
+            let read = file.read(&mut buffer[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        if filled == 0 {
+            break;
+        }
+        pieces.extend_from_slice(&Sha1::digest(&buffer[..filled]));
+        length += filled as u64;
+        if filled < piece_length {
+            break;
+        }
+    }
+
+    let mut info = BTreeMap::new();
+    info.insert(b"length".to_vec(), BencodeValue::Int(length as i64));
+    info.insert(b"name".to_vec(), BencodeValue::String(name.as_bytes().to_vec()));
+    info.insert(b"piece length".to_vec(), BencodeValue::Int(piece_length as i64));
+    info.insert(b"pieces".to_vec(), BencodeValue::String(pieces));
+    let info = BencodeValue::Dict(info);
+    let info_hash = hex::encode(Sha1::digest(info.encode()));
+
+    let mut torrent = BTreeMap::new();
+    torrent.insert(b"announce".to_vec(), BencodeValue::String(announce.as_bytes().to_vec()));
+    torrent.insert(b"info".to_vec(), info);
+    Ok((BencodeValue::Dict(torrent).encode(), info_hash))
+}
+
+/// SHA-256 of a file, reusing the cached value while size and mtime are unchanged.
+fn cached_sha256(path: &Path, size: u64, mtime: u64) -> std::io::Result<String> {
+    if let Some((cached_size, cached_mtime, hash)) = HASH_CACHE.lock().unwrap().get(path) {
+        if *cached_size == size && *cached_mtime == mtime {
+            return Ok(hash.clone());
+        }
+    }
+
+    let hash = sha256_file(path)?;
+    HASH_CACHE.lock().unwrap().insert(path.to_path_buf(), (size, mtime, hash.clone()));
+    Ok(hash)
+}
+
+/// Errors returned when resolving a client-supplied path in the seed directory.
+#[derive(Debug)]
+pub enum SeedPathError {
+    /// No file name was given
+    EmptyName,
+    /// The name is absolute or contains `..`, hidden or otherwise invalid components
+    InvalidPath(String),
+    /// The path resolves (e.g. through a symlink) to a location outside the seed root
+    OutsideRoot(String),
+    /// Nothing servable exists at the path
+    NotFound(String),
+}
+
+impl SeedPathError {
+    /// Error code reported to clients in `ErrorResponse::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SeedPathError::EmptyName => "EMPTY_FILENAME",
+            SeedPathError::InvalidPath(_) => "INVALID_PATH",
+            SeedPathError::OutsideRoot(_) => "PATH_OUTSIDE_SEED_DIR",
+            SeedPathError::NotFound(_) => "FILE_NOT_FOUND",
+        }
+    }
+}
+
+impl std::fmt::Display for SeedPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedPathError::EmptyName => write!(f, "No file name specified"),
+            SeedPathError::InvalidPath(name) => write!(f, "Invalid file path: {}", name),
+            SeedPathError::OutsideRoot(name) => write!(f, "Path escapes the seed directory: {}", name),
+            SeedPathError::NotFound(name) => write!(f, "File not found: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for SeedPathError {}
+
+/// Returns true if a path component is served to clients.
+///
+/// Hidden files and directories (including the publish staging area) are never served.
+fn is_visible_component(component: &str) -> bool {
+    !component.is_empty() && !component.starts_with('.')
+}
+
+/// Returns true if the file name is listed in the catalog.
+///
+/// `.torrent` metainfo files are not listed as content; torrents are
+/// reported through the `torrent` field of the file they describe.
+fn is_catalog_name(name: &str) -> bool {
+    !name.ends_with(".torrent")
+}
+
+/// The directory whose files the server seeds.
+///
+/// Client-supplied names are relative paths using `/` (or `\`) separators.
+/// They are resolved against the canonical root and rejected if they escape it,
+/// either lexically (`..`, absolute paths) or through symlinks.
+#[derive(Debug, Clone)]
+pub struct SeedDirectory {
+    root: PathBuf,
+}
+
+impl SeedDirectory {
+    /// Opens the seed directory at `root`, creating it if it does not exist.
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+        let root = root.as_ref().canonicalize()?;
+        Ok(Self { root })
+    }
+
+    /// Returns the canonical seed root.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Splits a client-supplied name into validated path components.
+    fn components<'a>(&self, name: &'a str) -> Result<Vec<&'a str>, SeedPathError> {
+        let trimmed = name.trim();
+        if trimmed.is_empty() {
+            return Err(SeedPathError::EmptyName);
+        }
+        // Reject absolute paths and Windows drive prefixes ("C:\...")
+        if trimmed.starts_with('/') || trimmed.starts_with('\\') || trimmed.contains(':') {
+            return Err(SeedPathError::InvalidPath(name.to_string()));
+        }
+
+        let mut components = Vec::new();
+        for component in trimmed.split(['/', '\\']) {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+            if !is_visible_component(component) {
+                return Err(SeedPathError::InvalidPath(name.to_string()));
+            }
+            components.push(component);
+        }
+        if components.is_empty() {
+            return Err(SeedPathError::EmptyName);
+        }
+        Ok(components)
+    }
+
+    /// Maps a client-supplied name to a path under the root without touching the filesystem.
+    ///
+    /// The result is lexically contained in the root but may not exist yet.
+    pub fn join(&self, name: &str) -> Result<PathBuf, SeedPathError> {
+        let components = self.components(name)?;
+        Ok(components.iter().fold(self.root.clone(), |path, c| path.join(c)))
+    }
+
+    /// Resolves a client-supplied name to an existing file inside the seed root.
+    ///
+    /// The path is canonicalized, so symlinks are followed and then checked
+    /// against the root.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, SeedPathError> {
+        let joined = self.join(name)?;
+        let canonical = joined.canonicalize()
+            .map_err(|_| SeedPathError::NotFound(name.to_string()))?;
+        if !canonical.starts_with(&self.root) {
+            return Err(SeedPathError::OutsideRoot(name.to_string()));
+        }
+        if !canonical.is_file() {
+            return Err(SeedPathError::NotFound(name.to_string()));
+        }
+        Ok(canonical)
+    }
+
+    /// Generates `<name>.torrent` next to a seed file and returns its info hash.
+    ///
+    /// The torrent is written to a hidden temporary file first and then renamed,
+    /// so readers never observe a partially written torrent.
+    pub fn write_torrent(&self, name: &str, announce: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let path = self.resolve(name)?;
+        let size = fs::metadata(&path)?.len();
+        let torrent_path = self.torrent_path(name)?;
+        let file_name = torrent_path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".torrent"))
+            .ok_or("Seed file has no name")?
+            .to_string();
+        let (torrent, info_hash) = create_torrent(&path, &file_name, announce, piece_length_for(size))?;
+
+        let temp_path = torrent_path.with_file_name(format!(".{}.torrent.tmp", file_name));
+        fs::write(&temp_path, &torrent)?;
+        fs::rename(&temp_path, &torrent_path)?;
+        crate::log_server!("Generated torrent {} (info_hash={})", torrent_path.display(), info_hash);
+        Ok(info_hash)
+    }
+
+    /// Returns the info hash of `<name>.torrent`, generating the torrent first if it is
+    /// missing, unreadable, older than the file or describes a different length.
+    ///
+    /// # Returns
+    /// Tuple of (info hash, whether the torrent was (re)generated)
+    pub fn ensure_torrent(&self, name: &str, announce: &str) -> Result<(String, bool), Box<dyn std::error::Error>> {
+        let path = self.resolve(name)?;
+        let metadata = fs::metadata(&path)?;
+        let torrent_path = self.torrent_path(name)?;
+
+        if let Ok(torrent_metadata) = fs::metadata(&torrent_path) {
+            let up_to_date = match (torrent_metadata.modified(), metadata.modified()) {
+                (Ok(torrent_time), Ok(file_time)) => torrent_time >= file_time,
+                _ => true,
+            };
+            if up_to_date {
+                if let Ok(torrent) = TorrentFile::from_file(&torrent_path.to_string_lossy()) {
+                    if torrent.length as u64 == metadata.len() {
+                        return Ok((torrent.info_hash, false));
+                    }
+                }
+            }
+        }
+
+        let info_hash = self.write_torrent(name, announce)?;
+        Ok((info_hash, true))
+    }
+
+    /// Path of `<name>.torrent`, next to the seed file as it is named (a
+    /// symlinked file's torrent stays next to the link, not its target).
+    fn torrent_path(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = self.join(&format!("{}.torrent", name))?;
+        let file_name = path.file_name().ok_or("Seed file has no name")?;
+        let parent = path.parent().ok_or("Seed file has no name")?.canonicalize()?;
+        if !parent.starts_with(&self.root) {
+            return Err(SeedPathError::OutsideRoot(name.to_string()).into());
+        }
+        Ok(parent.join(file_name))
+    }
+
+    /// Builds the catalog entry for a file in the seed directory.
+    pub fn describe_file(&self, name: &str) -> Result<SeedFileInfo, Box<dyn std::error::Error>> {
+        let path = self.resolve(name)?;
+        let metadata = fs::metadata(&path)?;
+        let size = metadata.len();
+        let mtime = metadata.modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let sha256 = cached_sha256(&path, size, mtime)?;
+
+        // Look for "<name>.torrent" next to the file
+        let torrent_name = format!("{}.torrent", name);
+        let (torrent, info_hash) = match self.resolve(&torrent_name) {
+            Ok(torrent_path) => match TorrentFile::from_file(&torrent_path.to_string_lossy()) {
+                Ok(t) => (Some(torrent_name), Some(t.info_hash)),
+                Err(e) => {
+                    crate::log_server!("WARNING: Unreadable torrent file {}: {}", torrent_path.display(), e);
+                    (Some(torrent_name), None)
+                }
+            },
+            Err(_) => (None, None),
+        };
+
+        Ok(SeedFileInfo {
+            name: name.to_string(),
+            size,
+            mtime,
+            sha256,
+            torrent,
+            info_hash,
+        })
+    }
+
+    /// Lists every servable file under the seed root, sorted.
+    ///
+    /// Names are relative to the root with `/` separators. Hidden entries and
+    /// symlinks that resolve outside the root are skipped.
+    ///
+    /// # Arguments
+    /// * `pattern` - Optional glob pattern applied to the relative names
+    pub fn list_file_names(&self, pattern: Option<&str>) -> std::io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        self.collect_names(&mut names)?;
+        names.retain(|name| is_catalog_name(name) && pattern.is_none_or(|p| glob_match(p, name)));
+        names.sort();
+        Ok(names)
+    }
+
+    /// Walks the tree breadth first, entering each canonical directory once:
+    /// symlinked directories can't form a cycle, and a directory reachable
+    /// several ways is listed under its shortest name.
+    fn collect_names(&self, names: &mut Vec<String>) -> std::io::Result<()> {
+        let mut visited = HashSet::from([self.root.clone()]);
+        let mut queue = VecDeque::from([(self.root.clone(), String::new())]);
+        while let Some((dir, prefix)) = queue.pop_front() {
+            let mut entries: Vec<(String, PathBuf)> = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                match entry.file_name().into_string() {
+                    Ok(name) => entries.push((name, entry.path())),
+                    Err(_) => continue, // Non UTF-8 names cannot be requested over JSON
+                }
+            }
+            entries.sort();
+
+            for (name, path) in entries {
+                if !is_visible_component(&name) {
+                    continue;
+                }
+                // Follow symlinks, but only within the root
+                let canonical = match path.canonicalize() {
+                    Ok(path) if path.starts_with(&self.root) => path,
+                    _ => continue,
+                };
+                let relative = format!("{}{}", prefix, name);
+                if canonical.is_dir() {
+                    if visited.insert(canonical.clone()) {
+                        queue.push_back((canonical, format!("{}/", relative)));
+                    }
+                } else if canonical.is_file() {
+                    names.push(relative);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(label: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("seed-test-{}-{}", label, rand::random::<u64>()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn glob_matches_stars_and_question_marks() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("docs/*.txt", "docs/a.txt"));
+        assert!(!glob_match("*.txt", "notes.bin"));
+        assert!(glob_match("file?.bin", "file1.bin"));
+        assert!(!glob_match("file?.bin", "file10.bin"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn resolve_rejects_names_escaping_the_root() {
+        let dir = TempDir::new("resolve");
+        let seed = SeedDirectory::new(dir.0.join("seed")).unwrap();
+        fs::create_dir_all(seed.root().join("sub")).unwrap();
+        fs::write(seed.root().join("sub/file.txt"), b"data").unwrap();
+        fs::write(dir.0.join("secret.txt"), b"secret").unwrap();
+
+        assert_eq!(seed.resolve("sub/file.txt").unwrap(), seed.root().join("sub/file.txt"));
+        assert_eq!(seed.resolve("sub\\file.txt").unwrap(), seed.root().join("sub/file.txt"));
+        assert!(matches!(seed.resolve(""), Err(SeedPathError::EmptyName)));
+        assert!(matches!(seed.resolve("../secret.txt"), Err(SeedPathError::InvalidPath(_))));
+        assert!(matches!(seed.resolve("sub/../../secret.txt"), Err(SeedPathError::InvalidPath(_))));
+        let absolute = dir.0.join("secret.txt").display().to_string();
+        assert!(matches!(seed.resolve(&absolute), Err(SeedPathError::InvalidPath(_))));
+        assert!(matches!(seed.resolve("C:\\secret.txt"), Err(SeedPathError::InvalidPath(_))));
+        assert!(matches!(seed.resolve(".staging/x"), Err(SeedPathError::InvalidPath(_))));
+        assert!(matches!(seed.resolve("sub/missing.txt"), Err(SeedPathError::NotFound(_))));
+        assert!(matches!(seed.resolve("sub"), Err(SeedPathError::NotFound(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_rejects_symlinks_out_of_the_root() {
+        let dir = TempDir::new("escape");
+        let seed = SeedDirectory::new(dir.0.join("seed")).unwrap();
+        fs::write(dir.0.join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(dir.0.join("secret.txt"), seed.root().join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&dir.0, seed.root().join("outside")).unwrap();
+
+        assert!(matches!(seed.resolve("link.txt"), Err(SeedPathError::OutsideRoot(_))));
+        assert!(matches!(seed.resolve("outside/secret.txt"), Err(SeedPathError::OutsideRoot(_))));
+        assert!(seed.list_file_names(None).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listing_survives_symlink_cycles() {
+        let dir = TempDir::new("cycle");
+        let seed = SeedDirectory::new(&dir.0).unwrap();
+        fs::create_dir_all(seed.root().join("a")).unwrap();
+        fs::create_dir_all(seed.root().join("b")).unwrap();
+        fs::write(seed.root().join("a/one.txt"), b"1").unwrap();
+        fs::write(seed.root().join("b/two.txt"), b"2").unwrap();
+        fs::write(seed.root().join("b/two.txt.torrent"), b"d").unwrap();
+        // Siblings linking to each other, and a link back to the root
+        std::os::unix::fs::symlink("../b", seed.root().join("a/to_b")).unwrap();
+        std::os::unix::fs::symlink("../a", seed.root().join("b/to_a")).unwrap();
+        std::os::unix::fs::symlink("..", seed.root().join("a/up")).unwrap();
+
+        let names = seed.list_file_names(None).unwrap();
+        // Each directory is entered once, under its shortest name
+        assert_eq!(names, vec!["a/one.txt".to_string(), "b/two.txt".to_string()]);
+        assert_eq!(seed.list_file_names(Some("*one.txt")).unwrap(), vec!["a/one.txt".to_string()]);
+    }
+}
@@ -0,0 +1,829 @@
+//! # Session
+//!
+//! Hosts many torrents in one process, for long-running clients.
+//!
+//! A `Session` owns one `PeerListener` (so one port and one peer id for all
+//! its torrents), and optionally one DHT node and one local discovery
+//! socket. Each added torrent runs as its own task through these states:
+//!
+//! 1. `Checking` - the output file is opened and the pieces already in it
+//!    are verified;
+//! 2. `Queued` - waiting for one of `max_active_downloads` download slots
+//!    (first come, first served);
+//! 3. `Downloading` - announced to its tracker and fetching from the swarm;
+//! 4. `Seeding` - serving pieces until the seeding limits in
+//!    `DownloadOptions` are reached (skipped unless `seed` is set);
+//! 5. `Complete`.
+//!
+//! Any failure moves the torrent to `Error`, which keeps the typed
+//! `crate::error::Error`. `pause` stops a torrent in any
+//! state and `resume` starts it over from `Checking` (keeping the pieces it
+//! has); `remove` forgets it, leaving its file in place.
+//!
+//! Every torrent draws on the same `Budget`: a cap on outgoing peer
+//! connections and download/upload rate limits.
+
+use crate::budget::{Budget, BudgetConfig};
+use crate::client::{announce_with_response, DownloadOptions, TorrentFile};
+use crate::dht::Dht;
+use crate::error::{Error, Result};
+use crate::lsd::LocalDiscovery;
+use crate::messages::{PeerInfo, TrackerAnnounceResponse};
+use crate::peer_wire::{PeerListener, PieceStore};
+use crate::pex::PeerSource;
+use crate::piece_picker::PickMode;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Torrents downloading at once unless configured otherwise.
+pub const DEFAULT_MAX_ACTIVE_DOWNLOADS: usize = 4;
+
+/// How often a seeding torrent checks its limits.
+const SEED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Session settings.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Listener port, pick mode, seeding limits, DHT and local discovery,
+    /// applied to every torrent
+    pub options: DownloadOptions,
+    /// Tracker for torrents whose `announce` isn't a `quic://host:port` URL
+    pub default_tracker: Option<(String, u16)>,
+    /// Torrents in the `Downloading` state at once; the rest wait `Queued`
+    pub max_active_downloads: usize,
+    /// Limits shared by all torrents
+    pub budget: BudgetConfig,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            options: DownloadOptions::default(),
+            default_tracker: None,
+            max_active_downloads: DEFAULT_MAX_ACTIVE_DOWNLOADS,
+            budget: BudgetConfig::default(),
+        }
+    }
+}
+
+/// Where a torrent is in its lifecycle.
+#[derive(Debug, Clone)]
+pub enum TorrentState {
+    /// Verifying the pieces already on disk
+    Checking,
+    /// Waiting for a download slot
+    Queued,
+    Downloading,
+    Seeding,
+    /// Downloaded, and done seeding if seeding is enabled
+    Complete,
+    Paused,
+    /// The failure that stopped the torrent
+    Error(Arc<Error>),
+}
+
+impl std::fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorrentState::Checking => write!(f, "checking"),
+            TorrentState::Queued => write!(f, "queued"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+            TorrentState::Complete => write!(f, "complete"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// A snapshot of one torrent.
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub output: PathBuf,
+    pub state: TorrentState,
+    /// Verified pieces (0 until checked)
+    pub pieces: usize,
+    pub piece_count: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+struct TorrentEntry {
+    torrent: TorrentFile,
+    output: PathBuf,
+    tracker: (String, u16),
+    state: TorrentState,
+    store: Option<Arc<PieceStore>>,
+    /// True from announcing `started` until announcing `stopped`, so the
+    /// tracker is told we left however the task ends
+    joined: bool,
+    /// Bumped whenever the torrent's task is stopped, so a task that is
+    /// still winding down can't overwrite the new state
+    generation: u64,
+    task: Option<JoinHandle<()>>,
+}
+
+struct SessionInner {
+    config: SessionConfig,
+    peer_id: String,
+    listener: PeerListener,
+    lsd: Option<Arc<LocalDiscovery>>,
+    dht: Option<Arc<Dht>>,
+    /// Download slots; waiters get them in FIFO order
+    active: Arc<Semaphore>,
+    torrents: Mutex<HashMap<String, TorrentEntry>>,
+}
+
+/// Manages many torrents with shared listener, discovery and budgets.
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for entry in self.inner.torrents.lock().unwrap().values_mut() {
+            if let Some(task) = entry.task.take() {
+                task.abort();
+            }
+        }
+        self.inner.listener.close();
+    }
+}
+
+impl Session {
+    /// Binds the peer listener and joins the DHT and local discovery as configured.
+    pub async fn start(config: SessionConfig) -> Result<Self> {
+        let peer_id = format!("-ST0001-{}", rand::random::<u64>());
+        let budget = Arc::new(Budget::new(config.budget.clone()));
+        let listener = PeerListener::bind_with_budget(config.options.peer_port, &peer_id, budget)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let dht = match &config.options.dht {
+            Some(dht_config) => {
+                let dht = Dht::start(dht_config.clone()).await.map_err(|e| Error::transport(format!("DHT: {}", e)))?;
+                let contacts = dht.bootstrap().await.map_err(|e| Error::transport(format!("DHT bootstrap: {}", e)))?;
+                crate::log_client!("[Session::start] DHT node {} on {} ({} contacts)", dht.id(), dht.local_addr(), contacts);
+                Some(dht)
+            }
+            None => None,
+        };
+
+        let (lsd, discovered) = match &config.options.lsd {
+            Some(lsd_config) => match LocalDiscovery::start(lsd_config.clone(), listener.port()).await {
+                Ok((lsd, discovered)) => (Some(lsd), Some(discovered)),
+                Err(e) => {
+                    // Not fatal: the network may not allow multicast
+                    crate::log_client!("[Session::start] Local discovery unavailable: {}", e);
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+
+        let inner = Arc::new(SessionInner {
+            active: Arc::new(Semaphore::new(config.max_active_downloads.max(1))),
+            config,
+            peer_id,
+            listener,
+            lsd,
+            dht,
+            torrents: Mutex::new(HashMap::new()),
+        });
+
+        // Peers found on the local network join their torrent's pool
+        if let Some(mut discovered) = discovered {
+            let session = Arc::downgrade(&inner);
+            tokio::spawn(async move {
+                while let Some(peer) = discovered.recv().await {
+                    let Some(session) = session.upgrade() else { break };
+                    let info = PeerInfo { ip: peer.addr.ip().to_string(), port: peer.addr.port(), peer_id: None };
+                    session.listener.add_peers(&peer.info_hash, &[info], PeerSource::Local);
+                }
+            });
+        }
+
+        crate::log_client!("[Session::start] Session started - peer_id={}, port={}, max_active_downloads={}, budget={:?}",
+            inner.peer_id, inner.listener.port(), inner.config.max_active_downloads, inner.config.budget);
+        Ok(Self { inner })
+    }
+
+    /// Port of the shared peer listener.
+    pub fn port(&self) -> u16 {
+        self.inner.listener.port()
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.inner.peer_id
+    }
+
+    /// Adds a torrent file and starts it.
+    ///
+    /// # Arguments
+    /// * `torrent_path` - The `.torrent` file
+    /// * `output_path` - Where the downloaded file goes (pieces already there are kept)
+    ///
+    /// # Returns
+    /// The torrent's info hash, which identifies it in the session
+    pub fn add(&self, torrent_path: &str, output_path: &str) -> Result<String> {
+        let torrent = TorrentFile::from_file(torrent_path)?;
+        self.add_torrent(torrent, Path::new(output_path), None)
+    }
+
+    /// Adds a parsed torrent and starts it.
+    ///
+    /// # Arguments
+    /// * `torrent` - The torrent
+    /// * `output` - Where the downloaded file goes
+    /// * `tracker` - Tracker to announce to; defaults to the torrent's
+    ///   `announce` URL, then the session's `default_tracker`
+    ///
+    /// # Returns
+    /// The torrent's info hash
+    pub fn add_torrent(&self, torrent: TorrentFile, output: &Path, tracker: Option<(String, u16)>) -> Result<String> {
+        let tracker = tracker
+            .or_else(|| torrent.tracker_address())
+            .or_else(|| self.inner.config.default_tracker.clone())
+            .ok_or_else(|| Error::Torrent(format!("No tracker for {}: announce URL '{}' isn't quic://host:port and no default is set", torrent.name, torrent.announce)))?;
+        let info_hash = torrent.info_hash.clone();
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::Session(format!("Torrent {} is already in the session", info_hash)));
+        }
+        crate::log_client!("[Session::add_torrent] Adding {} ({}) -> {}, tracker {}:{}",
+            torrent.name, info_hash, output.display(), tracker.0, tracker.1);
+        torrents.insert(info_hash.clone(), TorrentEntry {
+            torrent,
+            output: output.to_path_buf(),
+            tracker,
+            state: TorrentState::Checking,
+            store: None,
+            joined: false,
+            generation: 0,
+            task: None,
+        });
+        self.inner.spawn(&mut torrents, &info_hash);
+        Ok(info_hash)
+    }
+
+    /// Stops a torrent, keeping it in the session as `Paused`.
+    pub fn pause(&self, info_hash: &str) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents.get_mut(info_hash).ok_or_else(|| unknown_torrent(info_hash))?;
+        if matches!(entry.state, TorrentState::Paused) {
+            return Ok(());
+        }
+        self.inner.stop(entry);
+        entry.state = TorrentState::Paused;
+        crate::log_client!("[Session::pause] Paused {}", info_hash);
+        Ok(())
+    }
+
+    /// Starts a paused or failed torrent again, from `Checking`.
+    pub fn resume(&self, info_hash: &str) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents.get_mut(info_hash).ok_or_else(|| unknown_torrent(info_hash))?;
+        if !matches!(entry.state, TorrentState::Paused | TorrentState::Error(_)) {
+            return Err(Error::Session(format!("Torrent {} is {}, not paused", info_hash, entry.state)));
+        }
+        entry.state = TorrentState::Checking;
+        self.inner.spawn(&mut torrents, info_hash);
+        crate::log_client!("[Session::resume] Resumed {}", info_hash);
+        Ok(())
+    }
+
+    /// Stops a torrent and forgets it; its file stays on disk.
+    pub fn remove(&self, info_hash: &str) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let mut entry = torrents.remove(info_hash).ok_or_else(|| unknown_torrent(info_hash))?;
+        self.inner.stop(&mut entry);
+        crate::log_client!("[Session::remove] Removed {}", info_hash);
+        Ok(())
+    }
+
+    /// The status of one torrent.
+    pub fn status(&self, info_hash: &str) -> Option<TorrentStatus> {
+        self.inner.torrents.lock().unwrap().get(info_hash).map(|entry| status_of(info_hash, entry))
+    }
+
+    /// The status of every torrent, by name.
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let mut statuses: Vec<TorrentStatus> = self.inner.torrents.lock().unwrap().iter()
+            .map(|(info_hash, entry)| status_of(info_hash, entry))
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name).then(a.info_hash.cmp(&b.info_hash)));
+        statuses
+    }
+
+    /// Waits until no torrent is checking, queued or downloading.
+    pub async fn wait_idle(&self) {
+        loop {
+            let busy = self.inner.torrents.lock().unwrap().values()
+                .any(|entry| matches!(entry.state, TorrentState::Checking | TorrentState::Queued | TorrentState::Downloading));
+            if !busy {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    /// Stops every torrent, leaving their swarms, and closes the listener.
+    pub async fn shutdown(&self) {
+        // (info hash, tracker, store) of the torrents in a swarm
+        type Leaving = (String, (String, u16), Option<Arc<PieceStore>>);
+        let leaving: Vec<Leaving> = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            torrents.iter_mut()
+                .filter_map(|(info_hash, entry)| {
+                    let joined = std::mem::take(&mut entry.joined);
+                    if let Some(task) = entry.task.take() {
+                        task.abort();
+                    }
+                    entry.generation += 1;
+                    entry.state = TorrentState::Paused;
+                    joined.then(|| (info_hash.clone(), entry.tracker.clone(), entry.store.clone()))
+                })
+                .collect()
+        };
+        for (info_hash, tracker, store) in leaving {
+            if let Err(e) = self.inner.announce(&info_hash, &tracker, store.as_deref(), Some("stopped")).await {
+                crate::log_client!("[Session::shutdown] Stopped announce for {} failed: {}", info_hash, e);
+            }
+        }
+        self.inner.listener.close();
+        crate::log_client!("[Session::shutdown] Session closed");
+    }
+}
+
+fn unknown_torrent(info_hash: &str) -> Error {
+    Error::Session(format!("Unknown torrent {}", info_hash))
+}
+
+fn status_of(info_hash: &str, entry: &TorrentEntry) -> TorrentStatus {
+    let store = entry.store.as_deref();
+    TorrentStatus {
+        info_hash: info_hash.to_string(),
+        name: entry.torrent.name.clone(),
+        output: entry.output.clone(),
+        state: entry.state.clone(),
+        pieces: store.map_or(0, |store| store.have().iter().filter(|&&h| h).count()),
+        piece_count: entry.torrent.pieces.len(),
+        downloaded: store.map_or(0, |store| store.downloaded()),
+        uploaded: store.map_or(0, |store| store.uploaded()),
+    }
+}
+
+impl SessionInner {
+    /// Starts the task for a torrent (which must be in `torrents`).
+    fn spawn(self: &Arc<Self>, torrents: &mut HashMap<String, TorrentEntry>, info_hash: &str) {
+        let Some(entry) = torrents.get_mut(info_hash) else { return };
+        let generation = entry.generation;
+        let session = Arc::clone(self);
+        let info_hash = info_hash.to_string();
+        entry.task = Some(tokio::spawn(async move {
+            if let Err(e) = session.run(&info_hash, generation).await {
+                crate::log_client!("ERROR: [Session] Torrent {} failed: {}", info_hash, e);
+                session.stop_serving(&info_hash);
+                session.set_state(&info_hash, generation, TorrentState::Error(Arc::new(e)));
+                session.leave(&info_hash, generation).await;
+            }
+        }));
+    }
+
+    /// Cancels a torrent's task and stops serving it, leaving the swarm if
+    /// it had joined. The caller sets the new state.
+    fn stop(self: &Arc<Self>, entry: &mut TorrentEntry) {
+        if let Some(task) = entry.task.take() {
+            task.abort();
+        }
+        entry.generation += 1;
+        let info_hash = entry.torrent.info_hash.clone();
+        self.stop_serving(&info_hash);
+        if std::mem::take(&mut entry.joined) {
+            let session = Arc::clone(self);
+            let tracker = entry.tracker.clone();
+            let store = entry.store.clone();
+            tokio::spawn(async move {
+                if let Err(e) = session.announce(&info_hash, &tracker, store.as_deref(), Some("stopped")).await {
+                    crate::log_client!("[Session] Stopped announce for {} failed: {}", info_hash, e);
+                }
+            });
+        }
+    }
+
+    fn stop_serving(&self, info_hash: &str) {
+        self.listener.remove_torrent(info_hash);
+        if let Some(lsd) = &self.lsd {
+            lsd.remove_torrent(info_hash);
+        }
+    }
+
+    /// Updates a torrent's state, unless its task has been replaced.
+    ///
+    /// # Returns
+    /// `false` if the task is stale and should end
+    fn set_state(&self, info_hash: &str, generation: u64, state: TorrentState) -> bool {
+        let mut torrents = self.torrents.lock().unwrap();
+        match torrents.get_mut(info_hash) {
+            Some(entry) if entry.generation == generation => {
+                crate::log_client!("[Session] {} ({}) is now {}", entry.torrent.name, info_hash, state);
+                entry.state = state;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that a torrent is about to announce `started`.
+    ///
+    /// # Returns
+    /// `false` if the task is stale and should end
+    fn join(&self, info_hash: &str, generation: u64) -> bool {
+        let mut torrents = self.torrents.lock().unwrap();
+        match torrents.get_mut(info_hash) {
+            Some(entry) if entry.generation == generation => {
+                entry.joined = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Announces `stopped` if the torrent joined its swarm, unless its task
+    /// has been replaced (`stop` then announces it instead).
+    async fn leave(&self, info_hash: &str, generation: u64) {
+        let leaving = {
+            let mut torrents = self.torrents.lock().unwrap();
+            match torrents.get_mut(info_hash) {
+                Some(entry) if entry.generation == generation && entry.joined => {
+                    entry.joined = false;
+                    Some((entry.tracker.clone(), entry.store.clone()))
+                }
+                _ => None,
+            }
+        };
+        if let Some((tracker, store)) = leaving {
+            if let Err(e) = self.announce(info_hash, &tracker, store.as_deref(), Some("stopped")).await {
+                crate::log_client!("[Session] Stopped announce for {} failed: {}", info_hash, e);
+            }
+        }
+    }
+
+    /// Announces a torrent to its tracker with the store's current totals.
+    async fn announce(
+        &self,
+        info_hash: &str,
+        tracker: &(String, u16),
+        store: Option<&PieceStore>,
+        event: Option<&'static str>,
+    ) -> Result<TrackerAnnounceResponse> {
+        let (uploaded, downloaded, left) = store.map_or((0, 0, 0), |store| (store.uploaded(), store.downloaded(), store.left()));
+        announce_with_response(&tracker.0, tracker.1, info_hash, &self.peer_id, self.listener.port(), uploaded, downloaded, left, event).await
+    }
+
+    /// Takes a torrent from `Checking` to `Complete`.
+    async fn run(&self, info_hash: &str, generation: u64) -> Result<()> {
+        let (torrent, output, tracker) = {
+            let torrents = self.torrents.lock().unwrap();
+            let entry = torrents.get(info_hash).ok_or_else(|| unknown_torrent(info_hash))?;
+            (entry.torrent.clone(), entry.output.clone(), entry.tracker.clone())
+        };
+
+        // Hashing a large file takes a while; keep it off the runtime's threads
+        self.set_state(info_hash, generation, TorrentState::Checking);
+        let store = tokio::task::spawn_blocking(move || PieceStore::open(torrent, &output))
+            .await
+            .map_err(std::io::Error::from)??;
+        let store = Arc::new(store);
+        {
+            let mut torrents = self.torrents.lock().unwrap();
+            match torrents.get_mut(info_hash) {
+                Some(entry) if entry.generation == generation => entry.store = Some(Arc::clone(&store)),
+                _ => return Ok(()),
+            }
+        }
+        self.listener.add_torrent(Arc::clone(&store));
+        if let Some(lsd) = &self.lsd {
+            lsd.add_torrent(info_hash);
+        }
+
+        let options = &self.config.options;
+        let mut interval = Duration::from_secs(60);
+        if !store.is_complete() {
+            if !self.set_state(info_hash, generation, TorrentState::Queued) {
+                return Ok(());
+            }
+            let _slot = Arc::clone(&self.active).acquire_owned().await
+                .map_err(|_| Error::Session("Session closed".into()))?;
+            if !self.set_state(info_hash, generation, TorrentState::Downloading) || !self.join(info_hash, generation) {
+                return Ok(());
+            }
+
+            // Reported instead of the download's failure if no other source delivers anything
+            let mut tracker_error = None;
+            let mut peers = match self.announce(info_hash, &tracker, Some(&store), Some("started")).await {
+                Ok(response) => {
+                    interval = Duration::from_secs(response.interval.max(1));
+                    response.peers
+                }
+                Err(e) if self.dht.is_some() || self.lsd.is_some() || !store.torrent().url_list.is_empty() => {
+                    crate::log_client!("[Session] Tracker announce for {} failed, continuing with other sources: {}", info_hash, e);
+                    tracker_error = Some(e);
+                    Vec::new()
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(dht) = &self.dht {
+                match dht.announce(info_hash, self.listener.port()).await {
+                    Ok(found) => peers.extend(found),
+                    Err(e) => crate::log_client!("[Session] DHT announce for {} failed: {}", info_hash, e),
+                }
+            }
+
+            let mode = if options.sequential { PickMode::Sequential } else { PickMode::RarestFirst };
+            let downloaded = self.listener.download(Arc::clone(&store), &peers, (&tracker.0, tracker.1), mode).await;
+            if let Err(e) = downloaded {
+                return Err(match tracker_error {
+                    Some(tracker_error) if store.downloaded() == 0 => tracker_error,
+                    _ => Error::Torrent(e.to_string()),
+                });
+            }
+            if let Err(e) = self.announce(info_hash, &tracker, Some(&store), Some("completed")).await {
+                crate::log_client!("[Session] Completed announce for {} failed: {}", info_hash, e);
+            }
+        } else if options.seed {
+            // Already complete: join the swarm as a seed
+            if !self.join(info_hash, generation) {
+                return Ok(());
+            }
+            match self.announce(info_hash, &tracker, Some(&store), Some("started")).await {
+                Ok(response) => interval = Duration::from_secs(response.interval.max(1)),
+                Err(e) => crate::log_client!("[Session] Started announce for {} failed: {}", info_hash, e),
+            }
+        }
+
+        if options.seed && self.set_state(info_hash, generation, TorrentState::Seeding) {
+            self.seed(info_hash, &tracker, &store, interval).await;
+        }
+
+        // Done: leave the swarm and stop serving
+        self.stop_serving(info_hash);
+        if self.set_state(info_hash, generation, TorrentState::Complete) {
+            self.leave(info_hash, generation).await;
+        }
+        Ok(())
+    }
+
+    /// Seeds until the seeding limits are reached, re-announcing every
+    /// `interval` (and to the DHT every `DHT_REANNOUNCE_INTERVAL`).
+    async fn seed(&self, info_hash: &str, tracker: &(String, u16), store: &PieceStore, interval: Duration) {
+        let started = Instant::now();
+        let mut next_announce = started + interval;
+        let mut next_dht_announce = started + crate::dht::DHT_REANNOUNCE_INTERVAL;
+        loop {
+            if let Some(reason) = self.config.options.seed_limit_reached(store.uploaded(), store.torrent().length as u64, started.elapsed()) {
+                crate::log_client!("[Session] Stopped seeding {}: {}", info_hash, reason);
+                return;
+            }
+            if Instant::now() >= next_announce {
+                if let Err(e) = self.announce(info_hash, tracker, Some(store), None).await {
+                    crate::log_client!("[Session] Re-announce for {} failed: {}", info_hash, e);
+                }
+                next_announce = Instant::now() + interval;
+            }
+            if let Some(dht) = &self.dht {
+                if Instant::now() >= next_dht_announce {
+                    if let Err(e) = dht.announce(info_hash, self.listener.port()).await {
+                        crate::log_client!("[Session] DHT re-announce for {} failed: {}", info_hash, e);
+                    }
+                    next_dht_announce = Instant::now() + crate::dht::DHT_REANNOUNCE_INTERVAL;
+                }
+            }
+            tokio::time::sleep(SEED_CHECK_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic_tracker::{run_quic_tracker_with_config, TrackerConfig};
+    use crate::seed::SeedDirectory;
+    use std::sync::OnceLock;
+
+    /// Size of each test file; at `RATE` a download takes a few seconds
+    const SIZE: usize = 96 * 1024;
+    const RATE: u64 = 32 * 1024;
+
+    /// One runtime for every test: the shared QUIC client's endpoint is
+    /// driven by the runtime it was created on.
+    fn runtime() -> &'static tokio::runtime::Runtime {
+        static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap())
+    }
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(label: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("session-test-{}-{}", label, rand::random::<u64>()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn content(name: &str) -> Vec<u8> {
+        (0..SIZE).map(|i| (i * 31 + name.len() * 7) as u8 ^ name.as_bytes()[0]).collect()
+    }
+
+    /// A tracker on a free port, seeding `names` from its seed directory.
+    struct Tracker {
+        port: u16,
+        dir: TempDir,
+        task: JoinHandle<()>,
+    }
+
+    impl Tracker {
+        async fn start(names: &[&str]) -> Self {
+            let dir = TempDir::new("seed");
+            for name in names {
+                std::fs::write(dir.0.join(name), content(name)).unwrap();
+            }
+            let port = std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+            let config = TrackerConfig {
+                port,
+                enable_ai: false,
+                enable_work_dist: false,
+                seed_dir: dir.0.clone(),
+                seed_scan_interval_secs: 0,
+                ..TrackerConfig::default()
+            };
+            let task = tokio::spawn(async move {
+                run_quic_tracker_with_config(config).await.unwrap();
+            });
+            let tracker = Self { port, dir, task };
+
+            // Up once it lists every file with its generated torrent
+            let deadline = Instant::now() + Duration::from_secs(20);
+            loop {
+                if let Ok(files) = crate::client::list_all_files_quic("127.0.0.1", port, None).await {
+                    if names.iter().all(|name| files.iter().any(|f| f.name == *name && f.info_hash.is_some())) {
+                        return tracker;
+                    }
+                }
+                assert!(Instant::now() < deadline, "tracker didn't come up");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        fn torrent(&self, name: &str) -> TorrentFile {
+            TorrentFile::from_file(self.dir.0.join(format!("{}.torrent", name)).to_str().unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for Tracker {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    /// One download slot and a download rate slow enough to watch.
+    async fn slow_session() -> Session {
+        Session::start(SessionConfig {
+            options: DownloadOptions { peer_port: 0, lsd: None, ..DownloadOptions::default() },
+            default_tracker: None,
+            max_active_downloads: 1,
+            budget: BudgetConfig { download_rate: Some(RATE), ..BudgetConfig::default() },
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    fn state(session: &Session, info_hash: &str) -> TorrentState {
+        session.status(info_hash).unwrap().state
+    }
+
+    /// Waits until one of two torrents downloads while the other is queued.
+    ///
+    /// # Returns
+    /// (downloading, queued)
+    async fn downloading_and_queued(session: &Session, a: &str, b: &str) -> (String, String) {
+        let mut found = None;
+        wait_for("a download and a queued torrent", || {
+            found = match (state(session, a), state(session, b)) {
+                (TorrentState::Downloading, TorrentState::Queued) => Some((a.to_string(), b.to_string())),
+                (TorrentState::Queued, TorrentState::Downloading) => Some((b.to_string(), a.to_string())),
+                _ => None,
+            };
+            found.is_some()
+        })
+        .await;
+        found.unwrap()
+    }
+
+    #[test]
+    fn max_active_downloads_queues_the_rest() {
+        runtime().block_on(async {
+            let tracker = Tracker::start(&["a.bin", "b.bin"]).await;
+            let out = TempDir::new("queue");
+            let session = slow_session().await;
+            let a = session.add_torrent(tracker.torrent("a.bin"), &out.0.join("a.bin"), None).unwrap();
+            let b = session.add_torrent(tracker.torrent("b.bin"), &out.0.join("b.bin"), None).unwrap();
+
+            let (first, second) = downloading_and_queued(&session, &a, &b).await;
+            wait_for("the first download", || {
+                // The slot frees only once every piece is in, so read the queued one first
+                let queued = matches!(state(&session, &second), TorrentState::Queued);
+                let status = session.status(&first).unwrap();
+                let done = status.pieces == status.piece_count;
+                assert!(queued || done, "second torrent left the queue at {}/{} pieces", status.pieces, status.piece_count);
+                done
+            })
+            .await;
+
+            session.wait_idle().await;
+            for status in session.torrents() {
+                assert!(matches!(status.state, TorrentState::Complete), "{} is {}", status.name, status.state);
+                assert_eq!(std::fs::read(&status.output).unwrap(), content(&status.name));
+            }
+            session.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn paused_torrents_resume_to_complete() {
+        runtime().block_on(async {
+            let tracker = Tracker::start(&["c.bin", "d.bin"]).await;
+            let out = TempDir::new("pause");
+            let session = slow_session().await;
+            let c = session.add_torrent(tracker.torrent("c.bin"), &out.0.join("c.bin"), None).unwrap();
+            let d = session.add_torrent(tracker.torrent("d.bin"), &out.0.join("d.bin"), None).unwrap();
+
+            let (downloading, queued) = downloading_and_queued(&session, &c, &d).await;
+            session.pause(&queued).unwrap();
+            session.pause(&downloading).unwrap();
+            assert!(session.resume(&c).is_ok() && session.pause(&c).is_ok(), "pause right after resume");
+
+            // The stopped tasks must not move either torrent on
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            for status in session.torrents() {
+                assert!(matches!(status.state, TorrentState::Paused), "{} is {}", status.name, status.state);
+            }
+            assert!(session.resume(&c).is_ok() && session.resume(&d).is_ok());
+            assert!(session.resume(&c).is_err(), "only paused torrents resume");
+
+            session.wait_idle().await;
+            for status in session.torrents() {
+                assert!(matches!(status.state, TorrentState::Complete), "{} is {}", status.name, status.state);
+                assert_eq!(std::fs::read(&status.output).unwrap(), content(&status.name));
+            }
+            session.remove(&c).unwrap();
+            assert!(session.status(&c).is_none());
+            session.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn failed_download_leaves_the_swarm() {
+        runtime().block_on(async {
+            let tracker = Tracker::start(&["e.bin"]).await;
+            // A torrent for a file the tracker doesn't have
+            let elsewhere = TempDir::new("elsewhere");
+            std::fs::write(elsewhere.0.join("missing.bin"), content("missing.bin")).unwrap();
+            let announce = format!("quic://127.0.0.1:{}/announce", tracker.port);
+            let (info_hash, _) = SeedDirectory::new(&elsewhere.0).unwrap().ensure_torrent("missing.bin", &announce).unwrap();
+            let torrent = TorrentFile::from_file(elsewhere.0.join("missing.bin.torrent").to_str().unwrap()).unwrap();
+
+            let out = TempDir::new("failed");
+            let session = slow_session().await;
+            session.add_torrent(torrent, &out.0.join("missing.bin"), None).unwrap();
+            wait_for("the download to fail", || matches!(state(&session, &info_hash), TorrentState::Error(_))).await;
+
+            let response = announce_with_response("127.0.0.1", tracker.port, &info_hash, "-TEST01-probe", 1, 0, 0, 0, Some("started"))
+                .await
+                .unwrap();
+            assert!(response.peers.iter().all(|peer| peer.port != session.port()), "failed torrent still in the swarm: {:?}", response.peers);
+            session.shutdown().await;
+        });
+    }
+}
@@ -0,0 +1,439 @@
+//! # Web Seeds
+//!
+//! Fetches pieces from plain HTTP servers listed in a torrent's `url-list`
+//! (BEP 19), as one more source next to QUIC peers and the tracker's seed
+//! directory.
+//!
+//! A URL ending in `/` names a directory and the torrent's `name` is
+//! appended; any other URL is the file itself. Blocks are read with
+//! `Range: bytes=first-last` requests over a minimal HTTP/1.1 client on a
+//! plain `TcpStream` (one connection per request, no TLS). A server that
+//! ignores `Range` and answers `200` still answers that request (the
+//! response is read up to the wanted bytes and the connection dropped), but
+//! every later block would re-read the file from the start, so the web seed
+//! is dropped after it.
+//!
+//! A web seed that fails isn't dropped at once: `Backoff` waits
+//! `WEB_SEED_RETRY_DELAY`, doubling up to `MAX_WEB_SEED_RETRY_DELAY`, and
+//! gives up after `MAX_WEB_SEED_FAILURES` failures in a row. Its blocks go
+//! back to the other sources meanwhile.
+
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Wait after the first failure of a web seed.
+pub const WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait between two attempts.
+pub const MAX_WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Failures in a row after which a web seed is dropped.
+pub const MAX_WEB_SEED_FAILURES: u32 = 5;
+
+/// How long to wait for the server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long one ranged request may take, headers and body.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest status or header line accepted.
+const MAX_HEADER_LINE: usize = 8 * 1024;
+
+/// Most header lines accepted in a response.
+const MAX_HEADERS: usize = 100;
+
+/// Errors crossing task boundaries must be `Send`.
+pub type WebSeedResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A parsed `http://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    /// Path and query, starting with `/`
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parses an `http://host[:port][/path]` URL; other schemes are rejected.
+    pub fn parse(url: &str) -> WebSeedResult<Self> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| format!("Unsupported web seed URL (only http:// is supported): {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // Bracketed IPv6 literals keep their colons
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse::<u16>().map_err(|_| format!("Invalid port in URL: {}", url))?)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in URL: {}", url).into());
+        }
+        Ok(Self {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Value of the `Host` header.
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == 80 { host } else { format!("{}:{}", host, self.port) }
+    }
+}
+
+impl std::fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.host_header(), self.path)
+    }
+}
+
+/// The URL of the torrent's file on a web seed.
+///
+/// # Arguments
+/// * `base` - A `url-list` entry
+/// * `name` - The torrent's `name`
+///
+/// # Returns
+/// `base` with the (percent-encoded) name appended if it ends in `/`,
+/// otherwise `base` unchanged
+pub fn file_url(base: &str, name: &str) -> String {
+    if base.ends_with('/') {
+        format!("{}{}", base, urlencoding::encode(name))
+    } else {
+        base.to_string()
+    }
+}
+
+/// The answer to a `fetch_range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeResponse {
+    /// Exactly the bytes asked for
+    pub data: Vec<u8>,
+    /// False if the server ignored `Range` and sent the whole file (`200`)
+    pub ranged: bool,
+}
+
+/// Reads `length` bytes at `offset` of the file at `url` with a ranged GET.
+///
+/// # Returns
+/// Exactly `length` bytes and whether the server honoured `Range`; an error
+/// for any other status than `206` or `200`, a `Content-Range` that doesn't
+/// start at `offset`, or a short body
+pub async fn fetch_range(url: &HttpUrl, offset: u64, length: u64) -> WebSeedResult<RangeResponse> {
+    if length == 0 {
+        return Ok(RangeResponse { data: Vec::new(), ranged: true });
+    }
+    tokio::time::timeout(REQUEST_TIMEOUT, fetch_range_inner(url, offset, length))
+        .await
+        .map_err(|_| format!("Request to {} timed out", url))?
+}
+
+async fn fetch_range_inner(url: &HttpUrl, offset: u64, length: u64) -> WebSeedResult<RangeResponse> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((url.host.as_str(), url.port)))
+        .await
+        .map_err(|_| format!("Connection to {} timed out", url))??;
+    let mut stream = BufReader::new(stream);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\nUser-Agent: quic-torrent/1\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n",
+        url.path, url.host_header(), offset, offset + length - 1,
+    );
+    crate::log_client_sent!("[web_seed::fetch_range] GET {} bytes={}-{}", url, offset, offset + length - 1);
+    stream.get_mut().write_all(request.as_bytes()).await?;
+
+    let status_line = read_line(&mut stream).await?;
+    let status = parse_status(&status_line)?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut stream).await?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err("Too many response headers".into());
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    if header("transfer-encoding").is_some_and(|te| !te.eq_ignore_ascii_case("identity")) {
+        return Err(format!("Unsupported transfer encoding from {}: {}", url, header("transfer-encoding").unwrap_or("")).into());
+    }
+
+    let ranged = match status {
+        206 => {
+            let range = header("content-range").ok_or("206 response without Content-Range")?;
+            let start = parse_content_range_start(range)
+                .ok_or_else(|| format!("Invalid Content-Range: {}", range))?;
+            if start != offset {
+                return Err(format!("Server sent range starting at {}, wanted {}", start, offset).into());
+            }
+            true
+        }
+        200 => {
+            // Range ignored: skip to the wanted bytes of the whole file
+            crate::log_client!("[web_seed::fetch_range] {} ignored the Range header, skipping {} bytes", url, offset);
+            let skipped = tokio::io::copy(&mut (&mut stream).take(offset), &mut tokio::io::sink()).await?;
+            if skipped < offset {
+                return Err(format!("File at {} is shorter than {} bytes", url, offset).into());
+            }
+            false
+        }
+        _ => return Err(format!("{} from {}", status_line.trim(), url).into()),
+    };
+
+    let mut data = vec![0u8; length as usize];
+    stream.read_exact(&mut data).await
+        .map_err(|e| format!("Short response from {} ({} bytes wanted): {}", url, length, e))?;
+    crate::log_client!("[web_seed::fetch_range] Received {} bytes at offset {} from {}", length, offset, url);
+    Ok(RangeResponse { data, ranged })
+}
+
+/// Reads one CRLF-terminated line, without the terminator.
+async fn read_line(stream: &mut BufReader<TcpStream>) -> WebSeedResult<String> {
+    let mut line = Vec::new();
+    let read = (&mut *stream).take(MAX_HEADER_LINE as u64).read_until(b'\n', &mut line).await?;
+    if read == 0 {
+        return Err("Connection closed before the response headers ended".into());
+    }
+    if line.last() != Some(&b'\n') {
+        return Err("Response header line too long".into());
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The status code of an `HTTP/1.x NNN Reason` line.
+fn parse_status(line: &str) -> WebSeedResult<u16> {
+    let mut parts = line.split_whitespace();
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(format!("Not an HTTP/1.x response: {}", line).into());
+    }
+    parts.next()
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Invalid status line: {}", line).into())
+}
+
+/// The first byte of a `Content-Range: bytes first-last/total` value.
+pub fn parse_content_range_start(value: &str) -> Option<u64> {
+    let range = value.trim().strip_prefix("bytes")?.trim_start();
+    let (first, _) = range.split_once('-')?;
+    first.trim().parse().ok()
+}
+
+/// Retry timing for one failing source.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    max_failures: u32,
+    /// Failures since the last success
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(WEB_SEED_RETRY_DELAY, MAX_WEB_SEED_RETRY_DELAY, MAX_WEB_SEED_FAILURES)
+    }
+}
+
+impl Backoff {
+    /// # Arguments
+    /// * `base` - Wait after the first failure
+    /// * `max` - Longest wait
+    /// * `max_failures` - Failures in a row before giving up
+    pub fn new(base: Duration, max: Duration, max_failures: u32) -> Self {
+        Self { base, max, max_failures, failures: 0, retry_at: None }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// True unless waiting after a failure.
+    pub fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    /// When the source may be tried again (`now` if it already may).
+    pub fn retry_at(&self, now: Instant) -> Instant {
+        self.retry_at.map_or(now, |at| at.max(now))
+    }
+
+    /// Records a failure.
+    ///
+    /// # Returns
+    /// When to try again; `None` once `max_failures` is reached
+    pub fn failure(&mut self, now: Instant) -> Option<Instant> {
+        self.failures += 1;
+        if self.failures >= self.max_failures {
+            self.retry_at = None;
+            return None;
+        }
+        let delay = self.base
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(self.max);
+        self.retry_at = Some(now + delay);
+        self.retry_at
+    }
+
+    /// Records a success, resetting the delay.
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// The file the test servers hold.
+    fn file() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Starts an HTTP server on loopback answering every request with
+    /// `respond(range)`, where `range` is the requested `(first, last)`.
+    async fn serve(respond: fn(Option<(u64, u64)>) -> Vec<u8>) -> HttpUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut range = None;
+                    loop {
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 || line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some(value) = line.trim().strip_prefix("Range: bytes=") {
+                            let (first, last) = value.split_once('-').unwrap();
+                            range = Some((first.parse().unwrap(), last.parse().unwrap()));
+                        }
+                    }
+                    // The client hangs up once it has its bytes
+                    let _ = stream.get_mut().write_all(&respond(range)).await;
+                });
+            }
+        });
+        HttpUrl::parse(&format!("http://127.0.0.1:{}/file.bin", port)).unwrap()
+    }
+
+    fn response(head: String, body: &[u8]) -> Vec<u8> {
+        let mut response = head.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn partial(range: Option<(u64, u64)>) -> Vec<u8> {
+        let (first, last) = range.unwrap();
+        let file = file();
+        let body = &file[first as usize..=last as usize];
+        response(format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+            first, last, file.len(), body.len(),
+        ), body)
+    }
+
+    fn whole(_: Option<(u64, u64)>) -> Vec<u8> {
+        let file = file();
+        response(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", file.len()), &file)
+    }
+
+    fn wrong_range(range: Option<(u64, u64)>) -> Vec<u8> {
+        let (first, last) = range.unwrap();
+        let body = vec![0u8; (last - first + 1) as usize];
+        response(format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-{}/10000\r\n\r\n", last - first), &body)
+    }
+
+    fn garbled_range(_: Option<(u64, u64)>) -> Vec<u8> {
+        response("HTTP/1.1 206 Partial Content\r\nContent-Range: pages 1-2\r\n\r\n".to_string(), b"xx")
+    }
+
+    fn not_found(_: Option<(u64, u64)>) -> Vec<u8> {
+        response("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(), b"")
+    }
+
+    #[tokio::test]
+    async fn reads_a_partial_response() {
+        let url = serve(partial).await;
+        let response = fetch_range(&url, 1000, 3000).await.unwrap();
+        assert!(response.ranged);
+        assert_eq!(response.data, file()[1000..4000]);
+    }
+
+    #[tokio::test]
+    async fn skips_to_the_range_when_the_server_ignores_it() {
+        let url = serve(whole).await;
+        let response = fetch_range(&url, 5000, 2000).await.unwrap();
+        assert!(!response.ranged);
+        assert_eq!(response.data, file()[5000..7000]);
+
+        assert!(fetch_range(&url, 9000, 2000).await.is_err(), "past the end of the file");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_content_range() {
+        let url = serve(wrong_range).await;
+        let e = fetch_range(&url, 1000, 100).await.unwrap_err();
+        assert!(e.to_string().contains("starting at 0"), "{}", e);
+
+        let url = serve(garbled_range).await;
+        let e = fetch_range(&url, 0, 2).await.unwrap_err();
+        assert!(e.to_string().contains("Invalid Content-Range"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_statuses() {
+        let url = serve(not_found).await;
+        let e = fetch_range(&url, 0, 100).await.unwrap_err();
+        assert!(e.to_string().contains("404"), "{}", e);
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(parse_content_range_start("bytes 100-199/1000"), Some(100));
+        assert_eq!(parse_content_range_start("bytes 0-0/*"), Some(0));
+        assert_eq!(parse_content_range_start("bytes */1000"), None);
+        assert_eq!(parse_content_range_start("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_and_gives_up() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(15), 4);
+        assert!(backoff.ready(start));
+
+        assert_eq!(backoff.failure(start), Some(start + Duration::from_secs(5)));
+        assert!(!backoff.ready(start + Duration::from_secs(4)));
+        assert!(backoff.ready(start + Duration::from_secs(5)));
+        assert_eq!(backoff.failure(start), Some(start + Duration::from_secs(10)));
+        assert_eq!(backoff.failure(start), Some(start + Duration::from_secs(15)), "capped");
+        assert_eq!(backoff.failure(start), None, "gave up");
+        assert_eq!(backoff.failures(), 4);
+    }
+
+    #[test]
+    fn backoff_resets_on_success() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60), 3);
+        backoff.failure(start);
+        backoff.failure(start);
+        backoff.success();
+        assert!(backoff.ready(start));
+        assert_eq!(backoff.retry_at(start), start);
+        assert_eq!(backoff.failure(start), Some(start + Duration::from_secs(5)));
+    }
+}
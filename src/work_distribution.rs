@@ -3,7 +3,8 @@
 //! Implements weighted node tables for work delegation.
 //! Allows nodes to hand work to each other based on weighted IP address tables.
 
-use crate::messages::{AiRequest, AiResponse};
+use crate::messages::{AiRequest, AiResponse, ServerEvent};
+use crate::events::EventBus;
 use crate::quic_client::QuicClient;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -24,6 +25,18 @@ pub enum NodeCapability {
     Custom(String),
 }
 
+impl NodeCapability {
+    /// Name of the capability as sent to event subscribers.
+    pub fn name(&self) -> String {
+        match self {
+            NodeCapability::AiProcessing => "ai_processing".to_string(),
+            NodeCapability::FileServing => "file_serving".to_string(),
+            NodeCapability::Tracker => "tracker".to_string(),
+            NodeCapability::Custom(name) => name.clone(),
+        }
+    }
+}
+
 /// Node information for work distribution
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeInfo {
@@ -69,6 +82,7 @@ impl NodeInfo {
 pub struct WorkDistributionManager {
     nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,  // node_id -> NodeInfo
     capability_tables: Arc<RwLock<HashMap<NodeCapability, Vec<String>>>>,  // capability -> node_ids
+    events: Option<EventBus>,  // node registrations for subscribers
 }
 
 impl WorkDistributionManager {
@@ -76,9 +90,16 @@ impl WorkDistributionManager {
         Self {
             nodes: Arc::new(RwLock::new(HashMap::new())),
             capability_tables: Arc::new(RwLock::new(HashMap::new())),
+            events: None,
         }
     }
 
+    /// Publishes node registrations on `events` (`ServerEvent::WorkNode`).
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
     /// Register a node with its capabilities and weight
     pub fn register_node(&self, node_id: String, node_info: NodeInfo) {
         let mut nodes = self.nodes.write().unwrap();
@@ -95,9 +116,18 @@ impl WorkDistributionManager {
         
         // Add new entries
         for cap in &node_info.capabilities {
-            tables.entry(cap.clone()).or_insert_with(Vec::new).push(node_id.clone());
+            tables.entry(cap.clone()).or_default().push(node_id.clone());
         }
         
+        if let Some(events) = &self.events {
+            events.publish(ServerEvent::WorkNode {
+                node_id: node_id.clone(),
+                ip: node_info.ip.clone(),
+                port: node_info.port,
+                capabilities: node_info.capabilities.iter().map(NodeCapability::name).collect(),
+                weight: node_info.weight,
+            });
+        }
         nodes.insert(node_id, node_info);
         crate::log_server!("[WORK_DIST] Registered node with capabilities");
     }
@@ -152,12 +182,19 @@ impl WorkDistributionManager {
     }
 
     /// Delegate AI work to another node
+    ///
+    /// # Arguments
+    /// * `request` - The AI request to forward
+    /// * `capability` - The capability the chosen node must have
+    /// * `request_id` - Id of the client's request; the remote node gets the
+    ///   work under the same id, so its log lines can be joined with ours
     pub async fn delegate_ai_work(
         &self,
         request: &AiRequest,
         capability: &NodeCapability,
+        request_id: &str,
     ) -> Result<AiResponse, String> {
-        crate::log_server!("[WORK_DIST] Delegating AI work - capability: {:?}", capability);
+        crate::log_server!("[WORK_DIST] Delegating AI work - request_id: {}, capability: {:?}", request_id, capability);
         
         let (node_id, node_info) = match self.select_node(capability) {
             Some(n) => n,
@@ -186,9 +223,9 @@ impl WorkDistributionManager {
         crate::log_server!("[WORK_DIST] Request type: AiRequest");
         crate::log_server!("[WORK_DIST] Sending work request to remote node...");
         
-        let client = match QuicClient::new() {
+        let client = match QuicClient::shared() {
             Ok(c) => {
-                crate::log_server!("[WORK_DIST] QUIC client created");
+                crate::log_server!("[WORK_DIST] QUIC client ready (pooled connections)");
                 c
             }
             Err(e) => {
@@ -204,7 +241,8 @@ impl WorkDistributionManager {
         crate::log_server!("[WORK_DIST] Data sent to: Remote node {}:{}", node_info.ip, node_info.port);
         crate::log_server!("[WORK_DIST] Remote node will process via: quic_tracker::handle_ai_request() -> ai_processor::process_query_sync()");
         
-        let result = client.send_message::<_, AiResponse>(&node_info.ip, node_info.port, request).await;
+        let result = crate::logger::with_request_id(request_id.to_string(), 
+            client.send_message::<_, AiResponse>(&node_info.ip, node_info.port, request)).await;
         
         // Decrement active requests
         {